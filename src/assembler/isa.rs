@@ -0,0 +1,233 @@
+//! Instruction set tables, in the same TOML format as the files found in «cfg»
+//!
+//! The tables describe every instruction format field by field and every instruction as a format plus a list of fixed fields, so encoding an instruction only needs to scatter each field's bits as described by its encoding
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use serde::Deserialize;
+
+/// The RV32I base integer instruction set table
+pub const RV32I: &str = include_str!("../../cfg/rv32i.toml");
+/// The integer multiplication and division extension table
+pub const RV32M: &str = include_str!("../../cfg/rv32m.toml");
+/// The atomic instructions extension table
+pub const RV32A: &str = include_str!("../../cfg/rv32a.toml");
+/// The control and status register instructions table
+pub const ZICSR: &str = include_str!("../../cfg/zicsr.toml");
+/// The instruction-fetch fence table
+pub const ZIFENCEI: &str = include_str!("../../cfg/zifencei.toml");
+/// The machine-level privileged instructions table (trap return and wait for interrupt)
+pub const MACHINE: &str = include_str!("../../cfg/machine.toml");
+/// Extension tables bundled with the built-in assembler
+pub const EXTENSIONS: &[&str] = &[RV32M, RV32A, ZICSR, ZIFENCEI, MACHINE];
+
+/// Returns the instruction set the built-in assembler targets (RV32I plus every bundled extension), parsed only once
+pub fn builtin() -> &'static Isa {
+    static ISA: OnceLock<Isa> = OnceLock::new();
+    ISA.get_or_init(|| {
+        let mut isa = Isa::from_toml(RV32I).expect("The bundled RV32I table should be valid");
+        for extension in EXTENSIONS {
+            isa.merge(Isa::from_toml(extension).expect("The bundled tables should be valid"));
+        }
+        isa
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Isa {
+    pub meta: Meta,
+    /// Numeric constants, e.g. XLEN
+    #[serde(default)]
+    pub consts: HashMap<String, i64>,
+    #[serde(default)]
+    pub registers: Registers,
+    /// Format name -> (field name -> field)
+    #[serde(default)]
+    pub instruction_formats: HashMap<String, HashMap<String, Field>>,
+    /// Mnemonic -> instruction
+    #[serde(default)]
+    pub instructions: HashMap<String, InstructionSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Meta {
+    /// Name of the ISA/extension
+    pub name: String,
+    /// ISA letter code (e.g. RV32I)
+    pub code: String,
+    /// Version of the specification document the table is based on
+    pub spec: String,
+    /// Required base ISAs
+    #[serde(default)]
+    pub requires: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Registers {
+    /// Register number -> [name, optional ABI name, optional extra names]
+    #[serde(default)]
+    pub names: HashMap<String, Vec<String>>,
+    /// Register number -> size in bits (or the name of a constant)
+    #[serde(default)]
+    pub lengths: HashMap<String, toml::Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldKind {
+    Value,
+    Register,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Field {
+    #[serde(rename = "type")]
+    pub kind: FieldKind,
+    /// Total field size in bits
+    pub length: u32,
+    /// Every entry [vlast, vfirst, ifirst] copies bits [vlast:vfirst] of the value to bits [ifirst+vlast-vfirst:ifirst] of the instruction
+    pub encoding: Vec<[u32; 3]>,
+    /// Wether the value is sign extended by the hardware, by default this is the case if the field holds the most significant bit of the instruction
+    #[serde(default)]
+    pub signed: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InstructionSpec {
+    /// One of the instruction formats of the table
+    pub format: String,
+    /// Fields of the format, in the order they appear as arguments
+    pub args: Vec<String>,
+    /// Fields that are set to a constant value
+    #[serde(default)]
+    pub fields: HashMap<String, i64>,
+}
+
+impl Field {
+    /// Number of meaningful bits of the value, which isn't necessarily «length» (e.g. the J-type immediate is 21 bits wide)
+    fn width(&self) -> u32 {
+        self.encoding
+            .iter()
+            .map(|[vlast, _, _]| vlast + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Lowest bit of the value that is actually encoded, every bit below it must be zero (e.g. branch offsets are always even)
+    fn lowest_bit(&self) -> u32 {
+        self.encoding
+            .iter()
+            .map(|[_, vfirst, _]| *vfirst)
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.signed.unwrap_or_else(|| {
+            self.encoding
+                .iter()
+                .any(|[vlast, vfirst, ifirst]| ifirst + vlast - vfirst == 31)
+        })
+    }
+
+    /// Checks that the value can be represented by the field, either as a signed or as an unsigned number
+    pub fn check(&self, value: i64, signed: bool) -> Result<(), String> {
+        let width = self.width();
+        let (min, max) = if signed {
+            (-(1i64 << (width - 1)), (1i64 << (width - 1)) - 1)
+        } else {
+            (0, (1i64 << width) - 1)
+        };
+        if value < min || value > max {
+            return Err(format!(
+                "Value {value} doesn't fit in {width} bits (allowed range is {min}..={max})"
+            ));
+        }
+        let lowest_bit = self.lowest_bit();
+        if value & ((1 << lowest_bit) - 1) != 0 {
+            return Err(format!(
+                "Value {value} must be a multiple of {}",
+                1 << lowest_bit
+            ));
+        }
+        Ok(())
+    }
+
+    /// Scatters the bits of the value in an instruction, as described by the field's encoding
+    pub fn encode(&self, value: i64) -> u32 {
+        self.encoding
+            .iter()
+            .fold(0, |instruction, [vlast, vfirst, ifirst]| {
+                let mask = (1u64 << (vlast - vfirst + 1)) - 1;
+                let bits = ((value >> vfirst) as u64 & mask) as u32;
+                instruction | bits << ifirst
+            })
+    }
+}
+
+impl Isa {
+    pub fn from_toml(source: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(source)
+    }
+
+    /// Adds the constants, registers, formats and instructions of an extension to the table
+    pub fn merge(&mut self, extension: Isa) {
+        self.consts.extend(extension.consts);
+        self.registers.names.extend(extension.registers.names);
+        self.registers.lengths.extend(extension.registers.lengths);
+        self.instruction_formats
+            .extend(extension.instruction_formats);
+        self.instructions.extend(extension.instructions);
+    }
+
+    /// Looks up a register by any of its names (e.g. x8, s0 and fp are all register 8)
+    pub fn register(&self, name: &str) -> Option<u32> {
+        self.registers
+            .names
+            .iter()
+            .find(|(_, names)| names.iter().any(|n| n == name))
+            .and_then(|(number, _)| number.parse().ok())
+    }
+
+    /// Returns the ABI name of a register (e.g. «a0» for register 10), or its only name if it doesn't have one
+    pub fn register_abi_name(&self, number: u32) -> Option<&str> {
+        let names = self.registers.names.get(&number.to_string())?;
+        names.get(1).or(names.first()).map(String::as_str)
+    }
+
+    pub fn instruction(&self, mnemonic: &str) -> Option<&InstructionSpec> {
+        self.instructions.get(mnemonic)
+    }
+
+    pub fn format(&self, name: &str) -> Option<&HashMap<String, Field>> {
+        self.instruction_formats.get(name)
+    }
+
+    /// Encodes an instruction given a value for each of its arguments
+    pub fn encode(&self, mnemonic: &str, args: &HashMap<&str, i64>) -> Result<u32, String> {
+        let spec = self
+            .instruction(mnemonic)
+            .ok_or_else(|| format!("Unknown instruction «{mnemonic}»"))?;
+        let format = self
+            .format(&spec.format)
+            .ok_or_else(|| format!("Unknown instruction format «{}»", spec.format))?;
+        let mut instruction = 0;
+        for (name, field) in format {
+            // Constant fields are just bit patterns, while arguments are numbers
+            let (value, signed) = match spec.fields.get(name) {
+                Some(value) => (*value, false),
+                None => (
+                    *args
+                        .get(name.as_str())
+                        .ok_or_else(|| format!("Missing value for field «{name}»"))?,
+                    field.kind == FieldKind::Value && field.is_signed(),
+                ),
+            };
+            field
+                .check(value, signed)
+                .map_err(|error| format!("Invalid {name}: {error}"))?;
+            instruction |= field.encode(value);
+        }
+        Ok(instruction)
+    }
+}
@@ -0,0 +1,569 @@
+//! Built-in assembler for RV32I programs
+//!
+//! Instructions are encoded by the tables in «cfg» (see the «isa» module), pseudo-instructions are expanded by the «pseudo» module.
+//! The text section is placed at «RAM_BASE», the data section right after it
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    machine::memory::{
+        constants::{MEMORY_SIZE, RAM_BASE},
+        MemoryDump,
+    },
+    source_map::{SourceLocation, SourceMap},
+};
+
+use self::{
+    isa::{FieldKind, Isa},
+    parser::{Atom, Expr, Modifier, Operand, Statement},
+};
+
+pub mod isa;
+pub mod parser;
+pub mod pseudo;
+
+/// The encoding of a «nop», used to pad the text section
+const NOP: u32 = 0x00000013;
+
+/// An error, together with the line of source code it was found on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblyError {
+    /// Line number, starting from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+/// An assembled program
+#[derive(Debug, Default)]
+pub struct Program {
+    /// Address at which «contents» should be loaded
+    pub base: u32,
+    pub contents: MemoryDump,
+    /// Address of every label and value of every constant
+    pub symbols: HashMap<String, i64>,
+    /// Line each instruction comes from
+    pub source_map: SourceMap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Text,
+    Data,
+}
+
+/// Where a label points to, before the sections are placed in memory
+#[derive(Debug, Clone, Copy)]
+enum Symbol {
+    Label(Section, u32),
+    Constant(i64),
+}
+
+/// A base instruction whose operands are yet to be resolved
+struct PendingInstruction {
+    line: usize,
+    offset: u32,
+    mnemonic: String,
+    operands: Vec<Operand>,
+}
+
+/// Data whose value is yet to be resolved (e.g. «.word label»)
+struct PendingData {
+    line: usize,
+    section: Section,
+    offset: u32,
+    /// Size in bytes
+    size: u32,
+    value: Expr,
+}
+
+/// Assembles a program for the RV32I base integer instruction set and the bundled extensions, reporting every error found
+pub fn assemble(source: &str) -> Result<Program, Vec<AssemblyError>> {
+    Assembler::new(isa::builtin(), RAM_BASE as u32).assemble(source)
+}
+
+pub struct Assembler<'a> {
+    isa: &'a Isa,
+    base: u32,
+    errors: Vec<AssemblyError>,
+    symbols: HashMap<String, Symbol>,
+    section: Section,
+    text: Vec<u8>,
+    data: Vec<u8>,
+    /// Largest alignment requested in the data section
+    data_alignment: u32,
+    instructions: Vec<PendingInstruction>,
+    pending_data: Vec<PendingData>,
+    /// Value every «auipc» with a %pcrel_hi() operand takes the upper bits of, by offset in the text section
+    pcrel_hi: HashMap<u32, Expr>,
+    /// Labels put on the «auipc» of pseudo-instructions (e.g. «la»), which aren't symbols of the program
+    local_labels: HashMap<String, u32>,
+}
+
+impl<'a> Assembler<'a> {
+    pub fn new(isa: &'a Isa, base: u32) -> Self {
+        Self {
+            isa,
+            base,
+            errors: vec![],
+            symbols: HashMap::new(),
+            section: Section::Text,
+            text: vec![],
+            data: vec![],
+            data_alignment: 4,
+            instructions: vec![],
+            pending_data: vec![],
+            pcrel_hi: HashMap::new(),
+            local_labels: HashMap::new(),
+        }
+    }
+
+    pub fn assemble(mut self, source: &str) -> Result<Program, Vec<AssemblyError>> {
+        // First pass: collect labels and lay out every section
+        for (i, text) in source.lines().enumerate() {
+            let line = i + 1;
+            if let Err(message) = self.first_pass(line, text) {
+                self.errors.push(AssemblyError { line, message });
+            }
+        }
+
+        // The data section follows the text section
+        let text_size = self.text.len() as u32;
+        let data_base = align(self.base + text_size, self.data_alignment);
+        let symbols: HashMap<String, i64> = self
+            .symbols
+            .iter()
+            .map(|(name, symbol)| {
+                let value = match *symbol {
+                    Symbol::Label(Section::Text, offset) => (self.base + offset) as i64,
+                    Symbol::Label(Section::Data, offset) => (data_base + offset) as i64,
+                    Symbol::Constant(value) => value,
+                };
+                (name.clone(), value)
+            })
+            .collect();
+
+        // Second pass: resolve and encode
+        let mut source_map = SourceMap::default();
+        for instruction in std::mem::take(&mut self.instructions) {
+            let address = self.base + instruction.offset;
+            source_map.insert(
+                address,
+                4,
+                SourceLocation {
+                    file: None,
+                    line: instruction.line,
+                },
+            );
+            match self.encode(&instruction, address, &symbols) {
+                Ok(encoded) => {
+                    let offset = instruction.offset as usize;
+                    self.text[offset..offset + 4].copy_from_slice(&encoded.to_le_bytes());
+                }
+                Err(message) => self.errors.push(AssemblyError {
+                    line: instruction.line,
+                    message,
+                }),
+            }
+        }
+        let isa = self.isa;
+        for data in std::mem::take(&mut self.pending_data) {
+            let (section_base, bytes) = match data.section {
+                Section::Text => (self.base, &mut self.text),
+                Section::Data => (data_base, &mut self.data),
+            };
+            let value = data
+                .value
+                .evaluate(section_base + data.offset, |name| {
+                    symbols.get(name).or(isa.consts.get(name)).copied()
+                })
+                .and_then(|value| check_data(value, data.size));
+            match value {
+                Ok(value) => {
+                    let offset = data.offset as usize;
+                    bytes[offset..offset + data.size as usize]
+                        .copy_from_slice(&value.to_le_bytes()[..data.size as usize]);
+                }
+                Err(message) => self.errors.push(AssemblyError {
+                    line: data.line,
+                    message,
+                }),
+            }
+        }
+
+        if !self.errors.is_empty() {
+            self.errors.sort_by_key(|error| error.line);
+            return Err(self.errors);
+        }
+
+        let mut contents = self.text;
+        contents.resize((data_base - self.base) as usize, 0);
+        contents.append(&mut self.data);
+        Ok(Program {
+            base: self.base,
+            contents,
+            symbols,
+            source_map,
+        })
+    }
+
+    fn current(&mut self) -> &mut Vec<u8> {
+        match self.section {
+            Section::Text => &mut self.text,
+            Section::Data => &mut self.data,
+        }
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol) -> Result<(), String> {
+        if self.isa.register(name).is_some() {
+            return Err(format!("«{name}» is a register name"));
+        }
+        if self.symbols.insert(name.to_owned(), symbol).is_some() {
+            return Err(format!("Symbol «{name}» is defined more than once"));
+        }
+        Ok(())
+    }
+
+    /// Evaluates an expression that can only refer to constants defined so far
+    fn constant(&self, expr: &Expr) -> Option<i64> {
+        expr.evaluate(0, |name| match self.symbols.get(name) {
+            Some(Symbol::Constant(value)) => Some(*value),
+            Some(Symbol::Label(..)) => None,
+            None => self.isa.consts.get(name).copied(),
+        })
+        .ok()
+    }
+
+    fn first_pass(&mut self, line: usize, text: &str) -> Result<(), String> {
+        let parsed = parser::parse_line(text)?;
+        for label in parsed.labels {
+            let offset = self.current().len() as u32;
+            self.define(&label, Symbol::Label(self.section, offset))?;
+        }
+        match parsed.statement {
+            Some(Statement::Directive { name, args }) => self.directive(line, &name, &args),
+            Some(Statement::Instruction { mnemonic, operands }) => {
+                self.instruction(line, mnemonic, operands)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn instruction(
+        &mut self,
+        line: usize,
+        mnemonic: String,
+        operands: Vec<Operand>,
+    ) -> Result<(), String> {
+        if self.section != Section::Text {
+            return Err("Instructions can only be placed in the text section".to_owned());
+        }
+        if !self.text.len().is_multiple_of(4) {
+            return Err("Instructions must be aligned to 4 bytes, use .align 2".to_owned());
+        }
+        // Named like the ones the GNU assembler uses, so that they can't clash with the program's symbols
+        let auipc_label = format!(".Lpcrel_hi{}", self.local_labels.len());
+        let expansion = match pseudo::expand(&mnemonic, &operands, &auipc_label, |expr| {
+            self.constant(expr)
+        })? {
+            Some(expansion) => {
+                if expansion
+                    .first()
+                    .is_some_and(|(first, _)| *first == "auipc")
+                {
+                    self.local_labels
+                        .insert(auipc_label, self.text.len() as u32);
+                }
+                expansion
+                    .into_iter()
+                    .map(|(mnemonic, operands)| (mnemonic.to_owned(), operands))
+                    .collect()
+            }
+            None if self.isa.instruction(&mnemonic).is_some() => vec![(mnemonic, operands)],
+            None if pseudo::is_pseudo(&mnemonic) => {
+                return Err(format!("Wrong number of operands for «{mnemonic}»"))
+            }
+            None => return Err(format!("Unknown instruction «{mnemonic}»")),
+        };
+        for (mnemonic, operands) in expansion {
+            if let ("auipc", Some(Operand::Expr(expr))) = (mnemonic.as_str(), operands.get(1)) {
+                if expr.modifier == Modifier::PcrelHi {
+                    self.pcrel_hi.insert(
+                        self.text.len() as u32,
+                        expr.clone().with_modifier(Modifier::None),
+                    );
+                }
+            }
+            self.instructions.push(PendingInstruction {
+                line,
+                offset: self.text.len() as u32,
+                mnemonic,
+                operands,
+            });
+            self.text.extend_from_slice(&[0; 4]);
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, line: usize, name: &str, args: &[String]) -> Result<(), String> {
+        match name {
+            ".text" => self.section = Section::Text,
+            ".data" | ".rodata" | ".bss" => self.section = Section::Data,
+            ".section" => {
+                let section = args.first().ok_or("Expected a section name")?;
+                self.section = if section.starts_with(".text") {
+                    Section::Text
+                } else {
+                    Section::Data
+                };
+            }
+            // Every symbol is visible anyway, since there is no linking
+            ".globl" | ".global" | ".local" | ".type" | ".size" | ".file" | ".option" => {}
+            ".equ" | ".set" => {
+                let [symbol, value] = args else {
+                    return Err(format!("Expected «{name} symbol, value»"));
+                };
+                let value = self
+                    .constant(&parser::parse_expr(value)?)
+                    .ok_or("The value must be a constant")?;
+                self.define(symbol, Symbol::Constant(value))?;
+            }
+            ".byte" | ".half" | ".short" | ".2byte" | ".word" | ".long" | ".4byte" | ".dword"
+            | ".quad" | ".8byte" => {
+                let size = match name {
+                    ".byte" => 1,
+                    ".half" | ".short" | ".2byte" => 2,
+                    ".word" | ".long" | ".4byte" => 4,
+                    _ => 8,
+                };
+                for arg in args {
+                    let value = parser::parse_expr(arg)?;
+                    let offset = self.current().len() as u32;
+                    match self.constant(&value) {
+                        Some(value) => {
+                            let value = check_data(value, size)?;
+                            self.current()
+                                .extend_from_slice(&value.to_le_bytes()[..size as usize]);
+                        }
+                        None => {
+                            self.pending_data.push(PendingData {
+                                line,
+                                section: self.section,
+                                offset,
+                                size,
+                                value,
+                            });
+                            self.current().resize((offset + size) as usize, 0);
+                        }
+                    }
+                }
+            }
+            ".ascii" | ".asciz" | ".string" => {
+                for arg in args {
+                    let mut bytes = parser::parse_string(arg)?;
+                    if name != ".ascii" {
+                        bytes.push(0);
+                    }
+                    self.current().append(&mut bytes);
+                }
+            }
+            ".space" | ".skip" | ".zero" => {
+                let (size, fill) = match args {
+                    [size] => (size, None),
+                    [size, fill] if name != ".zero" => (size, Some(fill)),
+                    _ => return Err(format!("Expected «{name} size»")),
+                };
+                let size = self.constant_arg(size)?;
+                let fill = match fill {
+                    Some(fill) => self.constant_arg(fill)? as u8,
+                    None => 0,
+                };
+                let size = usize::try_from(size).map_err(|_| "The size can't be negative")?;
+                // Checked here, so that a typo in the size doesn't allocate gigabytes before failing to load
+                if self.text.len() + self.data.len() + size > MEMORY_SIZE {
+                    return Err(format!(
+                        "The program doesn't fit in memory ({MEMORY_SIZE} bytes)"
+                    ));
+                }
+                let end = self.current().len() + size;
+                self.current().resize(end, fill);
+            }
+            ".align" | ".p2align" | ".balign" => {
+                let [alignment, ..] = args else {
+                    return Err(format!("Expected «{name} alignment»"));
+                };
+                let alignment = self.constant_arg(alignment)?;
+                let alignment = if name == ".balign" {
+                    alignment
+                } else {
+                    1i64.checked_shl(alignment as u32).unwrap_or(0)
+                };
+                if alignment <= 0 || alignment > 4096 || alignment & (alignment - 1) != 0 {
+                    return Err("The alignment must be a power of two, up to 4096".to_owned());
+                }
+                self.align(alignment as u32);
+            }
+            _ => return Err(format!("Unknown directive «{name}»")),
+        }
+        Ok(())
+    }
+
+    fn constant_arg(&self, arg: &str) -> Result<i64, String> {
+        self.constant(&parser::parse_expr(arg)?)
+            .ok_or_else(|| format!("«{arg}» must be a constant"))
+    }
+
+    /// Pads the current section to the alignment, with «nop»s in the text section
+    fn align(&mut self, alignment: u32) {
+        let section = self.section;
+        let bytes = self.current();
+        let end = align(bytes.len() as u32, alignment) as usize;
+        if section == Section::Text && bytes.len().is_multiple_of(4) {
+            while bytes.len() < end {
+                bytes.extend_from_slice(&NOP.to_le_bytes());
+            }
+        } else {
+            bytes.resize(end, 0);
+        }
+        if section == Section::Data {
+            self.data_alignment = self.data_alignment.max(alignment);
+        }
+    }
+
+    /// Resolves the operands of an instruction and encodes it
+    fn encode(
+        &self,
+        instruction: &PendingInstruction,
+        address: u32,
+        symbols: &HashMap<String, i64>,
+    ) -> Result<u32, String> {
+        let mnemonic = instruction.mnemonic.as_str();
+        let spec = self
+            .isa
+            .instruction(mnemonic)
+            .ok_or_else(|| format!("Unknown instruction «{mnemonic}»"))?;
+        let format = self
+            .isa
+            .format(&spec.format)
+            .ok_or_else(|| format!("Unknown instruction format «{}»", spec.format))?;
+        // Branches and jumps take the offset from the instruction itself
+        let pc_relative = matches!(spec.format.as_str(), "B" | "J");
+        // Upper immediates are written without their lower 12 bits (e.g. «lui a0, 0x12345»)
+        let upper = spec.format == "U";
+
+        // Pair every argument of the instruction with the expression giving its value, a memory operand fills two arguments
+        let mut operands = instruction.operands.iter();
+        let mut assignments: Vec<(&str, Expr)> = vec![];
+        let mut args = spec.args.iter().map(String::as_str).peekable();
+        while let Some(arg) = args.next() {
+            match operands.next() {
+                Some(Operand::Expr(expr)) => assignments.push((arg, expr.clone())),
+                Some(Operand::Memory { offset, base }) => {
+                    let second = args.next();
+                    match (arg, second) {
+                        ("imm", Some("rs1")) => {
+                            assignments.push(("imm", offset.clone()));
+                            assignments.push(("rs1", Expr::symbol(base)));
+                        }
+                        ("rs1", Some("imm")) => {
+                            assignments.push(("rs1", Expr::symbol(base)));
+                            assignments.push(("imm", offset.clone()));
+                        }
+                        // Atomic instructions take a bare base register (e.g. «lr.w a0, (a1)»)
+                        ("rs1", None) if offset.evaluate(address, |_| None) == Ok(0) => {
+                            assignments.push(("rs1", Expr::symbol(base)));
+                        }
+                        _ => return Err(format!("Unexpected memory operand for «{mnemonic}»")),
+                    }
+                }
+                None => return Err(format!("«{mnemonic}» expects {} operands", spec.args.len())),
+            }
+        }
+        if operands.next().is_some() {
+            return Err(format!("Too many operands for «{mnemonic}»"));
+        }
+
+        let mut values = HashMap::new();
+        for (arg, expr) in assignments {
+            let field = format
+                .get(arg)
+                .ok_or_else(|| format!("Unknown field «{arg}» in format «{}»", spec.format))?;
+            let value = match field.kind {
+                FieldKind::Register => {
+                    let name = expr
+                        .as_identifier()
+                        .ok_or_else(|| format!("Expected a register for {arg}"))?;
+                    self.isa
+                        .register(name)
+                        .ok_or_else(|| format!("Unknown register «{name}»"))?
+                        as i64
+                }
+                FieldKind::Value if expr.modifier == Modifier::PcrelLo => {
+                    self.pcrel_lo(&expr, symbols)?
+                }
+                FieldKind::Value => {
+                    // Constants of the table (e.g. CSR names) can be shadowed by the program's own symbols
+                    let value = expr.evaluate(address, |name| {
+                        symbols.get(name).or(self.isa.consts.get(name)).copied()
+                    })?;
+                    if pc_relative && expr.references_symbols() {
+                        value - address as i64
+                    } else if upper {
+                        if !(-0x80000..=0xfffff).contains(&value) {
+                            return Err(format!("Value {value} doesn't fit in 20 bits"));
+                        }
+                        // Sign extended as the hardware would, since the field is checked as a signed one
+                        (value << 12) as i32 as i64
+                    } else {
+                        value
+                    }
+                }
+            };
+            values.insert(arg, value);
+        }
+        self.isa.encode(mnemonic, &values)
+    }
+
+    /// Evaluates «%pcrel_lo(label)», where «label» points to an «auipc» taking the upper bits of a value with %pcrel_hi(): the result is the lower bits of the same value, relative to that «auipc»
+    fn pcrel_lo(&self, expr: &Expr, symbols: &HashMap<String, i64>) -> Result<i64, String> {
+        let [(false, Atom::Symbol(label))] = expr.terms.as_slice() else {
+            return Err("%pcrel_lo() takes the label of an auipc".to_owned());
+        };
+        let address = match self.local_labels.get(label) {
+            Some(offset) => (self.base + offset) as i64,
+            None => *symbols
+                .get(label)
+                .ok_or_else(|| format!("Undefined symbol «{label}»"))?,
+        };
+        let auipc = u32::try_from(address - self.base as i64)
+            .ok()
+            .and_then(|offset| self.pcrel_hi.get(&offset))
+            .ok_or_else(|| {
+                format!("«{label}» doesn't label an auipc with a %pcrel_hi() operand")
+            })?;
+        let value = auipc.evaluate(address as u32, |name| {
+            symbols.get(name).or(self.isa.consts.get(name)).copied()
+        })?;
+        Ok(parser::lo(value - address))
+    }
+}
+
+fn align(value: u32, alignment: u32) -> u32 {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+/// Checks that a value fits in «size» bytes, either as a signed or an unsigned number
+fn check_data(value: i64, size: u32) -> Result<i64, String> {
+    if size < 8 {
+        let bits = size * 8;
+        if value < -(1 << (bits - 1)) || value >= 1 << bits {
+            return Err(format!("Value {value} doesn't fit in {size} bytes"));
+        }
+    }
+    Ok(value)
+}
@@ -0,0 +1,395 @@
+//! Line oriented parser for the assembly language, in a subset of the GNU assembler syntax
+
+/// What a single line of source code contains, without its labels
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    /// e.g. .word 1, 2, 3
+    Directive { name: String, args: Vec<String> },
+    /// e.g. lw a0, 4(sp)
+    Instruction {
+        mnemonic: String,
+        operands: Vec<Operand>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// A register name or an expression
+    Expr(Expr),
+    /// offset(base), as used by loads and stores
+    Memory { offset: Expr, base: String },
+}
+
+/// Relocation-like functions that can be applied to an expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modifier {
+    None,
+    /// %hi(), the upper 20 bits, rounded so that adding %lo() gives back the value
+    Hi,
+    /// %lo(), the sign extended lower 12 bits
+    Lo,
+    /// %pcrel_hi(), same as %hi() but relative to the address of the instruction
+    PcrelHi,
+    /// %pcrel_lo(label), the lower 12 bits matching the %pcrel_hi() of the auipc at «label», relative to that auipc as in the GNU assembler
+    ///
+    /// It can only be resolved by the assembler, which knows what every auipc takes the upper bits of
+    PcrelLo,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Atom {
+    Number(i64),
+    Symbol(String),
+}
+
+/// A sum of numbers and symbols, e.g. «label + 4»
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub modifier: Modifier,
+    /// Every term, together with wether it is negated
+    pub terms: Vec<(bool, Atom)>,
+}
+
+/// The result of parsing a whole line
+#[derive(Debug, Default)]
+pub struct Line {
+    pub labels: Vec<String>,
+    pub statement: Option<Statement>,
+}
+
+impl Expr {
+    pub fn number(value: i64) -> Self {
+        Self {
+            modifier: Modifier::None,
+            terms: vec![(false, Atom::Number(value))],
+        }
+    }
+
+    pub fn symbol(name: &str) -> Self {
+        Self {
+            modifier: Modifier::None,
+            terms: vec![(false, Atom::Symbol(name.to_owned()))],
+        }
+    }
+
+    pub fn with_modifier(mut self, modifier: Modifier) -> Self {
+        self.modifier = modifier;
+        self
+    }
+
+    /// If the expression is made of a single identifier (e.g. a register name) returns it
+    pub fn as_identifier(&self) -> Option<&str> {
+        match (self.modifier, self.terms.as_slice()) {
+            (Modifier::None, [(false, Atom::Symbol(name))]) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Wether the value of the expression depends on the address of a label
+    pub fn references_symbols(&self) -> bool {
+        self.terms
+            .iter()
+            .any(|(_, atom)| matches!(atom, Atom::Symbol(_)))
+    }
+
+    /// Evaluates the expression, «pc» being the address of the instruction it belongs to
+    pub fn evaluate(&self, pc: u32, lookup: impl Fn(&str) -> Option<i64>) -> Result<i64, String> {
+        let mut value: i64 = 0;
+        for (negated, atom) in &self.terms {
+            let term = match atom {
+                Atom::Number(number) => *number,
+                Atom::Symbol(name) => {
+                    lookup(name).ok_or_else(|| format!("Undefined symbol «{name}»"))?
+                }
+            };
+            value = if *negated {
+                value.wrapping_sub(term)
+            } else {
+                value.wrapping_add(term)
+            };
+        }
+        Ok(match self.modifier {
+            Modifier::None => value,
+            Modifier::Hi => hi(value),
+            Modifier::Lo => lo(value),
+            Modifier::PcrelHi => hi(value - pc as i64),
+            Modifier::PcrelLo => {
+                return Err("%pcrel_lo() can only be an operand of an instruction".to_owned())
+            }
+        })
+    }
+}
+
+pub fn hi(value: i64) -> i64 {
+    ((value + 0x800) >> 12) & 0xfffff
+}
+
+pub fn lo(value: i64) -> i64 {
+    (value & 0xfff) << 52 >> 52
+}
+
+/// Parses a line of source code, made of any number of labels followed by an optional statement and comment
+pub fn parse_line(text: &str) -> Result<Line, String> {
+    let mut rest = strip_comment(text).trim();
+    let mut line = Line::default();
+
+    // Labels
+    while let Some(colon) = rest.find(':') {
+        let label = rest[..colon].trim();
+        if !is_identifier(label) {
+            break;
+        }
+        line.labels.push(label.to_owned());
+        rest = rest[colon + 1..].trim();
+    }
+
+    if rest.is_empty() {
+        return Ok(line);
+    }
+
+    let (name, args) = match rest.find(char::is_whitespace) {
+        Some(end) => (&rest[..end], rest[end..].trim()),
+        None => (rest, ""),
+    };
+    let args = split_args(args)?;
+
+    line.statement = Some(if name.starts_with('.') {
+        Statement::Directive {
+            name: name.to_lowercase(),
+            args,
+        }
+    } else {
+        Statement::Instruction {
+            mnemonic: name.to_lowercase(),
+            operands: args
+                .iter()
+                .map(|arg| parse_operand(arg))
+                .collect::<Result<_, _>>()?,
+        }
+    });
+    Ok(line)
+}
+
+/// Removes a trailing «#» or «//» comment, ignoring comment characters inside string and character literals
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '#' => return &text[..i],
+                '/' if text[i..].starts_with("//") => return &text[..i],
+                _ => {}
+            },
+        }
+    }
+    text
+}
+
+/// Splits comma separated arguments, leaving commas inside string and character literals alone
+fn split_args(text: &str) -> Result<Vec<String>, String> {
+    if text.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for c in text.chars() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+                current.push(c);
+            }
+            None => match c {
+                ',' => args.push(std::mem::take(&mut current).trim().to_owned()),
+                '"' | '\'' => {
+                    quote = Some(c);
+                    current.push(c);
+                }
+                _ => current.push(c),
+            },
+        }
+    }
+    if quote.is_some() {
+        return Err("Unterminated literal".to_owned());
+    }
+    args.push(current.trim().to_owned());
+    if args.iter().any(String::is_empty) {
+        return Err("Empty argument".to_owned());
+    }
+    Ok(args)
+}
+
+pub fn parse_operand(text: &str) -> Result<Operand, String> {
+    // offset(base), where offset is optional
+    if let Some(stripped) = text.strip_suffix(')') {
+        if let Some(open) = stripped.rfind('(') {
+            let base = stripped[open + 1..].trim();
+            let offset = stripped[..open].trim();
+            // Modifiers such as %lo(symbol) also end with a parenthesis
+            let is_modifier = offset.starts_with('%') && !offset.contains('(');
+            if is_identifier(base) && !is_modifier {
+                return Ok(Operand::Memory {
+                    offset: if offset.is_empty() {
+                        Expr::number(0)
+                    } else {
+                        parse_expr(offset)?
+                    },
+                    base: base.to_owned(),
+                });
+            }
+        }
+    }
+    Ok(Operand::Expr(parse_expr(text)?))
+}
+
+pub fn parse_expr(text: &str) -> Result<Expr, String> {
+    let text = text.trim();
+    if let Some(stripped) = text.strip_prefix('%') {
+        let open = stripped
+            .find('(')
+            .ok_or_else(|| format!("Expected «(» after «%{stripped}»"))?;
+        let inner = stripped[open + 1..]
+            .strip_suffix(')')
+            .ok_or_else(|| format!("Expected «)» at the end of «{text}»"))?;
+        let modifier = match &stripped[..open] {
+            "hi" => Modifier::Hi,
+            "lo" => Modifier::Lo,
+            "pcrel_hi" => Modifier::PcrelHi,
+            "pcrel_lo" => Modifier::PcrelLo,
+            other => return Err(format!("Unknown modifier «%{other}»")),
+        };
+        return Ok(parse_expr(inner)?.with_modifier(modifier));
+    }
+
+    let mut terms = vec![];
+    let mut negated = false;
+    let mut rest = text;
+    loop {
+        rest = rest.trim_start();
+        // Any number of signs can precede a term
+        while let Some(stripped) = rest.strip_prefix(['-', '+']) {
+            negated ^= rest.starts_with('-');
+            rest = stripped.trim_start();
+        }
+        let (atom, remaining) = parse_atom(rest)?;
+        terms.push((negated, atom));
+        rest = remaining.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        negated = match rest.chars().next() {
+            Some('+') => false,
+            Some('-') => true,
+            _ => return Err(format!("Unexpected «{rest}» in expression «{text}»")),
+        };
+        rest = &rest[1..];
+    }
+    Ok(Expr {
+        modifier: Modifier::None,
+        terms,
+    })
+}
+
+/// Parses a number, character literal or symbol at the start of the text, returning the rest
+fn parse_atom(text: &str) -> Result<(Atom, &str), String> {
+    if let Some(stripped) = text.strip_prefix('\'') {
+        let mut chars = stripped.char_indices();
+        let value = match chars.next() {
+            Some((_, '\\')) => {
+                let (_, escape) = chars.next().ok_or("Unterminated character literal")?;
+                unescape(escape)?
+            }
+            Some((_, c)) => c,
+            None => return Err("Unterminated character literal".to_owned()),
+        };
+        let (end, _) = chars
+            .next()
+            .filter(|(_, c)| *c == '\'')
+            .ok_or("Unterminated character literal")?;
+        return Ok((Atom::Number(value as i64), &stripped[end + 1..]));
+    }
+
+    let end = text
+        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.' || c == '$'))
+        .unwrap_or(text.len());
+    let (token, rest) = text.split_at(end);
+    if token.is_empty() {
+        return Err(format!("Expected a number or a symbol, found «{text}»"));
+    }
+    if token.starts_with(|c: char| c.is_ascii_digit()) {
+        Ok((Atom::Number(parse_number(token)?), rest))
+    } else {
+        Ok((Atom::Symbol(token.to_owned()), rest))
+    }
+}
+
+fn parse_number(token: &str) -> Result<i64, String> {
+    let lowercase = token.to_lowercase();
+    let (digits, radix) = if let Some(hex) = lowercase.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(binary) = lowercase.strip_prefix("0b") {
+        (binary, 2)
+    } else if let Some(octal) = lowercase.strip_prefix("0o") {
+        (octal, 8)
+    } else {
+        (lowercase.as_str(), 10)
+    };
+    i64::from_str_radix(&digits.replace('_', ""), radix)
+        .map_err(|_| format!("Invalid number «{token}»"))
+}
+
+fn unescape(c: char) -> Result<char, String> {
+    Ok(match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        '\\' | '\'' | '"' => c,
+        _ => return Err(format!("Unknown escape sequence «\\{c}»")),
+    })
+}
+
+/// Parses a double quoted string literal, as used by .ascii and .asciz
+pub fn parse_string(text: &str) -> Result<Vec<u8>, String> {
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .ok_or_else(|| format!("Expected a string literal, found «{text}»"))?;
+    let mut bytes = vec![];
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            unescape(chars.next().ok_or("Unterminated escape sequence")?)?
+        } else {
+            c
+        };
+        let mut buffer = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+    }
+    Ok(bytes)
+}
+
+pub fn is_identifier(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_alphabetic() || c == '_' || c == '.' || c == '$')
+        && text
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == '$')
+}
@@ -0,0 +1,213 @@
+//! Pseudo-instructions, as listed in the RISC-V assembly programmer's handbook
+
+use super::parser::{Expr, Modifier, Operand};
+
+/// A base instruction, as produced by expanding a pseudo-instruction
+pub type Expansion = Vec<(&'static str, Vec<Operand>)>;
+
+fn reg(name: &str) -> Operand {
+    Operand::Expr(Expr::symbol(name))
+}
+
+/// CSRs are named like registers
+fn csr(name: &str) -> Operand {
+    reg(name)
+}
+
+fn imm(value: i64) -> Operand {
+    Operand::Expr(Expr::number(value))
+}
+
+fn with_modifier(operand: &Operand, modifier: Modifier) -> Result<Operand, String> {
+    match operand {
+        Operand::Expr(expr) if expr.modifier == Modifier::None => {
+            Ok(Operand::Expr(expr.clone().with_modifier(modifier)))
+        }
+        _ => Err("Expected a plain expression".to_owned()),
+    }
+}
+
+/// Splits a value into the upper and lower parts used by the two instructions that build it
+fn split(operand: &Operand, hi: Modifier, lo: Modifier) -> Result<(Operand, Operand), String> {
+    Ok((with_modifier(operand, hi)?, with_modifier(operand, lo)?))
+}
+
+/// Splits an address into the operands of an «auipc» labeled «auipc_label» and of the instruction after it, which takes the lower bits through the label
+fn split_pcrel(operand: &Operand, auipc_label: &str) -> Result<(Operand, Operand), String> {
+    Ok((
+        with_modifier(operand, Modifier::PcrelHi)?,
+        Operand::Expr(Expr::symbol(auipc_label).with_modifier(Modifier::PcrelLo)),
+    ))
+}
+
+/// Parses the predecessor/successor sets of a fence (e.g. «rw»)
+fn fence_set(operand: &Operand) -> Result<i64, String> {
+    let set = match operand {
+        Operand::Expr(expr) => expr.as_identifier(),
+        _ => None,
+    }
+    .ok_or("Expected a combination of i, o, r and w")?;
+    set.chars().try_fold(0, |bits, c| {
+        Ok(bits
+            | match c {
+                'i' => 0b1000,
+                'o' => 0b0100,
+                'r' => 0b0010,
+                'w' => 0b0001,
+                _ => return Err(format!("Unknown fence set «{set}»")),
+            })
+    })
+}
+
+/// Expands a pseudo-instruction into base instructions
+///
+/// Returns Ok(None) if the mnemonic together with the number of operands doesn't identify a pseudo-instruction (e.g. «jal label» is one, «jal ra, label» isn't).
+/// «constant» evaluates an expression if its value is already known, which lets «li» pick the shortest expansion.
+/// Expansions starting with an «auipc» (e.g. «la») refer to it as «auipc_label», which the assembler has to put on it
+pub fn expand(
+    mnemonic: &str,
+    operands: &[Operand],
+    auipc_label: &str,
+    constant: impl Fn(&Expr) -> Option<i64>,
+) -> Result<Option<Expansion>, String> {
+    let o = operands;
+    let expansion = match (mnemonic, o.len()) {
+        ("nop", 0) => vec![("addi", vec![reg("x0"), reg("x0"), imm(0)])],
+        ("li", 2) => {
+            let value = match &o[1] {
+                Operand::Expr(expr) => constant(expr),
+                _ => return Err("Expected an immediate".to_owned()),
+            };
+            match value {
+                Some(value) if (-2048..2048).contains(&value) => {
+                    vec![("addi", vec![o[0].clone(), reg("x0"), o[1].clone()])]
+                }
+                Some(value) if value & 0xfff == 0 => {
+                    vec![(
+                        "lui",
+                        vec![o[0].clone(), with_modifier(&o[1], Modifier::Hi)?],
+                    )]
+                }
+                _ => {
+                    let (hi, lo) = split(&o[1], Modifier::Hi, Modifier::Lo)?;
+                    vec![
+                        ("lui", vec![o[0].clone(), hi]),
+                        ("addi", vec![o[0].clone(), o[0].clone(), lo]),
+                    ]
+                }
+            }
+        }
+        ("la" | "lla", 2) => {
+            let (hi, lo) = split_pcrel(&o[1], auipc_label)?;
+            vec![
+                ("auipc", vec![o[0].clone(), hi]),
+                ("addi", vec![o[0].clone(), o[0].clone(), lo]),
+            ]
+        }
+        ("mv", 2) => vec![("addi", vec![o[0].clone(), o[1].clone(), imm(0)])],
+        ("not", 2) => vec![("xori", vec![o[0].clone(), o[1].clone(), imm(-1)])],
+        ("neg", 2) => vec![("sub", vec![o[0].clone(), reg("x0"), o[1].clone()])],
+        ("seqz", 2) => vec![("sltiu", vec![o[0].clone(), o[1].clone(), imm(1)])],
+        ("snez", 2) => vec![("sltu", vec![o[0].clone(), reg("x0"), o[1].clone()])],
+        ("sltz", 2) => vec![("slt", vec![o[0].clone(), o[1].clone(), reg("x0")])],
+        ("sgtz", 2) => vec![("slt", vec![o[0].clone(), reg("x0"), o[1].clone()])],
+        ("beqz", 2) => vec![("beq", vec![o[0].clone(), reg("x0"), o[1].clone()])],
+        ("bnez", 2) => vec![("bne", vec![o[0].clone(), reg("x0"), o[1].clone()])],
+        ("blez", 2) => vec![("bge", vec![reg("x0"), o[0].clone(), o[1].clone()])],
+        ("bgez", 2) => vec![("bge", vec![o[0].clone(), reg("x0"), o[1].clone()])],
+        ("bltz", 2) => vec![("blt", vec![o[0].clone(), reg("x0"), o[1].clone()])],
+        ("bgtz", 2) => vec![("blt", vec![reg("x0"), o[0].clone(), o[1].clone()])],
+        ("bgt", 3) => vec![("blt", vec![o[1].clone(), o[0].clone(), o[2].clone()])],
+        ("ble", 3) => vec![("bge", vec![o[1].clone(), o[0].clone(), o[2].clone()])],
+        ("bgtu", 3) => vec![("bltu", vec![o[1].clone(), o[0].clone(), o[2].clone()])],
+        ("bleu", 3) => vec![("bgeu", vec![o[1].clone(), o[0].clone(), o[2].clone()])],
+        ("j", 1) => vec![("jal", vec![reg("x0"), o[0].clone()])],
+        ("jal", 1) => vec![("jal", vec![reg("ra"), o[0].clone()])],
+        ("jr", 1) => vec![("jalr", vec![reg("x0"), o[0].clone(), imm(0)])],
+        ("jalr", 1) => vec![("jalr", vec![reg("ra"), o[0].clone(), imm(0)])],
+        ("ret", 0) => vec![("jalr", vec![reg("x0"), reg("ra"), imm(0)])],
+        ("call" | "tail", 1) => {
+            let (link, scratch) = if mnemonic == "call" {
+                ("ra", "ra")
+            } else {
+                ("x0", "t1")
+            };
+            let (hi, lo) = split_pcrel(&o[0], auipc_label)?;
+            vec![
+                ("auipc", vec![reg(scratch), hi]),
+                ("jalr", vec![reg(link), reg(scratch), lo]),
+            ]
+        }
+        ("fence", 0) => vec![("fence", vec![imm(0xff)])],
+        ("fence", 2) => vec![(
+            "fence",
+            vec![imm(fence_set(&o[0])? << 4 | fence_set(&o[1])?)],
+        )],
+        ("rdcycle" | "rdcycleh" | "rdinstret" | "rdinstreth", 1) => {
+            vec![("csrrs", vec![o[0].clone(), csr(&mnemonic[2..]), reg("x0")])]
+        }
+        ("csrr", 2) => vec![("csrrs", vec![o[0].clone(), o[1].clone(), reg("x0")])],
+        ("csrw" | "csrs" | "csrc", 2) => {
+            let base = match mnemonic {
+                "csrw" => "csrrw",
+                "csrs" => "csrrs",
+                _ => "csrrc",
+            };
+            vec![(base, vec![reg("x0"), o[0].clone(), o[1].clone()])]
+        }
+        ("csrwi" | "csrsi" | "csrci", 2) => {
+            let base = match mnemonic {
+                "csrwi" => "csrrwi",
+                "csrsi" => "csrrsi",
+                _ => "csrrci",
+            };
+            vec![(base, vec![reg("x0"), o[0].clone(), o[1].clone()])]
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(expansion))
+}
+
+/// Wether the mnemonic names a pseudo-instruction, with any number of operands
+pub fn is_pseudo(mnemonic: &str) -> bool {
+    matches!(
+        mnemonic,
+        "nop"
+            | "li"
+            | "la"
+            | "lla"
+            | "mv"
+            | "not"
+            | "neg"
+            | "seqz"
+            | "snez"
+            | "sltz"
+            | "sgtz"
+            | "beqz"
+            | "bnez"
+            | "blez"
+            | "bgez"
+            | "bltz"
+            | "bgtz"
+            | "bgt"
+            | "ble"
+            | "bgtu"
+            | "bleu"
+            | "j"
+            | "jr"
+            | "ret"
+            | "call"
+            | "tail"
+            | "rdcycle"
+            | "rdcycleh"
+            | "rdinstret"
+            | "rdinstreth"
+            | "csrr"
+            | "csrw"
+            | "csrs"
+            | "csrc"
+            | "csrwi"
+            | "csrsi"
+            | "csrci"
+    )
+}
@@ -0,0 +1,173 @@
+//! Differential co-simulation: runs a program in lockstep with a reference commit log (e.g. recorded with `spike -l --log-commits` or Sail), checking the architectural effects of every retired instruction
+
+use std::fmt;
+
+use crate::machine::{
+    cpu::{
+        disassembler::register_name,
+        trace::{parse_commit_log, TraceRecord},
+    },
+    Machine, MachineError,
+};
+
+/// How many instructions in a row can raise an exception before giving up on reaching the reference's next instruction
+const MAX_CONSECUTIVE_TRAPS: usize = 8;
+
+#[derive(Debug)]
+pub enum CosimError {
+    /// The reference log couldn't be parsed
+    Parse(String),
+    /// The reference log never reaches the machine's pc
+    NoStart {
+        pc: u32,
+    },
+    /// The machine couldn't execute an instruction the reference retired
+    Machine {
+        /// Number of instructions that matched before this one
+        index: usize,
+        /// Line of the reference log
+        line: usize,
+        expected: TraceRecord,
+        error: MachineError,
+    },
+    Divergence(Box<Divergence>),
+}
+
+/// The first instruction whose effects differ from the reference, together with the state of both sides
+#[derive(Debug)]
+pub struct Divergence {
+    /// Number of instructions that matched before this one
+    pub index: usize,
+    /// Line of the reference log
+    pub line: usize,
+    pub expected: TraceRecord,
+    pub actual: TraceRecord,
+    /// Registers as reconstructed from the reference log, assuming they agreed with ours until now
+    pub expected_registers: [u32; 32],
+    /// Our registers after executing the instruction
+    pub actual_registers: [u32; 32],
+}
+
+impl fmt::Display for CosimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(error) => write!(f, "Invalid reference log: {error}"),
+            Self::NoStart { pc } => write!(
+                f,
+                "The reference log never executes the instruction at 0x{pc:08x}"
+            ),
+            Self::Machine {
+                index,
+                line,
+                expected,
+                error,
+            } => write!(
+                f,
+                "Instruction {index} (reference line {line}) failed: {error:?}\nreference: {expected}"
+            ),
+            Self::Divergence(divergence) => divergence.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Divergence at instruction {} (reference line {}): {}",
+            self.index,
+            self.line,
+            self.expected.disassembly()
+        )?;
+        writeln!(f, "reference: {}", self.expected)?;
+        writeln!(f, "emulator:  {}", self.actual)?;
+        writeln!(f, "register  reference  emulator")?;
+        for (i, (expected, actual)) in self
+            .expected_registers
+            .iter()
+            .zip(self.actual_registers)
+            .enumerate()
+        {
+            let marker = if *expected != actual { "  <-" } else { "" };
+            writeln!(
+                f,
+                "{:<3} {:<5} 0x{expected:08x} 0x{actual:08x}{marker}",
+                format!("x{i}"),
+                register_name(i as u32)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl From<String> for CosimError {
+    fn from(value: String) -> Self {
+        Self::Parse(value)
+    }
+}
+
+/// Wether our record has the same effects as the reference one
+///
+/// The size of memory reads isn't part of commit logs, so only their address is compared
+fn matches(expected: &TraceRecord, actual: &TraceRecord) -> bool {
+    let read_address = |record: &TraceRecord| record.memory_read.map(|read| read.address);
+    expected.privilege == actual.privilege
+        && expected.pc == actual.pc
+        && expected.raw == actual.raw
+        && expected.register_write == actual.register_write
+        && expected.csr_write == actual.csr_write
+        && read_address(expected) == read_address(actual)
+        && expected.memory_write == actual.memory_write
+        && expected.trap == actual.trap
+}
+
+/// Runs the machine in lockstep with a reference commit log, stopping at the first divergence
+///
+/// Commit logs are of a single hart, so only the first one runs, with the host answering its commands as on any run.
+/// Reference records before the first one at the machine's pc are skipped (e.g. the instructions of Spike's boot ROM).
+/// Returns the number of instructions that were checked, which is every instruction of the log from the start
+pub fn cosimulate(machine: &mut Machine, reference_log: &str) -> Result<usize, CosimError> {
+    let reference = parse_commit_log(reference_log)?;
+    let start = machine.harts[0].pc;
+    let first = reference
+        .iter()
+        .position(|(_, record)| record.pc == start)
+        .ok_or(CosimError::NoStart { pc: start })?;
+
+    let mut expected_registers = machine.harts[0].registers;
+    for (index, (line, expected)) in reference[first..].iter().enumerate() {
+        // Instructions raising an exception don't retire, so they aren't part of commit logs
+        let mut traps = 0;
+        let actual = loop {
+            let record = machine.step_hart(0).map_err(|error| CosimError::Machine {
+                index,
+                line: *line,
+                expected: *expected,
+                error,
+            })?;
+            // A trap where the reference retired an instruction, or an endless sequence of them (e.g. a fault in the trap handler itself), is a divergence too
+            if record.trap.is_none() || record.pc == expected.pc || traps == MAX_CONSECUTIVE_TRAPS {
+                break record;
+            }
+            traps += 1;
+        };
+        if let Some((register, value)) = expected.register_write {
+            expected_registers[register as usize] = value;
+        }
+        // Both sides record the value written to x0, which is then discarded
+        expected_registers[0] = 0;
+        let mut actual_registers = machine.harts[0].registers;
+        actual_registers[0] = 0;
+        if !matches(expected, &actual) {
+            return Err(CosimError::Divergence(Box::new(Divergence {
+                index,
+                line: *line,
+                expected: *expected,
+                actual,
+                expected_registers,
+                actual_registers,
+            })));
+        }
+    }
+    Ok(reference.len() - first)
+}
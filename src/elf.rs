@@ -0,0 +1,162 @@
+//! Loads RV32 ELF executables, together with their symbols and DWARF line tables when available
+
+use std::{collections::HashMap, fmt};
+
+use addr2line::gimli;
+use object::{
+    Architecture, Object, ObjectSection, ObjectSegment, ObjectSymbol, SectionKind, SymbolKind,
+};
+
+use crate::{
+    machine::memory::{
+        constants::{MEMORY_SIZE, RAM_BASE},
+        MemoryDump,
+    },
+    source_map::{SourceLocation, SourceMap},
+};
+
+#[derive(Debug)]
+pub enum ElfError {
+    Parse(object::Error),
+    /// Only RV32 executables can be loaded
+    UnsupportedArchitecture(Architecture),
+    Dwarf(gimli::Error),
+    /// A segment doesn't fit in physical memory
+    OutsideMemory {
+        address: u32,
+        size: u32,
+    },
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(error) => write!(f, "Invalid ELF file: {error}"),
+            Self::UnsupportedArchitecture(architecture) => {
+                write!(f, "Unsupported architecture: {architecture:?}")
+            }
+            Self::Dwarf(error) => write!(f, "Invalid debugging information: {error}"),
+            Self::OutsideMemory { address, size } => write!(
+                f,
+                "Segment at 0x{address:08x} ({size} bytes) doesn't fit in physical memory"
+            ),
+        }
+    }
+}
+
+impl From<object::Error> for ElfError {
+    fn from(value: object::Error) -> Self {
+        Self::Parse(value)
+    }
+}
+
+impl From<gimli::Error> for ElfError {
+    fn from(value: gimli::Error) -> Self {
+        Self::Dwarf(value)
+    }
+}
+
+/// A loadable segment
+#[derive(Debug)]
+pub struct Segment {
+    pub address: u32,
+    /// Contents of the segment, already extended with zeros to its size in memory
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Executable {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    /// Address of every named symbol
+    pub symbols: HashMap<String, u32>,
+    /// Name and address of every function symbol
+    pub functions: Vec<(String, u32)>,
+    /// Empty if the executable has no DWARF line tables
+    pub source_map: SourceMap,
+}
+
+impl Executable {
+    pub fn parse(bytes: &[u8]) -> Result<Self, ElfError> {
+        let file = object::File::parse(bytes)?;
+        if file.architecture() != Architecture::Riscv32 {
+            return Err(ElfError::UnsupportedArchitecture(file.architecture()));
+        }
+
+        let mut segments = vec![];
+        for segment in file.segments() {
+            let mut data = segment.data()?.to_vec();
+            data.resize(segment.size() as usize, 0);
+            segments.push(Segment {
+                address: segment.address() as u32,
+                data,
+            });
+        }
+
+        let symbols = file
+            .symbols()
+            .filter_map(|symbol| {
+                let name = symbol.name().ok().filter(|name| !name.is_empty())?;
+                Some((name.to_owned(), symbol.address() as u32))
+            })
+            .collect();
+        let functions = file
+            .symbols()
+            .filter(|symbol| symbol.kind() == SymbolKind::Text)
+            .filter_map(|symbol| {
+                let name = symbol.name().ok().filter(|name| !name.is_empty())?;
+                Some((name.to_owned(), symbol.address() as u32))
+            })
+            .collect();
+
+        let mut source_map = SourceMap::default();
+        let context = addr2line::Context::new(&file)?;
+        for section in file.sections() {
+            if section.kind() != SectionKind::Text {
+                continue;
+            }
+            let (start, end) = (section.address(), section.address() + section.size());
+            for (address, size, location) in context.find_location_range(start, end)? {
+                if let Some(line) = location.line {
+                    source_map.insert(
+                        address as u32,
+                        size as u32,
+                        SourceLocation {
+                            file: location.file.map(str::to_owned),
+                            line: line as usize,
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(Self {
+            entry: file.entry() as u32,
+            segments,
+            symbols,
+            functions,
+            source_map,
+        })
+    }
+
+    /// Lays out every segment in an image of physical memory, starting from «RAM_BASE»
+    pub fn memory_dump(&self) -> Result<MemoryDump, ElfError> {
+        let mut memory_dump = vec![0; MEMORY_SIZE];
+        for segment in &self.segments {
+            let size = segment.data.len() as u32;
+            let start = (segment.address as usize).checked_sub(RAM_BASE);
+            match start {
+                Some(start) if start + segment.data.len() <= MEMORY_SIZE => {
+                    memory_dump[start..start + segment.data.len()].copy_from_slice(&segment.data)
+                }
+                _ => {
+                    return Err(ElfError::OutsideMemory {
+                        address: segment.address,
+                        size,
+                    })
+                }
+            }
+        }
+        Ok(memory_dump)
+    }
+}
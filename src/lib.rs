@@ -1,9 +1,16 @@
 pub use machine::*;
-use machine::{memory::MemoryDump, Machine};
+use memory::{MemoryDump, TooLarge};
 
+pub mod assembler;
+pub mod cosim;
+pub mod elf;
 pub mod machine;
+pub mod source_map;
 
-pub fn create_rv32(memory_dump: MemoryDump) -> Machine {
+#[cfg(all(feature = "jit", not(target_arch = "x86_64")))]
+compile_error!("The «jit» feature translates to x86-64 code, so it needs an x86-64 host");
+
+pub fn create_rv32(memory_dump: MemoryDump) -> Result<Machine, TooLarge> {
     Machine::new(memory_dump)
 }
 
@@ -0,0 +1,295 @@
+//! Branch predictors, and how well they predict the conditional branches of a program
+//!
+//! Predictors don't change what the program does: they are asked for a prediction on every conditional branch the CPU executes, then told where it went.
+
+use std::collections::HashMap;
+
+/// Predicts wether conditional branches are taken, learning from their outcome
+///
+/// Predictors are «Send», so that harts can run in threads of their own
+pub trait Predictor: Send {
+    /// Wether the branch at «pc», jumping to «target» when taken, is predicted to be taken
+    fn predict(&mut self, pc: u32, target: u32) -> bool;
+    /// Learns that the branch at «pc» was taken or not
+    fn update(&mut self, pc: u32, taken: bool);
+    /// Forgets everything learned
+    fn reset(&mut self);
+}
+
+/// The built-in predictors, with the size of their tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PredictorConfig {
+    /// Always predicts the same outcome
+    Static { taken: bool },
+    /// Predicts the last outcome of the branch, in a table of 2^«index_bits» entries indexed by its address
+    OneBit { index_bits: u32 },
+    /// Predicts with a 2-bit saturating counter, in a table of 2^«index_bits» entries indexed by the address of the branch
+    TwoBit { index_bits: u32 },
+    /// Predicts with a 2-bit saturating counter, indexed by the outcome of the last «history_bits» branches xored with the address of the branch
+    Gshare { history_bits: u32 },
+    /// Chooses between a 2-bit predictor and a gshare one, by which was right more often for the branch
+    Tournament { index_bits: u32, history_bits: u32 },
+}
+
+impl PredictorConfig {
+    pub fn build(self) -> Box<dyn Predictor> {
+        match self {
+            Self::Static { taken } => Box::new(Static { taken }),
+            Self::OneBit { index_bits } => Box::new(OneBit::new(index_bits)),
+            Self::TwoBit { index_bits } => Box::new(TwoBit::new(index_bits)),
+            Self::Gshare { history_bits } => Box::new(Gshare::new(history_bits)),
+            Self::Tournament {
+                index_bits,
+                history_bits,
+            } => Box::new(Tournament::new(index_bits, history_bits)),
+        }
+    }
+}
+
+/// Index of the entry for the branch at «pc» in a table of 2^«bits» entries, ignoring the lowest bits which are always zero
+fn index(pc: u32, bits: u32) -> usize {
+    ((pc >> 2) & ((1 << bits) - 1)) as usize
+}
+
+pub struct Static {
+    pub taken: bool,
+}
+
+impl Predictor for Static {
+    fn predict(&mut self, _pc: u32, _target: u32) -> bool {
+        self.taken
+    }
+
+    fn update(&mut self, _pc: u32, _taken: bool) {}
+
+    fn reset(&mut self) {}
+}
+
+pub struct OneBit {
+    index_bits: u32,
+    taken: Vec<bool>,
+}
+
+impl OneBit {
+    pub fn new(index_bits: u32) -> Self {
+        Self {
+            index_bits,
+            taken: vec![false; 1 << index_bits],
+        }
+    }
+}
+
+impl Predictor for OneBit {
+    fn predict(&mut self, pc: u32, _target: u32) -> bool {
+        self.taken[index(pc, self.index_bits)]
+    }
+
+    fn update(&mut self, pc: u32, taken: bool) {
+        self.taken[index(pc, self.index_bits)] = taken;
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.index_bits);
+    }
+}
+
+/// 2-bit saturating counters, predicting taken from 2 up, starting from weakly not taken
+#[derive(Clone)]
+struct Counters(Vec<u8>);
+
+impl Counters {
+    fn new(bits: u32) -> Self {
+        Self(vec![1; 1 << bits])
+    }
+
+    fn predict(&self, index: usize) -> bool {
+        self.0[index] >= 2
+    }
+
+    fn update(&mut self, index: usize, taken: bool) {
+        let counter = &mut self.0[index];
+        *counter = if taken {
+            (*counter + 1).min(3)
+        } else {
+            counter.saturating_sub(1)
+        };
+    }
+}
+
+pub struct TwoBit {
+    index_bits: u32,
+    counters: Counters,
+}
+
+impl TwoBit {
+    pub fn new(index_bits: u32) -> Self {
+        Self {
+            index_bits,
+            counters: Counters::new(index_bits),
+        }
+    }
+}
+
+impl Predictor for TwoBit {
+    fn predict(&mut self, pc: u32, _target: u32) -> bool {
+        self.counters.predict(index(pc, self.index_bits))
+    }
+
+    fn update(&mut self, pc: u32, taken: bool) {
+        self.counters.update(index(pc, self.index_bits), taken);
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.index_bits);
+    }
+}
+
+pub struct Gshare {
+    history_bits: u32,
+    /// Outcome of the last branches, the most recent in the lowest bit
+    history: u32,
+    counters: Counters,
+}
+
+impl Gshare {
+    pub fn new(history_bits: u32) -> Self {
+        Self {
+            history_bits,
+            history: 0,
+            counters: Counters::new(history_bits),
+        }
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        index(pc, self.history_bits) ^ self.history as usize
+    }
+}
+
+impl Predictor for Gshare {
+    fn predict(&mut self, pc: u32, _target: u32) -> bool {
+        self.counters.predict(self.index(pc))
+    }
+
+    fn update(&mut self, pc: u32, taken: bool) {
+        self.counters.update(self.index(pc), taken);
+        self.history = ((self.history << 1) | taken as u32) & ((1 << self.history_bits) - 1);
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.history_bits);
+    }
+}
+
+pub struct Tournament {
+    index_bits: u32,
+    local: TwoBit,
+    global: Gshare,
+    /// 2-bit saturating counters by branch address, choosing the global predictor from 2 up
+    choosers: Counters,
+}
+
+impl Tournament {
+    pub fn new(index_bits: u32, history_bits: u32) -> Self {
+        Self {
+            index_bits,
+            local: TwoBit::new(index_bits),
+            global: Gshare::new(history_bits),
+            choosers: Counters::new(index_bits),
+        }
+    }
+}
+
+impl Predictor for Tournament {
+    fn predict(&mut self, pc: u32, target: u32) -> bool {
+        if self.choosers.predict(index(pc, self.index_bits)) {
+            self.global.predict(pc, target)
+        } else {
+            self.local.predict(pc, target)
+        }
+    }
+
+    fn update(&mut self, pc: u32, taken: bool) {
+        let local = self.local.predict(pc, 0) == taken;
+        let global = self.global.predict(pc, 0) == taken;
+        // The chooser only learns when one of them was right and the other wasn't
+        if local != global {
+            self.choosers.update(index(pc, self.index_bits), global);
+        }
+        self.local.update(pc, taken);
+        self.global.update(pc, taken);
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.index_bits, self.global.history_bits);
+    }
+}
+
+/// How a branch (or all of them) behaved, and how well it was predicted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCounts {
+    pub executed: u64,
+    pub taken: u64,
+    pub mispredicted: u64,
+}
+
+impl BranchCounts {
+    /// Fraction of the executions that were predicted right, if there were any
+    pub fn accuracy(&self) -> Option<f64> {
+        (self.executed > 0)
+            .then(|| (self.executed - self.mispredicted) as f64 / self.executed as f64)
+    }
+}
+
+/// A predictor, along with how well it predicted every branch
+pub struct BranchPrediction {
+    pub predictor: Box<dyn Predictor>,
+    /// Counts of every branch, by address
+    pub branches: HashMap<u32, BranchCounts>,
+}
+
+impl BranchPrediction {
+    pub fn new(predictor: Box<dyn Predictor>) -> Self {
+        Self {
+            predictor,
+            branches: HashMap::new(),
+        }
+    }
+
+    /// Asks for a prediction of the branch at «pc», then tells the predictor where it went
+    pub fn observe(&mut self, pc: u32, target: u32, taken: bool) {
+        let predicted = self.predictor.predict(pc, target);
+        self.predictor.update(pc, taken);
+        let counts = self.branches.entry(pc).or_default();
+        counts.executed += 1;
+        counts.taken += taken as u64;
+        counts.mispredicted += (predicted != taken) as u64;
+    }
+
+    /// Counts of all branches together
+    pub fn total(&self) -> BranchCounts {
+        self.branches
+            .values()
+            .fold(BranchCounts::default(), |total, counts| BranchCounts {
+                executed: total.executed + counts.executed,
+                taken: total.taken + counts.taken,
+                mispredicted: total.mispredicted + counts.mispredicted,
+            })
+    }
+
+    /// Branches by how many times they were mispredicted, the most first (then by address)
+    pub fn worst(&self) -> Vec<(u32, BranchCounts)> {
+        let mut branches: Vec<_> = self
+            .branches
+            .iter()
+            .map(|(&pc, &counts)| (pc, counts))
+            .collect();
+        branches.sort_by_key(|&(pc, counts)| (std::cmp::Reverse(counts.mispredicted), pc));
+        branches
+    }
+
+    /// Forgets everything the predictor learned, along with the counts
+    pub fn reset(&mut self) {
+        self.predictor.reset();
+        self.branches.clear();
+    }
+}
@@ -5,17 +5,21 @@ use crate::machine::cpu::DecodeError;
 pub const XLEN: u8 = 32;
 pub type XLENType = u32;
 
-#[derive(Debug)]
+/// Bits of the predecessor and successor sets of a «fence» for memory reads and writes (the other two are for device input and output)
+pub const FENCE_R: u32 = 0b0010;
+pub const FENCE_W: u32 = 0b0001;
+
+#[derive(Debug, Clone, Copy)]
 pub enum Instruction {
     R(RType),
     I(IType),
     S(SType),
-    B,
-    U,
-    J,
+    B(BType),
+    U(UType),
+    J(JType),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct IType {
     /// Opcode, partially identifies the instruction
     pub opcode: u32,
@@ -29,7 +33,7 @@ pub struct IType {
     pub funct3: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct RType {
     /// Opcode, partially identifies the instruction
     pub opcode: u32,
@@ -45,7 +49,7 @@ pub struct RType {
     pub funct7: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct SType {
     /// Opcode, partially identifies the instruction
     pub opcode: u32,
@@ -59,6 +63,60 @@ pub struct SType {
     pub funct3: u32,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct BType {
+    /// Opcode, partially identifies the instruction
+    pub opcode: u32,
+    /// Source register n. 1
+    pub rs1: u32,
+    /// Source register n. 2
+    pub rs2: u32,
+    /// Immediate, the offset of the branch target from the branch itself
+    pub imm: u32,
+    /// Complements the opcode in identifying the instruction
+    pub funct3: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UType {
+    /// Opcode, identifies the instruction
+    pub opcode: u32,
+    /// Destination register
+    pub rd: u32,
+    /// Immediate, already shifted in the upper 20 bits
+    pub imm: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct JType {
+    /// Opcode, identifies the instruction
+    pub opcode: u32,
+    /// Destination register
+    pub rd: u32,
+    /// Immediate, the offset of the jump target from the jump itself
+    pub imm: u32,
+}
+
+impl Instruction {
+    /// The register the instruction writes its result to, if any
+    pub fn destination_register(&self) -> Option<u32> {
+        match self {
+            Self::R(instruction) => Some(instruction.rd),
+            // fence and the system instructions without a CSR (ecall, ebreak, ...) don't have a destination
+            Self::I(IType { opcode: 0x0f, .. })
+            | Self::I(IType {
+                opcode: 0x73,
+                funct3: 0x0,
+                ..
+            }) => None,
+            Self::I(instruction) => Some(instruction.rd),
+            Self::S(_) | Self::B(_) => None,
+            Self::U(instruction) => Some(instruction.rd),
+            Self::J(instruction) => Some(instruction.rd),
+        }
+    }
+}
+
 impl TryFrom<u32> for Instruction {
     type Error = DecodeError;
 
@@ -66,7 +124,7 @@ impl TryFrom<u32> for Instruction {
         let opcode = value & 0x7f;
         match opcode {
             // I Type
-            0x03 | 0x13 => {
+            0x03 | 0x0f | 0x13 | 0x67 | 0x73 => {
                 let rd = decode_destination_register(value);
                 // rs2 is ignored since it doesn't actually exist in I-type instructions
                 let (rs1, _) = decode_source_registers(value);
@@ -96,8 +154,8 @@ impl TryFrom<u32> for Instruction {
                     funct3,
                 }))
             }
-            // R Type
-            0x33 => {
+            // R Type (including the atomic memory operations, whose funct7 holds the aq and rl flags)
+            0x2f | 0x33 => {
                 let rd = decode_destination_register(value);
                 let (rs1, rs2) = decode_source_registers(value);
                 let (funct3, funct7) = decode_functs(value);
@@ -110,8 +168,39 @@ impl TryFrom<u32> for Instruction {
                     funct7,
                 }))
             }
-            0x0 => Err(DecodeError::OpcodeZero),
-            _ => unimplemented!("Unimpletented instruction type"),
+            // B Type
+            0x63 => {
+                let (rs1, rs2) = decode_source_registers(value);
+                // funct7 is ignored since it doesn't actually exist in B-type instructions
+                let (funct3, _) = decode_functs(value);
+                let imm = (((value & 0x80000000) as i32 >> 19) as u32)
+                    | ((value & 0x80) << 4)
+                    | ((value >> 20) & 0x7e0)
+                    | ((value >> 7) & 0x1e);
+                Ok(Self::B(BType {
+                    opcode,
+                    rs1,
+                    rs2,
+                    imm,
+                    funct3,
+                }))
+            }
+            // U Type
+            0x17 | 0x37 => {
+                let rd = decode_destination_register(value);
+                let imm = value & 0xfffff000;
+                Ok(Self::U(UType { opcode, rd, imm }))
+            }
+            // J Type
+            0x6f => {
+                let rd = decode_destination_register(value);
+                let imm = (((value & 0x80000000) as i32 >> 11) as u32)
+                    | (value & 0xff000)
+                    | ((value >> 9) & 0x800)
+                    | ((value >> 20) & 0x7fe);
+                Ok(Self::J(JType { opcode, rd, imm }))
+            }
+            _ => Err(DecodeError::UnsupportedOpcode(opcode)),
         }
     }
 }
@@ -119,20 +208,20 @@ impl TryFrom<u32> for Instruction {
 /// Decodes the source register(s) from a raw instruction (rs1 and rs2)
 fn decode_source_registers(raw_instruction: u32) -> (u32, u32) {
     (
-        ((raw_instruction >> 15) & 0x1f) as u32,
-        ((raw_instruction >> 20) & 0x1f) as u32,
+        ((raw_instruction >> 15) & 0x1f),
+        ((raw_instruction >> 20) & 0x1f),
     )
 }
 
 /// Decodes the destination register from a raw instruction (rd)
 fn decode_destination_register(raw_instruction: u32) -> u32 {
-    ((raw_instruction >> 7) & 0x1f) as u32
+    (raw_instruction >> 7) & 0x1f
 }
 
 /// Decodes the funct3 and funct7 register from a raw instruction
 fn decode_functs(raw_instruction: u32) -> (u32, u32) {
     (
         ((raw_instruction >> 12) & 0x7),
-        ((raw_instruction >> 25) & 0x3F),
+        ((raw_instruction >> 25) & 0x7F),
     )
 }
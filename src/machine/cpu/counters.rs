@@ -0,0 +1,113 @@
+//! Hardware performance counters: mcycle, minstret and the event counters mhpmcounter3-6, counting the events selected in mhpmevent3-6
+//!
+//! The interpreter takes a cycle per instruction, so mcycle counts every instruction executed, including the ones raising an exception, while minstret only counts the ones that retired.
+
+use super::{
+    csr::{Csrs, HPM_COUNTERS, MCOUNTINHIBIT, MCYCLE, MHPMCOUNTER3, MHPMEVENT3, MINSTRET},
+    trace::TraceRecord,
+};
+
+/// Events the event counters can count, written to mhpmevent3-6 by their code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Retired instructions reading memory, atomic memory operations included
+    Loads = 1,
+    /// Retired instructions writing memory, atomic memory operations included
+    Stores = 2,
+    /// Retired conditional branches
+    Branches = 3,
+    TakenBranches = 4,
+    /// Exceptions raised
+    Traps = 5,
+}
+
+impl Event {
+    pub const ALL: [Self; 5] = [
+        Self::Loads,
+        Self::Stores,
+        Self::Branches,
+        Self::TakenBranches,
+        Self::Traps,
+    ];
+
+    pub fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|&event| event as u32 == code)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Loads => "Loads",
+            Self::Stores => "Stores",
+            Self::Branches => "Branches",
+            Self::TakenBranches => "Taken branches",
+            Self::Traps => "Traps",
+        }
+    }
+
+    /// Wether the instruction in «record», after which the CPU went on to «next_pc», is one to count
+    fn happened(self, record: &TraceRecord, next_pc: u32) -> bool {
+        let retired = record.trap.is_none();
+        let branch = retired && record.raw & 0x7f == 0x63;
+        match self {
+            Self::Loads => retired && record.memory_read.is_some(),
+            Self::Stores => retired && record.memory_write.is_some(),
+            Self::Branches => branch,
+            Self::TakenBranches => branch && next_pc != record.pc.wrapping_add(4),
+            Self::Traps => record.trap.is_some(),
+        }
+    }
+}
+
+/// The 64-bit value of the counter whose low half is at «low»
+pub fn read(csrs: &Csrs, low: u16) -> u64 {
+    (csrs.get(low + 0x80) as u64) << 32 | csrs.get(low) as u64
+}
+
+/// Adds «amount» to the counter whose low half is at «low», unless it's inhibited in mcountinhibit
+fn add(csrs: &mut Csrs, low: u16, amount: u64) {
+    if csrs.get(MCOUNTINHIBIT) & (1 << (low - MCYCLE)) != 0 {
+        return;
+    }
+    let value = read(csrs, low).wrapping_add(amount);
+    csrs.set(low, value as u32);
+    csrs.set(low + 0x80, (value >> 32) as u32);
+}
+
+/// Counts the instruction in «record», after which the CPU went on to «next_pc»
+///
+/// A counter written by the instruction keeps the value written, as in the specification
+pub fn count(csrs: &mut Csrs, record: &TraceRecord, next_pc: u32) {
+    let written = record.csr_write.map(|(address, _)| address & !0x80);
+    let increment = |csrs: &mut Csrs, low: u16| {
+        if written != Some(low) {
+            add(csrs, low, 1);
+        }
+    };
+    increment(csrs, MCYCLE);
+    if record.trap.is_none() {
+        increment(csrs, MINSTRET);
+    }
+    for i in 0..HPM_COUNTERS {
+        if Event::from_code(csrs.get(MHPMEVENT3 + i))
+            .is_some_and(|event| event.happened(record, next_pc))
+        {
+            increment(csrs, MHPMCOUNTER3 + i);
+        }
+    }
+}
+
+/// Counts «amount» instructions run natively, which never raise exceptions
+pub fn count_native(csrs: &mut Csrs, amount: u64) {
+    add(csrs, MCYCLE, amount);
+    add(csrs, MINSTRET, amount);
+}
+
+/// Wether an event counter counts loads, stores or branches, which native code can't tell about
+pub fn counts_unseen_events(csrs: &Csrs) -> bool {
+    (0..HPM_COUNTERS).any(|i| {
+        matches!(
+            Event::from_code(csrs.get(MHPMEVENT3 + i)),
+            Some(Event::Loads | Event::Stores | Event::Branches | Event::TakenBranches)
+        )
+    })
+}
@@ -0,0 +1,421 @@
+//! Control and status registers, as per the RISC-V privileged spec
+//!
+//! Only machine mode is implemented, so every CSR listed here is a machine level one, except for satp which is kept around (always in Bare mode) to inspect programs setting up virtual memory, and the read-only copies of the counters (cycle, instret and hpmcounter3-6) which programs commonly read with pseudo-instructions like «rdcycle»
+
+use super::counters::Event;
+
+// Machine information registers
+pub const MVENDORID: u16 = 0xf11;
+pub const MARCHID: u16 = 0xf12;
+pub const MIMPID: u16 = 0xf13;
+pub const MHARTID: u16 = 0xf14;
+// Machine trap setup
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MCOUNTINHIBIT: u16 = 0x320;
+pub const MHPMEVENT3: u16 = 0x323;
+// Machine trap handling
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+// Supervisor protection and translation
+pub const SATP: u16 = 0x180;
+// Machine counters, the high halves are 0x80 above the low ones
+pub const MCYCLE: u16 = 0xb00;
+pub const MINSTRET: u16 = 0xb02;
+pub const MHPMCOUNTER3: u16 = 0xb03;
+pub const MCYCLEH: u16 = 0xb80;
+pub const MINSTRETH: u16 = 0xb82;
+pub const MHPMCOUNTER3H: u16 = 0xb83;
+// Read-only copies of the machine counters, 0x100 above them
+pub const CYCLE: u16 = 0xc00;
+pub const INSTRET: u16 = 0xc02;
+pub const HPMCOUNTER3: u16 = 0xc03;
+pub const CYCLEH: u16 = 0xc80;
+pub const INSTRETH: u16 = 0xc82;
+pub const HPMCOUNTER3H: u16 = 0xc83;
+
+/// How many event counters there are, mhpmcounter3 to mhpmcounter6 (the others are unimplemented)
+pub const HPM_COUNTERS: u16 = 4;
+/// The event selector of the last implemented event counter
+const LAST_MHPMEVENT: u16 = MHPMEVENT3 + HPM_COUNTERS - 1;
+
+// mstatus fields
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_MPP: u32 = 0b11 << 11;
+
+// mip/mie bits
+pub const MSIP: u32 = 1 << 3;
+pub const MTIP: u32 = 1 << 7;
+pub const MEIP: u32 = 1 << 11;
+
+/// The value of misa: MXL = 1 (32 bits) and the A, I and M extensions
+const MISA_VALUE: u32 = (1 << 30) | 1 | (1 << (b'I' - b'A')) | (1 << (b'M' - b'A'));
+
+/// Describes an implemented CSR
+pub struct Csr {
+    pub address: u16,
+    pub name: &'static str,
+    /// Bits that software can write, the others keep their value
+    pub write_mask: u32,
+}
+
+/// A counter, or one of its halves, writable as a whole
+const fn counter(address: u16, name: &'static str) -> Csr {
+    Csr {
+        address,
+        name,
+        write_mask: 0xffff_ffff,
+    }
+}
+
+/// Every implemented CSR, any other address is considered unimplemented
+pub const IMPLEMENTED: &[Csr] = &[
+    Csr {
+        address: MVENDORID,
+        name: "mvendorid",
+        write_mask: 0,
+    },
+    Csr {
+        address: MARCHID,
+        name: "marchid",
+        write_mask: 0,
+    },
+    Csr {
+        address: MIMPID,
+        name: "mimpid",
+        write_mask: 0,
+    },
+    Csr {
+        address: MHARTID,
+        name: "mhartid",
+        write_mask: 0,
+    },
+    Csr {
+        address: MSTATUS,
+        name: "mstatus",
+        // MPP is writable but only machine mode exists, see «Csrs::write»
+        write_mask: MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP,
+    },
+    Csr {
+        address: MISA,
+        name: "misa",
+        // Extensions can't be turned off
+        write_mask: 0,
+    },
+    Csr {
+        address: MIE,
+        name: "mie",
+        write_mask: MSIP | MTIP | MEIP,
+    },
+    Csr {
+        address: MTVEC,
+        name: "mtvec",
+        write_mask: 0xffff_ffff,
+    },
+    Csr {
+        address: MCOUNTINHIBIT,
+        name: "mcountinhibit",
+        // CY, IR and HPM3-6, bit 1 is reserved for the time counter which can't be stopped
+        write_mask: 0b111_1101,
+    },
+    // Events are WARL, and unknown ones count nothing
+    counter(MHPMEVENT3, "mhpmevent3"),
+    counter(MHPMEVENT3 + 1, "mhpmevent4"),
+    counter(MHPMEVENT3 + 2, "mhpmevent5"),
+    counter(MHPMEVENT3 + 3, "mhpmevent6"),
+    Csr {
+        address: MSCRATCH,
+        name: "mscratch",
+        write_mask: 0xffff_ffff,
+    },
+    Csr {
+        address: MEPC,
+        name: "mepc",
+        // Instructions are always aligned to 4 bytes
+        write_mask: !0b11,
+    },
+    Csr {
+        address: MCAUSE,
+        name: "mcause",
+        write_mask: 0xffff_ffff,
+    },
+    Csr {
+        address: MTVAL,
+        name: "mtval",
+        write_mask: 0xffff_ffff,
+    },
+    Csr {
+        address: MIP,
+        name: "mip",
+        // Pending interrupts are only set and cleared by the devices raising them
+        write_mask: 0,
+    },
+    Csr {
+        address: SATP,
+        name: "satp",
+        // MODE is hardwired to Bare, since address translation isn't implemented
+        write_mask: 0x7fff_ffff,
+    },
+    counter(MCYCLE, "mcycle"),
+    counter(MINSTRET, "minstret"),
+    counter(MHPMCOUNTER3, "mhpmcounter3"),
+    counter(MHPMCOUNTER3 + 1, "mhpmcounter4"),
+    counter(MHPMCOUNTER3 + 2, "mhpmcounter5"),
+    counter(MHPMCOUNTER3 + 3, "mhpmcounter6"),
+    counter(MCYCLEH, "mcycleh"),
+    counter(MINSTRETH, "minstreth"),
+    counter(MHPMCOUNTER3H, "mhpmcounter3h"),
+    counter(MHPMCOUNTER3H + 1, "mhpmcounter4h"),
+    counter(MHPMCOUNTER3H + 2, "mhpmcounter5h"),
+    counter(MHPMCOUNTER3H + 3, "mhpmcounter6h"),
+    // Their write mask doesn't matter, being read-only
+    counter(CYCLE, "cycle"),
+    counter(INSTRET, "instret"),
+    counter(HPMCOUNTER3, "hpmcounter3"),
+    counter(HPMCOUNTER3 + 1, "hpmcounter4"),
+    counter(HPMCOUNTER3 + 2, "hpmcounter5"),
+    counter(HPMCOUNTER3 + 3, "hpmcounter6"),
+    counter(CYCLEH, "cycleh"),
+    counter(INSTRETH, "instreth"),
+    counter(HPMCOUNTER3H, "hpmcounter3h"),
+    counter(HPMCOUNTER3H + 1, "hpmcounter4h"),
+    counter(HPMCOUNTER3H + 2, "hpmcounter5h"),
+    counter(HPMCOUNTER3H + 3, "hpmcounter6h"),
+];
+
+#[derive(Debug)]
+pub enum CsrError {
+    Unimplemented(u16),
+    /// Attempted write to a read-only CSR
+    ReadOnly(u16),
+}
+
+pub fn find(address: u16) -> Option<&'static Csr> {
+    IMPLEMENTED.iter().find(|csr| csr.address == address)
+}
+
+/// Looks up the address of a CSR by name
+pub fn address(name: &str) -> Option<u16> {
+    IMPLEMENTED
+        .iter()
+        .find(|csr| csr.name == name)
+        .map(|csr| csr.address)
+}
+
+pub struct Csrs {
+    /// Indexed by address, only the implemented CSRs are ever used
+    values: Vec<u32>,
+}
+
+impl Default for Csrs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Csrs {
+    pub fn new() -> Self {
+        let mut csrs = Self {
+            values: vec![0; 4096],
+        };
+        csrs.set(MISA, MISA_VALUE);
+        csrs.set(MSTATUS, MSTATUS_MPP);
+        csrs
+    }
+
+    /// Reads a CSR as an instruction would
+    pub fn read(&self, address: u16) -> Result<u32, CsrError> {
+        find(address)
+            .map(|_| self.get(address))
+            .ok_or(CsrError::Unimplemented(address))
+    }
+
+    /// Writes a CSR as an instruction would, leaving read-only bits alone
+    pub fn write(&mut self, address: u16, value: u32) -> Result<(), CsrError> {
+        let csr = find(address).ok_or(CsrError::Unimplemented(address))?;
+        // The top two bits of the address mark read-only CSRs
+        if address >> 10 == 0b11 {
+            return Err(CsrError::ReadOnly(address));
+        }
+        let mut value = (self.get(address) & !csr.write_mask) | (value & csr.write_mask);
+        match address {
+            // Only machine mode exists, so it's the only legal value of MPP
+            MSTATUS => value |= MSTATUS_MPP,
+            // Only the direct (0) and vectored (1) modes exist
+            MTVEC if value & 0b11 > 1 => value &= !0b11,
+            _ => {}
+        }
+        self.set(address, value);
+        Ok(())
+    }
+
+    /// Reads a CSR without any check, as the hardware itself would
+    pub fn get(&self, address: u16) -> u32 {
+        match address {
+            // The read-only copies of the counters
+            0xc00..=0xc1f | 0xc80..=0xc9f => self.values[address as usize - 0x100],
+            _ => self.values[address as usize],
+        }
+    }
+
+    /// Writes a CSR without any check, as the hardware itself would
+    pub fn set(&mut self, address: u16, value: u32) {
+        self.values[address as usize] = value;
+    }
+}
+
+/// Name of a trap cause, as found in mcause
+pub fn cause_name(interrupt: bool, code: u32) -> &'static str {
+    if interrupt {
+        match code {
+            1 => "Supervisor software interrupt",
+            3 => "Machine software interrupt",
+            5 => "Supervisor timer interrupt",
+            7 => "Machine timer interrupt",
+            9 => "Supervisor external interrupt",
+            11 => "Machine external interrupt",
+            _ => "Reserved",
+        }
+    } else {
+        match code {
+            0 => "Instruction address misaligned",
+            1 => "Instruction access fault",
+            2 => "Illegal instruction",
+            3 => "Breakpoint",
+            4 => "Load address misaligned",
+            5 => "Load access fault",
+            6 => "Store/AMO address misaligned",
+            7 => "Store/AMO access fault",
+            8 => "Environment call from U-mode",
+            9 => "Environment call from S-mode",
+            11 => "Environment call from M-mode",
+            12 => "Instruction page fault",
+            13 => "Load page fault",
+            15 => "Store/AMO page fault",
+            _ => "Reserved",
+        }
+    }
+}
+
+fn privilege_name(level: u32) -> &'static str {
+    match level {
+        0b00 => "U",
+        0b01 => "S",
+        0b11 => "M",
+        _ => "Reserved",
+    }
+}
+
+fn bit(value: u32, bit: u32) -> String {
+    ((value >> bit) & 1).to_string()
+}
+
+/// Names and values of the interrupt bits found in mip and mie
+fn interrupt_fields(value: u32) -> Vec<(&'static str, String)> {
+    [
+        ("SSIP/SSIE", 1),
+        ("MSIP/MSIE", 3),
+        ("STIP/STIE", 5),
+        ("MTIP/MTIE", 7),
+        ("SEIP/SEIE", 9),
+        ("MEIP/MEIE", 11),
+    ]
+    .into_iter()
+    .map(|(name, position)| (name, bit(value, position)))
+    .collect()
+}
+
+/// Breaks the value of a CSR down into its fields, as (name, value) pairs
+///
+/// CSRs without any meaningful field (e.g. mscratch) result in an empty list
+pub fn fields(address: u16, value: u32) -> Vec<(&'static str, String)> {
+    match address {
+        MSTATUS => vec![
+            ("SD", bit(value, 31)),
+            ("TSR", bit(value, 22)),
+            ("TW", bit(value, 21)),
+            ("TVM", bit(value, 20)),
+            ("MXR", bit(value, 19)),
+            ("SUM", bit(value, 18)),
+            ("MPRV", bit(value, 17)),
+            ("XS", ((value >> 15) & 0b11).to_string()),
+            ("FS", ((value >> 13) & 0b11).to_string()),
+            ("MPP", privilege_name((value >> 11) & 0b11).to_owned()),
+            ("VS", ((value >> 9) & 0b11).to_string()),
+            ("SPP", privilege_name((value >> 8) & 0b1).to_owned()),
+            ("MPIE", bit(value, 7)),
+            ("UBE", bit(value, 6)),
+            ("SPIE", bit(value, 5)),
+            ("MIE", bit(value, 3)),
+            ("SIE", bit(value, 1)),
+        ],
+        MISA => vec![
+            ("MXL", (16 << (value >> 30)).to_string() + " bits"),
+            (
+                "Extensions",
+                (0..26)
+                    .filter(|i| value & (1 << i) != 0)
+                    .map(|i| (b'A' + i as u8) as char)
+                    .collect(),
+            ),
+        ],
+        MTVEC => vec![
+            ("BASE", format!("0x{:08x}", value & !0b11)),
+            (
+                "MODE",
+                match value & 0b11 {
+                    0 => "Direct",
+                    1 => "Vectored",
+                    _ => "Reserved",
+                }
+                .to_owned(),
+            ),
+        ],
+        MCAUSE => {
+            let interrupt = value >> 31 == 1;
+            let code = value & 0x7fff_ffff;
+            vec![
+                ("Interrupt", bit(value, 31)),
+                ("Exception code", code.to_string()),
+                ("Cause", cause_name(interrupt, code).to_owned()),
+            ]
+        }
+        MIP | MIE => interrupt_fields(value),
+        MCOUNTINHIBIT => [
+            ("CY", 0),
+            ("IR", 2),
+            ("HPM3", 3),
+            ("HPM4", 4),
+            ("HPM5", 5),
+            ("HPM6", 6),
+        ]
+        .into_iter()
+        .map(|(name, position)| (name, bit(value, position)))
+        .collect(),
+        MHPMEVENT3..=LAST_MHPMEVENT => vec![(
+            "Event",
+            Event::from_code(value)
+                .map_or("None", Event::name)
+                .to_owned(),
+        )],
+        SATP => vec![
+            (
+                "MODE",
+                if value >> 31 == 1 { "Sv32" } else { "Bare" }.to_owned(),
+            ),
+            ("ASID", ((value >> 22) & 0x1ff).to_string()),
+            ("PPN", format!("0x{:06x}", value & 0x3f_ffff)),
+            (
+                "Root page table",
+                format!("0x{:09x}", (value as u64 & 0x3f_ffff) << 12),
+            ),
+        ],
+        _ => vec![],
+    }
+}
@@ -0,0 +1,380 @@
+//! Cache of decoded basic blocks, so that instructions executed again are neither fetched nor decoded again
+//!
+//! A block is a run of instructions ending with the first one that may not fall through to the next (a branch, a jump, a system instruction or «fence.i»).
+//! Blocks are dropped when a store overwrites any of their instructions, and all of them are dropped by «fence.i».
+//! With the «jit» feature, blocks the interpreter enters often enough are translated to native code too, and dropping any of them undoes every link between the translations.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasherDefault, Hasher},
+};
+
+use crate::machine::memory::{AccessKind, MemoryBus};
+
+use super::constants::Instruction;
+#[cfg(feature = "jit")]
+use super::jit::{self, Link, Native, Translation};
+
+/// Longest block that gets decoded at once, so that running into a long stretch of straight-line code (or data) doesn't decode it all
+const MAX_BLOCK_LENGTH: usize = 64;
+/// Stores are matched against the blocks in the same page
+const PAGE_SHIFT: u32 = 12;
+/// Entries of the table of recently entered blocks, which is looked up before the map of all of them
+const RECENT_BLOCKS: usize = 4096;
+/// Marks an empty entry of the table of recently entered blocks, since blocks always start at an even address
+const NO_BLOCK: u32 = 1;
+/// Times the interpreter enters a block before it's translated, so that code running only a few times isn't
+#[cfg(feature = "jit")]
+const TRANSLATION_THRESHOLD: u32 = 16;
+
+/// Hashes addresses with a single multiplication, since the default hasher is slower than decoding the instruction again
+#[derive(Default)]
+struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u32(byte as u32);
+        }
+    }
+
+    fn write_u32(&mut self, address: u32) {
+        self.0 = (self.0 ^ address as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+
+    fn finish(&self) -> u64 {
+        // The table picks buckets with the low bits, which the multiplication leaves with less entropy
+        self.0 ^ (self.0 >> 32)
+    }
+}
+
+type AddressMap<V> = HashMap<u32, V, BuildHasherDefault<AddressHasher>>;
+
+pub struct DecodeCache {
+    /// Decoded instructions (with their raw form) of every block, a dropped block is left empty for the next one to reuse
+    blocks: Vec<Vec<(u32, Instruction)>>,
+    /// Indices of the dropped blocks
+    free: Vec<usize>,
+    /// Index of every block, by the address of its first instruction
+    starts: AddressMap<usize>,
+    /// First address and index of recently entered blocks, by a few bits of the address
+    recent: Vec<(u32, usize)>,
+    /// Address ranges (first included, last excluded) of the blocks with instructions in each page
+    pages: AddressMap<Vec<(u32, u32)>>,
+    /// Block the last instruction came from, with the address and index of the next instruction in it
+    current: Option<(usize, u32, usize)>,
+    /// Native code of every block, by index
+    #[cfg(feature = "jit")]
+    native: Vec<Native>,
+    /// Address the native code last went on to without being linked there, and the link of the exit it took
+    #[cfg(feature = "jit")]
+    unlinked: Option<(u32, *const Link)>,
+    /// First address of the blocks dropped since «natively_executed» was last called, with how many of their instructions ran natively
+    #[cfg(feature = "jit")]
+    dropped_reached: Vec<(u32, usize)>,
+}
+
+// SAFETY: The only pointer is «unlinked», to a link owned by one of the translations in «native»
+#[cfg(feature = "jit")]
+unsafe impl Send for DecodeCache {}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self {
+            blocks: vec![],
+            free: vec![],
+            starts: AddressMap::default(),
+            recent: vec![(NO_BLOCK, 0); RECENT_BLOCKS],
+            pages: AddressMap::default(),
+            current: None,
+            #[cfg(feature = "jit")]
+            native: vec![],
+            #[cfg(feature = "jit")]
+            unlinked: None,
+            #[cfg(feature = "jit")]
+            dropped_reached: vec![],
+        }
+    }
+
+    /// Returns the decoded instruction at «pc» and its raw form, decoding the block starting there if needed
+    ///
+    /// Nothing is returned if the instruction can't be fetched or decoded, which is left to the CPU to report
+    pub fn lookup(&mut self, pc: u32, memory_bus: &mut MemoryBus) -> Option<(u32, Instruction)> {
+        // Fast path: the instruction following the previous one in the same block
+        if let Some((block, next_pc, index)) = &mut self.current {
+            if pc == *next_pc {
+                if let Some(&decoded) = self.blocks[*block].get(*index) {
+                    *next_pc = pc.wrapping_add(4);
+                    *index += 1;
+                    return Some(decoded);
+                }
+            }
+        }
+        let block = self.enter(pc, memory_bus)?;
+        self.current = Some((block, pc.wrapping_add(4), 1));
+        #[cfg(feature = "jit")]
+        self.count_entry(block, pc);
+        self.blocks[block].first().copied()
+    }
+
+    /// Returns the index of the block starting at «pc» if it has native code (see «translation»)
+    ///
+    /// Nothing is returned in the middle of the block the last instruction came from, or for blocks that weren't translated.
+    /// The exit the native code last went on to «pc» through is linked to it
+    #[cfg(feature = "jit")]
+    pub fn native(&mut self, pc: u32, memory_bus: &mut MemoryBus) -> Option<usize> {
+        if let Some((block, next_pc, index)) = self.current {
+            if pc == next_pc && index < self.blocks[block].len() {
+                return None;
+            }
+        }
+        let block = self.enter(pc, memory_bus)?;
+        let Native::Translated(translation) = &self.native[block] else {
+            return None;
+        };
+        if let Some((_, link)) = self.unlinked.take_if(|&mut (target, _)| target == pc) {
+            // SAFETY: Links are forgotten whenever a block (and so a translation) is dropped
+            unsafe { translation.link(link) };
+        }
+        // The native code may run any number of instructions after it
+        self.current = None;
+        Some(block)
+    }
+
+    /// Native code of «block», if it has any
+    #[cfg(feature = "jit")]
+    pub fn translation(&self, block: usize) -> Option<&Translation> {
+        match &self.native[block] {
+            Native::Translated(translation) => Some(translation),
+            _ => None,
+        }
+    }
+
+    /// Wether the block starting at «pc» was already decoded and translated, without decoding it
+    #[cfg(feature = "jit")]
+    pub fn has_native(&self, pc: u32) -> bool {
+        let block = match self.recent[recent_slot(pc)] {
+            (start, block) if start == pc => Some(block),
+            _ => self.starts.get(&pc).copied(),
+        };
+        block.is_some_and(|block| matches!(self.native[block], Native::Translated(_)))
+    }
+
+    /// Remembers that native code went on to «pc» through «link», unless it's null, to link it there the next time native code runs from «pc»
+    #[cfg(feature = "jit")]
+    pub fn exited(&mut self, pc: u32, link: *const Link) {
+        self.unlinked = (!link.is_null()).then_some((pc, link));
+    }
+
+    /// Calls «visit» with the address of every instruction executed natively since the last call (or the first one)
+    #[cfg(feature = "jit")]
+    pub fn natively_executed(&mut self, mut visit: impl FnMut(u32)) {
+        let mut reached = std::mem::take(&mut self.dropped_reached);
+        for native in &self.native {
+            if let Native::Translated(translation) = native {
+                reached.push((translation.pc, translation.take_reached()));
+            }
+        }
+        for (start, count) in reached {
+            for index in 0..count {
+                visit(start.wrapping_add(4 * index as u32));
+            }
+        }
+    }
+
+    /// Finds the index of the block starting at «pc», decoding it if needed
+    fn enter(&mut self, pc: u32, memory_bus: &mut MemoryBus) -> Option<usize> {
+        let slot = recent_slot(pc);
+        match self.recent[slot] {
+            (start, block) if start == pc => Some(block),
+            _ => {
+                let block = match self.starts.get(&pc) {
+                    Some(&block) => block,
+                    None => self.decode(pc, memory_bus)?,
+                };
+                self.recent[slot] = (pc, block);
+                Some(block)
+            }
+        }
+    }
+
+    /// Decodes the block starting at «pc», returning its index unless not even its first instruction can be decoded
+    fn decode(&mut self, pc: u32, memory_bus: &mut MemoryBus) -> Option<usize> {
+        let block = self.free.pop().unwrap_or_else(|| {
+            self.blocks.push(vec![]);
+            #[cfg(feature = "jit")]
+            self.native.push(Native::Untried(0));
+            self.blocks.len() - 1
+        });
+        let instructions = &mut self.blocks[block];
+        decode_block(pc, memory_bus, instructions);
+        if instructions.is_empty() {
+            self.free.push(block);
+            self.current = None;
+            return None;
+        }
+        let end = pc.wrapping_add(4 * instructions.len() as u32);
+        for page in (pc >> PAGE_SHIFT)..=(end.wrapping_sub(1) >> PAGE_SHIFT) {
+            self.pages.entry(page).or_default().push((pc, end));
+        }
+        self.starts.insert(pc, block);
+        Some(block)
+    }
+
+    /// Wether a store of «size» bytes at «address» overwrites any of the blocks
+    pub fn overlaps(&self, address: u32, size: u32) -> bool {
+        let end = address.wrapping_add(size);
+        (address >> PAGE_SHIFT..=end.wrapping_sub(1) >> PAGE_SHIFT).any(|page| {
+            self.pages.get(&page).is_some_and(|ranges| {
+                ranges
+                    .iter()
+                    .any(|&(start, block_end)| start < end && address < block_end)
+            })
+        })
+    }
+
+    /// Drops the blocks overwritten by a store of «size» bytes at «address»
+    pub fn invalidate(&mut self, address: u32, size: u32) {
+        let end = address.wrapping_add(size);
+        for page in (address >> PAGE_SHIFT)..=(end.wrapping_sub(1) >> PAGE_SHIFT) {
+            let Some(ranges) = self.pages.get_mut(&page) else {
+                continue;
+            };
+            let overwritten = |&(start, block_end): &(u32, u32)| start < end && address < block_end;
+            // Most stores don't overwrite code, and stop here
+            if !ranges.iter().any(overwritten) {
+                continue;
+            }
+            let dropped: Vec<u32> = ranges
+                .iter()
+                .filter(|range| overwritten(range))
+                .map(|&(start, _)| start)
+                .collect();
+            ranges.retain(|range| !overwritten(range));
+            if ranges.is_empty() {
+                self.pages.remove(&page);
+            }
+            for start in dropped {
+                self.drop_block(start);
+            }
+        }
+    }
+
+    /// Drops the block starting at «start», if it wasn't already dropped through another page
+    fn drop_block(&mut self, start: u32) {
+        let Some(block) = self.starts.remove(&start) else {
+            return;
+        };
+        self.blocks[block].clear();
+        #[cfg(feature = "jit")]
+        if self.drop_native(block) {
+            for native in &self.native {
+                if let Native::Translated(translation) = native {
+                    translation.unlink();
+                }
+            }
+            self.unlinked = None;
+        }
+        self.free.push(block);
+        let slot = recent_slot(start);
+        if self.recent[slot].0 == start {
+            self.recent[slot] = (NO_BLOCK, 0);
+        }
+        if matches!(self.current, Some((current, _, _)) if current == block) {
+            self.current = None;
+        }
+    }
+
+    /// Drops every block, as «fence.i» requires
+    pub fn clear(&mut self) {
+        for &block in self.starts.values() {
+            self.blocks[block].clear();
+            self.free.push(block);
+        }
+        self.starts.clear();
+        self.recent.fill((NO_BLOCK, 0));
+        self.pages.clear();
+        self.current = None;
+        // No translation is left to link
+        #[cfg(feature = "jit")]
+        {
+            for native in &mut self.native {
+                if let Native::Translated(translation) =
+                    std::mem::replace(native, Native::Untried(0))
+                {
+                    self.dropped_reached
+                        .push((translation.pc, translation.take_reached()));
+                }
+            }
+            self.unlinked = None;
+        }
+    }
+}
+
+#[cfg(feature = "jit")]
+impl DecodeCache {
+    /// Drops the native code of «block», returning wether there was any: then the links to it must be undone
+    fn drop_native(&mut self, block: usize) -> bool {
+        match std::mem::replace(&mut self.native[block], Native::Untried(0)) {
+            Native::Translated(translation) => {
+                self.dropped_reached
+                    .push((translation.pc, translation.take_reached()));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Counts the interpreter entering «block», which starts at «pc», translating it once it did often enough
+    fn count_entry(&mut self, block: usize, pc: u32) {
+        let Native::Untried(entries) = &mut self.native[block] else {
+            return;
+        };
+        *entries += 1;
+        if *entries >= TRANSLATION_THRESHOLD {
+            self.native[block] = match jit::translate(pc, &self.blocks[block]) {
+                Some(translation) => Native::Translated(translation),
+                None => Native::Unsupported,
+            };
+        }
+    }
+}
+
+fn recent_slot(address: u32) -> usize {
+    (address >> 2) as usize % RECENT_BLOCKS
+}
+
+/// Decodes the instructions from «pc» up to the end of its basic block into «block», stopping early at anything that can't be fetched or decoded
+fn decode_block(pc: u32, memory_bus: &mut MemoryBus, block: &mut Vec<(u32, Instruction)>) {
+    let mut address = pc;
+    while block.len() < MAX_BLOCK_LENGTH {
+        let Ok(raw) = memory_bus.read_u32(address, AccessKind::Fetch) else {
+            break;
+        };
+        let Ok(instruction) = Instruction::try_from(raw) else {
+            break;
+        };
+        block.push((raw, instruction));
+        if ends_block(raw) {
+            break;
+        }
+        address = address.wrapping_add(4);
+    }
+}
+
+/// Wether the instruction may not fall through to the next one, or changes what the next ones are
+fn ends_block(raw: u32) -> bool {
+    match raw & 0x7f {
+        // Branches, jal, jalr and the system instructions
+        0x63 | 0x6f | 0x67 | 0x73 => true,
+        // fence.i
+        0x0f => (raw >> 12) & 0x7 == 0x1,
+        _ => false,
+    }
+}
@@ -0,0 +1,263 @@
+//! Turns raw instructions back into assembly, using ABI register names and pseudo-instructions where they apply
+//!
+//! Branch and jump targets are printed relative to the instruction itself (e.g. «beqz a0, pc - 8»), since a raw instruction doesn't carry its own address
+
+use crate::assembler::isa;
+
+use super::{constants::*, csr};
+
+/// ABI name of a register (e.g. «sp» for x2)
+pub fn register_name(register: u32) -> &'static str {
+    isa::builtin()
+        .register_abi_name(register)
+        .unwrap_or("unknown")
+}
+
+/// Name of a CSR, or its address if it isn't implemented
+fn csr_name(address: u32) -> String {
+    csr::find(address as u16)
+        .map(|csr| csr.name.to_owned())
+        .unwrap_or_else(|| format!("0x{address:03x}"))
+}
+
+/// Formats an offset relative to the program counter
+fn relative(offset: u32) -> String {
+    let offset = offset as i32;
+    if offset < 0 {
+        format!("pc - {}", offset.unsigned_abs())
+    } else {
+        format!("pc + {offset}")
+    }
+}
+
+/// Formats the predecessor/successor set of a fence (e.g. «rw»)
+fn fence_set(bits: u32) -> String {
+    ["i", "o", "r", "w"]
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| bits & (0b1000 >> i) != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Disassembles a single instruction, falling back to a «.word» directive for anything that can't be decoded
+pub fn disassemble(raw: u32) -> String {
+    let instruction = match Instruction::try_from(raw) {
+        Ok(instruction) => instruction,
+        Err(_) => return format!(".word 0x{raw:08x}"),
+    };
+    let r = register_name;
+    let unknown = || format!(".word 0x{raw:08x}");
+    match instruction {
+        Instruction::R(RType {
+            opcode: 0x33,
+            rd,
+            rs1,
+            rs2,
+            funct3,
+            funct7,
+        }) => {
+            let mnemonic = match (funct3, funct7) {
+                (0x0, 0x00) => "add",
+                (0x0, 0x20) if rs1 == 0 => return format!("neg {}, {}", r(rd), r(rs2)),
+                (0x0, 0x20) => "sub",
+                (0x1, 0x00) => "sll",
+                (0x2, 0x00) if rs2 == 0 => return format!("sltz {}, {}", r(rd), r(rs1)),
+                (0x2, 0x00) if rs1 == 0 => return format!("sgtz {}, {}", r(rd), r(rs2)),
+                (0x2, 0x00) => "slt",
+                (0x3, 0x00) if rs1 == 0 => return format!("snez {}, {}", r(rd), r(rs2)),
+                (0x3, 0x00) => "sltu",
+                (0x4, 0x00) => "xor",
+                (0x5, 0x00) => "srl",
+                (0x5, 0x20) => "sra",
+                (0x6, 0x00) => "or",
+                (0x7, 0x00) => "and",
+                (funct3, 0x01) => [
+                    "mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu",
+                ][funct3 as usize],
+                _ => return unknown(),
+            };
+            format!("{mnemonic} {}, {}, {}", r(rd), r(rs1), r(rs2))
+        }
+        Instruction::R(RType {
+            opcode: 0x2f,
+            rd,
+            rs1,
+            rs2,
+            funct3: 0x2,
+            funct7,
+        }) => {
+            let mnemonic = match funct7 >> 2 {
+                0x02 if rs2 == 0 => "lr.w",
+                0x03 => "sc.w",
+                0x00 => "amoadd.w",
+                0x01 => "amoswap.w",
+                0x04 => "amoxor.w",
+                0x08 => "amoor.w",
+                0x0c => "amoand.w",
+                0x10 => "amomin.w",
+                0x14 => "amomax.w",
+                0x18 => "amominu.w",
+                0x1c => "amomaxu.w",
+                _ => return unknown(),
+            };
+            // Ordering bits
+            let ordering = match funct7 & 0b11 {
+                0b00 => "",
+                0b01 => ".rl",
+                0b10 => ".aq",
+                _ => ".aqrl",
+            };
+            if mnemonic == "lr.w" {
+                format!("{mnemonic}{ordering} {}, ({})", r(rd), r(rs1))
+            } else {
+                format!("{mnemonic}{ordering} {}, {}, ({})", r(rd), r(rs2), r(rs1))
+            }
+        }
+        Instruction::R(_) => unknown(),
+        Instruction::I(IType {
+            opcode,
+            rd,
+            rs1,
+            imm,
+            funct3,
+        }) => {
+            let signed = imm as i32;
+            match (opcode, funct3) {
+                // Loads
+                (0x03, _) => {
+                    let mnemonic = match funct3 {
+                        0x0 => "lb",
+                        0x1 => "lh",
+                        0x2 => "lw",
+                        0x4 => "lbu",
+                        0x5 => "lhu",
+                        _ => return unknown(),
+                    };
+                    format!("{mnemonic} {}, {signed}({})", r(rd), r(rs1))
+                }
+                (0x0f, 0x1) => "fence.i".to_owned(),
+                (0x0f, 0x0) => {
+                    let (predecessor, successor) = ((imm >> 4) & 0xf, imm & 0xf);
+                    if predecessor == 0xf && successor == 0xf {
+                        "fence".to_owned()
+                    } else {
+                        format!("fence {}, {}", fence_set(predecessor), fence_set(successor))
+                    }
+                }
+                (0x13, 0x0) if rd == 0 && rs1 == 0 && imm == 0 => "nop".to_owned(),
+                (0x13, 0x0) if rs1 == 0 => format!("li {}, {signed}", r(rd)),
+                (0x13, 0x0) if imm == 0 => format!("mv {}, {}", r(rd), r(rs1)),
+                (0x13, 0x3) if imm == 1 => format!("seqz {}, {}", r(rd), r(rs1)),
+                (0x13, 0x4) if signed == -1 => format!("not {}, {}", r(rd), r(rs1)),
+                // Shifts by an immediate, whose upper bits act as a funct7
+                (0x13, 0x1 | 0x5) => {
+                    let mnemonic = match (funct3, imm >> 5) {
+                        (0x1, 0x00) => "slli",
+                        (0x5, 0x00) => "srli",
+                        (0x5, 0x20) => "srai",
+                        _ => return unknown(),
+                    };
+                    format!("{mnemonic} {}, {}, {}", r(rd), r(rs1), imm & 0x1f)
+                }
+                (0x13, _) => {
+                    let mnemonic = match funct3 {
+                        0x0 => "addi",
+                        0x2 => "slti",
+                        0x3 => "sltiu",
+                        0x4 => "xori",
+                        0x6 => "ori",
+                        0x7 => "andi",
+                        _ => return unknown(),
+                    };
+                    format!("{mnemonic} {}, {}, {signed}", r(rd), r(rs1))
+                }
+                (0x67, 0x0) if rd == 0 && rs1 == 1 && imm == 0 => "ret".to_owned(),
+                (0x67, 0x0) if rd == 0 && imm == 0 => format!("jr {}", r(rs1)),
+                (0x67, 0x0) if rd == 1 && imm == 0 => format!("jalr {}", r(rs1)),
+                (0x67, 0x0) => format!("jalr {}, {signed}({})", r(rd), r(rs1)),
+                (0x73, 0x0) if rd == 0 && rs1 == 0 => match imm {
+                    0x000 => "ecall".to_owned(),
+                    0x001 => "ebreak".to_owned(),
+                    0x105 => "wfi".to_owned(),
+                    0x302 => "mret".to_owned(),
+                    _ => unknown(),
+                },
+                // CSR instructions, the immediate forms take a zero extended immediate in place of rs1
+                (0x73, 0x1..=0x3 | 0x5..=0x7) => {
+                    let csr = csr_name(imm & 0xfff);
+                    let mnemonic = ["csrrw", "csrrs", "csrrc"][(funct3 & 0x3) as usize - 1];
+                    let (mnemonic, source) = if funct3 & 0x4 == 0 {
+                        (mnemonic.to_owned(), r(rs1).to_owned())
+                    } else {
+                        (format!("{mnemonic}i"), rs1.to_string())
+                    };
+                    if funct3 == 0x2 && rs1 == 0 {
+                        format!("csrr {}, {csr}", r(rd))
+                    } else if rd == 0 {
+                        // «csrrw zero, csr, rs» is «csrw csr, rs» and so on
+                        let alias = mnemonic.replacen("csrr", "csr", 1);
+                        format!("{alias} {csr}, {source}")
+                    } else {
+                        format!("{mnemonic} {}, {csr}, {source}", r(rd))
+                    }
+                }
+                _ => unknown(),
+            }
+        }
+        Instruction::S(SType {
+            opcode: 0x23,
+            rs1,
+            rs2,
+            imm,
+            funct3,
+        }) => {
+            let mnemonic = match funct3 {
+                0x0 => "sb",
+                0x1 => "sh",
+                0x2 => "sw",
+                _ => return unknown(),
+            };
+            format!("{mnemonic} {}, {}({})", r(rs2), imm as i32, r(rs1))
+        }
+        Instruction::S(_) => unknown(),
+        Instruction::B(BType {
+            rs1,
+            rs2,
+            imm,
+            funct3,
+            ..
+        }) => {
+            let target = relative(imm);
+            match (funct3, rs1, rs2) {
+                (0x0, _, 0) => format!("beqz {}, {target}", r(rs1)),
+                (0x1, _, 0) => format!("bnez {}, {target}", r(rs1)),
+                (0x4, _, 0) => format!("bltz {}, {target}", r(rs1)),
+                (0x4, 0, _) => format!("bgtz {}, {target}", r(rs2)),
+                (0x5, _, 0) => format!("bgez {}, {target}", r(rs1)),
+                (0x5, 0, _) => format!("blez {}, {target}", r(rs2)),
+                _ => {
+                    let mnemonic = match funct3 {
+                        0x0 => "beq",
+                        0x1 => "bne",
+                        0x4 => "blt",
+                        0x5 => "bge",
+                        0x6 => "bltu",
+                        0x7 => "bgeu",
+                        _ => return unknown(),
+                    };
+                    format!("{mnemonic} {}, {}, {target}", r(rs1), r(rs2))
+                }
+            }
+        }
+        Instruction::U(UType { opcode, rd, imm }) => {
+            let mnemonic = if opcode == 0x37 { "lui" } else { "auipc" };
+            format!("{mnemonic} {}, 0x{:x}", r(rd), imm >> 12)
+        }
+        Instruction::J(JType { rd, imm, .. }) => match rd {
+            0 => format!("j {}", relative(imm)),
+            1 => format!("jal {}", relative(imm)),
+            _ => format!("jal {}, {}", r(rd), relative(imm)),
+        },
+    }
+}
@@ -0,0 +1,605 @@
+//! Translation of basic blocks into native x86-64 code
+//!
+//! Operations on registers, «lui», «auipc», «mul», loads, stores, and branches and jumps to aligned targets are translated; a translation covers the longest run of them at the start of a block, and everything else is left to the interpreter.
+//! Loads and stores call back into Rust to go through the bus of the hart, store buffer included. One that would raise an exception isn't made: the code returns before it, so that the interpreter executes it and the exception is precise.
+//! A store overwriting decoded instructions or completing a command to the host returns right after it, so that the caller can answer before anything else runs.
+//!
+//! Translations are chained: a branch or jump to a block that was translated too jumps straight into its code, as long as the instructions executed stay within the budget the run was given.
+//! The links are made by the caller, the first time each exit is taken, and all of them are undone whenever a block is dropped.
+//!
+//! The native code follows the System V calling convention: it takes a pointer to the registers (in rdi), one to its «Context» (in rsi) and the budget (in rdx), and returns the address of the next instruction in the low half of rax and the number of instructions executed in the high half.
+//! While it runs, rbx holds the registers, r12 the context, r13 the instructions executed and r14 the budget; the instructions themselves only use eax, ecx and edx.
+
+use std::cell::Cell;
+
+use memmap2::{Mmap, MmapMut};
+
+use super::{
+    constants::{BType, IType, Instruction, JType, RType, SType, UType},
+    decode_cache::DecodeCache,
+    trace::MemoryWrite,
+};
+use crate::machine::{
+    htif::Htif,
+    memory::{AccessKind, MemoryBus},
+};
+
+/// What is known about the native code of a block
+pub enum Native {
+    /// The block hasn't been translated yet, and the interpreter entered it this many times
+    Untried(u32),
+    /// The block starts with an instruction that can't be translated
+    Unsupported,
+    Translated(Translation),
+}
+
+pub struct Translation {
+    /// Address of the first instruction
+    pub pc: u32,
+    code: Mmap,
+    /// How many instructions the code executes at most
+    pub length: usize,
+    /// Where the branches and jumps to other blocks go, read by the native code
+    links: Box<[Link]>,
+    /// Most instructions executed in a single pass through the code since the last «take_reached», written by the native code
+    reached: Box<Cell<u32>>,
+}
+
+/// The code a branch or jump leaving a translation jumps to, once it's linked
+#[repr(C)]
+#[derive(Default)]
+pub struct Link {
+    /// Address of the code after the prologue, zero until linked
+    code: Cell<usize>,
+    /// Instructions the code executes at most, for the budget to be checked before jumping
+    length: Cell<usize>,
+}
+
+/// What the native code reaches through r12
+#[repr(C)]
+pub struct Context<'a, 'b> {
+    /// Link of the last exit taken to a block that wasn't linked yet, written by the native code
+    pub unlinked: *const Link,
+    memory_bus: &'a mut MemoryBus<'b>,
+    /// Blocks the code comes from, which a store must not overwrite without returning right after it
+    decode_cache: &'a DecodeCache,
+    htif: Option<&'a Htif>,
+    /// Wether every store is kept in «writes», instead of only those the caller must answer
+    every_write: bool,
+    pub writes: Vec<MemoryWrite>,
+    /// Wether the code returned for the interpreter to take over, before a load or store it couldn't make or after a store it couldn't go past
+    pub stopped: bool,
+}
+
+impl<'a, 'b> Context<'a, 'b> {
+    pub fn new(
+        memory_bus: &'a mut MemoryBus<'b>,
+        decode_cache: &'a DecodeCache,
+        htif: Option<&'a Htif>,
+        every_write: bool,
+    ) -> Self {
+        Self {
+            unlinked: std::ptr::null(),
+            memory_bus,
+            decode_cache,
+            htif,
+            every_write,
+            writes: vec![],
+            stopped: false,
+        }
+    }
+}
+
+impl Translation {
+    /// Executes the translated instructions, and the ones of the translations linked to them, as long as there are at most «budget» of them
+    ///
+    /// Returns the address of the next instruction and how many were executed
+    pub fn run(
+        &self,
+        registers: &mut [u32; 32],
+        context: &mut Context,
+        budget: usize,
+    ) -> (u32, usize) {
+        // SAFETY: The code was emitted by «translate», which only reads and writes the 32 registers, calls «load» and «store» with the context, and jumps to the code of the translations linked to it, which are all alive since dropping any of them undoes every link
+        let function = unsafe {
+            std::mem::transmute::<*const u8, extern "sysv64" fn(*mut u32, *mut Context, u64) -> u64>(
+                self.code.as_ptr(),
+            )
+        };
+        let result = function(registers.as_mut_ptr(), context, budget as u64);
+        (result as u32, (result >> 32) as usize)
+    }
+
+    /// Makes the exit with «link» jump straight to this code
+    ///
+    /// # Safety
+    /// «link» must belong to a translation that's still alive
+    pub unsafe fn link(&self, link: *const Link) {
+        // SAFETY: Guaranteed by the caller
+        let link = unsafe { &*link };
+        link.code.set(self.code.as_ptr() as usize + PROLOGUE.len());
+        link.length.set(self.length);
+    }
+
+    /// Makes every exit of this code return to the caller again
+    pub fn unlink(&self) {
+        for link in &self.links {
+            link.code.set(0);
+        }
+    }
+
+    /// Most instructions executed in a single pass through the code since the last call, which are the first ones of the block
+    pub fn take_reached(&self) -> usize {
+        self.reached.replace(0) as usize
+    }
+}
+
+/// Host registers, numbered as in ModRM bytes
+#[derive(Clone, Copy)]
+enum Host {
+    Eax = 0,
+    Ecx = 1,
+    Edx = 2,
+}
+
+/// Operations of eax with ecx, leaving the result in eax
+const ADD: &[u8] = &[0x01, 0xc8];
+const SUB: &[u8] = &[0x29, 0xc8];
+const XOR: &[u8] = &[0x31, 0xc8];
+const OR: &[u8] = &[0x09, 0xc8];
+const AND: &[u8] = &[0x21, 0xc8];
+/// Shifts by cl, which x86 masks to five bits like RISC-V does
+const SHL: &[u8] = &[0xd3, 0xe0];
+const SHR: &[u8] = &[0xd3, 0xe8];
+const SAR: &[u8] = &[0xd3, 0xf8];
+/// cmp eax, ecx; setl/setb al; movzx eax, al
+const SLT: &[u8] = &[0x39, 0xc8, 0x0f, 0x9c, 0xc0, 0x0f, 0xb6, 0xc0];
+const SLTU: &[u8] = &[0x39, 0xc8, 0x0f, 0x92, 0xc0, 0x0f, 0xb6, 0xc0];
+/// imul eax, ecx
+const MUL: &[u8] = &[0x0f, 0xaf, 0xc1];
+
+/// push rbx; push r12; push r13; push r14; sub rsp, 8 (realigning the stack for calls); mov rbx, rdi; mov r12, rsi; mov r14, rdx; xor r13d, r13d
+const PROLOGUE: &[u8] = &[
+    0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x48, 0x83, 0xec, 0x08, 0x48, 0x89, 0xfb, 0x49, 0x89,
+    0xf4, 0x49, 0x89, 0xd6, 0x45, 0x31, 0xed,
+];
+/// shl r13, 32; or rax, r13; add rsp, 8; pop r14; pop r13; pop r12; pop rbx; ret
+const EPILOGUE: &[u8] = &[
+    0x49, 0xc1, 0xe5, 0x20, 0x4c, 0x09, 0xe8, 0x48, 0x83, 0xc4, 0x08, 0x41, 0x5e, 0x41, 0x5d, 0x41,
+    0x5c, 0x5b, 0xc3,
+];
+
+/// Second byte of the near conditional jumps
+const JB: u8 = 0x82;
+const JAE: u8 = 0x83;
+const JE: u8 = 0x84;
+const JNE: u8 = 0x85;
+const JL: u8 = 0x8c;
+const JGE: u8 = 0x8d;
+
+/// Results of «store»
+const STORED: u32 = 0;
+/// The store wasn't made, the interpreter makes it
+const HANDED_OVER: u32 = 1;
+/// The store was made, but nothing may run after it
+const STORED_LAST: u32 = 2;
+/// Result of «load» when the load wasn't made, above any value loaded
+const LOAD_HANDED_OVER: u64 = 1 << 32;
+
+struct Emitter {
+    code: Vec<u8>,
+    /// Positions of the offsets of the jumps to the epilogue
+    returns: Vec<usize>,
+    /// Links of the exits, of which «linked» are used
+    links: Box<[Link]>,
+    linked: usize,
+    reached: Box<Cell<u32>>,
+}
+
+impl Emitter {
+    fn new() -> Self {
+        Self {
+            code: PROLOGUE.to_vec(),
+            returns: vec![],
+            // A translation ends with a branch, with two exits, or falls through or jumps to a single one
+            links: Box::new([Link::default(), Link::default()]),
+            linked: 0,
+            reached: Box::default(),
+        }
+    }
+
+    /// Loads a RISC-V register into a host register
+    fn load(&mut self, host: Host, register: u32) {
+        if register == 0 {
+            // xor host, host
+            self.code
+                .extend([0x31, 0xc0 | (host as u8) << 3 | host as u8]);
+        } else {
+            // mov host, [rbx + 4 * register]
+            self.code.extend([0x8b, 0x83 | (host as u8) << 3]);
+            self.code.extend((4 * register).to_le_bytes());
+        }
+    }
+
+    /// Stores eax into a RISC-V register, unless it is x0
+    fn store(&mut self, register: u32) {
+        if register != 0 {
+            // mov [rbx + 4 * register], eax
+            self.code.extend([0x89, 0x83]);
+            self.code.extend((4 * register).to_le_bytes());
+        }
+    }
+
+    /// mov host, value
+    fn immediate(&mut self, host: Host, value: u32) {
+        self.code.push(0xb8 + host as u8);
+        self.code.extend(value.to_le_bytes());
+    }
+
+    /// rd = operation(rs1, second operand already in ecx)
+    fn operation(&mut self, rd: u32, rs1: u32, operation: &[u8]) {
+        self.load(Host::Eax, rs1);
+        self.code.extend(operation);
+        self.store(rd);
+    }
+
+    /// Puts rs1 + imm in eax and esi, where it's the second argument of the helpers
+    fn address(&mut self, rs1: u32, imm: u32) {
+        self.load(Host::Eax, rs1);
+        // add eax, imm; mov esi, eax
+        self.code.push(0x05);
+        self.code.extend(imm.to_le_bytes());
+        self.code.extend([0x89, 0xc6]);
+    }
+
+    /// Calls «helper» with the context as its first argument, the others being already in place
+    fn call(&mut self, helper: *const ()) {
+        // mov rdi, r12; mov rax, helper; call rax
+        self.code.extend([0x4c, 0x89, 0xe7, 0x48, 0xb8]);
+        self.code.extend((helper as u64).to_le_bytes());
+        self.code.extend([0xff, 0xd0]);
+    }
+
+    /// Emits a conditional jump to be landed later, returning where its offset is
+    fn jump_if(&mut self, condition: u8) -> usize {
+        self.code.extend([0x0f, condition, 0, 0, 0, 0]);
+        self.code.len() - 4
+    }
+
+    /// Makes the jump whose offset is at «position» land here
+    fn land(&mut self, position: usize) {
+        let offset = (self.code.len() - position - 4) as u32;
+        self.code[position..position + 4].copy_from_slice(&offset.to_le_bytes());
+    }
+
+    /// Emits a jump (whose opcode is already there) to the epilogue
+    fn jump_to_epilogue(&mut self) {
+        self.returns.push(self.code.len());
+        self.code.extend([0, 0, 0, 0]);
+    }
+
+    /// Counts «executed» instructions of this block, the next one being at the address in eax
+    fn count(&mut self, executed: usize) {
+        if executed > 0 {
+            // mov rcx, reached; cmp dword [rcx], executed; jae +6; mov dword [rcx], executed
+            self.code.extend([0x48, 0xb9]);
+            self.code
+                .extend((self.reached.as_ptr() as u64).to_le_bytes());
+            self.code.extend([0x81, 0x39]);
+            self.code.extend((executed as u32).to_le_bytes());
+            self.code.extend([0x73, 0x06, 0xc7, 0x01]);
+            self.code.extend((executed as u32).to_le_bytes());
+        }
+        // add r13, executed
+        self.code.extend([0x49, 0x81, 0xc5]);
+        self.code.extend((executed as u32).to_le_bytes());
+    }
+
+    /// Returns after «executed» instructions of this block, the next one being at the address in eax
+    fn leave(&mut self, executed: usize) {
+        self.count(executed);
+        // jmp epilogue
+        self.code.push(0xe9);
+        self.jump_to_epilogue();
+    }
+
+    /// Returns after «executed» instructions of this block, the next one being at «pc»
+    fn exit(&mut self, pc: u32, executed: usize) {
+        self.immediate(Host::Eax, pc);
+        self.leave(executed);
+    }
+
+    /// Goes on at «pc» after «executed» instructions of this block, jumping straight to its code once it's linked and fits in the budget
+    fn chain(&mut self, pc: u32, executed: usize) {
+        self.immediate(Host::Eax, pc);
+        self.count(executed);
+        let link = &self.links[self.linked] as *const Link as u64;
+        self.linked += 1;
+        // mov rcx, link; mov rdx, [rcx]; test rdx, rdx; jz unlinked
+        self.code.extend([0x48, 0xb9]);
+        self.code.extend(link.to_le_bytes());
+        self.code
+            .extend([0x48, 0x8b, 0x11, 0x48, 0x85, 0xd2, 0x74, 0x12]);
+        // mov r8, [rcx + 8]; add r8, r13; cmp r8, r14; ja epilogue; jmp rdx
+        self.code.extend([
+            0x4c, 0x8b, 0x41, 0x08, 0x4d, 0x01, 0xe8, 0x4d, 0x39, 0xf0, 0x0f, 0x87,
+        ]);
+        self.jump_to_epilogue();
+        self.code.extend([0xff, 0xe2]);
+        // unlinked: mov [r12], rcx; jmp epilogue
+        self.code.extend([0x49, 0x89, 0x0c, 0x24, 0xe9]);
+        self.jump_to_epilogue();
+    }
+
+    /// Appends the epilogue, which every return jumps to, and maps the code
+    fn finish(mut self, pc: u32, length: usize) -> Option<Translation> {
+        let epilogue = self.code.len();
+        for position in std::mem::take(&mut self.returns) {
+            let offset = (epilogue - position - 4) as u32;
+            self.code[position..position + 4].copy_from_slice(&offset.to_le_bytes());
+        }
+        self.code.extend(EPILOGUE);
+        let mut memory = MmapMut::map_anon(self.code.len()).ok()?;
+        memory.copy_from_slice(&self.code);
+        Some(Translation {
+            pc,
+            code: memory.make_exec().ok()?,
+            length,
+            links: self.links,
+            reached: self.reached,
+        })
+    }
+}
+
+/// Translates the longest run of supported instructions at the start of the block at «pc», if there is one
+pub fn translate(pc: u32, block: &[(u32, Instruction)]) -> Option<Translation> {
+    let mut emitter = Emitter::new();
+    let mut length = 0;
+    let mut ended = false;
+    for &(_, instruction) in block {
+        let Some(jumped) = emit(
+            &mut emitter,
+            pc.wrapping_add(4 * length as u32),
+            length,
+            instruction,
+        ) else {
+            break;
+        };
+        length += 1;
+        if jumped {
+            ended = true;
+            break;
+        }
+    }
+    if length == 0 {
+        return None;
+    }
+    // Jumps and branches go on to their target themselves
+    if !ended {
+        emitter.chain(pc.wrapping_add(4 * length as u32), length);
+    }
+    emitter.finish(pc, length)
+}
+
+/// Emits the instruction at «pc», after «index» others of its block, returning wether it jumped (ending the translation), or nothing if it can't be translated
+fn emit(emitter: &mut Emitter, pc: u32, index: usize, instruction: Instruction) -> Option<bool> {
+    match instruction {
+        Instruction::I(IType {
+            opcode: 0x13,
+            rd,
+            rs1,
+            imm,
+            funct3,
+        }) => {
+            let operation = match (funct3, imm >> 5) {
+                (0x0, _) => ADD,
+                (0x1, 0x00) => SHL,
+                (0x2, _) => SLT,
+                (0x3, _) => SLTU,
+                (0x4, _) => XOR,
+                (0x5, 0x00) => SHR,
+                (0x5, 0x20) => SAR,
+                (0x6, _) => OR,
+                (0x7, _) => AND,
+                _ => return None,
+            };
+            emitter.immediate(Host::Ecx, imm);
+            emitter.operation(rd, rs1, operation);
+        }
+        Instruction::R(RType {
+            opcode: 0x33,
+            rd,
+            rs1,
+            rs2,
+            funct3,
+            funct7,
+        }) => {
+            let operation = match (funct3, funct7) {
+                (0x0, 0x00) => ADD,
+                (0x0, 0x20) => SUB,
+                (0x1, 0x00) => SHL,
+                (0x2, 0x00) => SLT,
+                (0x3, 0x00) => SLTU,
+                (0x4, 0x00) => XOR,
+                (0x5, 0x00) => SHR,
+                (0x5, 0x20) => SAR,
+                (0x6, 0x00) => OR,
+                (0x7, 0x00) => AND,
+                (0x0, 0x01) => MUL,
+                _ => return None,
+            };
+            emitter.load(Host::Ecx, rs2);
+            emitter.operation(rd, rs1, operation);
+        }
+        Instruction::U(UType { opcode, rd, imm }) => {
+            let value = if opcode == 0x37 {
+                imm
+            } else {
+                pc.wrapping_add(imm)
+            };
+            emitter.immediate(Host::Eax, value);
+            emitter.store(rd);
+        }
+        // lb, lh, lw, lbu and lhu
+        Instruction::I(IType {
+            opcode: 0x03,
+            rd,
+            rs1,
+            imm,
+            funct3: funct3 @ (0x0 | 0x1 | 0x2 | 0x4 | 0x5),
+        }) => {
+            emitter.address(rs1, imm);
+            emitter.immediate(Host::Edx, funct3);
+            emitter.call(load as *const ());
+            // bt rax, 32; jae loaded
+            emitter.code.extend([0x48, 0x0f, 0xba, 0xe0, 0x20]);
+            let loaded = emitter.jump_if(JAE);
+            emitter.exit(pc, index);
+            emitter.land(loaded);
+            emitter.store(rd);
+        }
+        // sb, sh and sw
+        Instruction::S(SType {
+            opcode: 0x23,
+            rs1,
+            rs2,
+            imm,
+            funct3: funct3 @ 0x0..=0x2,
+        }) => {
+            emitter.address(rs1, imm);
+            emitter.load(Host::Edx, rs2);
+            emitter.immediate(Host::Ecx, funct3);
+            emitter.call(store as *const ());
+            // test eax, eax; jz stored; cmp eax, HANDED_OVER; jne stored_last
+            emitter.code.extend([0x85, 0xc0]);
+            let stored = emitter.jump_if(JE);
+            emitter.code.extend([0x83, 0xf8, HANDED_OVER as u8]);
+            let stored_last = emitter.jump_if(JNE);
+            emitter.exit(pc, index);
+            emitter.land(stored_last);
+            emitter.exit(pc.wrapping_add(4), index + 1);
+            emitter.land(stored);
+        }
+        // Only targets aligned to four bytes, which can't raise an exception
+        Instruction::B(BType {
+            rs1,
+            rs2,
+            imm,
+            funct3,
+            ..
+        }) if imm.is_multiple_of(4) => {
+            let taken = match funct3 {
+                0x0 => JE,
+                0x1 => JNE,
+                0x4 => JL,
+                0x5 => JGE,
+                0x6 => JB,
+                0x7 => JAE,
+                _ => return None,
+            };
+            emitter.load(Host::Eax, rs1);
+            emitter.load(Host::Ecx, rs2);
+            // cmp eax, ecx
+            emitter.code.extend([0x39, 0xc8]);
+            let jump = emitter.jump_if(taken);
+            emitter.chain(pc.wrapping_add(4), index + 1);
+            emitter.land(jump);
+            emitter.chain(pc.wrapping_add(imm), index + 1);
+            return Some(true);
+        }
+        Instruction::J(JType { rd, imm, .. }) if imm.is_multiple_of(4) => {
+            emitter.immediate(Host::Eax, pc.wrapping_add(4));
+            emitter.store(rd);
+            emitter.chain(pc.wrapping_add(imm), index + 1);
+            return Some(true);
+        }
+        // jalr, returning to the caller since its target isn't known in advance
+        Instruction::I(IType {
+            opcode: 0x67,
+            rd,
+            rs1,
+            imm,
+            funct3: 0x0,
+        }) => {
+            emitter.address(rs1, imm);
+            // and eax, !1; test al, 2; jz aligned
+            emitter.code.extend([0x83, 0xe0, 0xfe, 0xa8, 0x02]);
+            let aligned = emitter.jump_if(JE);
+            // A misaligned target raises an exception, which the interpreter takes
+            emitter.exit(pc, index);
+            emitter.land(aligned);
+            // mov edx, eax
+            emitter.code.extend([0x89, 0xc2]);
+            emitter.immediate(Host::Eax, pc.wrapping_add(4));
+            emitter.store(rd);
+            // mov eax, edx
+            emitter.code.extend([0x89, 0xd0]);
+            emitter.leave(index + 1);
+            return Some(true);
+        }
+        _ => return None,
+    }
+    Some(false)
+}
+
+/// Loads the value at «address» for the load instruction with «funct3», sign or zero extending it, or returns «LOAD_HANDED_OVER» if the interpreter must load it
+extern "sysv64" fn load(context: &mut Context, address: u32, funct3: u32) -> u64 {
+    let memory_bus = &mut *context.memory_bus;
+    let value = match funct3 {
+        0x0 => memory_bus
+            .read_u8(address, AccessKind::Load)
+            .map(|value| value as i8 as i32 as u32),
+        0x1 => memory_bus
+            .read_u16(address, AccessKind::Load)
+            .map(|value| value as i16 as i32 as u32),
+        0x2 => memory_bus.read_u32(address, AccessKind::Load),
+        0x4 => memory_bus.read_u8(address, AccessKind::Load).map(u32::from),
+        0x5 => memory_bus
+            .read_u16(address, AccessKind::Load)
+            .map(u32::from),
+        _ => unreachable!("only these loads are translated"),
+    };
+    value.map_or_else(
+        |_| {
+            context.stopped = true;
+            LOAD_HANDED_OVER
+        },
+        u64::from,
+    )
+}
+
+/// Stores «value» at «address» for the store instruction with «funct3», returning «STORED», «HANDED_OVER» or «STORED_LAST»
+extern "sysv64" fn store(context: &mut Context, address: u32, value: u32, funct3: u32) -> u32 {
+    let memory_bus = &mut *context.memory_bus;
+    let (result, size) = match funct3 {
+        0x0 => (
+            memory_bus.write_u8(address, value as u8, AccessKind::Store),
+            1,
+        ),
+        0x1 => (
+            memory_bus.write_u16(address, value as u16, AccessKind::Store),
+            2,
+        ),
+        0x2 => (memory_bus.write_u32(address, value, AccessKind::Store), 4),
+        _ => unreachable!("only these stores are translated"),
+    };
+    if result.is_err() {
+        context.stopped = true;
+        return HANDED_OVER;
+    }
+    let last = context.decode_cache.overlaps(address, size)
+        || context.htif.is_some_and(|htif| htif.watches(address));
+    if last || context.every_write {
+        context.writes.push(MemoryWrite {
+            address,
+            size: 8 * size,
+            value: (value as u64) & ((1 << (8 * size)) - 1),
+        });
+    }
+    if last {
+        context.stopped = true;
+        STORED_LAST
+    } else {
+        STORED
+    }
+}
@@ -1,37 +1,36 @@
-use super::memory::{MemoryBus, MemoryError};
+use super::htif::Htif;
+use super::memory::{AccessKind, MemoryBus, MemoryError};
 // Since the «constants» module provides everything spec-related that is needed to implement this CPU, everything from there is imported without an alias
+use self::branch_prediction::BranchPrediction;
 use self::constants::*;
-use log::debug;
+use self::csr::*;
+use self::decode_cache::DecodeCache;
+#[cfg(feature = "jit")]
+use self::jit::Context;
+use self::trace::{MemoryRead, MemoryWrite, TraceRecord};
+use self::trap::{Exception, Trap};
 
+pub mod branch_prediction;
 pub mod constants;
+pub mod counters;
+pub mod csr;
+pub mod decode_cache;
+pub mod disassembler;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod trace;
+pub mod trap;
 
+/// Errors the emulator can't recover from, as opposed to exceptions, which are taken as traps like the hardware would
 #[derive(Debug)]
 pub enum CpuError {
-    Fetch(FetchError),
     Decode(DecodeError),
-    Execute(ExecuteError),
-}
-
-#[derive(Debug)]
-pub enum FetchError {
-    Memory(MemoryError),
 }
 
 #[derive(Debug)]
 pub enum DecodeError {
-    OpcodeZero,
-}
-
-#[derive(Debug)]
-pub enum ExecuteError {
-    // A memory error can be encountered during execution of a load or store instruction
-    Memory(MemoryError),
-}
-
-impl From<FetchError> for CpuError {
-    fn from(value: FetchError) -> Self {
-        Self::Fetch(value)
-    }
+    /// The opcode doesn't belong to any of the supported instructions
+    UnsupportedOpcode(u32),
 }
 
 impl From<DecodeError> for CpuError {
@@ -40,204 +39,738 @@ impl From<DecodeError> for CpuError {
     }
 }
 
+/// What «Cpu::advance_native» did
+#[derive(Debug, Default)]
+pub struct NativeRun {
+    /// Instructions executed
+    pub executed: usize,
+    /// Stores made, in program order
+    pub writes: Vec<MemoryWrite>,
+}
+
+/// Privilege levels, numbered as in the MPP field of mstatus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
 pub struct Cpu {
+    /// Address the program counter is set to on reset
+    pub reset_vector: XLENType,
     /// Registers x0-x31, with x0 emulated as being hardwired to zero
     pub registers: [XLENType; 32],
     /// Program counter
     pub pc: XLENType,
+    /// Control and status registers
+    pub csrs: Csrs,
+    /// Only machine mode is implemented for now
+    pub privilege: Privilege,
+    /// Address reserved by the last lr.w, until the next sc.w
+    pub reservation: Option<u32>,
+    /// Instructions already decoded, which must be told about changes to memory made from outside of the CPU
+    pub decode_cache: DecodeCache,
+    /// Predictor asked about every conditional branch, if one is plugged in
+    pub branch_prediction: Option<BranchPrediction>,
 }
 
 impl Cpu {
     pub fn new(reset_vector: usize) -> Self {
         Self {
+            reset_vector: reset_vector as u32,
             registers: [0; 32],
             pc: reset_vector as u32,
+            csrs: Csrs::new(),
+            privilege: Privilege::Machine,
+            reservation: None,
+            decode_cache: DecodeCache::new(),
+            branch_prediction: None,
         }
     }
 
-    pub fn rewind(&mut self) -> Result<(), ()> {
-        todo!()
+    /// The same CPU, as hart number «id» (which is found in mhartid)
+    pub fn with_hart_id(mut self, id: u32) -> Self {
+        self.csrs.set(MHARTID, id);
+        self
     }
 
-    pub fn advance(&mut self, mut memory_bus: &mut MemoryBus) -> Result<(), CpuError> {
+    /// Emulates the CPU receiving a reset signal: the hart restarts from the reset vector, in machine mode, with every CSR (and so every pending interrupt) back to its reset value, apart from its mhartid
+    ///
+    /// The specification leaves the registers undefined on reset, here they are cleared like Spike does
+    ///
+    /// The branch predictor stays plugged in, but forgets everything
+    pub fn reset(&mut self) {
+        let mut branch_prediction = self.branch_prediction.take();
+        if let Some(branch_prediction) = &mut branch_prediction {
+            branch_prediction.reset();
+        }
+        *self = Self {
+            branch_prediction,
+            ..Self::new(self.reset_vector as usize)
+        }
+        .with_hart_id(self.csrs.get(MHARTID));
+    }
+
+    /// Executes a single instruction, returning its architectural effects
+    ///
+    /// An instruction raising an exception doesn't retire: the trap is taken and recorded instead
+    pub fn advance(&mut self, memory_bus: &mut MemoryBus) -> Result<TraceRecord, CpuError> {
         self.registers[0] = 0; // Emulates x0 being hardwired to zero
-        debug!(
-            "New instruction cycle started\nRegisters: {:?}\nPC: {:?}",
-            self.registers, self.pc,
-        );
-        // 1) Fetch
-        let raw_instruction = self.fetch(&memory_bus)?;
-        // Increment the program counter (by four bytes, since every instruction is 32 bits long)
-        // Note: In the compressed instruction set instructions can be 16 bits long only
-        self.pc += 4;
-        // 2) Decode
-        let instruction = self.decode(raw_instruction)?;
-        // 3) Execute
-        self.execute(instruction, &mut memory_bus)?;
-        Ok(())
+        let mut record = TraceRecord {
+            privilege: self.privilege as u8,
+            pc: self.pc,
+            ..Default::default()
+        };
+        match self.cycle(memory_bus, &mut record) {
+            Ok(()) => {}
+            Err(trap) => {
+                // Without a handler the trap is recorded but not taken, leaving the hart on the faulting instruction for the machine to stop there
+                if self.trap_handler().is_some() {
+                    self.take_trap(trap, record.pc);
+                }
+                record.trap = Some(trap);
+            }
+        }
+        counters::count(&mut self.csrs, &record, self.pc);
+        Ok(record)
     }
 
-    /// Emulates the CPU receiveing a reset signal
-    pub fn reset(&mut self, mut memory_bus: &mut MemoryBus) -> Result<(), CpuError> {
-        while self.pc < memory_bus.memory.size() as u32 {
-            self.advance(&mut memory_bus)?;
+    /// Runs the instructions from pc natively, as long as they were translated and there are at most «limit» of them, returning how many ran and what they wrote
+    ///
+    /// The run stops before a load or store the interpreter must make (one raising an exception), and after a store overwriting decoded instructions or watched by «htif», which the caller must answer.
+    /// Only those stores are returned, unless «every_write» is set.
+    /// Native code doesn't record what it does, so nothing runs natively while loads, stores or branches are counted
+    #[cfg(feature = "jit")]
+    pub fn advance_native(
+        &mut self,
+        memory_bus: &mut MemoryBus,
+        limit: usize,
+        htif: Option<&Htif>,
+        every_write: bool,
+    ) -> NativeRun {
+        let mut run = NativeRun::default();
+        if counters::counts_unseen_events(&self.csrs) {
+            return run;
         }
+        while run.executed < limit {
+            let Some(translation) = self
+                .decode_cache
+                .native(self.pc, memory_bus)
+                .and_then(|block| self.decode_cache.translation(block))
+            else {
+                break;
+            };
+            if translation.length > limit - run.executed {
+                break;
+            }
+            let mut context = Context::new(memory_bus, &self.decode_cache, htif, every_write);
+            let (pc, executed) =
+                translation.run(&mut self.registers, &mut context, limit - run.executed);
+            let (unlinked, writes, stopped) = (context.unlinked, context.writes, context.stopped);
+            self.pc = pc;
+            run.executed += executed;
+            self.decode_cache.exited(pc, unlinked);
+            for write in &writes {
+                self.decode_cache.invalidate(write.address, write.size / 8);
+            }
+            run.writes.extend(writes);
+            if stopped || executed == 0 {
+                break;
+            }
+        }
+        counters::count_native(&mut self.csrs, run.executed as u64);
+        run
+    }
+
+    /// Wether native code starts at «pc», for «advance_native» to run
+    ///
+    /// Blocks are translated once the interpreter entered them often enough
+    #[cfg(feature = "jit")]
+    pub fn has_native(&self, pc: u32) -> bool {
+        self.decode_cache.has_native(pc)
+    }
+
+    /// Nothing is translated without the «jit» feature
+    #[cfg(not(feature = "jit"))]
+    pub fn has_native(&self, _pc: u32) -> bool {
+        false
+    }
+
+    /// Nothing runs natively without the «jit» feature
+    #[cfg(not(feature = "jit"))]
+    pub fn advance_native(
+        &mut self,
+        _memory_bus: &mut MemoryBus,
+        _limit: usize,
+        _htif: Option<&Htif>,
+        _every_write: bool,
+    ) -> NativeRun {
+        NativeRun::default()
+    }
+
+    /// Calls «visit» with the address of every instruction executed natively since the last call, in no particular order and maybe more than once
+    pub fn natively_executed(&mut self, visit: impl FnMut(u32)) {
+        #[cfg(feature = "jit")]
+        self.decode_cache.natively_executed(visit);
+        #[cfg(not(feature = "jit"))]
+        let _ = visit;
+    }
+
+    /// Address traps are taken to, unless «mtvec» still holds its reset value of zero, where nothing is mapped
+    pub fn trap_handler(&self) -> Option<u32> {
+        Some(self.csrs.get(MTVEC) & !0b11).filter(|&address| address != 0)
+    }
+
+    /// Fetches, decodes and executes an instruction
+    fn cycle(&mut self, memory_bus: &mut MemoryBus, record: &mut TraceRecord) -> Result<(), Trap> {
+        let (raw_instruction, instruction) = match self.decode_cache.lookup(self.pc, memory_bus) {
+            Some(decoded) => decoded,
+            // Only instructions that can't be fetched or decoded are missing from the cache, and they trap here
+            None => {
+                // 1) Fetch
+                let raw_instruction = self.fetch(memory_bus)?;
+                record.raw = raw_instruction;
+                // 2) Decode
+                // Note: An all zero instruction is illegal too, so that running into zeroed memory traps
+                let instruction = self
+                    .decode(raw_instruction)
+                    .map_err(|_| Trap::new(Exception::IllegalInstruction, raw_instruction))?;
+                (raw_instruction, instruction)
+            }
+        };
+        record.raw = raw_instruction;
+        let destination = instruction.destination_register();
+        // 3) Execute
+        self.execute(instruction, raw_instruction, memory_bus, record)?;
+        // Writes to x0 are recorded with the value the instruction computed, as Spike does, even though it gets discarded
+        record.register_write = destination.map(|rd| (rd, self.registers[rd as usize]));
         Ok(())
     }
 
-    fn fetch(&self, memory_bus: &MemoryBus) -> Result<u32, FetchError> {
-        // Note: While here the fetch is always for 4 bytes (indicated by size: 32 (bits)), in the compressed instruction set instructions can be 16 bits long only
-        let raw_instruction = memory_bus.load(self.pc as usize, 32)? as u32;
-        debug!(
-            "Fetch phase succeded\nRaw instruction: {:?}",
-            raw_instruction
-        );
-        Ok(raw_instruction)
+    fn fetch(&self, memory_bus: &mut MemoryBus) -> Result<u32, Trap> {
+        // Note: While here the fetch is always for 4 bytes (read as a u32), in the compressed instruction set instructions can be 16 bits long only
+        Ok(memory_bus.read_u32(self.pc, AccessKind::Fetch)?)
     }
 
     fn decode(&self, raw_instruction: u32) -> Result<Instruction, DecodeError> {
-        let decoded_instruction = Instruction::try_from(raw_instruction)?;
-        debug!(
-            "Decode phase succeded\nDecoded instruction: {:?}",
-            decoded_instruction
+        Instruction::try_from(raw_instruction)
+    }
+
+    /// Enters the trap handler in machine mode, as described by the privileged spec
+    fn take_trap(&mut self, trap: Trap, pc: u32) {
+        self.csrs.set(MEPC, pc);
+        self.csrs.set(MCAUSE, trap.exception.code());
+        self.csrs.set(MTVAL, trap.value);
+        let mstatus = self.csrs.get(MSTATUS);
+        // MPIE = MIE, MIE = 0, MPP = the privilege level the trap was taken from
+        let mpie = if mstatus & MSTATUS_MIE != 0 {
+            MSTATUS_MPIE
+        } else {
+            0
+        };
+        let mpp = (self.privilege as u32) << 11;
+        self.csrs.set(
+            MSTATUS,
+            (mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | mpie | mpp,
         );
-        Ok(decoded_instruction)
+        self.privilege = Privilege::Machine;
+        // Exceptions always go to the base address, even in vectored mode
+        self.pc = self.csrs.get(MTVEC) & !0b11;
     }
 
+    /// Returns from a trap handler, as described by the privileged spec
+    fn mret(&mut self) {
+        let mstatus = self.csrs.get(MSTATUS);
+        self.privilege = match (mstatus & MSTATUS_MPP) >> 11 {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        };
+        // MIE = MPIE, MPIE = 1, MPP = the least privileged mode (which is machine mode, since it is the only one)
+        let mie = if mstatus & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+        self.csrs.set(
+            MSTATUS,
+            (mstatus & !MSTATUS_MIE) | mie | MSTATUS_MPIE | MSTATUS_MPP,
+        );
+        self.pc = self.csrs.get(MEPC);
+    }
+
+    /// Jumps to the target of a taken branch or jump, which must be aligned to four bytes
+    fn jump(&mut self, target: u32) -> Result<(), Trap> {
+        if !target.is_multiple_of(4) {
+            return Err(Trap::new(Exception::InstructionAddressMisaligned, target));
+        }
+        self.pc = target;
+        Ok(())
+    }
+
+    /// Reads a value with «read», which decides its width
+    fn load<'a, T>(
+        &self,
+        memory_bus: &MemoryBus<'a>,
+        address: u32,
+        kind: AccessKind,
+        read: fn(&MemoryBus<'a>, u32, AccessKind) -> Result<T, MemoryError>,
+        record: &mut TraceRecord,
+    ) -> Result<T, Trap> {
+        record.memory_read = Some(MemoryRead {
+            address,
+            size: 8 * size_of::<T>() as u32,
+        });
+        Ok(read(memory_bus, address, kind)?)
+    }
+
+    /// Writes a value with «write», which decides its width, and drops the cached instructions it overwrites
+    fn store<'a, T: Copy + Into<u64>>(
+        &mut self,
+        memory_bus: &mut MemoryBus<'a>,
+        address: u32,
+        value: T,
+        kind: AccessKind,
+        write: fn(&mut MemoryBus<'a>, u32, T, AccessKind) -> Result<(), MemoryError>,
+        record: &mut TraceRecord,
+    ) -> Result<(), Trap> {
+        let size = size_of::<T>() as u32;
+        record.memory_write = Some(MemoryWrite {
+            address,
+            size: 8 * size,
+            value: value.into(),
+        });
+        write(memory_bus, address, value, kind)?;
+        self.decode_cache.invalidate(address, size);
+        Ok(())
+    }
+
+    /// Executes a decoded instruction, recording the memory and CSR accesses it makes
     fn execute(
         &mut self,
         instruction: Instruction,
+        raw_instruction: u32,
         memory_bus: &mut MemoryBus,
-    ) -> Result<(), ExecuteError> {
-        debug!("Execute phase started");
+        record: &mut TraceRecord,
+    ) -> Result<(), Trap> {
+        let illegal = Trap::new(Exception::IllegalInstruction, raw_instruction);
+        // Address of the instruction, the program counter is only moved forward once it has been executed
+        let pc = self.pc;
+        let mut next_pc = pc.wrapping_add(4);
         match instruction {
             Instruction::I(instruction) => {
+                let rs1 = self.registers[instruction.rs1 as usize];
+                let imm = instruction.imm;
+                let rd = instruction.rd as usize;
                 match instruction.opcode {
                     // Load instructions
                     0x03 => {
                         // Memory address
-                        let address = self.registers[instruction.rs1 as usize]
-                            .wrapping_add(instruction.imm)
-                            as usize; // As usize since it will always be used to index the contents of the memory
-                        match instruction.funct3 {
+                        let address = rs1.wrapping_add(imm);
+                        self.registers[rd] = match instruction.funct3 {
                             // lb
-                            0x0 => {
-                                let val = memory_bus.load(address, 8)?;
-                                self.registers[instruction.rd as usize] = val as i8 as i32 as u32;
-                            }
+                            0x0 => self.load(
+                                memory_bus,
+                                address,
+                                AccessKind::Load,
+                                MemoryBus::read_u8,
+                                record,
+                            )? as i8 as i32 as u32,
                             // lh
-                            0x1 => {
-                                let val = memory_bus.load(address, 16)?;
-                                self.registers[instruction.rd as usize] = val as i16 as i32 as u32;
-                            }
+                            0x1 => self.load(
+                                memory_bus,
+                                address,
+                                AccessKind::Load,
+                                MemoryBus::read_u16,
+                                record,
+                            )? as i16 as i32 as u32,
                             // lw
-                            0x2 => {
-                                let val = memory_bus.load(address, 32)?;
-                                self.registers[instruction.rd as usize] = val as i32 as u32;
-                            }
+                            0x2 => self.load(
+                                memory_bus,
+                                address,
+                                AccessKind::Load,
+                                MemoryBus::read_u32,
+                                record,
+                            )?,
                             // lbu
-                            0x4 => {
-                                let val = memory_bus.load(address, 8)?;
-                                self.registers[instruction.rd as usize] = val as u32;
-                            }
+                            0x4 => self.load(
+                                memory_bus,
+                                address,
+                                AccessKind::Load,
+                                MemoryBus::read_u8,
+                                record,
+                            )? as u32,
                             // lhu
-                            0x5 => {
-                                let val = memory_bus.load(address, 16)?;
-                                self.registers[instruction.rd as usize] = val as u32;
+                            0x5 => self.load(
+                                memory_bus,
+                                address,
+                                AccessKind::Load,
+                                MemoryBus::read_u16,
+                                record,
+                            )? as u32,
+                            _ => return Err(illegal),
+                        };
+                    }
+                    // fence, fence.i
+                    // Note: Loads are performed in program order, so only stores waiting in a store buffer can be reordered: «fence» waits for them when it orders
+                    // earlier writes (PW) before later reads or writes (SR, SW). Without a store buffer memory accesses are all performed in program order.
+                    // Stores already drop the cached instructions they overwrite, «fence.i» drops all of them (with their native code) regardless,
+                    // so that instructions written by any means (including edits from outside the CPU not reported to the cache) are fetched again
+                    0x0f => match instruction.funct3 {
+                        0x0 => {
+                            let (predecessors, successors) = ((imm >> 4) & 0xf, imm & 0xf);
+                            if predecessors & FENCE_W != 0 && successors & (FENCE_R | FENCE_W) != 0
+                            {
+                                memory_bus.drain();
                             }
-                            _ => {}
                         }
-                    }
+                        0x1 => {
+                            memory_bus.drain();
+                            self.decode_cache.clear();
+                        }
+                        _ => return Err(illegal),
+                    },
                     // Operations on registers
                     0x13 => {
-                        match instruction.funct3 {
+                        let shamt = imm & 0x1f;
+                        self.registers[rd] = match (instruction.funct3, imm >> 5) {
                             // addi
-                            0x0 => {
-                                self.registers[instruction.rd as usize] = self.registers
-                                    [instruction.rs1 as usize]
-                                    .wrapping_add(instruction.imm);
+                            (0x0, _) => rs1.wrapping_add(imm),
+                            // slli
+                            (0x1, 0x00) => rs1 << shamt,
+                            // slti
+                            (0x2, _) => ((rs1 as i32) < (imm as i32)) as u32,
+                            // sltiu
+                            (0x3, _) => (rs1 < imm) as u32,
+                            // xori
+                            (0x4, _) => rs1 ^ imm,
+                            // srli
+                            (0x5, 0x00) => rs1 >> shamt,
+                            // srai
+                            (0x5, 0x20) => ((rs1 as i32) >> shamt) as u32,
+                            // ori
+                            (0x6, _) => rs1 | imm,
+                            // andi
+                            (0x7, _) => rs1 & imm,
+                            _ => return Err(illegal),
+                        };
+                    }
+                    // jalr
+                    0x67 => {
+                        if instruction.funct3 != 0x0 {
+                            return Err(illegal);
+                        }
+                        // The least significant bit of the target is cleared
+                        self.jump(rs1.wrapping_add(imm) & !1)?;
+                        next_pc = self.pc;
+                        self.registers[rd] = pc.wrapping_add(4);
+                    }
+                    // ecall, ebreak, mret, wfi
+                    0x73 if instruction.funct3 == 0x0 => {
+                        if rd != 0 || instruction.rs1 != 0 {
+                            return Err(illegal);
+                        }
+                        match imm {
+                            // ecall
+                            0x000 => {
+                                let exception = match self.privilege {
+                                    Privilege::User => Exception::EnvironmentCallFromU,
+                                    Privilege::Supervisor => Exception::EnvironmentCallFromS,
+                                    Privilege::Machine => Exception::EnvironmentCallFromM,
+                                };
+                                return Err(Trap::new(exception, 0));
                             }
-                            _ => unimplemented!(
-                                "Unsupported instruction, detected while analyzing funct3"
-                            ),
+                            // ebreak
+                            0x001 => return Err(Trap::new(Exception::Breakpoint, pc)),
+                            // mret
+                            0x302 if self.privilege == Privilege::Machine => {
+                                self.mret();
+                                next_pc = self.pc;
+                                record.csr_write = Some((MSTATUS, self.csrs.get(MSTATUS)));
+                            }
+                            // wfi
+                            // Note: Resuming at the next instruction is always allowed, the machine stops if there's no interrupt to wait for
+                            0x105 => {}
+                            _ => return Err(illegal),
+                        }
+                    }
+                    // CSR instructions
+                    0x73 => {
+                        if instruction.funct3 == 0x4 {
+                            return Err(illegal);
+                        }
+                        let address = (imm & 0xfff) as u16;
+                        // The immediate forms take a zero extended immediate in place of rs1
+                        let source = if instruction.funct3 & 0x4 == 0 {
+                            rs1
+                        } else {
+                            instruction.rs1
+                        };
+                        // csrrw doesn't read the CSR if rd is x0, csrrs/csrrc don't write it if rs1 (or the immediate) is zero
+                        let reads = instruction.funct3 & 0x3 != 0x1 || rd != 0;
+                        let writes = instruction.funct3 & 0x3 == 0x1 || instruction.rs1 != 0;
+                        let old = if reads {
+                            self.csrs.read(address).map_err(|_| illegal)?
+                        } else {
+                            0
+                        };
+                        if writes {
+                            let new = match instruction.funct3 & 0x3 {
+                                // csrrw(i)
+                                0x1 => source,
+                                // csrrs(i)
+                                0x2 => old | source,
+                                // csrrc(i)
+                                _ => old & !source,
+                            };
+                            self.csrs.write(address, new).map_err(|_| illegal)?;
+                            record.csr_write = Some((address, self.csrs.get(address)));
                         }
+                        self.registers[rd] = old;
                     }
-                    _ => unimplemented!("Unsupported instruction, detected while analyzing opcode"),
+                    _ => return Err(illegal),
                 }
             }
             Instruction::R(instruction) => {
+                let rs1 = self.registers[instruction.rs1 as usize];
+                let rs2 = self.registers[instruction.rs2 as usize];
+                let rd = instruction.rd as usize;
                 match instruction.opcode {
                     0x33 => {
-                        match (instruction.funct3, instruction.funct7) {
+                        let shamt = rs2 & 0x1f;
+                        self.registers[rd] = match (instruction.funct3, instruction.funct7) {
                             // add
-                            (0x0, 0x0) => {
-                                self.registers[instruction.rd as usize] = self.registers
-                                    [instruction.rs1 as usize]
-                                    .wrapping_add(self.registers[instruction.rs2 as usize]);
-                            },
+                            (0x0, 0x00) => rs1.wrapping_add(rs2),
                             // sub
-                            (0x0, 0x20) => {
-                                self.registers[instruction.rd as usize] = self.registers
-                                    [instruction.rs1 as usize]
-                                    .wrapping_sub(self.registers[instruction.rs2 as usize]);
-                            }
-                            _ => unimplemented!(
-                                "Unsupported instruction, detected while analyzing funct3 and funct7"
-                            ),
+                            (0x0, 0x20) => rs1.wrapping_sub(rs2),
+                            // sll
+                            (0x1, 0x00) => rs1 << shamt,
+                            // slt
+                            (0x2, 0x00) => ((rs1 as i32) < (rs2 as i32)) as u32,
+                            // sltu
+                            (0x3, 0x00) => (rs1 < rs2) as u32,
+                            // xor
+                            (0x4, 0x00) => rs1 ^ rs2,
+                            // srl
+                            (0x5, 0x00) => rs1 >> shamt,
+                            // sra
+                            (0x5, 0x20) => ((rs1 as i32) >> shamt) as u32,
+                            // or
+                            (0x6, 0x00) => rs1 | rs2,
+                            // and
+                            (0x7, 0x00) => rs1 & rs2,
+                            // M extension
+                            (funct3, 0x01) => multiply_divide(funct3, rs1, rs2),
+                            _ => return Err(illegal),
+                        };
+                    }
+                    // A extension
+                    0x2f => {
+                        if instruction.funct3 != 0x2 {
+                            return Err(illegal);
+                        }
+                        // The lowest two bits of funct7 are the aq and rl flags. Loads are performed in program order, so only rl matters:
+                        // it makes the stores waiting in the store buffer reach memory first, as do the ones to the same word regardless
+                        let funct5 = instruction.funct7 >> 2;
+                        let release = instruction.funct7 & 0b01 != 0;
+                        let address = rs1;
+                        if !address.is_multiple_of(4) {
+                            // Misaligned lr.w raises a load exception, sc.w and AMOs a store/AMO one
+                            let exception = if funct5 == 0x02 {
+                                Exception::LoadAddressMisaligned
+                            } else {
+                                Exception::StoreAddressMisaligned
+                            };
+                            return Err(Trap::new(exception, address));
                         }
+                        if release {
+                            memory_bus.drain();
+                        } else {
+                            memory_bus.drain_overlapping(address, 4);
+                        }
+                        self.registers[rd] = match funct5 {
+                            // lr.w
+                            0x02 if instruction.rs2 == 0 => {
+                                let value = self.load(
+                                    memory_bus,
+                                    address,
+                                    AccessKind::Load,
+                                    MemoryBus::read_u32,
+                                    record,
+                                )?;
+                                self.reservation = Some(address);
+                                value
+                            }
+                            // sc.w, which always invalidates the reservation, and writes straight to memory like an AMO
+                            0x03 => {
+                                if self.reservation.take() == Some(address) {
+                                    self.store(
+                                        memory_bus,
+                                        address,
+                                        rs2,
+                                        AccessKind::Amo,
+                                        MemoryBus::write_u32,
+                                        record,
+                                    )?;
+                                    0
+                                } else {
+                                    1
+                                }
+                            }
+                            // AMOs
+                            0x00 | 0x01 | 0x04 | 0x08 | 0x0c | 0x10 | 0x14 | 0x18 | 0x1c => {
+                                let old = self.load(
+                                    memory_bus,
+                                    address,
+                                    AccessKind::Amo,
+                                    MemoryBus::read_u32,
+                                    record,
+                                )?;
+                                let new = match funct5 {
+                                    // amoadd.w
+                                    0x00 => old.wrapping_add(rs2),
+                                    // amoswap.w
+                                    0x01 => rs2,
+                                    // amoxor.w
+                                    0x04 => old ^ rs2,
+                                    // amoor.w
+                                    0x08 => old | rs2,
+                                    // amoand.w
+                                    0x0c => old & rs2,
+                                    // amomin.w
+                                    0x10 => (old as i32).min(rs2 as i32) as u32,
+                                    // amomax.w
+                                    0x14 => (old as i32).max(rs2 as i32) as u32,
+                                    // amominu.w
+                                    0x18 => old.min(rs2),
+                                    // amomaxu.w
+                                    _ => old.max(rs2),
+                                };
+                                self.store(
+                                    memory_bus,
+                                    address,
+                                    new,
+                                    AccessKind::Amo,
+                                    MemoryBus::write_u32,
+                                    record,
+                                )?;
+                                old
+                            }
+                            _ => return Err(illegal),
+                        };
                     }
-                    _ => unimplemented!("Unsupported instruction, detected while analyzing opcode"),
+                    _ => return Err(illegal),
                 }
             }
             Instruction::S(instruction) => {
                 // Memory address
                 let address =
                     self.registers[instruction.rs1 as usize].wrapping_add(instruction.imm);
-                match instruction.opcode {
-                    0x23 => {
-                        match instruction.funct3 {
-                            0x0 => memory_bus.store(
-                                address as usize,
-                                8,
-                                self.registers[instruction.rs2 as usize] as usize,
-                            )?, // sb
-                            0x1 => memory_bus.store(
-                                address as usize,
-                                16,
-                                self.registers[instruction.rs2 as usize] as usize,
-                            )?, // sh
-                            0x2 => memory_bus.store(
-                                address as usize,
-                                32,
-                                self.registers[instruction.rs2 as usize] as usize,
-                            )?, // sw
-                            _ => {}
-                        }
-                    }
-                    _ => unimplemented!("Unsupported instruction, detected while analyzing opcode"),
+                let value = self.registers[instruction.rs2 as usize];
+                match instruction.funct3 {
+                    // sb
+                    0x0 => self.store(
+                        memory_bus,
+                        address,
+                        value as u8,
+                        AccessKind::Store,
+                        MemoryBus::write_u8,
+                        record,
+                    )?,
+                    // sh
+                    0x1 => self.store(
+                        memory_bus,
+                        address,
+                        value as u16,
+                        AccessKind::Store,
+                        MemoryBus::write_u16,
+                        record,
+                    )?,
+                    // sw
+                    0x2 => self.store(
+                        memory_bus,
+                        address,
+                        value,
+                        AccessKind::Store,
+                        MemoryBus::write_u32,
+                        record,
+                    )?,
+                    _ => return Err(illegal),
+                }
+            }
+            Instruction::B(instruction) => {
+                let rs1 = self.registers[instruction.rs1 as usize];
+                let rs2 = self.registers[instruction.rs2 as usize];
+                let taken = match instruction.funct3 {
+                    // beq
+                    0x0 => rs1 == rs2,
+                    // bne
+                    0x1 => rs1 != rs2,
+                    // blt
+                    0x4 => (rs1 as i32) < (rs2 as i32),
+                    // bge
+                    0x5 => (rs1 as i32) >= (rs2 as i32),
+                    // bltu
+                    0x6 => rs1 < rs2,
+                    // bgeu
+                    0x7 => rs1 >= rs2,
+                    _ => return Err(illegal),
+                };
+                let target = pc.wrapping_add(instruction.imm);
+                if let Some(branch_prediction) = &mut self.branch_prediction {
+                    branch_prediction.observe(pc, target, taken);
                 }
+                if taken {
+                    self.jump(target)?;
+                    next_pc = self.pc;
+                }
+            }
+            Instruction::U(instruction) => {
+                self.registers[instruction.rd as usize] = match instruction.opcode {
+                    // lui
+                    0x37 => instruction.imm,
+                    // auipc
+                    _ => pc.wrapping_add(instruction.imm),
+                };
+            }
+            // jal
+            Instruction::J(instruction) => {
+                self.jump(pc.wrapping_add(instruction.imm))?;
+                next_pc = self.pc;
+                self.registers[instruction.rd as usize] = pc.wrapping_add(4);
             }
-            _ => todo!(),
         };
-        debug!("Succesfully executed instruction");
+        self.pc = next_pc;
         Ok(())
     }
 }
 
-impl From<ExecuteError> for CpuError {
-    fn from(value: ExecuteError) -> Self {
-        Self::Execute(value)
-    }
-}
-
-impl From<MemoryError> for FetchError {
-    fn from(value: MemoryError) -> Self {
-        Self::Memory(value)
-    }
-}
-
-impl From<MemoryError> for ExecuteError {
-    fn from(value: MemoryError) -> Self {
-        Self::Memory(value)
+/// Executes an instruction of the M extension, as identified by funct3
+fn multiply_divide(funct3: u32, rs1: u32, rs2: u32) -> u32 {
+    let (signed1, signed2) = (rs1 as i32, rs2 as i32);
+    match funct3 {
+        // mul
+        0x0 => rs1.wrapping_mul(rs2),
+        // mulh
+        0x1 => ((signed1 as i64 * signed2 as i64) >> 32) as u32,
+        // mulhsu
+        0x2 => ((signed1 as i64 * rs2 as i64) >> 32) as u32,
+        // mulhu
+        0x3 => ((rs1 as u64 * rs2 as u64) >> 32) as u32,
+        // div, where dividing by zero gives -1 and overflowing gives the dividend
+        0x4 => match signed2 {
+            0 => u32::MAX,
+            _ => signed1.wrapping_div(signed2) as u32,
+        },
+        // divu
+        0x5 => rs1.checked_div(rs2).unwrap_or(u32::MAX),
+        // rem, where dividing by zero gives the dividend and overflowing gives zero
+        0x6 => match signed2 {
+            0 => rs1,
+            _ => signed1.wrapping_rem(signed2) as u32,
+        },
+        // remu
+        _ => rs1.checked_rem(rs2).unwrap_or(rs1),
     }
 }
@@ -0,0 +1,221 @@
+//! Per-instruction execution trace, printable in the same format as Spike's commit log (`spike -l --log-commits`) so that traces can be compared line by line against the reference simulator
+
+use std::{
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+};
+
+use super::{csr, disassembler::disassemble, trap::Trap};
+
+/// Architectural effects of a single retired instruction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Privilege level the instruction was executed in (0 = U, 1 = S, 3 = M)
+    pub privilege: u8,
+    pub pc: u32,
+    /// The instruction as fetched from memory
+    pub raw: u32,
+    /// Destination register and the value written to it
+    pub register_write: Option<(u32, u32)>,
+    /// Address of the CSR written by the instruction and its new value
+    pub csr_write: Option<(u16, u32)>,
+    pub memory_read: Option<MemoryRead>,
+    pub memory_write: Option<MemoryWrite>,
+    /// The exception raised by the instruction, which then didn't retire
+    pub trap: Option<Trap>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRead {
+    pub address: u32,
+    /// Size in bits
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u32,
+    /// Size in bits
+    pub size: u32,
+    pub value: u64,
+}
+
+impl TraceRecord {
+    pub fn disassembly(&self) -> String {
+        disassemble(self.raw)
+    }
+
+    /// The line Spike prints before executing the instruction, e.g. «core   0: 0x80000000 (0x00000297) auipc t0, 0x0»
+    pub fn instruction_line(&self) -> String {
+        format!(
+            "core   0: 0x{:08x} (0x{:08x}) {}",
+            self.pc,
+            self.raw,
+            self.disassembly()
+        )
+    }
+}
+
+/// Formats the commit line of the instruction, e.g. «core   0: 3 0x80000000 (0x00000297) x5  0x80000000»
+///
+/// Instructions raising an exception don't commit, so the trap is reported as Spike does instead (e.g. «core   0: exception trap_illegal_instruction, epc 0x80000004» followed by the value of mtval)
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(trap) = self.trap {
+            writeln!(
+                f,
+                "core   0: exception {}, epc 0x{:08x}",
+                trap.exception.spike_name(),
+                self.pc
+            )?;
+            return write!(f, "core   0:           tval 0x{:08x}", trap.value);
+        }
+        write!(
+            f,
+            "core   0: {} 0x{:08x} (0x{:08x})",
+            self.privilege, self.pc, self.raw
+        )?;
+        if let Some((register, value)) = self.register_write {
+            write!(f, " x{register:<2} 0x{value:08x}")?;
+        }
+        if let Some((address, value)) = self.csr_write {
+            let name = csr::find(address).map_or("unknown", |csr| csr.name);
+            write!(f, " c{address}_{name} 0x{value:08x}")?;
+        }
+        if let Some(read) = self.memory_read {
+            write!(f, " mem 0x{:08x}", read.address)?;
+        }
+        if let Some(write) = self.memory_write {
+            let digits = write.size as usize / 4;
+            write!(
+                f,
+                " mem 0x{:08x} 0x{:0digits$x}",
+                write.address, write.value
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a commit line, as formatted by Spike or by «TraceRecord»'s Display implementation
+impl FromStr for TraceRecord {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let rest = line
+            .trim()
+            .strip_prefix("core")
+            .ok_or("Expected a line starting with «core»")?;
+        let mut tokens = rest.split_whitespace().skip(1).peekable();
+        let mut next = |what: &str| tokens.next().ok_or(format!("Missing {what}"));
+        let privilege = next("privilege level")?
+            .parse()
+            .map_err(|_| "Invalid privilege level")?;
+        let pc = hex(next("pc")?)? as u32;
+        let raw = next("instruction")?;
+        let raw = raw
+            .strip_prefix('(')
+            .and_then(|raw| raw.strip_suffix(')'))
+            .ok_or("Expected the instruction between parentheses")?;
+        let mut record = Self {
+            privilege,
+            pc,
+            raw: hex(raw)? as u32,
+            ..Default::default()
+        };
+        while let Some(token) = tokens.next() {
+            let mut value = || {
+                tokens
+                    .next()
+                    .ok_or(format!("Missing value after «{token}»"))
+            };
+            if token == "mem" {
+                let address = hex(value()?)? as u32;
+                // A write is followed by the value written, a read isn't
+                match tokens.next_if(|token| token.starts_with("0x")) {
+                    Some(written) => {
+                        record.memory_write = Some(MemoryWrite {
+                            address,
+                            size: (written.len() as u32 - 2) * 4,
+                            value: hex(written)?,
+                        })
+                    }
+                    None => record.memory_read = Some(MemoryRead { address, size: 0 }),
+                }
+            } else if let Some(register) = token.strip_prefix('x') {
+                let register = register
+                    .parse()
+                    .map_err(|_| format!("Invalid register «{token}»"))?;
+                record.register_write = Some((register, hex(value()?)? as u32));
+            } else if let Some(csr) = token.strip_prefix('c') {
+                let address = csr
+                    .split('_')
+                    .next()
+                    .and_then(|address| address.parse().ok())
+                    .ok_or(format!("Invalid CSR «{token}»"))?;
+                record.csr_write = Some((address, hex(value()?)? as u32));
+            } else {
+                return Err(format!("Unexpected «{token}»"));
+            }
+        }
+        Ok(record)
+    }
+}
+
+fn hex(text: &str) -> Result<u64, String> {
+    text.strip_prefix("0x")
+        .and_then(|digits| u64::from_str_radix(digits, 16).ok())
+        .ok_or(format!("Invalid hexadecimal number «{text}»"))
+}
+
+/// Parses every commit line of a log, as (line number, record) pairs
+///
+/// Anything that isn't a commit line is skipped, like the instruction lines printed by «-l» and the exception notices
+pub fn parse_commit_log(log: &str) -> Result<Vec<(usize, TraceRecord)>, String> {
+    log.lines()
+        .enumerate()
+        .filter(|(_, line)| is_commit_line(line))
+        .map(|(i, line)| {
+            line.parse()
+                .map(|record| (i + 1, record))
+                .map_err(|error| format!("Line {}: {error}", i + 1))
+        })
+        .collect()
+}
+
+/// Commit lines have a privilege level right after the core number, instruction lines have the pc
+fn is_commit_line(line: &str) -> bool {
+    let mut tokens = line.split_whitespace();
+    tokens.next() == Some("core")
+        && tokens
+            .nth(1)
+            .is_some_and(|token| token.len() == 1 && token.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Writes trace records as a commit log
+pub struct CommitLog<W: Write> {
+    writer: W,
+    /// Wether every commit line is preceded by the disassembled instruction, as Spike does when both «-l» and «--log-commits» are given
+    instructions: bool,
+}
+
+impl<W: Write> CommitLog<W> {
+    pub fn new(writer: W, instructions: bool) -> Self {
+        Self {
+            writer,
+            instructions,
+        }
+    }
+
+    pub fn log(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if self.instructions {
+            writeln!(self.writer, "{}", record.instruction_line())?;
+        }
+        writeln!(self.writer, "{record}")
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
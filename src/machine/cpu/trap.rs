@@ -0,0 +1,75 @@
+//! Synchronous exceptions, which are taken as traps into machine mode
+
+use crate::machine::memory::{AccessKind, MemoryError};
+
+/// Exception codes, as found in mcause
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6,
+    StoreAccessFault = 7,
+    EnvironmentCallFromU = 8,
+    EnvironmentCallFromS = 9,
+    EnvironmentCallFromM = 11,
+}
+
+impl Exception {
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+
+    /// Name Spike uses for the exception in its logs
+    pub fn spike_name(self) -> &'static str {
+        match self {
+            Self::InstructionAddressMisaligned => "trap_instruction_address_misaligned",
+            Self::InstructionAccessFault => "trap_instruction_access_fault",
+            Self::IllegalInstruction => "trap_illegal_instruction",
+            Self::Breakpoint => "trap_breakpoint",
+            Self::LoadAddressMisaligned => "trap_load_address_misaligned",
+            Self::LoadAccessFault => "trap_load_access_fault",
+            Self::StoreAddressMisaligned => "trap_store_address_misaligned",
+            Self::StoreAccessFault => "trap_store_access_fault",
+            Self::EnvironmentCallFromU => "trap_user_ecall",
+            Self::EnvironmentCallFromS => "trap_supervisor_ecall",
+            Self::EnvironmentCallFromM => "trap_machine_ecall",
+        }
+    }
+}
+
+/// An exception raised by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trap {
+    pub exception: Exception,
+    /// Written to mtval: the faulting address, the illegal instruction or zero
+    pub value: u32,
+}
+
+impl Trap {
+    pub fn new(exception: Exception, value: u32) -> Self {
+        Self { exception, value }
+    }
+}
+
+impl From<MemoryError> for Trap {
+    /// The exception for a failed access depends on what it was made for, and always reports the address
+    fn from(value: MemoryError) -> Self {
+        let (misaligned, address, kind) = match value {
+            MemoryError::Unmapped { address, kind } => (false, address, kind),
+            MemoryError::Misaligned { address, kind } => (true, address, kind),
+        };
+        let exception = match (kind, misaligned) {
+            (AccessKind::Fetch, false) => Exception::InstructionAccessFault,
+            (AccessKind::Fetch, true) => Exception::InstructionAddressMisaligned,
+            (AccessKind::Load, false) => Exception::LoadAccessFault,
+            (AccessKind::Load, true) => Exception::LoadAddressMisaligned,
+            (AccessKind::Store | AccessKind::Amo, false) => Exception::StoreAccessFault,
+            (AccessKind::Store | AccessKind::Amo, true) => Exception::StoreAddressMisaligned,
+        };
+        Self::new(exception, address)
+    }
+}
@@ -0,0 +1,192 @@
+//! Flattened device tree (DTB) describing the machine, as OpenSBI and Linux expect to find it in a1 when they start
+//!
+//! The tree is built from the machine itself every time it's needed, so it always describes the harts, memory and devices it actually has.
+//! There's no UART, CLINT, PLIC or virtio device to describe yet: besides memory and the harts (each with its local interrupt controller), the only device is the host interface, described like Spike does.
+
+use super::{
+    cpu::csr::{MHARTID, MISA},
+    memory::constants::RAM_BASE,
+    Machine,
+};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// Version of the format written, and the oldest one it's compatible with
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+/// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+/// Size of the header, which the memory reservation block follows
+const HEADER_SIZE: usize = 40;
+
+/// Extensions in the order they appear in ISA strings, as they are named by the bits of misa
+const CANONICAL_ORDER: &[u8] = b"IEMAFDQCBPVH";
+
+/// The device tree of «machine», as a blob ready to be put in memory
+pub fn generate(machine: &Machine) -> Vec<u8> {
+    let mut tree = DeviceTree::default();
+    tree.begin_node("");
+    tree.property_u32("#address-cells", 1);
+    tree.property_u32("#size-cells", 1);
+    tree.property_string("compatible", "rv-emu");
+    tree.property_string("model", "rv-emu");
+
+    tree.begin_node("chosen");
+    tree.end_node();
+
+    tree.begin_node("cpus");
+    tree.property_u32("#address-cells", 1);
+    tree.property_u32("#size-cells", 0);
+    for cpu in &machine.harts {
+        let id = cpu.csrs.get(MHARTID);
+        tree.begin_node(&format!("cpu@{id:x}"));
+        tree.property_string("device_type", "cpu");
+        tree.property_u32("reg", id);
+        tree.property_string("status", "okay");
+        tree.property_string("compatible", "riscv");
+        tree.property_string("riscv,isa", &isa_string(cpu.csrs.get(MISA)));
+        tree.begin_node("interrupt-controller");
+        tree.property_u32("#interrupt-cells", 1);
+        tree.property("interrupt-controller", &[]);
+        tree.property_string("compatible", "riscv,cpu-intc");
+        tree.property_u32("phandle", id + 1);
+        tree.end_node();
+        tree.end_node();
+    }
+    tree.end_node();
+
+    tree.begin_node(&format!("memory@{RAM_BASE:x}"));
+    tree.property_string("device_type", "memory");
+    tree.property_u32s("reg", &[RAM_BASE as u32, machine.memory.size() as u32]);
+    tree.end_node();
+
+    if machine.htif.is_some() {
+        tree.begin_node("htif");
+        tree.property_string("compatible", "ucb,htif0");
+        tree.end_node();
+    }
+
+    tree.end_node();
+    tree.finish()
+}
+
+/// ISA string of a hart with «misa» (e.g. «rv32ima_zicsr_zifencei»)
+pub fn isa_string(misa: u32) -> String {
+    let base = match misa >> 30 {
+        2 => "rv64",
+        3 => "rv128",
+        _ => "rv32",
+    };
+    let extensions: String = CANONICAL_ORDER
+        .iter()
+        .filter(|&&letter| misa & (1 << (letter - b'A')) != 0)
+        .map(|&letter| letter.to_ascii_lowercase() as char)
+        .collect();
+    // Zicsr and Zifencei are always implemented, but have no bit in misa
+    format!("{base}{extensions}_zicsr_zifencei")
+}
+
+/// Device tree being built, node by node
+#[derive(Default)]
+struct DeviceTree {
+    /// Structure block
+    structure: Vec<u8>,
+    /// Strings block, holding the names of the properties
+    strings: Vec<u8>,
+}
+
+impl DeviceTree {
+    fn token(&mut self, token: u32) {
+        self.structure.extend(token.to_be_bytes());
+    }
+
+    /// Pads the structure block to a multiple of four bytes, as every token must be aligned
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    fn property(&mut self, name: &str, value: &[u8]) {
+        let offset = self.string_offset(name);
+        self.token(FDT_PROP);
+        self.structure.extend((value.len() as u32).to_be_bytes());
+        self.structure.extend(offset.to_be_bytes());
+        self.structure.extend(value);
+        self.align();
+    }
+
+    fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_u32s(name, &[value]);
+    }
+
+    fn property_u32s(&mut self, name: &str, values: &[u32]) {
+        let value: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        self.property(name, &value);
+    }
+
+    fn property_string(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+
+    /// Offset of «name» in the strings block, which is added to it if it isn't there yet
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for string in self.strings.split(|&byte| byte == 0) {
+            if string == name.as_bytes() && offset < self.strings.len() {
+                return offset as u32;
+            }
+            offset += string.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+
+    /// The blob: the header, an empty memory reservation block, then the structure and strings blocks
+    fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+        // The memory reservation block is aligned to 8 bytes, and ends with an empty entry
+        let reservations = HEADER_SIZE.next_multiple_of(8);
+        let structure = reservations + 16;
+        let strings = structure + self.structure.len();
+        let total = strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            structure as u32,
+            strings as u32,
+            reservations as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            // Physical ID of the boot hart
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        blob.resize(structure, 0);
+        blob.extend(self.structure);
+        blob.extend(self.strings);
+        blob
+    }
+}
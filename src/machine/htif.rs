@@ -0,0 +1,128 @@
+//! Berkeley Host-Target Interface (HTIF), as implemented by Spike's front-end server
+//!
+//! The program asks the host for services by writing a command to «tohost», a 64 bits word in memory, and the host answers by writing to «fromhost».
+//! A command is made of the device (bits 63-56), the command for that device (bits 55-48) and a payload (bits 47-0):
+//! - Device 0, command 0: an odd payload asks to exit with status «payload >> 1», an even one is the address of eight 64 bits words holding a system call number and its arguments, which is executed by the host («pk» proxies its system calls this way)
+//! - Device 1 (console), command 1: writes the character in the lowest byte of the payload
+
+use log::warn;
+
+use super::memory::{AccessKind, MemoryBus, MemoryError};
+
+/// System calls proxied to the host, numbered as in Linux
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+
+/// Error codes returned by proxied system calls
+const EBADF: i64 = 9;
+const ENOSYS: i64 = 38;
+
+#[derive(Debug)]
+pub enum HtifError {
+    /// A command points outside of physical memory
+    Memory(MemoryError),
+    UnsupportedCommand {
+        device: u8,
+        command: u8,
+    },
+}
+
+impl From<MemoryError> for HtifError {
+    fn from(value: MemoryError) -> Self {
+        Self::Memory(value)
+    }
+}
+
+pub struct Htif {
+    /// Address of «tohost»
+    pub tohost: u32,
+    /// Address of «fromhost», without it system calls can't be answered
+    pub fromhost: Option<u32>,
+    /// Exit status the program asked for, once it did
+    pub exit_code: Option<u32>,
+    /// Everything written to the console and to the standard output and error
+    pub output: Vec<u8>,
+}
+
+impl Htif {
+    pub fn new(tohost: u32, fromhost: Option<u32>) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            exit_code: None,
+            output: vec![],
+        }
+    }
+
+    /// Forgets the exit status and the output of the previous run
+    pub fn reset(&mut self) {
+        self.exit_code = None;
+        self.output.clear();
+    }
+
+    /// Finds «tohost» and «fromhost» among the symbols of a program, which must define at least «tohost»
+    pub fn from_symbols(symbol: impl Fn(&str) -> Option<u32>) -> Option<Self> {
+        Some(Self::new(symbol("tohost")?, symbol("fromhost")))
+    }
+
+    /// Wether a store to «address» completes a command: 32 bits programs write the low half of «tohost» first, so the command is only read once its high half is written
+    pub fn watches(&self, address: u32) -> bool {
+        (4..8).contains(&address.wrapping_sub(self.tohost))
+    }
+
+    /// Executes the command in «tohost», if there is one
+    pub fn poll(&mut self, memory_bus: &mut MemoryBus) -> Result<(), HtifError> {
+        let command = memory_bus.read_u64(self.tohost, AccessKind::Load)?;
+        if command == 0 {
+            return Ok(());
+        }
+        // The host acknowledges the command by clearing «tohost»
+        memory_bus.write_u64(self.tohost, 0, AccessKind::Store)?;
+
+        let device = (command >> 56) as u8;
+        let code = (command >> 48) as u8;
+        let payload = command & 0xffff_ffff_ffff;
+        match (device, code) {
+            (0, 0) if payload & 1 == 1 => self.exit_code = Some((payload >> 1) as u32),
+            (0, 0) => {
+                let result = self.syscall(memory_bus, payload as u32)?;
+                memory_bus.write_u64(payload as u32, result as u64, AccessKind::Store)?;
+                if let Some(fromhost) = self.fromhost {
+                    memory_bus.write_u64(fromhost, 1, AccessKind::Store)?;
+                }
+            }
+            (1, 1) => self.output.push(payload as u8),
+            (device, command) => return Err(HtifError::UnsupportedCommand { device, command }),
+        }
+        Ok(())
+    }
+
+    /// Executes the system call described at «address», returning its result
+    fn syscall(&mut self, memory_bus: &mut MemoryBus, address: u32) -> Result<i64, HtifError> {
+        let mut args = [0; 8];
+        for (i, arg) in args.iter_mut().enumerate() {
+            *arg = memory_bus.read_u64(address + 8 * i as u32, AccessKind::Load)?;
+        }
+        Ok(match args[0] {
+            SYS_WRITE => {
+                let (fd, buffer, length) = (args[1], args[2] as u32, args[3] as u32);
+                if fd != 1 && fd != 2 {
+                    return Ok(-EBADF);
+                }
+                let mut bytes = vec![0; length as usize];
+                memory_bus.read_bytes(buffer, &mut bytes, AccessKind::Load)?;
+                self.output.extend(bytes);
+                length as i64
+            }
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_code = Some(args[1] as u32);
+                0
+            }
+            number => {
+                warn!("Unsupported system call {number}");
+                -ENOSYS
+            }
+        })
+    }
+}
@@ -0,0 +1,257 @@
+//! Litmus tests: tiny programs running on several harts at once, whose possible outcomes show what a memory model allows
+//!
+//! A run is a sequence of choices between executing the next instruction of a hart and making one of the ready stores in a store buffer reach memory.
+//! «enumerate» tries every sequence, running the test from the start for each of them, while «sample» makes the choices at random.
+//! Instructions that don't access memory are executed as soon as they can, since the other harts can't tell when they were.
+
+use std::collections::BTreeMap;
+
+use crate::assembler::{assemble, AssemblyError};
+
+use super::{
+    memory::{AccessKind, Memory, MemoryBus, MemoryDump, TooLarge},
+    next_random, Machine, MachineError, MemoryModel,
+};
+
+/// Steps a single run can take, past which it's given up on (e.g. when a hart spins forever)
+const MAX_STEPS: usize = 10_000;
+
+/// A litmus test, whose harts each run their own code on the same shared variables
+#[derive(Debug, Clone, Default)]
+pub struct Litmus {
+    /// Code of every hart, which is done once it goes past its last instruction; the labels of «data» are visible to all of them
+    pub harts: Vec<String>,
+    /// Directives laying out the shared variables (e.g. «x: .word 0»)
+    pub data: String,
+    /// Registers making up the outcome, as (hart, register number)
+    pub registers: Vec<(usize, usize)>,
+    /// Labels of «data» whose word makes up the outcome, after the registers
+    pub words: Vec<String>,
+}
+
+/// How many runs ended with every outcome, made of the registers then of the words of the test
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outcomes {
+    pub counts: BTreeMap<Vec<u32>, u64>,
+    /// Runs in which a hart stopped (e.g. on an exception with no trap handler) or that took too many steps
+    pub incomplete: u64,
+}
+
+impl Outcomes {
+    /// Wether any run ended with «outcome»
+    pub fn contains(&self, outcome: &[u32]) -> bool {
+        self.counts.contains_key(outcome)
+    }
+
+    pub fn runs(&self) -> u64 {
+        self.counts.values().sum::<u64>() + self.incomplete
+    }
+
+    fn record(&mut self, outcome: Option<Vec<u32>>) {
+        match outcome {
+            Some(outcome) => *self.counts.entry(outcome).or_default() += 1,
+            None => self.incomplete += 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LitmusError {
+    Assembly(Vec<AssemblyError>),
+    /// One of the words of the outcome isn't a label of the test
+    UndefinedWord(String),
+    Machine(MachineError),
+    /// The test doesn't fit in memory
+    TooLarge(TooLarge),
+}
+
+impl From<MachineError> for LitmusError {
+    fn from(value: MachineError) -> Self {
+        Self::Machine(value)
+    }
+}
+
+impl From<TooLarge> for LitmusError {
+    fn from(value: TooLarge) -> Self {
+        Self::TooLarge(value)
+    }
+}
+
+/// Every outcome «litmus» can end with under «memory_model», and how many of the possible runs end with each
+///
+/// The runs grow exponentially with the memory accesses, so this is only for tests with a handful of them and no loops; «sample» works with any
+pub fn enumerate(litmus: &Litmus, memory_model: MemoryModel) -> Result<Outcomes, LitmusError> {
+    let mut runner = Runner::new(litmus, memory_model)?;
+    let mut outcomes = Outcomes::default();
+    // Choices made by the run, along with how many there were to choose from: every run replays those of the previous one, apart from the last with alternatives left
+    let mut path: Vec<(usize, usize)> = vec![];
+    loop {
+        let mut depth = 0;
+        let outcome = runner.run(|alternatives| {
+            if depth == path.len() {
+                path.push((0, alternatives));
+            }
+            depth += 1;
+            path[depth - 1].0
+        })?;
+        outcomes.record(outcome);
+        while let Some((choice, alternatives)) = path.pop() {
+            if choice + 1 < alternatives {
+                path.push((choice + 1, alternatives));
+                break;
+            }
+        }
+        if path.is_empty() {
+            return Ok(outcomes);
+        }
+    }
+}
+
+/// Outcomes of «runs» runs of «litmus» under «memory_model», making the choices at random from «seed»
+pub fn sample(
+    litmus: &Litmus,
+    memory_model: MemoryModel,
+    runs: u64,
+    seed: u64,
+) -> Result<Outcomes, LitmusError> {
+    let mut runner = Runner::new(litmus, memory_model)?;
+    let mut outcomes = Outcomes::default();
+    // The xorshift generator would be stuck at zero
+    let mut random = seed.max(1);
+    for _ in 0..runs {
+        let outcome =
+            runner.run(|alternatives| (next_random(&mut random) % alternatives as u64) as usize)?;
+        outcomes.record(outcome);
+    }
+    Ok(outcomes)
+}
+
+/// What a run can do next
+#[derive(Debug, Clone, Copy)]
+enum Choice {
+    /// Execute the next instruction of a hart
+    Execute(usize),
+    /// Make the store at an index of the store buffer of a hart reach memory
+    Commit(usize, usize),
+}
+
+/// A machine running a litmus test, again and again
+struct Runner<'a> {
+    litmus: &'a Litmus,
+    machine: Machine,
+    /// Memory as the test starts, with the program loaded
+    memory: MemoryDump,
+    /// Where the code of every hart starts, and where it ends
+    code: Vec<(u32, u32)>,
+    words: Vec<u32>,
+}
+
+impl<'a> Runner<'a> {
+    fn new(litmus: &'a Litmus, memory_model: MemoryModel) -> Result<Self, LitmusError> {
+        let mut source = String::new();
+        for (hart, code) in litmus.harts.iter().enumerate() {
+            source += &format!("litmus_start{hart}:\n{code}\nlitmus_end{hart}:\n");
+        }
+        source += &format!(".data\n{}\n", litmus.data);
+        let program = assemble(&source).map_err(LitmusError::Assembly)?;
+        let label = |name: &str| program.symbols.get(name).map(|&address| address as u32);
+        let code = (0..litmus.harts.len())
+            .map(|hart| {
+                let start = label(&format!("litmus_start{hart}"));
+                let end = label(&format!("litmus_end{hart}"));
+                start.zip(end).expect("every hart has its labels")
+            })
+            .collect();
+        let words = litmus
+            .words
+            .iter()
+            .map(|word| label(word).ok_or_else(|| LitmusError::UndefinedWord(word.clone())))
+            .collect::<Result<_, _>>()?;
+        let memory = Memory::new(program.contents)?.contents;
+        let mut machine = Machine::new(memory.clone())?;
+        machine.memory_model = memory_model;
+        machine.set_harts(litmus.harts.len());
+        Ok(Self {
+            litmus,
+            machine,
+            memory,
+            code,
+            words,
+        })
+    }
+
+    /// Runs the test from the start, making every choice with «choose», which is given how many there are to choose from
+    ///
+    /// Returns the outcome, unless the run didn't complete
+    fn run(
+        &mut self,
+        mut choose: impl FnMut(usize) -> usize,
+    ) -> Result<Option<Vec<u32>>, MachineError> {
+        self.machine.memory.contents.clone_from(&self.memory);
+        self.machine.reset();
+        for (cpu, &(start, _)) in self.machine.harts.iter_mut().zip(&self.code) {
+            cpu.pc = start;
+        }
+        for _ in 0..MAX_STEPS {
+            let running: Vec<_> = (0..self.code.len())
+                .filter(|&hart| self.machine.harts[hart].pc != self.code[hart].1)
+                .collect();
+            let choice = match running.iter().find(|&&hart| !self.accesses_memory(hart)) {
+                Some(&hart) => Choice::Execute(hart),
+                None => {
+                    let mut choices: Vec<_> = running.into_iter().map(Choice::Execute).collect();
+                    for (hart, store_buffer) in self.machine.store_buffers.iter().enumerate() {
+                        choices.extend(
+                            store_buffer
+                                .ready()
+                                .map(|index| Choice::Commit(hart, index)),
+                        );
+                    }
+                    if choices.is_empty() {
+                        return Ok(Some(self.outcome()));
+                    }
+                    choices[choose(choices.len())]
+                }
+            };
+            match choice {
+                Choice::Execute(hart) => {
+                    self.machine.current = hart;
+                    self.machine.step()?;
+                    if self.machine.stopped.is_some() {
+                        return Ok(None);
+                    }
+                }
+                Choice::Commit(hart, index) => {
+                    self.machine.commit_store(hart, index);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Wether the next instruction of «hart» is a load, a store, an atomic instruction or a fence, which the other harts could tell apart from the ones before and after it
+    fn accesses_memory(&mut self, hart: usize) -> bool {
+        let pc = self.machine.harts[hart].pc;
+        // An instruction that can't be fetched raises an exception, which doesn't matter to the other harts
+        MemoryBus::new(&mut self.machine.memory)
+            .read_u32(pc, AccessKind::Fetch)
+            .is_ok_and(|raw| matches!(raw & 0x7f, 0x03 | 0x0f | 0x23 | 0x2f))
+    }
+
+    /// The registers, then the words, making up the outcome of the run that just completed
+    fn outcome(&mut self) -> Vec<u32> {
+        let mut outcome: Vec<_> = self
+            .litmus
+            .registers
+            .iter()
+            .map(|&(hart, register)| self.machine.harts[hart].registers[register])
+            .collect();
+        let memory_bus = MemoryBus::new(&mut self.machine.memory);
+        outcome.extend(self.words.iter().map(|&address| {
+            memory_bus
+                .read_u32(address, AccessKind::Load)
+                .expect("the labels of the test are in memory")
+        }));
+        outcome
+    }
+}
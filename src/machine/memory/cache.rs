@@ -0,0 +1,415 @@
+//! Model of a cache hierarchy between the CPU and memory, to see how programs use it
+//!
+//! Only tags are modelled: the data always stays in memory, which the caches never change the behavior of, and they only count hits and misses.
+//! They are told about every access the CPU makes (see «Caches::observe»), including the fetches the decode cache saves it from making.
+
+use std::fmt;
+
+use super::AccessKind;
+use crate::machine::cpu::{trace::TraceRecord, trap::Exception};
+
+/// Which line of a full set gets replaced
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Replacement {
+    /// The least recently used
+    #[default]
+    Lru,
+    /// The one that was filled first
+    Fifo,
+    /// Any of them, chosen by a pseudo-random generator with a fixed seed so that runs can be repeated
+    Random,
+}
+
+/// When writes reach the next level
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// When the line they made dirty is replaced
+    #[default]
+    WriteBack,
+    /// Right away, so that lines are never dirty
+    WriteThrough,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Size in bytes
+    pub size: u32,
+    /// Lines in every set
+    pub associativity: u32,
+    /// Size of a line in bytes
+    pub line_size: u32,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+    /// Wether a write miss fills the line (and then writes to it like a hit) rather than just going to the next level
+    pub write_allocate: bool,
+}
+
+impl CacheConfig {
+    /// A 4 KiB, 2-way set associative cache with 32-byte lines, small enough for its sets to be looked at one by one
+    pub const L1: Self = Self {
+        size: 4 << 10,
+        associativity: 2,
+        line_size: 32,
+        replacement: Replacement::Lru,
+        write_policy: WritePolicy::WriteBack,
+        write_allocate: true,
+    };
+    /// A 64 KiB, 8-way set associative cache with 64-byte lines
+    pub const L2: Self = Self {
+        size: 64 << 10,
+        associativity: 8,
+        line_size: 64,
+        replacement: Replacement::Lru,
+        write_policy: WritePolicy::WriteBack,
+        write_allocate: true,
+    };
+
+    pub fn sets(&self) -> u32 {
+        self.size / (self.associativity * self.line_size)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheError {
+    /// Sizes and associativity must be powers of two
+    NotAPowerOfTwo,
+    /// The lines of a single set don't fit in the cache
+    TooSmall,
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAPowerOfTwo => write!(f, "Sizes and associativity must be powers of two"),
+            Self::TooSmall => write!(f, "The cache is smaller than a set"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Line {
+    pub valid: bool,
+    /// Wether it was written to since it was filled, and must be written back when replaced
+    pub dirty: bool,
+    pub tag: u32,
+    /// When it was filled, for FIFO replacement
+    pub filled: u64,
+    /// When it was last accessed, for LRU replacement
+    pub used: u64,
+}
+
+/// Hits and misses of one kind of access
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Counts {
+    pub fn accesses(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    /// Fraction of the accesses that hit, if there were any
+    pub fn hit_rate(&self) -> Option<f64> {
+        (self.accesses() > 0).then(|| self.hits as f64 / self.accesses() as f64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Statistics {
+    pub fetch: Counts,
+    pub load: Counts,
+    pub store: Counts,
+    pub amo: Counts,
+    /// Dirty lines written back to the next level when replaced
+    pub writebacks: u64,
+}
+
+impl Statistics {
+    pub fn of(&self, kind: AccessKind) -> Counts {
+        match kind {
+            AccessKind::Fetch => self.fetch,
+            AccessKind::Load => self.load,
+            AccessKind::Store => self.store,
+            AccessKind::Amo => self.amo,
+        }
+    }
+
+    fn of_mut(&mut self, kind: AccessKind) -> &mut Counts {
+        match kind {
+            AccessKind::Fetch => &mut self.fetch,
+            AccessKind::Load => &mut self.load,
+            AccessKind::Store => &mut self.store,
+            AccessKind::Amo => &mut self.amo,
+        }
+    }
+
+    pub fn total(&self) -> Counts {
+        let all = [self.fetch, self.load, self.store, self.amo];
+        Counts {
+            hits: all.iter().map(|counts| counts.hits).sum(),
+            misses: all.iter().map(|counts| counts.misses).sum(),
+        }
+    }
+}
+
+/// An access that goes on to the next level: the first byte, how many bytes, wether it writes, and what it was made for
+type Request = (u32, u32, bool, AccessKind);
+
+pub struct Cache {
+    pub config: CacheConfig,
+    /// Lines of every set, by index
+    pub sets: Vec<Vec<Line>>,
+    pub statistics: Statistics,
+    /// Set and way of the line the last access went to, to highlight it
+    pub last_access: Option<(usize, usize)>,
+    /// Counts accesses, to order them for replacement
+    clock: u64,
+    /// State of the xorshift generator used for random replacement
+    random: u64,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Result<Self, CacheError> {
+        if ![config.size, config.associativity, config.line_size]
+            .iter()
+            .all(|value| value.is_power_of_two())
+        {
+            return Err(CacheError::NotAPowerOfTwo);
+        }
+        if config
+            .associativity
+            .checked_mul(config.line_size)
+            .is_none_or(|set_size| set_size > config.size)
+        {
+            return Err(CacheError::TooSmall);
+        }
+        Ok(Self {
+            config,
+            sets: vec![
+                vec![Line::default(); config.associativity as usize];
+                config.sets() as usize
+            ],
+            statistics: Statistics::default(),
+            last_access: None,
+            clock: 0,
+            random: 0x2545_f491_4f6c_dd1d,
+        })
+    }
+
+    /// Splits an address into the tag and the index of its set
+    pub fn locate(&self, address: u32) -> (u32, usize) {
+        let line = address / self.config.line_size;
+        let sets = self.config.sets();
+        (line / sets, (line % sets) as usize)
+    }
+
+    /// First address of the line with «tag» in the set at «index»
+    pub fn line_address(&self, tag: u32, index: usize) -> u32 {
+        (tag * self.config.sets() + index as u32) * self.config.line_size
+    }
+
+    /// Forgets every line, along with the statistics
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config).expect("The configuration was already checked");
+    }
+
+    /// Makes an access of «size» bytes from «address», one line at a time, pushing what has to go on to the next level to «next»
+    fn access(
+        &mut self,
+        address: u32,
+        size: u32,
+        write: bool,
+        kind: AccessKind,
+        next: &mut Vec<Request>,
+    ) {
+        let line_size = self.config.line_size;
+        let end = address.saturating_add(size);
+        for line in address / line_size..=(end - 1) / line_size {
+            let start = address.max(line * line_size);
+            let line_end = end.min((line + 1).saturating_mul(line_size));
+            self.access_line(start, line_end - start, write, kind, next);
+        }
+    }
+
+    fn access_line(
+        &mut self,
+        address: u32,
+        size: u32,
+        write: bool,
+        kind: AccessKind,
+        next: &mut Vec<Request>,
+    ) {
+        self.clock += 1;
+        let (tag, index) = self.locate(address);
+        let hit = self.sets[index]
+            .iter()
+            .position(|line| line.valid && line.tag == tag);
+        let counts = self.statistics.of_mut(kind);
+        if hit.is_some() {
+            counts.hits += 1;
+        } else {
+            counts.misses += 1;
+        }
+        let way = match hit {
+            Some(way) => way,
+            // Writes that miss without allocating only go to the next level
+            None if write && !self.config.write_allocate => {
+                self.last_access = None;
+                next.push((address, size, true, kind));
+                return;
+            }
+            None => {
+                let way = self.victim(index);
+                let victim = self.sets[index][way];
+                if victim.valid && victim.dirty {
+                    self.statistics.writebacks += 1;
+                    let victim_address = self.line_address(victim.tag, index);
+                    next.push((
+                        victim_address,
+                        self.config.line_size,
+                        true,
+                        AccessKind::Store,
+                    ));
+                }
+                let line_address = address - address % self.config.line_size;
+                next.push((line_address, self.config.line_size, false, kind));
+                self.sets[index][way] = Line {
+                    valid: true,
+                    dirty: false,
+                    tag,
+                    filled: self.clock,
+                    used: self.clock,
+                };
+                way
+            }
+        };
+        let line = &mut self.sets[index][way];
+        line.used = self.clock;
+        if write {
+            match self.config.write_policy {
+                WritePolicy::WriteBack => line.dirty = true,
+                WritePolicy::WriteThrough => next.push((address, size, true, kind)),
+            }
+        }
+        self.last_access = Some((index, way));
+    }
+
+    /// Way of the line to replace in the set at «index», preferring invalid ones
+    fn victim(&mut self, index: usize) -> usize {
+        let set = &self.sets[index];
+        if let Some(way) = set.iter().position(|line| !line.valid) {
+            return way;
+        }
+        match self.config.replacement {
+            Replacement::Lru => (0..set.len()).min_by_key(|&way| set[way].used).unwrap_or(0),
+            Replacement::Fifo => (0..set.len())
+                .min_by_key(|&way| set[way].filled)
+                .unwrap_or(0),
+            Replacement::Random => {
+                self.random ^= self.random << 13;
+                self.random ^= self.random >> 7;
+                self.random ^= self.random << 17;
+                (self.random % set.len() as u64) as usize
+            }
+        }
+    }
+}
+
+/// Split first level instruction and data caches, and an optional unified second level one, in front of memory
+pub struct Caches {
+    pub l1i: Cache,
+    pub l1d: Cache,
+    pub l2: Option<Cache>,
+    /// Bytes read from memory
+    pub memory_reads: u64,
+    /// Bytes written to memory
+    pub memory_writes: u64,
+}
+
+impl Caches {
+    pub fn new(
+        l1i: CacheConfig,
+        l1d: CacheConfig,
+        l2: Option<CacheConfig>,
+    ) -> Result<Self, CacheError> {
+        Ok(Self {
+            l1i: Cache::new(l1i)?,
+            l1d: Cache::new(l1d)?,
+            l2: l2.map(Cache::new).transpose()?,
+            memory_reads: 0,
+            memory_writes: 0,
+        })
+    }
+
+    /// Forgets every line, along with the statistics
+    pub fn reset(&mut self) {
+        self.l1i.reset();
+        self.l1d.reset();
+        if let Some(l2) = &mut self.l2 {
+            l2.reset();
+        }
+        self.memory_reads = 0;
+        self.memory_writes = 0;
+    }
+
+    /// Makes an access of «size» bytes from «address», through the first level cache for its kind
+    pub fn access(&mut self, address: u32, size: u32, write: bool, kind: AccessKind) {
+        let mut requests = vec![];
+        let l1 = match kind {
+            AccessKind::Fetch => &mut self.l1i,
+            _ => &mut self.l1d,
+        };
+        l1.access(address, size, write, kind, &mut requests);
+        let mut to_memory = vec![];
+        match &mut self.l2 {
+            Some(l2) => {
+                for (address, size, write, kind) in requests {
+                    l2.access(address, size, write, kind, &mut to_memory);
+                }
+            }
+            None => to_memory = requests,
+        }
+        for (_, size, write, _) in to_memory {
+            if write {
+                self.memory_writes += size as u64;
+            } else {
+                self.memory_reads += size as u64;
+            }
+        }
+    }
+
+    /// Makes the accesses of the instruction in «record»: its fetch, then its read and its write
+    ///
+    /// The read and the write of atomic instructions are both counted as AMO accesses, accesses that raised an exception aren't counted
+    pub fn observe(&mut self, record: &TraceRecord) {
+        if record
+            .trap
+            .is_some_and(|trap| trap.exception == Exception::InstructionAccessFault)
+        {
+            return;
+        }
+        self.access(record.pc, 4, false, AccessKind::Fetch);
+        if record.trap.is_some() {
+            return;
+        }
+        let atomic = record.raw & 0x7f == 0x2f;
+        if let Some(read) = record.memory_read {
+            let kind = if atomic {
+                AccessKind::Amo
+            } else {
+                AccessKind::Load
+            };
+            self.access(read.address, read.size / 8, false, kind);
+        }
+        if let Some(write) = record.memory_write {
+            let kind = if atomic {
+                AccessKind::Amo
+            } else {
+                AccessKind::Store
+            };
+            self.access(write.address, write.size / 8, true, kind);
+        }
+    }
+}
@@ -1,4 +1,6 @@
-pub const MEMORY_SIZE: usize = 4 * (1 << 10); // 4 KiB
+pub const MEMORY_SIZE: usize = 1 << 20; // 1 MiB
 
 /// The address at which the actual physical memory starts, everything before this isn't real memory (e.g. Memory Mapped I/O)
-pub const RAM_BASE: usize = 0x80;
+///
+/// This is where Spike and QEMU put it too, so that programs linked for them (e.g. riscv-tests) can run unchanged
+pub const RAM_BASE: usize = 0x8000_0000;
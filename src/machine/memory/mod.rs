@@ -1,12 +1,42 @@
+pub mod cache;
 pub mod constants;
+pub mod store_buffer;
 
 pub type MemoryDump = Vec<u8>;
 
 // Since the «constants» module provides the specifications that are needed to implement this memory, everything from there is imported without an alias
 use constants::*;
 
+use std::fmt;
+
+use self::store_buffer::StoreBuffer;
+use super::cpu::trace::MemoryWrite;
+
 pub struct Memory {
     pub contents: MemoryDump,
+    /// What happens on accesses that aren't aligned to their size
+    pub misaligned_accesses: MisalignedAccesses,
+}
+
+/// How accesses that aren't aligned to their size are handled, which the specification leaves to the execution environment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MisalignedAccesses {
+    /// Performed like aligned ones, as if the hardware supported them
+    #[default]
+    Transparent,
+    /// Refused, so that the CPU raises an address-misaligned exception
+    Trap,
+}
+
+/// What an access is made for, which decides the exception it raises if it fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    /// Instruction fetch
+    Fetch,
+    Load,
+    Store,
+    /// Atomic memory operation, which both reads and writes and fails like a store
+    Amo,
 }
 
 impl Memory {
@@ -14,12 +44,37 @@ impl Memory {
         self.contents.len()
     }
 
-    pub fn new(mut memory_dump: MemoryDump) -> Self {
+    /// Fills the rest of memory with zeros, unless the dump doesn't fit in it
+    pub fn new(mut memory_dump: MemoryDump) -> Result<Self, TooLarge> {
+        if memory_dump.len() > MEMORY_SIZE {
+            return Err(TooLarge {
+                size: memory_dump.len(),
+            });
+        }
         memory_dump.resize(MEMORY_SIZE, 0); // Resize to be the desired lenght
 
-        Self {
+        Ok(Self {
             contents: memory_dump,
-        }
+            misaligned_accesses: MisalignedAccesses::default(),
+        })
+    }
+
+    /// Copies the bytes starting at «offset» into «bytes», unless some of them are past the end
+    pub fn read_bytes(&self, offset: usize, bytes: &mut [u8]) -> Option<()> {
+        let source = self
+            .contents
+            .get(offset..offset.checked_add(bytes.len())?)?;
+        bytes.copy_from_slice(source);
+        Some(())
+    }
+
+    /// Copies «bytes» to the ones starting at «offset», unless some of them are past the end
+    pub fn write_bytes(&mut self, offset: usize, bytes: &[u8]) -> Option<()> {
+        let destination = self
+            .contents
+            .get_mut(offset..offset.checked_add(bytes.len())?)?;
+        destination.copy_from_slice(bytes);
+        Some(())
     }
 }
 
@@ -28,66 +83,218 @@ impl Memory {
 /// This doesn't emulate the control/address buses and there is no MAR or MDR on the CPU
 pub struct MemoryBus<'a> {
     pub memory: &'a mut Memory,
+    /// Where the stores of the hart go before reaching memory, if they don't reach it right away
+    pub store_buffer: Option<&'a mut StoreBuffer>,
 }
 
 impl<'a> MemoryBus<'a> {
     pub fn new(memory: &'a mut Memory) -> Self {
-        Self { memory }
+        Self {
+            memory,
+            store_buffer: None,
+        }
     }
 
-    pub fn load(&self, address: usize, size: usize) -> Result<usize, MemoryError> {
-        if address >= RAM_BASE {
-            match size {
-                8 => Ok(self.load8(address)),
-                16 => todo!(),
-                32 => Ok(self.load32(address)),
-                64 => todo!(),
-                _ => Err(MemoryError::UnsupportedAddressingSize),
-            }
-        } else {
-            todo!("Accessing anything other than actual memory is yet to be implemented")
+    /// A bus putting stores in «store_buffer», and letting loads see the ones still there
+    ///
+    /// Atomic memory operations (and «sc.w») still go straight to memory, once the stores to the same bytes have reached it
+    pub fn buffered(memory: &'a mut Memory, store_buffer: &'a mut StoreBuffer) -> Self {
+        Self {
+            memory,
+            store_buffer: Some(store_buffer),
+        }
+    }
+
+    /// Makes every buffered store reach memory, as a fence ordering them before what comes next does
+    pub fn drain(&mut self) {
+        if let Some(store_buffer) = &mut self.store_buffer {
+            store_buffer.drain(self.memory);
         }
     }
 
-    pub fn store(&mut self, address: usize, size: usize, value: usize) -> Result<(), MemoryError> {
-        if address >= RAM_BASE {
-            match size {
-                8 => todo!(),
-                16 => todo!(),
-                32 => Ok(self.store32(address, value)),
-                64 => todo!(),
-                _ => Err(MemoryError::UnsupportedAddressingSize),
+    /// Makes the buffered stores to any of the «size» bytes from «address» reach memory, along with the older ones
+    pub fn drain_overlapping(&mut self, address: u32, size: usize) {
+        if let Some(store_buffer) = &mut self.store_buffer {
+            store_buffer.drain_overlapping(address, size, self.memory);
+        }
+    }
+
+    pub fn read_u8(&self, address: u32, kind: AccessKind) -> Result<u8, MemoryError> {
+        self.read(address, kind).map(u8::from_le_bytes)
+    }
+
+    pub fn read_u16(&self, address: u32, kind: AccessKind) -> Result<u16, MemoryError> {
+        self.read(address, kind).map(u16::from_le_bytes)
+    }
+
+    pub fn read_u32(&self, address: u32, kind: AccessKind) -> Result<u32, MemoryError> {
+        self.read(address, kind).map(u32::from_le_bytes)
+    }
+
+    pub fn read_u64(&self, address: u32, kind: AccessKind) -> Result<u64, MemoryError> {
+        self.read(address, kind).map(u64::from_le_bytes)
+    }
+
+    pub fn write_u8(
+        &mut self,
+        address: u32,
+        value: u8,
+        kind: AccessKind,
+    ) -> Result<(), MemoryError> {
+        self.write(address, value.to_le_bytes(), kind)
+    }
+
+    pub fn write_u16(
+        &mut self,
+        address: u32,
+        value: u16,
+        kind: AccessKind,
+    ) -> Result<(), MemoryError> {
+        self.write(address, value.to_le_bytes(), kind)
+    }
+
+    pub fn write_u32(
+        &mut self,
+        address: u32,
+        value: u32,
+        kind: AccessKind,
+    ) -> Result<(), MemoryError> {
+        self.write(address, value.to_le_bytes(), kind)
+    }
+
+    pub fn write_u64(
+        &mut self,
+        address: u32,
+        value: u64,
+        kind: AccessKind,
+    ) -> Result<(), MemoryError> {
+        self.write(address, value.to_le_bytes(), kind)
+    }
+
+    /// The byte at «address» as a load would read it
+    pub fn peek_u8(&self, address: u32) -> Option<u8> {
+        let mut byte = [0];
+        self.read_bytes(address, &mut byte, AccessKind::Load).ok()?;
+        Some(byte[0])
+    }
+
+    /// Reads the bytes starting at «address» into «bytes», all of them or none
+    ///
+    /// Bulk accesses are made of bytes, so they are never misaligned
+    pub fn read_bytes(
+        &self,
+        address: u32,
+        bytes: &mut [u8],
+        kind: AccessKind,
+    ) -> Result<(), MemoryError> {
+        let offset = self.offset(address, kind)?;
+        self.memory
+            .read_bytes(offset, bytes)
+            .ok_or(MemoryError::Unmapped { address, kind })?;
+        // Instructions are fetched from memory, only loads see the stores of the hart that haven't reached it yet
+        if let (Some(store_buffer), AccessKind::Load | AccessKind::Amo) = (&self.store_buffer, kind)
+        {
+            store_buffer.forward(address, bytes);
+        }
+        Ok(())
+    }
+
+    /// Writes «bytes» to the ones starting at «address», all of them or none
+    ///
+    /// Bulk accesses are made of bytes, so they are never misaligned. Stores of up to eight bytes go to the store buffer, if there is one
+    pub fn write_bytes(
+        &mut self,
+        address: u32,
+        bytes: &[u8],
+        kind: AccessKind,
+    ) -> Result<(), MemoryError> {
+        let offset = self.offset(address, kind)?;
+        let unmapped = MemoryError::Unmapped { address, kind };
+        match &mut self.store_buffer {
+            Some(store_buffer) if kind == AccessKind::Store && bytes.len() <= 8 => {
+                // Checked now, since the store can't fail once it leaves the buffer
+                if offset + bytes.len() > self.memory.size() {
+                    return Err(unmapped);
+                }
+                let mut value = [0; 8];
+                value[..bytes.len()].copy_from_slice(bytes);
+                let store = MemoryWrite {
+                    address,
+                    size: 8 * bytes.len() as u32,
+                    value: u64::from_le_bytes(value),
+                };
+                store_buffer.push(store, self.memory);
+                Ok(())
             }
-        } else {
-            todo!("Accessing anything other than actual memory is yet to be implemented")
+            _ => self.memory.write_bytes(offset, bytes).ok_or(unmapped),
         }
     }
 
-    // TODO: Return as the correct type instead of usize
+    fn read<const N: usize>(&self, address: u32, kind: AccessKind) -> Result<[u8; N], MemoryError> {
+        self.check_alignment(address, N, kind)?;
+        let mut bytes = [0; N];
+        self.read_bytes(address, &mut bytes, kind)?;
+        Ok(bytes)
+    }
+
+    fn write<const N: usize>(
+        &mut self,
+        address: u32,
+        bytes: [u8; N],
+        kind: AccessKind,
+    ) -> Result<(), MemoryError> {
+        self.check_alignment(address, N, kind)?;
+        self.write_bytes(address, &bytes, kind)
+    }
 
-    fn load8(&self, address: usize) -> usize {
-        let index = (address - RAM_BASE) as usize;
-        return self.memory.contents[index] as usize;
+    /// Checks that an access of «size» bytes is aligned, unless misaligned accesses are handled transparently
+    ///
+    /// Misaligned accesses take priority over access faults, as in the specification
+    fn check_alignment(
+        &self,
+        address: u32,
+        size: usize,
+        kind: AccessKind,
+    ) -> Result<(), MemoryError> {
+        if self.memory.misaligned_accesses == MisalignedAccesses::Trap
+            && !address.is_multiple_of(size as u32)
+        {
+            return Err(MemoryError::Misaligned { address, kind });
+        }
+        Ok(())
     }
 
-    fn load32(&self, address: usize) -> usize {
-        let index = (address - RAM_BASE) as usize;
-        return (self.memory.contents[index] as usize)
-            | ((self.memory.contents[index + 1] as usize) << 8)
-            | ((self.memory.contents[index + 2] as usize) << 16)
-            | ((self.memory.contents[index + 3] as usize) << 24);
+    /// Finds the offset in physical memory of «address»
+    ///
+    /// Accessing anything other than actual memory (e.g. Memory Mapped I/O) is yet to be implemented
+    fn offset(&self, address: u32, kind: AccessKind) -> Result<usize, MemoryError> {
+        (address as usize)
+            .checked_sub(RAM_BASE)
+            .ok_or(MemoryError::Unmapped { address, kind })
     }
+}
+
+/// A memory dump is bigger than physical memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooLarge {
+    /// Size of the dump in bytes
+    pub size: usize,
+}
 
-    fn store32(&mut self, address: usize, value: usize) {
-        let index = (address - RAM_BASE) as usize;
-        self.memory.contents[index] = (value & 0xff) as u8;
-        self.memory.contents[index + 1] = ((value >> 8) & 0xff) as u8;
-        self.memory.contents[index + 2] = ((value >> 16) & 0xff) as u8;
-        self.memory.contents[index + 3] = ((value >> 24) & 0xff) as u8;
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The program takes {} bytes, but memory is only {MEMORY_SIZE} bytes",
+            self.size
+        )
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemoryError {
-    UnsupportedAddressingSize,
+    /// Nothing is mapped at (some of the bytes from) the address
+    Unmapped { address: u32, kind: AccessKind },
+    /// The access isn't aligned to its size, and misaligned accesses trap
+    Misaligned { address: u32, kind: AccessKind },
 }
@@ -0,0 +1,107 @@
+//! Store buffers, holding the stores of a hart until they reach memory, so that the other harts can see them late and out of order
+//!
+//! This is how the emulator gets the reorderings RVWMO allows that matter the most: a load can be performed before an earlier store of the same hart reaches memory, and stores to different addresses can reach it in any order.
+//! Stores to overlapping bytes always reach memory in program order, and a hart's loads see its own stores before anyone else does.
+
+use std::collections::VecDeque;
+
+use crate::machine::cpu::trace::MemoryWrite;
+
+use super::{AccessKind, Memory, MemoryBus};
+
+/// Stores a hart can have waiting before the oldest one is made to reach memory
+pub const CAPACITY: usize = 8;
+
+#[derive(Debug, Clone, Default)]
+pub struct StoreBuffer {
+    /// Stores waiting to reach memory, the oldest first
+    pub stores: VecDeque<MemoryWrite>,
+}
+
+impl StoreBuffer {
+    pub fn is_empty(&self) -> bool {
+        self.stores.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.stores.len()
+    }
+
+    /// Adds a store, making the oldest one reach «memory» if there are too many
+    pub fn push(&mut self, store: MemoryWrite, memory: &mut Memory) {
+        self.stores.push_back(store);
+        if self.stores.len() > CAPACITY {
+            self.commit(0, memory);
+        }
+    }
+
+    /// Indices of the stores that can reach memory next, those with no older store to any of their bytes
+    pub fn ready(&self) -> impl Iterator<Item = usize> + '_ {
+        self.stores
+            .iter()
+            .enumerate()
+            .filter(|&(i, store)| {
+                !self
+                    .stores
+                    .range(..i)
+                    .any(|older| overlap(older, store.address, bytes(store)))
+            })
+            .map(|(i, _)| i)
+    }
+
+    /// Makes the store at «index», which must be ready, reach «memory»
+    pub fn commit(&mut self, index: usize, memory: &mut Memory) {
+        let Some(store) = self.stores.remove(index) else {
+            return;
+        };
+        let value = store.value.to_le_bytes();
+        // Buffered stores were checked to be in memory when they were executed
+        MemoryBus::new(memory)
+            .write_bytes(store.address, &value[..bytes(&store)], AccessKind::Store)
+            .expect("buffered stores are in memory");
+    }
+
+    /// Makes every store reach «memory», in program order
+    pub fn drain(&mut self, memory: &mut Memory) {
+        while !self.stores.is_empty() {
+            self.commit(0, memory);
+        }
+    }
+
+    /// Makes the stores to any of the «size» bytes from «address» reach «memory», along with every older one
+    pub fn drain_overlapping(&mut self, address: u32, size: usize, memory: &mut Memory) {
+        if let Some(youngest) = self
+            .stores
+            .iter()
+            .rposition(|store| overlap(store, address, size))
+        {
+            for _ in 0..=youngest {
+                self.commit(0, memory);
+            }
+        }
+    }
+
+    /// Puts the bytes of the stores to any of the «bytes» from «address» over them, from the oldest to the youngest
+    pub fn forward(&self, address: u32, bytes: &mut [u8]) {
+        for store in &self.stores {
+            let value = store.value.to_le_bytes();
+            for (i, byte) in value[..self::bytes(store)].iter().enumerate() {
+                let offset = (store.address as u64 + i as u64).wrapping_sub(address as u64);
+                if let Some(destination) = bytes.get_mut(offset as usize) {
+                    *destination = *byte;
+                }
+            }
+        }
+    }
+}
+
+/// Bytes written by «store»
+fn bytes(store: &MemoryWrite) -> usize {
+    store.size as usize / 8
+}
+
+/// Wether «store» writes any of the «size» bytes from «address»
+fn overlap(store: &MemoryWrite, address: u32, size: usize) -> bool {
+    (store.address as u64) < address as u64 + size as u64
+        && (address as u64) < store.address as u64 + bytes(store) as u64
+}
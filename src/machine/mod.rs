@@ -1,20 +1,169 @@
+use std::{collections::BTreeSet, fmt, sync::Mutex, thread};
+
 use self::{
-    cpu::{Cpu, CpuError},
-    memory::{Memory, MemoryBus, MemoryDump},
+    cpu::{
+        csr::{MHARTID, MIE, MIP},
+        trace::{MemoryWrite, TraceRecord},
+        trap::{Exception, Trap},
+        Cpu, CpuError,
+    },
+    htif::{Htif, HtifError},
+    memory::{
+        cache::Caches, constants::RAM_BASE, store_buffer::StoreBuffer, AccessKind, Memory,
+        MemoryBus, MemoryDump, TooLarge,
+    },
+    pipeline::Pipeline,
+    profiler::Profiler,
 };
 
 pub mod cpu;
+pub mod device_tree;
+pub mod htif;
+pub mod litmus;
 pub mod memory;
+pub mod pipeline;
+pub mod profiler;
+
+/// «ecall» number asking to exit, as in Linux
+const SYS_EXIT: u32 = 93;
+/// Raw «wfi» instruction
+const WFI: u32 = 0x1050_0073;
+/// Seed of the xorshift generator deciding when buffered stores reach memory, fixed so that runs can be repeated
+const SEED: u64 = 0x2545_f491_4f6c_dd1d;
 
-/// A generic machine
+/// A generic machine, with one or more harts sharing memory and the devices
 pub struct Machine {
-    pub cpu: Cpu,
+    /// Harts by their mhartid, there's always at least one
+    pub harts: Vec<Cpu>,
+    /// Hart executing the next instruction, or the one that stopped the program or reached a breakpoint
+    pub current: usize,
+    /// How the harts take turns, when there are several of them
+    pub scheduling: Scheduling,
+    /// Instructions the current hart executed since its turn started
+    executed_in_quantum: usize,
     pub memory: Memory,
+    /// Which orders the harts can see each other's stores in
+    pub memory_model: MemoryModel,
+    /// Stores of every hart that haven't reached memory yet, always empty with a sequentially consistent memory
+    pub store_buffers: Vec<StoreBuffer>,
+    /// State of the generator deciding when buffered stores reach memory during a run
+    random: u64,
+    /// Host interface, if the program has a «tohost» to talk to it
+    pub htif: Option<Htif>,
+    /// Addresses «run» stops at, before executing the instruction there
+    pub breakpoints: BTreeSet<u32>,
+    /// Why the program stopped by itself, after which it can't go on
+    pub stopped: Option<StopReason>,
+    /// Model of the caches the accesses of the first hart go through, if enabled
+    pub caches: Option<Caches>,
+    /// Model of the five-stage pipeline the instructions of the first hart go through, if enabled
+    pub pipeline: Option<Pipeline>,
+    /// Profiler sampling the retired instructions of every hart, if enabled
+    pub profiler: Option<Profiler>,
+    /// Wether resetting puts a device tree describing the machine at the end of memory, passing its address in a1 (and the mhartid in a0) to every hart, as OpenSBI and Linux expect
+    pub device_tree: bool,
+}
+
+/// How the harts take turns running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheduling {
+    /// One hart at a time, each executing «quantum» instructions before handing over to the next one
+    RoundRobin { quantum: usize },
+    /// Every hart in a host thread of its own, executing an instruction at a time with memory to itself, in whatever order the host lets them
+    ///
+    /// Only «run» uses threads: «step» and «run_until» take turns after every instruction instead
+    Threads,
+}
+
+impl Default for Scheduling {
+    fn default() -> Self {
+        Self::RoundRobin { quantum: 100 }
+    }
+}
+
+/// Memory consistency model, deciding which orders the harts can see each other's stores in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MemoryModel {
+    /// Every memory access is performed as it's executed, so all the harts see them in the same order they were executed in
+    #[default]
+    SequentiallyConsistent,
+    /// Stores wait in a store buffer of their hart, reaching memory later and in any order (apart from stores to the same bytes), as RVWMO allows, until a «fence» or an atomic instruction releasing them waits for them
+    ///
+    /// «step» leaves them in the buffers (unless one fills up), «Machine::commit_store» makes one reach memory, and «run» and «run_until» make them reach it at random after every instruction
+    Rvwmo,
+}
+
+impl MemoryModel {
+    pub const ALL: [Self; 2] = [Self::SequentiallyConsistent, Self::Rvwmo];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::SequentiallyConsistent => "Sequentially consistent",
+            Self::Rvwmo => "RVWMO (store buffers)",
+        }
+    }
+}
+
+/// Why a machine stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// An «ebreak» with no trap handler to take it
+    Ebreak,
+    /// An «ecall» asking to exit (a7 = 93, as in Linux) with no trap handler to take it, with the status in a0
+    EcallExit(u32),
+    /// A «wfi» with no interrupt both pending and enabled, and nothing that could ever raise one
+    WaitForInterrupt,
+    /// The run executed as many instructions as it was allowed to
+    InstructionLimit,
+    /// The program counter reached a breakpoint
+    Breakpoint(u32),
+    /// The condition of «run_until» was met
+    Condition,
+    /// An illegal instruction with no trap handler to take it
+    IllegalInstruction { pc: u32, raw: u32 },
+    /// Any other exception with no trap handler to take it
+    UnhandledTrap { pc: u32, trap: Trap },
+    /// The program asked to exit through the host interface, with this status
+    HostExit(u32),
+}
+
+impl StopReason {
+    /// Wether the program can't go on, as opposed to a run interrupted by the debugger
+    pub fn is_final(&self) -> bool {
+        !matches!(
+            self,
+            Self::InstructionLimit | Self::Breakpoint(_) | Self::Condition
+        )
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ebreak => write!(f, "Reached an ebreak"),
+            Self::EcallExit(status) => write!(f, "Exited with status {status} (ecall)"),
+            Self::WaitForInterrupt => write!(f, "Waiting for an interrupt that will never come"),
+            Self::InstructionLimit => write!(f, "Reached the instruction limit"),
+            Self::Breakpoint(address) => write!(f, "Reached the breakpoint at 0x{address:08x}"),
+            Self::Condition => write!(f, "Met the condition to stop at"),
+            Self::IllegalInstruction { pc, raw } => {
+                write!(f, "Illegal instruction 0x{raw:08x} at 0x{pc:08x}")
+            }
+            Self::UnhandledTrap { pc, trap } => write!(
+                f,
+                "Unhandled {} at 0x{pc:08x} (tval 0x{:08x})",
+                trap.exception.spike_name(),
+                trap.value
+            ),
+            Self::HostExit(status) => write!(f, "Exited with status {status}"),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum MachineError {
     Cpu(CpuError),
+    Htif(HtifError),
 }
 
 impl From<CpuError> for MachineError {
@@ -23,18 +172,615 @@ impl From<CpuError> for MachineError {
     }
 }
 
+impl From<HtifError> for MachineError {
+    fn from(value: HtifError) -> Self {
+        Self::Htif(value)
+    }
+}
+
 impl Machine {
-    pub fn new(memory_dump: MemoryDump) -> Self {
+    pub fn new(memory_dump: MemoryDump) -> Result<Self, TooLarge> {
         let memory = memory_dump;
-        Self {
-            cpu: Cpu::new(0x80), // TODO: Make reset vector adjustable
-            memory: Memory::new(memory),
+        Ok(Self {
+            harts: vec![Cpu::new(RAM_BASE)],
+            current: 0,
+            scheduling: Scheduling::default(),
+            executed_in_quantum: 0,
+            memory: Memory::new(memory)?,
+            memory_model: MemoryModel::default(),
+            store_buffers: vec![StoreBuffer::default()],
+            random: SEED,
+            htif: None,
+            breakpoints: BTreeSet::new(),
+            stopped: None,
+            caches: None,
+            pipeline: None,
+            profiler: None,
+            device_tree: false,
+        })
+    }
+
+    /// Gives the machine «count» harts (at least one) with mhartid from 0 up, the new ones starting from the same reset vector as the first, then resets it
+    ///
+    /// The harts kept keep their branch predictor, the new ones don't have any
+    pub fn set_harts(&mut self, count: usize) {
+        let reset_vector = self.harts[0].reset_vector;
+        self.harts.truncate(count.max(1));
+        for id in self.harts.len()..count {
+            self.harts
+                .push(Cpu::new(reset_vector as usize).with_hart_id(id as u32));
+        }
+        self.reset();
+    }
+
+    /// Starts from the reset vector again, resetting the harts and the devices
+    ///
+    /// Memory is left as it is, like the hardware would, and so are breakpoints; the stores still in the store buffers are lost, the caches and the pipeline are emptied, along with their statistics, and the profiler drops its samples.
+    /// With «device_tree» set, the device tree is put in memory again, so that it describes the machine as it is now
+    pub fn reset(&mut self) {
+        for cpu in &mut self.harts {
+            cpu.reset();
+        }
+        self.current = 0;
+        self.executed_in_quantum = 0;
+        self.store_buffers = vec![StoreBuffer::default(); self.harts.len()];
+        self.random = SEED;
+        if let Some(htif) = &mut self.htif {
+            htif.reset();
+        }
+        if let Some(caches) = &mut self.caches {
+            caches.reset();
+        }
+        if let Some(pipeline) = &mut self.pipeline {
+            pipeline.reset();
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.reset();
+        }
+        self.stopped = None;
+        if self.device_tree {
+            let address = self.load_device_tree();
+            for cpu in &mut self.harts {
+                cpu.registers[10] = cpu.csrs.get(MHARTID);
+                cpu.registers[11] = address;
+            }
+        }
+    }
+
+    /// Puts the device tree of the machine (see «device_tree::generate») at the end of memory, aligned to a page, returning its address
+    pub fn load_device_tree(&mut self) -> u32 {
+        let blob = device_tree::generate(self);
+        let address = (RAM_BASE + self.memory.size() - blob.len()) as u32 & !0xfff;
+        MemoryBus::new(&mut self.memory)
+            .write_bytes(address, &blob, AccessKind::Store)
+            .expect("the device tree fits in memory");
+        for cpu in &mut self.harts {
+            cpu.decode_cache.invalidate(address, blob.len() as u32);
+        }
+        address
+    }
+
+    /// Sets where every hart starts (e.g. the entry point of an executable) and resets the machine to start from there
+    pub fn reset_to(&mut self, reset_vector: u32) {
+        for cpu in &mut self.harts {
+            cpu.reset_vector = reset_vector;
+        }
+        self.reset();
+    }
+
+    /// Makes the store at «index» in the store buffer of «hart» reach memory, if it's one of the ready ones (see «StoreBuffer::ready»)
+    ///
+    /// Returns wether it was
+    pub fn commit_store(&mut self, hart: usize, index: usize) -> bool {
+        let store_buffer = &mut self.store_buffers[hart];
+        if !store_buffer.ready().any(|ready| ready == index) {
+            return false;
+        }
+        store_buffer.commit(index, &mut self.memory);
+        true
+    }
+
+    /// Makes every store still in a store buffer reach memory, in the program order of each hart
+    pub fn drain_store_buffers(&mut self) {
+        for store_buffer in &mut self.store_buffers {
+            store_buffer.drain(&mut self.memory);
+        }
+    }
+
+    /// The bus «hart» reaches memory through, which loads see the stores it buffered on
+    pub fn bus(&mut self, hart: usize) -> MemoryBus<'_> {
+        hart_bus(
+            &mut self.memory,
+            self.memory_model,
+            &mut self.store_buffers[hart],
+        )
+    }
+
+    /// The harts, apart from what they share
+    fn split(&mut self) -> (&mut [Cpu], Uncore<'_>) {
+        let alone = self.harts.len() == 1;
+        (
+            &mut self.harts,
+            Uncore {
+                memory: &mut self.memory,
+                memory_model: self.memory_model,
+                store_buffers: &mut self.store_buffers,
+                random: &mut self.random,
+                htif: &mut self.htif,
+                caches: &mut self.caches,
+                pipeline: &mut self.pipeline,
+                profiler: &mut self.profiler,
+                alone,
+            },
+        )
+    }
+
+    /// Executes a single instruction on the current hart, then lets the host answer the command it may have written to «tohost»
+    ///
+    /// If the program can't go on after it, the reason is left in «stopped»; otherwise the next hart takes over if the turn of this one is over
+    pub fn step(&mut self) -> Result<TraceRecord, MachineError> {
+        let record = self.step_hart(self.current)?;
+        if self.stopped.is_none() {
+            self.schedule(1);
+        }
+        Ok(record)
+    }
+
+    /// Executes a single instruction on «hart», whether it's its turn or not, with the host answering like on any step
+    ///
+    /// The turns of the harts are left as they were
+    pub fn step_hart(&mut self, hart: usize) -> Result<TraceRecord, MachineError> {
+        let (harts, mut uncore) = self.split();
+        let (record, stopped, overwritten) = uncore.step(&mut harts[hart], hart)?;
+        for (_, cpu) in harts
+            .iter_mut()
+            .enumerate()
+            .filter(|&(other, _)| other != hart)
+        {
+            if let Some(write) = written(&record) {
+                clear_reservation(&mut cpu.reservation, write);
+            }
+            if let Some(overwritten) = overwritten {
+                overwritten.invalidate(cpu);
+            }
+        }
+        self.stopped = stopped;
+        Ok(record)
+    }
+
+    /// Runs at most «budget» instructions of «hart» natively, with the host answering like on any step, returning how many ran
+    ///
+    /// Loads and stores go through the bus of the hart, but the ones raising an exception are left to the interpreter
+    fn run_native(&mut self, hart: usize, budget: usize) -> Result<usize, MachineError> {
+        let (harts, mut uncore) = self.split();
+        let (ran, stopped, overwritten) = uncore.run_native(&mut harts[hart], hart, budget)?;
+        uncore.drain_randomly();
+        for (_, cpu) in harts
+            .iter_mut()
+            .enumerate()
+            .filter(|&(other, _)| other != hart)
+        {
+            for &overwritten in &overwritten {
+                if let Overwritten::Store(write) = overwritten {
+                    clear_reservation(&mut cpu.reservation, write);
+                }
+                overwritten.invalidate(cpu);
+            }
+        }
+        self.stopped = stopped;
+        Ok(ran)
+    }
+
+    /// Counts «executed» instructions of the current hart, handing over to the next one at the end of its turn
+    fn schedule(&mut self, executed: usize) {
+        self.executed_in_quantum += executed;
+        if self.executed_in_quantum >= self.quantum() {
+            self.current = (self.current + 1) % self.harts.len();
+            self.executed_in_quantum = 0;
         }
     }
 
-    /// Boots and runs the machine normally
-    pub fn boot(&mut self) -> Result<(), MachineError> {
-        self.cpu.reset(&mut MemoryBus::new(&mut self.memory))?;
-        Ok(())
+    /// Instructions a hart executes in its turn, which never ends if it's alone
+    fn quantum(&self) -> usize {
+        match self.scheduling {
+            _ if self.harts.len() == 1 => usize::MAX,
+            Scheduling::RoundRobin { quantum } => quantum.max(1),
+            Scheduling::Threads => 1,
+        }
+    }
+
+    /// Executes at most «limit» instructions, until the program stops or the program counter of the hart about to execute reaches a breakpoint
+    ///
+    /// A breakpoint at the first instruction doesn't stop the run, so that it can be resumed from there.
+    /// With the «jit» feature, translated instructions run natively unless there are breakpoints (see «run_native»).
+    pub fn run(&mut self, limit: usize) -> Result<StopReason, MachineError> {
+        if self.scheduling == Scheduling::Threads && self.harts.len() > 1 {
+            return self.run_threads(limit);
+        }
+        self.execute(limit, true, |_, _| false)
+    }
+
+    /// Like «run», but also stops as soon as «condition» holds after executing an instruction, which is given with its effects
+    ///
+    /// Every instruction is interpreted, since «condition» needs to see them all
+    pub fn run_until(
+        &mut self,
+        limit: usize,
+        condition: impl FnMut(&Self, &TraceRecord) -> bool,
+    ) -> Result<StopReason, MachineError> {
+        self.execute(limit, false, condition)
+    }
+
+    /// Like «run», but gives «observe» the effects of every instruction that is interpreted, on a single host thread
+    ///
+    /// The instructions run natively are told by «Cpu::natively_executed» instead
+    pub fn run_observed(
+        &mut self,
+        limit: usize,
+        mut observe: impl FnMut(&TraceRecord),
+    ) -> Result<StopReason, MachineError> {
+        self.execute(limit, true, |_, record| {
+            observe(record);
+            false
+        })
+    }
+
+    fn execute(
+        &mut self,
+        limit: usize,
+        native: bool,
+        mut condition: impl FnMut(&Self, &TraceRecord) -> bool,
+    ) -> Result<StopReason, MachineError> {
+        if let Some(reason) = self.stopped {
+            return Ok(reason);
+        }
+        let mut executed = 0;
+        while executed < limit {
+            let pc = self.harts[self.current].pc;
+            if executed > 0 && self.breakpoints.contains(&pc) {
+                return Ok(StopReason::Breakpoint(pc));
+            }
+            // Native code doesn't tell the caches, the branch predictor, the pipeline or the profiler about the instructions it runs
+            if cfg!(feature = "jit")
+                && native
+                && self.harts[self.current].has_native(pc)
+                && self.breakpoints.is_empty()
+                && self.caches.is_none()
+                && self.pipeline.is_none()
+                && self.profiler.is_none()
+                && self.harts[self.current].branch_prediction.is_none()
+            {
+                // Without going past the turn of the hart
+                let budget = (limit - executed).min(self.quantum() - self.executed_in_quantum);
+                let ran = self.run_native(self.current, budget)?;
+                if ran > 0 {
+                    executed += ran;
+                    if let Some(reason) = self.stopped {
+                        return Ok(reason);
+                    }
+                    self.schedule(ran);
+                    continue;
+                }
+            }
+            let record = self.step()?;
+            self.split().1.drain_randomly();
+            executed += 1;
+            if let Some(reason) = self.stopped {
+                return Ok(reason);
+            }
+            if condition(self, &record) {
+                return Ok(StopReason::Condition);
+            }
+        }
+        Ok(StopReason::InstructionLimit)
+    }
+
+    /// Runs every hart in a host thread of its own, as «run» does
+    ///
+    /// Harts take a lock on memory and the devices for every instruction, so that each executes atomically; which hart goes next is up to the host
+    fn run_threads(&mut self, limit: usize) -> Result<StopReason, MachineError> {
+        if let Some(reason) = self.stopped {
+            return Ok(reason);
+        }
+        let reservations: Vec<_> = self.harts.iter().map(|cpu| cpu.reservation).collect();
+        let breakpoints = self.breakpoints.clone();
+        let (harts, uncore) = self.split();
+        let shared = Mutex::new(Shared {
+            uncore,
+            reservations,
+            overwritten: vec![vec![]; harts.len()],
+            executed: 0,
+            outcome: None,
+        });
+        thread::scope(|scope| {
+            for (hart, cpu) in harts.iter_mut().enumerate() {
+                let (shared, breakpoints) = (&shared, &breakpoints);
+                scope.spawn(move || {
+                    let mut first = true;
+                    loop {
+                        {
+                            let mut shared = shared.lock().unwrap();
+                            if shared.outcome.is_some() {
+                                break;
+                            }
+                            if let Some(outcome) = shared.step(cpu, hart, limit, breakpoints, first)
+                            {
+                                shared.outcome = Some((hart, outcome));
+                                break;
+                            }
+                        }
+                        first = false;
+                        // Gives the other threads a chance at the lock
+                        thread::yield_now();
+                    }
+                });
+            }
+        });
+        let shared = shared.into_inner().unwrap();
+        let (reservations, overwritten) = (shared.reservations, shared.overwritten);
+        let (hart, outcome) = shared
+            .outcome
+            .expect("the threads only end once there's an outcome");
+        for ((cpu, reservation), overwritten) in
+            self.harts.iter_mut().zip(reservations).zip(overwritten)
+        {
+            cpu.reservation = reservation;
+            for overwritten in overwritten {
+                overwritten.invalidate(cpu);
+            }
+        }
+        let reason = outcome?;
+        if reason != StopReason::InstructionLimit {
+            self.current = hart;
+        }
+        if reason.is_final() {
+            self.stopped = Some(reason);
+        }
+        Ok(reason)
+    }
+
+    /// Exit status the program asked for through the host interface, once it did
+    pub fn exit_code(&self) -> Option<u32> {
+        self.htif.as_ref().and_then(|htif| htif.exit_code)
+    }
+}
+
+/// The bus of a hart with «store_buffer», which its stores go to under RVWMO
+fn hart_bus<'a>(
+    memory: &'a mut Memory,
+    memory_model: MemoryModel,
+    store_buffer: &'a mut StoreBuffer,
+) -> MemoryBus<'a> {
+    match memory_model {
+        MemoryModel::SequentiallyConsistent => MemoryBus::new(memory),
+        MemoryModel::Rvwmo => MemoryBus::buffered(memory, store_buffer),
+    }
+}
+
+/// What the harts share: memory, the devices and the models
+struct Uncore<'a> {
+    memory: &'a mut Memory,
+    memory_model: MemoryModel,
+    /// Store buffers of every hart, by their mhartid
+    store_buffers: &'a mut [StoreBuffer],
+    random: &'a mut u64,
+    htif: &'a mut Option<Htif>,
+    caches: &'a mut Option<Caches>,
+    pipeline: &'a mut Option<Pipeline>,
+    profiler: &'a mut Option<Profiler>,
+    /// Wether there's a single hart
+    alone: bool,
+}
+
+impl Uncore<'_> {
+    /// Executes a single instruction on «cpu», hart number «hart», then lets the host answer the command it may have written to «tohost»
+    ///
+    /// Returns its effects, along with the reason the program can't go on after it if it can't, and the memory it overwrote, which the other harts must decode again
+    fn step(
+        &mut self,
+        cpu: &mut Cpu,
+        hart: usize,
+    ) -> Result<(TraceRecord, Option<StopReason>, Option<Overwritten>), MachineError> {
+        let mut memory_bus = hart_bus(
+            self.memory,
+            self.memory_model,
+            &mut self.store_buffers[hart],
+        );
+        let record = cpu.advance(&mut memory_bus)?;
+        // Stores drop the cached instructions of the hart making them on their own
+        let mut overwritten = written(&record).map(Overwritten::Store);
+        // The caches and the pipeline are those of a single core
+        if hart == 0 {
+            if let Some(caches) = self.caches {
+                caches.observe(&record);
+            }
+            if let Some(pipeline) = self.pipeline {
+                pipeline.observe(&record, cpu.pc);
+            }
+        }
+        if let Some(profiler) = self.profiler {
+            profiler.observe(&record);
+        }
+        if let (Some(htif), Some(write)) = (&mut *self.htif, record.memory_write) {
+            if htif.watches(write.address) {
+                // The host sees the command once it reaches memory, along with everything written before it
+                memory_bus.drain();
+                htif.poll(&mut MemoryBus::new(memory_bus.memory))?;
+                // The host may write anywhere the command told it to
+                cpu.decode_cache.clear();
+                overwritten = Some(Overwritten::Everything);
+            }
+        }
+        let stopped = self.stop_reason(cpu, &record);
+        Ok((record, stopped, overwritten))
+    }
+
+    /// Runs at most «budget» instructions natively on «cpu», hart number «hart», then lets the host answer the command the last one may have written to «tohost»
+    ///
+    /// Returns how many ran, along with the reason the program can't go on after them if it can't, and the memory they overwrote, which the other harts must decode again
+    fn run_native(
+        &mut self,
+        cpu: &mut Cpu,
+        hart: usize,
+        budget: usize,
+    ) -> Result<(usize, Option<StopReason>, Vec<Overwritten>), MachineError> {
+        let mut memory_bus = hart_bus(
+            self.memory,
+            self.memory_model,
+            &mut self.store_buffers[hart],
+        );
+        let run = cpu.advance_native(&mut memory_bus, budget, self.htif.as_ref(), !self.alone);
+        let mut overwritten: Vec<_> = run.writes.iter().copied().map(Overwritten::Store).collect();
+        if let Some(htif) = &mut *self.htif {
+            if run.writes.iter().any(|write| htif.watches(write.address)) {
+                memory_bus.drain();
+                htif.poll(&mut MemoryBus::new(memory_bus.memory))?;
+                cpu.decode_cache.clear();
+                overwritten.push(Overwritten::Everything);
+            }
+        }
+        let stopped = self
+            .htif
+            .as_ref()
+            .and_then(|htif| htif.exit_code)
+            .map(StopReason::HostExit);
+        Ok((run.executed, stopped, overwritten))
+    }
+
+    /// Gives every store buffer a chance to make one of its ready stores, chosen at random, reach memory
+    fn drain_randomly(&mut self) {
+        for store_buffer in self.store_buffers.iter_mut() {
+            // Half of the time, so that a store can stay in the buffer for a while
+            if store_buffer.is_empty() || next_random(self.random).is_multiple_of(2) {
+                continue;
+            }
+            let ready: Vec<_> = store_buffer.ready().collect();
+            let index = ready[(next_random(self.random) % ready.len() as u64) as usize];
+            store_buffer.commit(index, self.memory);
+        }
+    }
+
+    /// Why the program can't go on after «cpu» executed the instruction in «record», if it can't
+    fn stop_reason(&self, cpu: &Cpu, record: &TraceRecord) -> Option<StopReason> {
+        if let Some(exit_code) = self.htif.as_ref().and_then(|htif| htif.exit_code) {
+            return Some(StopReason::HostExit(exit_code));
+        }
+        let pc = record.pc;
+        match record.trap {
+            Some(trap) if cpu.trap_handler().is_none() => Some(match trap.exception {
+                Exception::Breakpoint => StopReason::Ebreak,
+                Exception::IllegalInstruction => StopReason::IllegalInstruction {
+                    pc,
+                    raw: trap.value,
+                },
+                Exception::EnvironmentCallFromU
+                | Exception::EnvironmentCallFromS
+                | Exception::EnvironmentCallFromM
+                    if cpu.registers[17] == SYS_EXIT =>
+                {
+                    StopReason::EcallExit(cpu.registers[10])
+                }
+                _ => StopReason::UnhandledTrap { pc, trap },
+            }),
+            // Nothing raises interrupts yet, so only one that is already pending can wake the hart up; with other harts around it just waits for them, like a hint
+            None if self.alone
+                && record.raw == WFI
+                && cpu.csrs.get(MIP) & cpu.csrs.get(MIE) == 0 =>
+            {
+                Some(StopReason::WaitForInterrupt)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// State of the harts running in threads, behind the lock
+struct Shared<'a> {
+    uncore: Uncore<'a>,
+    /// Reservations of every hart, which the others clear by writing to the reserved address
+    reservations: Vec<Option<u32>>,
+    /// What the others overwrote since each hart last executed, which it must decode again
+    overwritten: Vec<Vec<Overwritten>>,
+    /// Instructions executed by all of the harts together
+    executed: usize,
+    /// The hart that ended the run, and why it did (unless it failed)
+    outcome: Option<(usize, Result<StopReason, MachineError>)>,
+}
+
+impl Shared<'_> {
+    /// Executes an instruction on «cpu», unless the run is over, returning how it ended if it did
+    fn step(
+        &mut self,
+        cpu: &mut Cpu,
+        hart: usize,
+        limit: usize,
+        breakpoints: &BTreeSet<u32>,
+        first: bool,
+    ) -> Option<Result<StopReason, MachineError>> {
+        if self.executed >= limit {
+            return Some(Ok(StopReason::InstructionLimit));
+        }
+        if !first && breakpoints.contains(&cpu.pc) {
+            return Some(Ok(StopReason::Breakpoint(cpu.pc)));
+        }
+        cpu.reservation = self.reservations[hart];
+        for overwritten in self.overwritten[hart].drain(..) {
+            overwritten.invalidate(cpu);
+        }
+        let (record, stopped, overwritten) = match self.uncore.step(cpu, hart) {
+            Ok(stepped) => stepped,
+            Err(error) => return Some(Err(error)),
+        };
+        self.uncore.drain_randomly();
+        self.executed += 1;
+        self.reservations[hart] = cpu.reservation;
+        for other in (0..self.reservations.len()).filter(|&other| other != hart) {
+            if let Some(write) = written(&record) {
+                clear_reservation(&mut self.reservations[other], write);
+            }
+            self.overwritten[other].extend(overwritten);
+        }
+        stopped.map(Ok)
+    }
+}
+
+/// Steps the xorshift generator in «state», returning its new state
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// What the instruction in «record» wrote to memory, if it did
+fn written(record: &TraceRecord) -> Option<MemoryWrite> {
+    record.memory_write.filter(|_| record.trap.is_none())
+}
+
+/// Memory overwritten by a hart, or by the host on its behalf
+#[derive(Debug, Clone, Copy)]
+enum Overwritten {
+    Store(MemoryWrite),
+    /// Anywhere the command the host answered told it to write
+    Everything,
+}
+
+impl Overwritten {
+    /// Drops the instructions «cpu» cached from the overwritten memory
+    fn invalidate(self, cpu: &mut Cpu) {
+        match self {
+            Self::Store(write) => cpu.decode_cache.invalidate(write.address, write.size / 8),
+            Self::Everything => cpu.decode_cache.clear(),
+        }
+    }
+}
+
+/// Clears «reservation» if «write» overlaps the reserved word
+fn clear_reservation(reservation: &mut Option<u32>, write: MemoryWrite) {
+    let end = write.address as u64 + write.size as u64 / 8;
+    if reservation.is_some_and(|address| {
+        (write.address as u64) < address as u64 + 4 && (address as u64) < end
+    }) {
+        *reservation = None;
     }
 }
@@ -0,0 +1,232 @@
+//! Cycle-level model of a classic five-stage pipeline (IF, ID, EX, MEM, WB), kept apart from the interpreter
+//!
+//! The interpreter still executes one instruction at a time: the model is told about every instruction it executes, in order, and works out the cycle it would have entered every stage in.
+//! Register values are forwarded to EX from the end of EX (or of MEM, for loads), or, without forwarding, read in ID from the register file in the same cycle WB writes them.
+//! Branches and jumps are predicted not taken and resolved in EX, flushing the two instructions fetched after them when they are taken; exceptions flush them too.
+
+use std::collections::VecDeque;
+
+use super::cpu::{
+    constants::{IType, Instruction},
+    trace::TraceRecord,
+};
+
+/// How many of the last instructions are kept, for the pipeline diagram
+const HISTORY: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineConfig {
+    /// Wether results are forwarded to the instructions that need them, instead of waiting for them to be written back
+    pub forwarding: bool,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self { forwarding: true }
+    }
+}
+
+/// The cycle an instruction entered every stage in, counting from 1
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StageCycles {
+    pub fetch: u64,
+    pub decode: u64,
+    pub execute: u64,
+    pub memory: u64,
+    pub writeback: u64,
+}
+
+/// An instruction that went through the pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineEntry {
+    pub pc: u32,
+    /// Wether it was fetched after a taken branch or jump (or an exception) and flushed before being executed, so it only reached IF and maybe ID
+    pub flushed: bool,
+    /// Stages a flushed instruction didn't reach are left at 0
+    pub cycles: StageCycles,
+}
+
+impl PipelineEntry {
+    /// Last cycle the instruction was in the pipeline
+    pub fn last_cycle(&self) -> u64 {
+        if self.flushed {
+            self.cycles.fetch.max(self.cycles.decode)
+        } else {
+            self.cycles.writeback
+        }
+    }
+
+    /// Name of the stage the instruction was in during «cycle», if it was in the pipeline
+    pub fn stage(&self, cycle: u64) -> Option<&'static str> {
+        let cycles = &self.cycles;
+        if cycle < cycles.fetch || cycle > self.last_cycle() {
+            None
+        } else if cycle < cycles.decode || cycles.decode == 0 {
+            Some("IF")
+        } else if cycle < cycles.execute || cycles.execute == 0 {
+            Some("ID")
+        } else if cycle < cycles.memory {
+            Some("EX")
+        } else if cycle < cycles.writeback {
+            Some("MEM")
+        } else {
+            Some("WB")
+        }
+    }
+
+    /// Wether the instruction was held in its stage during «cycle», instead of entering it
+    pub fn stalled(&self, cycle: u64) -> bool {
+        let cycles = &self.cycles;
+        self.stage(cycle).is_some()
+            && ![
+                cycles.fetch,
+                cycles.decode,
+                cycles.execute,
+                cycles.memory,
+                cycles.writeback,
+            ]
+            .contains(&cycle)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineStatistics {
+    /// Cycles from the first fetch to the last write back
+    pub cycles: u64,
+    pub instructions: u64,
+    /// Cycles instructions were held in ID waiting for their operands
+    pub stalls: u64,
+    /// Instructions fetched and then flushed
+    pub flushed: u64,
+}
+
+impl PipelineStatistics {
+    /// Cycles per instruction, if any was executed
+    pub fn cpi(&self) -> Option<f64> {
+        (self.instructions > 0).then(|| self.cycles as f64 / self.instructions as f64)
+    }
+}
+
+pub struct Pipeline {
+    pub config: PipelineConfig,
+    /// The last instructions that went through the pipeline, flushed ones included, the oldest first
+    pub history: VecDeque<PipelineEntry>,
+    pub statistics: PipelineStatistics,
+    /// Stages of the previous instruction that wasn't flushed
+    previous: Option<StageCycles>,
+    /// First cycle the value of every register can be used in EX
+    ready: [u64; 32],
+    /// First cycle the next instruction can be fetched in, after a taken branch or jump
+    redirect: u64,
+}
+
+impl Pipeline {
+    pub fn new(config: PipelineConfig) -> Self {
+        Self {
+            config,
+            history: VecDeque::with_capacity(HISTORY),
+            statistics: PipelineStatistics::default(),
+            previous: None,
+            ready: [0; 32],
+            redirect: 1,
+        }
+    }
+
+    /// Empties the pipeline, along with the statistics
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config);
+    }
+
+    /// Works out the cycles of the instruction in «record», after which the CPU went on to «next_pc»
+    pub fn observe(&mut self, record: &TraceRecord, next_pc: u32) {
+        // An instruction enters a stage once the previous one left it
+        let (fetch, decode, execute) = match self.previous {
+            Some(previous) => {
+                let fetch = previous.decode.max(self.redirect);
+                let decode = (fetch + 1).max(previous.execute);
+                let operands = sources(record.raw)
+                    .into_iter()
+                    .flatten()
+                    .map(|register| self.ready[register as usize])
+                    .max()
+                    .unwrap_or(0);
+                let execute = (decode + 1).max(previous.memory).max(operands);
+                (fetch, decode, execute)
+            }
+            None => (self.redirect, self.redirect + 1, self.redirect + 2),
+        };
+        let cycles = StageCycles {
+            fetch,
+            decode,
+            execute,
+            memory: execute + 1,
+            writeback: execute + 2,
+        };
+        self.statistics.stalls += execute - (decode + 1);
+        self.statistics.instructions += 1;
+        self.statistics.cycles = cycles.writeback;
+        self.push(PipelineEntry {
+            pc: record.pc,
+            flushed: false,
+            cycles,
+        });
+
+        // Atomic memory operations, like loads, only have their result at the end of MEM
+        let load = matches!(record.raw & 0x7f, 0x03 | 0x2f);
+        if let Some(rd) = Instruction::try_from(record.raw)
+            .ok()
+            .and_then(|instruction| instruction.destination_register())
+            .filter(|&rd| rd != 0)
+        {
+            self.ready[rd as usize] = match (self.config.forwarding, load) {
+                (true, false) => cycles.memory,
+                (true, true) => cycles.writeback,
+                // Read in ID in the same cycle as WB
+                (false, _) => cycles.writeback + 1,
+            };
+        }
+
+        // The two instructions fetched while the branch went through ID and EX are flushed
+        if record.trap.is_some() || next_pc != record.pc.wrapping_add(4) {
+            for (i, (fetch, decode)) in [(decode, execute), (execute, 0)].into_iter().enumerate() {
+                self.push(PipelineEntry {
+                    pc: record.pc.wrapping_add(4 * (i as u32 + 1)),
+                    flushed: true,
+                    cycles: StageCycles {
+                        fetch,
+                        decode,
+                        ..StageCycles::default()
+                    },
+                });
+            }
+            self.statistics.flushed += 2;
+            self.redirect = execute + 1;
+        }
+        self.previous = Some(cycles);
+    }
+
+    fn push(&mut self, entry: PipelineEntry) {
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(entry);
+    }
+}
+
+/// Registers the instruction reads
+fn sources(raw: u32) -> [Option<u32>; 2] {
+    match Instruction::try_from(raw) {
+        Ok(Instruction::R(instruction)) => [Some(instruction.rs1), Some(instruction.rs2)],
+        Ok(Instruction::S(instruction)) => [Some(instruction.rs1), Some(instruction.rs2)],
+        Ok(Instruction::B(instruction)) => [Some(instruction.rs1), Some(instruction.rs2)],
+        // fence, the system instructions without a CSR and the CSR instructions with an immediate don't read rs1
+        Ok(Instruction::I(IType { opcode: 0x0f, .. }))
+        | Ok(Instruction::I(IType {
+            opcode: 0x73,
+            funct3: 0x0 | 0x5..=0x7,
+            ..
+        })) => [None, None],
+        Ok(Instruction::I(instruction)) => [Some(instruction.rs1), None],
+        _ => [None, None],
+    }
+}
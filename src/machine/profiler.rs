@@ -0,0 +1,119 @@
+//! Sampling profiler, attributing retired instructions to the functions of the program
+//!
+//! Every «period» retired instructions the address of the last one is sampled, the addresses are only mapped to functions when the profile is shown.
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::cpu::trace::TraceRecord;
+
+pub struct Profiler {
+    /// Retired instructions between two samples, 1 samples all of them
+    pub period: u64,
+    /// Retired instructions left before the next sample
+    countdown: u64,
+    /// Samples taken at every address
+    pub samples: HashMap<u32, u64>,
+    pub total: u64,
+}
+
+/// Samples of a function in a flat profile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionSamples {
+    /// None for the samples outside of any known function
+    pub name: Option<String>,
+    pub samples: u64,
+}
+
+impl Profiler {
+    /// A profiler sampling every «period» retired instructions, which must be at least 1
+    pub fn new(period: u64) -> Self {
+        let period = period.max(1);
+        Self {
+            period,
+            countdown: period,
+            samples: HashMap::new(),
+            total: 0,
+        }
+    }
+
+    /// Drops every sample
+    pub fn reset(&mut self) {
+        *self = Self::new(self.period);
+    }
+
+    /// Counts the instruction in «record», if it retired
+    pub fn observe(&mut self, record: &TraceRecord) {
+        if record.trap.is_some() {
+            return;
+        }
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = self.period;
+            *self.samples.entry(record.pc).or_default() += 1;
+            self.total += 1;
+        }
+    }
+
+    /// Addresses by how many samples they got, the most first (then by address)
+    pub fn hot_addresses(&self) -> Vec<(u32, u64)> {
+        let mut addresses: Vec<_> = self
+            .samples
+            .iter()
+            .map(|(&address, &samples)| (address, samples))
+            .collect();
+        addresses.sort_by_key(|&(address, samples)| (std::cmp::Reverse(samples), address));
+        addresses
+    }
+
+    /// Samples of every function in «functions» that got any, the most first (then by name)
+    pub fn flat_profile(&self, functions: &Functions) -> Vec<FunctionSamples> {
+        let mut profile: HashMap<Option<&str>, u64> = HashMap::new();
+        for (&address, &samples) in &self.samples {
+            *profile.entry(functions.containing(address)).or_default() += samples;
+        }
+        let mut profile: Vec<_> = profile
+            .into_iter()
+            .map(|(name, samples)| FunctionSamples {
+                name: name.map(str::to_owned),
+                samples,
+            })
+            .collect();
+        profile.sort_by(|a, b| b.samples.cmp(&a.samples).then(a.name.cmp(&b.name)));
+        profile
+    }
+}
+
+/// Functions of a program, each assumed to go on until the next one starts
+#[derive(Debug, Default)]
+pub struct Functions {
+    /// Name of the function starting at every address
+    starts: BTreeMap<u32, String>,
+}
+
+impl Functions {
+    /// Takes «symbols» as the start of the functions, the first in alphabetical order winning when several start at the same address
+    pub fn new(symbols: impl IntoIterator<Item = (String, u32)>) -> Self {
+        let mut starts: BTreeMap<u32, String> = BTreeMap::new();
+        for (name, address) in symbols {
+            match starts.get(&address) {
+                Some(existing) if *existing <= name => {}
+                _ => {
+                    starts.insert(address, name);
+                }
+            }
+        }
+        Self { starts }
+    }
+
+    /// Name of the function «address» is in, if it's after the start of any
+    pub fn containing(&self, address: u32) -> Option<&str> {
+        self.starts
+            .range(..=address)
+            .next_back()
+            .map(|(_, name)| name.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.starts.is_empty()
+    }
+}
@@ -1,22 +1,46 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // Hides console window on Windows in release
 
-use std::{fs, process::Command};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File},
+    hash::Hash,
+    io::BufWriter,
+    sync::Arc,
+};
 
 use egui_dock::{DockArea, NodeIndex, Style, Tree};
 use egui_memory_editor::MemoryEditor;
 use emu::{
-    cpu::{CpuError, DecodeError},
+    assembler::{assemble, AssemblyError, Program},
+    cpu::{
+        branch_prediction::{BranchPrediction, PredictorConfig},
+        counters::{self, Event},
+        csr,
+        disassembler::{disassemble, register_name},
+        trace::CommitLog,
+    },
     create_rv32,
-    machine::Machine,
+    elf::Executable,
+    machine::{
+        htif::Htif,
+        pipeline::{Pipeline, PipelineConfig},
+        profiler::{Functions, Profiler},
+        Machine, MachineError, MemoryModel, Scheduling, StopReason,
+    },
     memory::{
+        cache::{CacheConfig, Caches, Replacement, WritePolicy},
         constants::{MEMORY_SIZE, RAM_BASE},
-        MemoryBus,
+        AccessKind, MemoryBus, MisalignedAccesses,
     },
+    source_map::SourceMap,
 };
 use log::{debug, error};
 
 use eframe::{
-    egui::{self, Button, Ui},
+    egui::{
+        self, text::LayoutJob, Button, Color32, DragValue, Galley, Label, RichText, Sense,
+        TextFormat, TextStyle, Ui,
+    },
     NativeOptions,
 };
 
@@ -26,12 +50,50 @@ fn main() -> eframe::Result<()> {
     eframe::run_native("rvemu", options, Box::new(|_cc| Box::<MyApp>::default()))
 }
 
+/// How many instructions the disassembly pane shows before and after the one pointed by pc
+const DISASSEMBLY_BEFORE_PC: usize = 16;
+const DISASSEMBLY_AFTER_PC: usize = 48;
+/// How many instructions «Run until end» executes at most, since a program that never ends (e.g. one stuck in a trap loop) would freeze the GUI
+const RUN_LIMIT: usize = 1_000_000;
+/// How many harts the machine can be given from the editor pane
+const MAX_HARTS: usize = 8;
+/// How many of the most sampled addresses the profiler pane lists
+const HOT_ADDRESSES: usize = 50;
+
 struct TabViewer<'a> {
     machine: &'a mut Machine,
-    /// Wether the emulator has reached an instruction with opcode equal to zero
-    has_reached_end: &'a mut bool,
+    /// Why the last step or run stopped, if it did
+    stop_reason: &'a mut Option<StopReason>,
     mem_editor: &'a mut MemoryEditor,
     code: &'a mut String,
+    /// Errors found while assembling «code»
+    assembly_errors: &'a mut Vec<AssemblyError>,
+    /// Where the instructions of the loaded program come from
+    source_map: &'a mut SourceMap,
+    /// Addresses of the instructions executed since the program was loaded
+    executed: &'a mut BTreeSet<u32>,
+    elf_path: &'a mut String,
+    /// Error found while loading an ELF executable
+    load_error: &'a mut Option<String>,
+    /// Contents of the source files referenced by «source_map», read when first shown
+    source_files: &'a mut HashMap<String, String>,
+    register_columns: &'a mut RegisterColumns,
+    /// Values of the registers before the last instruction was executed
+    previous_registers: &'a mut [u32; 32],
+    commit_log_path: &'a mut String,
+    /// Where executed instructions are logged, if logging was started
+    commit_log: &'a mut Option<CommitLog<BufWriter<File>>>,
+    cache_settings: &'a mut CacheSettings,
+    predictor_settings: &'a mut PredictorSettings,
+    pipeline_settings: &'a mut PipelineSettings,
+    profiler_settings: &'a mut ProfilerSettings,
+    /// Functions of the loaded program, for the profile
+    functions: &'a mut Functions,
+    hart_settings: &'a mut HartSettings,
+    /// How the machine handles misaligned accesses, which is built again on every load
+    misaligned_accesses: &'a mut MisalignedAccesses,
+    /// Hart whose registers, CSRs and code are shown
+    hart: &'a mut usize,
 }
 
 impl egui_dock::TabViewer for TabViewer<'_> {
@@ -43,6 +105,13 @@ impl egui_dock::TabViewer for TabViewer<'_> {
             "Editor" => self.editor_pane(ui),
             "Registers" => self.registers_pane(ui),
             "Memory" => self.memory_pane(ui),
+            "Disassembly" => self.disassembly_pane(ui),
+            "CSRs" => self.csrs_pane(ui),
+            "Input/output" => self.io_pane(ui),
+            "Caches" => self.caches_pane(ui),
+            "Branches" => self.branches_pane(ui),
+            "Pipeline" => self.pipeline_pane(ui),
+            "Profiler" => self.profiler_pane(ui),
             _ => {
                 ui.label(format!("Content of {tab}"));
             }
@@ -60,130 +129,1310 @@ impl TabViewer<'_> {
             if ui.button("Reset & Compile & Load in memory").clicked() {
                 debug!("Resetting machine");
                 debug!("Assembling code: {:?}", &self.code);
-                let output = Command::new("rvasm")
-                    .arg("-s")
-                    .arg(&self.code)
-                    .arg("-o")
-                    .arg("out.bin")
-                    .arg("-a")
-                    .arg("RV32I")
-                    .arg("-f")
-                    .arg("flat")
-                    .output();
-                if let Err(output) = output {
-                    error!("Error while assembling code: {output}");
-                } else if let Ok(output) = output {
-                    if !output.status.success() {
-                        error!(
-                            "Error while assembling code (from assembler):\nstderr: {:?}\nExit status: {:?}",
-                            String::from_utf8(output.stderr)
-                                .expect("Couldn't parse the assembler's stderr as UTF-8"),
-                            output
-                                .status
-                                .code()
-                                .expect("Couldn't get the assembler's exit status")
-                        )
-                    } else {
-                        debug!("Successfully assembled code")
+                match assemble(self.code) {
+                    Ok(program) => {
+                        debug!("Successfully assembled code");
+                        self.assembly_errors.clear();
+                        if let Err(error) = self.load_program(program) {
+                            error!("Error while loading the program: {error}");
+                            *self.load_error = Some(error);
+                        }
+                    }
+                    Err(errors) => {
+                        error!("Error while assembling code: {:?}", errors);
+                        *self.assembly_errors = errors;
                     }
                 }
-
-                *self.machine =
-                    create_rv32(fs::read("out.bin").expect("Couldn't read assembled file"));
-
-                *self.has_reached_end = false;
             }
 
-            if !*self.has_reached_end {
-                let advance_button = ui.button("Step >>");
-                let tillend_button = ui.button("Run until end");
+            if self.machine.stopped.is_none() {
+                if ui.button("Step >>").clicked() {
+                    let result = self.step();
+                    self.handle_result(result);
+                }
 
-                // TODO: Find better way, removing duplication for the buttons
-                if advance_button.clicked() {
-                    let mut memory_bus = MemoryBus::new(&mut self.machine.memory);
-                    if let Err(error) = self.machine.cpu.advance(&mut memory_bus) {
-                        // Reaching an instruction with opcode zero shouldn't be considered an error as it is actually expected here and it signals the end of the program
-                        if let CpuError::Decode(DecodeError::OpcodeZero) = error {
-                            *self.has_reached_end = true;
-                        } else {
-                            error!("Error while executing single instruction: {:?}", error);
+                if ui.button("Run until end").clicked() {
+                    let result = self.run();
+                    self.handle_result(result);
+                }
+            } else {
+                ui.add_enabled(false, Button::new("Step >>"));
+                ui.add_enabled(false, Button::new("Run until end"));
+            }
+        });
+        self.harts_editor(ui);
+        ui.horizontal(|ui| {
+            ui.label("ELF executable:");
+            ui.text_edit_singleline(self.elf_path);
+            if ui.button("Reset & Load").clicked() {
+                if let Err(error) = self.load_elf() {
+                    error!("Error while loading ELF executable: {error}");
+                    *self.load_error = Some(error);
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Commit log:");
+            ui.add_enabled(
+                self.commit_log.is_none(),
+                egui::TextEdit::singleline(self.commit_log_path),
+            );
+            match self.commit_log.take() {
+                Some(mut commit_log) => {
+                    if ui.button("Stop logging").clicked() {
+                        if let Err(error) = commit_log.flush() {
+                            error!("Error while writing the commit log: {error}");
                         }
+                    } else {
+                        *self.commit_log = Some(commit_log);
                     }
                 }
-
-                if tillend_button.clicked() {
-                    let mut memory_bus = MemoryBus::new(&mut self.machine.memory);
-                    if let Err(error) = self.machine.cpu.reset(&mut memory_bus) {
-                        // Reaching an instruction with opcode zero shouldn't be considered an error as it is actually expected here and it signals the end of the program
-                        if let CpuError::Decode(DecodeError::OpcodeZero) = error {
-                            *self.has_reached_end = true;
-                        } else {
-                            error!("Error while executing single instruction: {:?}", error);
+                None => {
+                    if ui.button("Start logging").clicked() {
+                        match File::create(&*self.commit_log_path) {
+                            Ok(file) => {
+                                *self.commit_log = Some(CommitLog::new(BufWriter::new(file), true))
+                            }
+                            Err(error) => error!(
+                                "Couldn't create commit log {}: {error}",
+                                self.commit_log_path
+                            ),
                         }
                     }
                 }
-            } else {
-                ui.add_enabled(false, Button::new("Step >>"));
-                ui.add_enabled(false, Button::new("Run until end"));
             }
         });
-        ui.add_sized(
-            ui.available_size(),
-            egui::TextEdit::multiline(self.code).code_editor(),
+        if let Some(load_error) = self.load_error {
+            ui.colored_label(ui.visuals().error_fg_color, load_error.as_str());
+        }
+        if let Some(stop_reason) = self.stop_reason {
+            ui.label(format!("Stopped: {stop_reason}"));
+        }
+        for error in self.assembly_errors.iter() {
+            ui.colored_label(ui.visuals().error_fg_color, error.to_string());
+        }
+
+        // Code coming from an executable with debugging information is shown instead of the editor, only while it is loaded
+        let pc = self.machine.harts[*self.hart].pc;
+        let source_file = self
+            .source_map
+            .lookup(pc)
+            .or_else(|| {
+                self.source_map
+                    .iter()
+                    .map(|(_, _, location)| location)
+                    .next()
+            })
+            .and_then(|location| location.file.clone());
+
+        let next_line = self
+            .source_map
+            .lookup(pc)
+            .filter(|location| location.file == source_file)
+            .map(|location| location.line);
+        let executed_lines: BTreeSet<usize> = self
+            .executed
+            .iter()
+            .filter_map(|address| self.source_map.lookup(*address))
+            .filter(|location| location.file == source_file)
+            .map(|location| location.line)
+            .collect();
+        let errors = &*self.assembly_errors;
+        let error_background = ui.visuals().error_fg_color.linear_multiply(0.2);
+        let next_background = ui.visuals().selection.bg_fill;
+        let executed_background = ui.visuals().selection.bg_fill.linear_multiply(0.3);
+        let mut layouter = |ui: &Ui, text: &str, wrap_width: f32| {
+            code_layout(ui, text, wrap_width, |line| {
+                if Some(line) == next_line {
+                    Some(next_background)
+                } else if errors.iter().any(|error| error.line == line) {
+                    Some(error_background)
+                } else {
+                    executed_lines
+                        .contains(&line)
+                        .then_some(executed_background)
+                }
+            })
+        };
+
+        match source_file {
+            Some(file) => {
+                let source = self.source_files.entry(file.clone()).or_insert_with(|| {
+                    fs::read_to_string(&file)
+                        .unwrap_or_else(|error| format!("Couldn't read {file}: {error}"))
+                });
+                ui.label(RichText::new(&file).monospace());
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    ui.add_sized(
+                        ui.available_size(),
+                        egui::TextEdit::multiline(&mut source.as_str())
+                            .code_editor()
+                            .layouter(&mut layouter),
+                    );
+                });
+            }
+            None => {
+                ui.add_sized(
+                    ui.available_size(),
+                    egui::TextEdit::multiline(self.code)
+                        .code_editor()
+                        .layouter(&mut layouter),
+                );
+            }
+        }
+    }
+
+    /// Forgets everything about the previously loaded program
+    fn loaded(&mut self) {
+        // The new machine is built with a single hart, without caches, a branch predictor, a pipeline or a profiler, and with misaligned accesses handled transparently
+        self.apply_hart_settings();
+        self.machine.memory.misaligned_accesses = *self.misaligned_accesses;
+        self.apply_cache_settings();
+        self.apply_predictor_settings();
+        self.apply_pipeline_settings();
+        self.apply_profiler_settings();
+        *self.stop_reason = None;
+        *self.load_error = None;
+        self.executed.clear();
+        self.source_files.clear();
+        *self.previous_registers = self.machine.harts[*self.hart].registers;
+    }
+
+    /// Chooses how many harts there are and how they take turns, and which one is shown
+    fn harts_editor(&mut self, ui: &mut Ui) {
+        let settings = &mut *self.hart_settings;
+        let mut apply = false;
+        ui.horizontal(|ui| {
+            ui.label("Harts:");
+            apply |= ui
+                .add(DragValue::new(&mut settings.count).clamp_range(1..=MAX_HARTS))
+                .changed();
+            let threads = settings.scheduling == Scheduling::Threads;
+            egui::ComboBox::from_id_source("scheduling")
+                .selected_text(if threads {
+                    "Host threads"
+                } else {
+                    "Round robin"
+                })
+                .show_ui(ui, |ui| {
+                    apply |= ui
+                        .selectable_value(
+                            &mut settings.scheduling,
+                            Scheduling::RoundRobin {
+                                quantum: settings.quantum,
+                            },
+                            "Round robin",
+                        )
+                        .changed();
+                    apply |= ui
+                        .selectable_value(
+                            &mut settings.scheduling,
+                            Scheduling::Threads,
+                            "Host threads",
+                        )
+                        .changed();
+                });
+            if !threads {
+                ui.label("Quantum:");
+                if ui
+                    .add(DragValue::new(&mut settings.quantum).clamp_range(1..=1_000_000))
+                    .changed()
+                {
+                    settings.scheduling = Scheduling::RoundRobin {
+                        quantum: settings.quantum,
+                    };
+                    self.machine.scheduling = settings.scheduling;
+                }
+            }
+            ui.label("Memory:");
+            let memory_model = settings.memory_model;
+            egui::ComboBox::from_id_source("memory_model")
+                .selected_text(memory_model.name())
+                .show_ui(ui, |ui| {
+                    for model in MemoryModel::ALL {
+                        ui.selectable_value(&mut settings.memory_model, model, model.name());
+                    }
+                });
+            if settings.memory_model != memory_model {
+                // Stores still buffered reach memory before the model changes
+                self.machine.drain_store_buffers();
+                self.machine.memory_model = settings.memory_model;
+            }
+            apply |= ui
+                .checkbox(&mut settings.device_tree, "Device tree")
+                .on_hover_text("Put a device tree describing the machine at the end of memory on reset, with its address in a1")
+                .changed();
+            ui.label("Show hart:");
+            egui::ComboBox::from_id_source("hart")
+                .selected_text(self.hart.to_string())
+                .show_ui(ui, |ui| {
+                    for hart in 0..self.machine.harts.len() {
+                        ui.selectable_value(&mut *self.hart, hart, hart.to_string());
+                    }
+                });
+            if self.machine.harts.len() > 1 {
+                ui.label(format!("Next to execute: hart {}", self.machine.current));
+            }
+            if settings.memory_model == MemoryModel::Rvwmo {
+                ui.label(format!(
+                    "Buffered stores: {}",
+                    self.machine.store_buffers[*self.hart].len()
+                ));
+            }
+        });
+        if apply {
+            // New harts start from scratch, and so does everything else
+            self.apply_hart_settings();
+            self.apply_predictor_settings();
+            *self.stop_reason = None;
+            self.executed.clear();
+            *self.previous_registers = self.machine.harts[*self.hart].registers;
+        }
+    }
+
+    /// Gives the machine the chosen harts, resetting it
+    fn apply_hart_settings(&mut self) {
+        let settings = &*self.hart_settings;
+        self.machine.scheduling = settings.scheduling;
+        self.machine.memory_model = settings.memory_model;
+        self.machine.device_tree = settings.device_tree;
+        if self.machine.harts.len() != settings.count {
+            self.machine.set_harts(settings.count);
+        } else if settings.device_tree {
+            // Puts the tree in memory
+            self.machine.reset();
+        }
+        *self.hart = (*self.hart).min(settings.count - 1);
+    }
+
+    fn load_program(&mut self, program: Program) -> Result<(), String> {
+        *self.machine = create_rv32(program.contents).map_err(|error| error.to_string())?;
+        self.machine.htif =
+            Htif::from_symbols(|name| program.symbols.get(name).map(|&address| address as u32));
+        *self.source_map = program.source_map;
+        // Labels are the closest thing to functions
+        *self.functions = Functions::new(
+            program
+                .symbols
+                .into_iter()
+                .map(|(name, address)| (name, address as u32)),
         );
+        self.loaded();
+        Ok(())
     }
 
-    fn registers_pane(&mut self, ui: &mut Ui) {
-        egui::Grid::new("grid")
-            .num_columns(3)
-            .min_col_width(18.0)
+    fn load_elf(&mut self) -> Result<(), String> {
+        let bytes = fs::read(&*self.elf_path)
+            .map_err(|error| format!("Couldn't read {}: {error}", self.elf_path))?;
+        let executable = Executable::parse(&bytes).map_err(|error| error.to_string())?;
+        let memory_dump = executable
+            .memory_dump()
+            .map_err(|error| error.to_string())?;
+        *self.machine = create_rv32(memory_dump).map_err(|error| error.to_string())?;
+        self.machine.reset_to(executable.entry);
+        self.machine.htif = Htif::from_symbols(|name| executable.symbols.get(name).copied());
+        *self.source_map = executable.source_map;
+        *self.functions = Functions::new(executable.functions);
+        self.loaded();
+        Ok(())
+    }
+
+    /// Executes a single instruction, remembering it was executed
+    fn step(&mut self) -> Result<(), MachineError> {
+        *self.stop_reason = None;
+        self.executed
+            .insert(self.machine.harts[self.machine.current].pc);
+        *self.previous_registers = self.machine.harts[*self.hart].registers;
+        let record = self.machine.step()?;
+        if let Some(commit_log) = self.commit_log {
+            if let Err(error) = commit_log.log(&record) {
+                error!("Error while writing the commit log, logging stopped: {error}");
+                *self.commit_log = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes instructions until the program stops or reaches a breakpoint, or at most «RUN_LIMIT» of them
+    fn run(&mut self) -> Result<(), MachineError> {
+        *self.previous_registers = self.machine.harts[*self.hart].registers;
+        // Threads can't tell what they execute
+        if self.machine.scheduling == Scheduling::Threads && self.machine.harts.len() > 1 {
+            let reason = self.machine.run(RUN_LIMIT)?;
+            *self.stop_reason = Some(reason);
+            *self.hart = self.machine.current;
+            return Ok(());
+        }
+        let (executed, commit_log) = (&mut *self.executed, &mut *self.commit_log);
+        // Without a commit log to write, translated instructions can run natively
+        if commit_log.is_none() {
+            let reason = self.machine.run_observed(RUN_LIMIT, |record| {
+                executed.insert(record.pc);
+            })?;
+            for cpu in &mut self.machine.harts {
+                cpu.natively_executed(|pc| {
+                    executed.insert(pc);
+                });
+            }
+            *self.stop_reason = Some(reason);
+            return Ok(());
+        }
+        let reason = self.machine.run_until(RUN_LIMIT, |_, record| {
+            executed.insert(record.pc);
+            if let Some(log) = commit_log {
+                if let Err(error) = log.log(record) {
+                    error!("Error while writing the commit log, logging stopped: {error}");
+                    *commit_log = None;
+                }
+            }
+            false
+        })?;
+        *self.stop_reason = Some(reason);
+        Ok(())
+    }
+
+    fn handle_result(&mut self, result: Result<(), MachineError>) {
+        if let Some(reason) = self.machine.stopped {
+            *self.stop_reason = Some(reason);
+        }
+        if let Err(error) = result {
+            error!("Error while executing instructions: {:?}", error);
+        }
+    }
+
+    /// Shows what the program wrote through the host interface, and its exit status
+    fn io_pane(&mut self, ui: &mut Ui) {
+        let Some(htif) = &self.machine.htif else {
+            ui.label("The program has no «tohost» symbol, so it can't use the host interface");
+            return;
+        };
+        if let Some(exit_code) = htif.exit_code {
+            ui.label(format!("Exited with status {exit_code}"));
+        }
+        egui::ScrollArea::vertical()
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                ui.add(egui::Label::new(
+                    RichText::new(String::from_utf8_lossy(&htif.output)).monospace(),
+                ));
+            });
+    }
+
+    /// Configures the cache model, and shows its statistics and the lines in every set of one of the caches
+    fn caches_pane(&mut self, ui: &mut Ui) {
+        let settings = &mut *self.cache_settings;
+        let mut apply = false;
+        ui.horizontal(|ui| {
+            apply |= ui
+                .checkbox(&mut settings.enabled, "Simulate caches")
+                .changed();
+            apply |= ui
+                .button("Apply configuration")
+                .on_hover_text("Empties the caches and clears their statistics")
+                .clicked();
+        });
+        if let Some(error) = &settings.error {
+            ui.colored_label(ui.visuals().error_fg_color, error.as_str());
+        }
+        egui::CollapsingHeader::new("Configuration").show(ui, |ui| {
+            egui::Grid::new("cache configuration")
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.label("");
+                    ui.label("L1I");
+                    ui.label("L1D");
+                    ui.checkbox(&mut settings.l2_enabled, "L2");
+                    ui.end_row();
+                    let mut configs = [&mut settings.l1i, &mut settings.l1d, &mut settings.l2];
+                    for (name, field) in [
+                        ("Size (bytes)", CacheField::Size),
+                        ("Ways", CacheField::Associativity),
+                        ("Line size (bytes)", CacheField::LineSize),
+                        ("Replacement", CacheField::Replacement),
+                        ("Write policy", CacheField::WritePolicy),
+                        ("Write allocate", CacheField::WriteAllocate),
+                    ] {
+                        ui.label(name);
+                        for (i, config) in configs.iter_mut().enumerate() {
+                            cache_field_editor(ui, config, field, (name, i));
+                        }
+                        ui.end_row();
+                    }
+                });
+        });
+        if apply {
+            self.apply_cache_settings();
+        }
+
+        let settings = &mut *self.cache_settings;
+        let Some(caches) = &self.machine.caches else {
+            ui.label("Caches aren't simulated");
+            return;
+        };
+        let levels = [Some(&caches.l1i), Some(&caches.l1d), caches.l2.as_ref()];
+        egui::Grid::new("cache statistics")
             .striped(true)
             .show(ui, |ui| {
-                ui.add_sized(ui.available_size(), |ui: &mut Ui| {
-                    ui.label(format!("Register"))
+                for title in [
+                    "Cache",
+                    "Fetches",
+                    "Loads",
+                    "Stores",
+                    "AMOs",
+                    "Hit rate",
+                    "Writebacks",
+                ] {
+                    ui.label(title);
+                }
+                ui.end_row();
+                for (name, cache) in ["L1I", "L1D", "L2"].iter().zip(levels) {
+                    let Some(cache) = cache else {
+                        continue;
+                    };
+                    let statistics = &cache.statistics;
+                    ui.label(*name);
+                    for counts in [
+                        statistics.fetch,
+                        statistics.load,
+                        statistics.store,
+                        statistics.amo,
+                    ] {
+                        ui.label(format!("{} / {}", counts.hits, counts.accesses()))
+                            .on_hover_text("Hits / accesses");
+                    }
+                    match statistics.total().hit_rate() {
+                        Some(rate) => ui.label(format!("{:.1}%", rate * 100.0)),
+                        None => ui.label("-"),
+                    };
+                    ui.label(statistics.writebacks.to_string());
+                    ui.end_row();
+                }
+            });
+        ui.label(format!(
+            "Memory traffic: {} bytes read, {} bytes written",
+            caches.memory_reads, caches.memory_writes
+        ));
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Sets of");
+            for (i, (name, cache)) in ["L1I", "L1D", "L2"].iter().zip(levels).enumerate() {
+                if cache.is_some() {
+                    ui.selectable_value(&mut settings.shown, i, *name);
+                }
+            }
+        });
+        let Some(cache) = levels.get(settings.shown).copied().flatten() else {
+            return;
+        };
+        let highlight = ui.visuals().selection.bg_fill;
+        let row_height = ui.text_style_height(&TextStyle::Monospace) + ui.spacing().item_spacing.y;
+        egui::ScrollArea::both().show_rows(ui, row_height, cache.sets.len(), |ui, rows| {
+            for index in rows {
+                ui.horizontal(|ui| {
+                    ui.label(RichText::new(format!("{index:>4}")).monospace().weak());
+                    for (way, line) in cache.sets[index].iter().enumerate() {
+                        // Dirty lines are marked with an asterisk
+                        let text = match (line.valid, line.dirty) {
+                            (false, _) => RichText::new(format!("{:^11}", "-")).weak(),
+                            (true, dirty) => RichText::new(format!(
+                                "0x{:08x}{}",
+                                line.tag,
+                                if dirty { "*" } else { " " }
+                            )),
+                        };
+                        let text = if cache.last_access == Some((index, way)) {
+                            text.background_color(highlight)
+                        } else {
+                            text
+                        };
+                        let label = ui.label(text.monospace());
+                        if line.valid {
+                            label.on_hover_text(format!(
+                                "Tag 0x{:x}, holds 0x{:08x}",
+                                line.tag,
+                                cache.line_address(line.tag, index)
+                            ));
+                        }
+                    }
                 });
-                ui.add_sized(ui.available_size(), |ui: &mut Ui| ui.label("Binary"));
-                ui.add_sized(ui.available_size(), |ui: &mut Ui| {
-                    ui.label(format!("Two's complement"))
+            }
+        });
+    }
+
+    /// Builds the caches of the machine from their settings, empty
+    fn apply_cache_settings(&mut self) {
+        let settings = &mut *self.cache_settings;
+        settings.error = None;
+        self.machine.caches = None;
+        if !settings.enabled {
+            return;
+        }
+        let l2 = settings.l2_enabled.then_some(settings.l2);
+        match Caches::new(settings.l1i, settings.l1d, l2) {
+            Ok(caches) => self.machine.caches = Some(caches),
+            Err(error) => settings.error = Some(error.to_string()),
+        }
+    }
+
+    /// Chooses the branch predictor, and shows how well it predicts the branches of the program, the worst predicted first
+    fn branches_pane(&mut self, ui: &mut Ui) {
+        let settings = &mut *self.predictor_settings;
+        let mut apply = false;
+        ui.horizontal(|ui| {
+            apply |= ui
+                .checkbox(&mut settings.enabled, "Simulate a branch predictor")
+                .changed();
+            let predictor = &mut settings.predictor;
+            egui::ComboBox::from_id_source("predictor")
+                .selected_text(predictor_name(predictor))
+                .show_ui(ui, |ui| {
+                    for choice in PREDICTORS {
+                        apply |= ui
+                            .selectable_value(predictor, choice, predictor_name(&choice))
+                            .changed();
+                    }
                 });
+            // Tables of up to a million entries
+            let bits = |ui: &mut Ui, value: &mut u32, name: &str| {
+                ui.label(name);
+                ui.add(DragValue::new(value).clamp_range(0..=20)).changed()
+            };
+            match predictor {
+                PredictorConfig::Static { .. } => {}
+                PredictorConfig::OneBit { index_bits } | PredictorConfig::TwoBit { index_bits } => {
+                    apply |= bits(ui, index_bits, "Index bits");
+                }
+                PredictorConfig::Gshare { history_bits } => {
+                    apply |= bits(ui, history_bits, "History bits");
+                }
+                PredictorConfig::Tournament {
+                    index_bits,
+                    history_bits,
+                } => {
+                    apply |= bits(ui, index_bits, "Index bits");
+                    apply |= bits(ui, history_bits, "History bits");
+                }
+            }
+        });
+        if apply {
+            self.apply_predictor_settings();
+        }
+
+        let Some(prediction) = &self.machine.harts[*self.hart].branch_prediction else {
+            ui.label("Branches aren't predicted");
+            return;
+        };
+        let total = prediction.total();
+        match total.accuracy() {
+            Some(accuracy) => ui.label(format!(
+                "{} branches executed, {} mispredicted: {:.1}% accuracy",
+                total.executed,
+                total.mispredicted,
+                accuracy * 100.0
+            )),
+            None => ui.label("No branch executed yet"),
+        };
+        let memory_bus = MemoryBus::new(&mut self.machine.memory);
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            egui::Grid::new("branches").striped(true).show(ui, |ui| {
+                for title in [
+                    "Address",
+                    "Instruction",
+                    "Executed",
+                    "Taken",
+                    "Mispredicted",
+                    "Accuracy",
+                ] {
+                    ui.label(title);
+                }
                 ui.end_row();
-                for (i, register) in self.machine.cpu.registers.iter().enumerate() {
-                    ui.add_sized(ui.available_size(), |ui: &mut Ui| ui.label(format!("x{i}")));
-                    ui.add_sized(ui.available_size(), |ui: &mut Ui| {
-                        ui.label(register.to_string())
-                    });
-                    ui.add_sized(ui.available_size(), |ui: &mut Ui| {
-                        let sign = register >> 31 & 0b1;
-                        if sign == 1 {
-                            let mut num = !*register;
-                            num = num.wrapping_add(1);
-                            ui.label(format!("-{}", num))
+                for (pc, counts) in prediction.worst() {
+                    ui.label(RichText::new(format!("0x{pc:08x}")).monospace());
+                    let instruction = memory_bus
+                        .read_u32(pc, AccessKind::Fetch)
+                        .map(disassemble)
+                        .unwrap_or_default();
+                    ui.label(RichText::new(instruction).monospace());
+                    ui.label(counts.executed.to_string());
+                    ui.label(format!(
+                        "{:.1}%",
+                        counts.taken as f64 / counts.executed as f64 * 100.0
+                    ));
+                    ui.label(counts.mispredicted.to_string());
+                    ui.label(format!("{:.1}%", counts.accuracy().unwrap_or(0.0) * 100.0));
+                    ui.end_row();
+                }
+            });
+        });
+    }
+
+    /// Plugs the chosen branch predictor in, starting from scratch
+    fn apply_predictor_settings(&mut self) {
+        let settings = &*self.predictor_settings;
+        // Every hart has a predictor of its own
+        for cpu in &mut self.machine.harts {
+            cpu.branch_prediction = settings
+                .enabled
+                .then(|| BranchPrediction::new(settings.predictor.build()));
+        }
+    }
+
+    /// Draws which stage the last instructions were in at every cycle, stalls and flushed instructions standing out
+    fn pipeline_pane(&mut self, ui: &mut Ui) {
+        let settings = &mut *self.pipeline_settings;
+        let mut apply = false;
+        ui.horizontal(|ui| {
+            apply |= ui
+                .checkbox(&mut settings.enabled, "Simulate a five-stage pipeline")
+                .changed();
+            apply |= ui
+                .checkbox(&mut settings.config.forwarding, "Forwarding")
+                .changed();
+        });
+        if apply {
+            self.apply_pipeline_settings();
+        }
+
+        let Some(pipeline) = &self.machine.pipeline else {
+            ui.label("The pipeline isn't simulated");
+            return;
+        };
+        let statistics = pipeline.statistics;
+        match statistics.cpi() {
+            Some(cpi) => ui.label(format!(
+                "{} instructions in {} cycles ({cpi:.2} CPI), {} stall cycles, {} instructions flushed",
+                statistics.instructions, statistics.cycles, statistics.stalls, statistics.flushed
+            )),
+            None => ui.label("No instruction executed yet"),
+        };
+        let (Some(first), Some(last)) = (
+            pipeline.history.front().map(|entry| entry.cycles.fetch),
+            pipeline
+                .history
+                .iter()
+                .map(|entry| entry.last_cycle())
+                .max(),
+        ) else {
+            return;
+        };
+        let stall = ui.visuals().warn_fg_color;
+        let memory_bus = MemoryBus::new(&mut self.machine.memory);
+        egui::ScrollArea::both().show(ui, |ui| {
+            egui::Grid::new("pipeline").striped(true).show(ui, |ui| {
+                ui.label("Address");
+                ui.label("Instruction");
+                for cycle in first..=last {
+                    ui.label(RichText::new(cycle.to_string()).monospace());
+                }
+                ui.end_row();
+                for entry in &pipeline.history {
+                    ui.label(RichText::new(format!("0x{:08x}", entry.pc)).monospace());
+                    let instruction = memory_bus
+                        .read_u32(entry.pc, AccessKind::Fetch)
+                        .map(disassemble)
+                        .unwrap_or_default();
+                    if entry.flushed {
+                        ui.label(RichText::new(format!("{instruction} (flushed)")).weak());
+                    } else {
+                        ui.label(RichText::new(instruction).monospace());
+                    }
+                    for cycle in first..=last {
+                        let stage =
+                            RichText::new(entry.stage(cycle).unwrap_or_default()).monospace();
+                        if entry.stalled(cycle) {
+                            ui.label(stage.color(stall));
+                        } else if entry.flushed {
+                            ui.label(stage.weak());
                         } else {
-                            ui.label(register.to_string())
+                            ui.label(stage);
                         }
-                    });
+                    }
                     ui.end_row();
                 }
             });
+        });
+    }
+
+    /// Puts the pipeline model in, starting from an empty pipeline
+    fn apply_pipeline_settings(&mut self) {
+        let settings = &*self.pipeline_settings;
+        self.machine.pipeline = settings.enabled.then(|| Pipeline::new(settings.config));
+    }
+
+    /// Shows the performance counters, choosing what the event counters count, and where the program spends its time
+    fn profiler_pane(&mut self, ui: &mut Ui) {
+        let csrs = &mut self.machine.harts[*self.hart].csrs;
+        egui::Grid::new("counters").striped(true).show(ui, |ui| {
+            for (name, low) in [("mcycle", csr::MCYCLE), ("minstret", csr::MINSTRET)] {
+                ui.label(RichText::new(name).monospace());
+                ui.label(counters::read(csrs, low).to_string());
+                ui.end_row();
+            }
+            for i in 0..csr::HPM_COUNTERS {
+                ui.label(RichText::new(format!("mhpmcounter{}", i + 3)).monospace());
+                ui.label(counters::read(csrs, csr::MHPMCOUNTER3 + i).to_string());
+                let mut event = Event::from_code(csrs.get(csr::MHPMEVENT3 + i));
+                egui::ComboBox::from_id_source(("event", i))
+                    .selected_text(event.map_or("Nothing", Event::name))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut event, None, "Nothing");
+                        for choice in Event::ALL {
+                            ui.selectable_value(&mut event, Some(choice), choice.name());
+                        }
+                    });
+                csrs.set(csr::MHPMEVENT3 + i, event.map_or(0, |event| event as u32));
+                ui.end_row();
+            }
+        });
+        ui.separator();
+
+        let settings = &mut *self.profiler_settings;
+        let mut apply = false;
+        ui.horizontal(|ui| {
+            apply |= ui
+                .checkbox(&mut settings.enabled, "Profile the program")
+                .changed();
+            ui.label("Sample every");
+            apply |= ui
+                .add(DragValue::new(&mut settings.period).clamp_range(1..=1_000_000))
+                .changed();
+            ui.label("instructions");
+        });
+        if apply {
+            self.apply_profiler_settings();
+        }
+
+        let Some(profiler) = &self.machine.profiler else {
+            ui.label("The program isn't profiled");
+            return;
+        };
+        if profiler.total == 0 {
+            ui.label("No sample taken yet");
+            return;
+        }
+        let percentage =
+            |samples: u64| format!("{:.1}%", samples as f64 / profiler.total as f64 * 100.0);
+        let memory_bus = MemoryBus::new(&mut self.machine.memory);
+        let functions = &*self.functions;
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            ui.heading("Functions");
+            egui::Grid::new("flat profile")
+                .striped(true)
+                .show(ui, |ui| {
+                    for title in ["Function", "Samples", "Time"] {
+                        ui.label(title);
+                    }
+                    ui.end_row();
+                    for function in profiler.flat_profile(functions) {
+                        ui.label(
+                            RichText::new(function.name.as_deref().unwrap_or("(unknown)"))
+                                .monospace(),
+                        );
+                        ui.label(function.samples.to_string());
+                        ui.label(percentage(function.samples));
+                        ui.end_row();
+                    }
+                });
+            ui.heading("Hot addresses");
+            egui::Grid::new("hot addresses")
+                .striped(true)
+                .show(ui, |ui| {
+                    for title in ["Address", "Function", "Instruction", "Samples", "Time"] {
+                        ui.label(title);
+                    }
+                    ui.end_row();
+                    for (address, samples) in
+                        profiler.hot_addresses().into_iter().take(HOT_ADDRESSES)
+                    {
+                        ui.label(RichText::new(format!("0x{address:08x}")).monospace());
+                        ui.label(
+                            RichText::new(functions.containing(address).unwrap_or_default())
+                                .monospace(),
+                        );
+                        let instruction = memory_bus
+                            .read_u32(address, AccessKind::Fetch)
+                            .map(disassemble)
+                            .unwrap_or_default();
+                        ui.label(RichText::new(instruction).monospace());
+                        ui.label(samples.to_string());
+                        ui.label(percentage(samples));
+                        ui.end_row();
+                    }
+                });
+        });
+    }
+
+    /// Puts the profiler in, without any sample
+    fn apply_profiler_settings(&mut self) {
+        let settings = &*self.profiler_settings;
+        self.machine.profiler = settings.enabled.then(|| Profiler::new(settings.period));
+    }
+
+    fn registers_pane(&mut self, ui: &mut Ui) {
+        let columns = &mut *self.register_columns;
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut columns.abi_name, "ABI name");
+            ui.checkbox(&mut columns.hex, "Hex");
+            ui.checkbox(&mut columns.binary, "Binary");
+            ui.checkbox(&mut columns.unsigned, "Unsigned");
+            ui.checkbox(&mut columns.signed, "Signed");
+        });
+        let columns = *columns;
+        let highlight = ui.visuals().selection.bg_fill;
+        egui::ScrollArea::both().show(ui, |ui| {
+            egui::Grid::new("grid")
+                .min_col_width(18.0)
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.label("Register");
+                    for (shown, title) in [
+                        (columns.abi_name, "ABI name"),
+                        (columns.hex, "Hex"),
+                        (columns.binary, "Binary"),
+                        (columns.unsigned, "Unsigned"),
+                        (columns.signed, "Signed"),
+                    ] {
+                        if shown {
+                            ui.label(title);
+                        }
+                    }
+                    ui.end_row();
+                    for (i, register) in self.machine.harts[*self.hart]
+                        .registers
+                        .iter_mut()
+                        .enumerate()
+                    {
+                        // Registers written by the last instruction stand out
+                        let changed = *register != self.previous_registers[i];
+                        let name = |text: String| {
+                            let text = RichText::new(text).monospace();
+                            if changed {
+                                text.background_color(highlight).strong()
+                            } else {
+                                text
+                            }
+                        };
+                        ui.label(name(format!("x{i}")));
+                        if columns.abi_name {
+                            ui.label(name(register_name(i as u32).to_owned()));
+                        }
+                        // x0 is hardwired to zero, so editing it wouldn't have any effect
+                        register_editor(ui, register, columns, i != 0);
+                        ui.end_row();
+                    }
+                    ui.label(RichText::new("pc").monospace());
+                    if columns.abi_name {
+                        ui.label("");
+                    }
+                    register_editor(ui, &mut self.machine.harts[*self.hart].pc, columns, true);
+                    ui.end_row();
+                });
+        });
     }
 
     fn memory_pane(&mut self, ui: &mut Ui) {
+        let mut trap = *self.misaligned_accesses == MisalignedAccesses::Trap;
+        if ui
+            .checkbox(&mut trap, "Trap on misaligned accesses")
+            .changed()
+        {
+            *self.misaligned_accesses = if trap {
+                MisalignedAccesses::Trap
+            } else {
+                MisalignedAccesses::Transparent
+            };
+            self.machine.memory.misaligned_accesses = *self.misaligned_accesses;
+        }
+        let hart = *self.hart;
+        let mut edited = vec![];
+        // Both reads and writes go through the bus of the hart shown, just like its accesses: they see its buffered stores
         self.mem_editor.draw_editor_contents(
             ui,
-            &mut self.machine.memory.contents, // TODO: Perhaps should use memory bus
-            |mem, address| Some(mem[address - 0x80]), // TODO: Return none instead of some were applicable
-            |_, _, _: u8| {}, // TODO: Think about making memory editable directly in the memory editor window
+            &mut *self.machine,
+            |machine, address| machine.bus(hart).peek_u8(address as u32),
+            |machine, address, value: u8| {
+                let mut memory_bus = machine.bus(hart);
+                match memory_bus.write_u8(address as u32, value, AccessKind::Store) {
+                    // Edits reach memory right away, after the stores to the same byte still buffered
+                    Ok(()) => {
+                        memory_bus.drain_overlapping(address as u32, 1);
+                        edited.push(address as u32);
+                    }
+                    Err(error) => {
+                        error!("Error while editing memory at 0x{address:08x}: {:?}", error)
+                    }
+                }
+            },
         );
+        // Edited instructions must be decoded again, by every hart
+        for address in edited {
+            for cpu in &mut self.machine.harts {
+                cpu.decode_cache.invalidate(address, 1);
+            }
+        }
     }
+
+    fn csrs_pane(&mut self, ui: &mut Ui) {
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for csr in csr::IMPLEMENTED {
+                let value = self.machine.harts[*self.hart].csrs.get(csr.address);
+                let fields = csr::fields(csr.address, value);
+                let header = RichText::new(format!(
+                    "{:<13} 0x{:03x}  0x{value:08x}",
+                    csr.name, csr.address
+                ))
+                .monospace();
+                // CSRs without fields don't have anything to expand
+                if fields.is_empty() {
+                    ui.horizontal(|ui| {
+                        ui.add_space(ui.spacing().indent);
+                        ui.label(header);
+                    });
+                    continue;
+                }
+                egui::CollapsingHeader::new(header)
+                    .id_source(csr.address)
+                    .show(ui, |ui| {
+                        egui::Grid::new(("csr fields", csr.address))
+                            .striped(true)
+                            .show(ui, |ui| {
+                                for (name, value) in fields {
+                                    ui.label(RichText::new(name).monospace());
+                                    ui.label(RichText::new(value).monospace());
+                                    ui.end_row();
+                                }
+                            });
+                    });
+            }
+        });
+    }
+
+    fn disassembly_pane(&mut self, ui: &mut Ui) {
+        let pc = self.machine.harts[*self.hart].pc as usize;
+        let memory_bus = MemoryBus::new(&mut self.machine.memory);
+        let memory_end = RAM_BASE + memory_bus.memory.size();
+        // A window of instructions around pc, stepping by the instruction size so that pc is always part of it
+        let first = pc
+            .saturating_sub(DISASSEMBLY_BEFORE_PC * 4)
+            .max(RAM_BASE + pc % 4);
+        let last = (pc + DISASSEMBLY_AFTER_PC * 4).min(memory_end.saturating_sub(4));
+        let highlight = ui.visuals().selection.bg_fill;
+        let breakpoints = &mut self.machine.breakpoints;
+        ui.label("Click on an address to set or clear a breakpoint there");
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            egui::Grid::new("disassembly")
+                .num_columns(4)
+                .striped(true)
+                .show(ui, |ui| {
+                    for address in (first..=last).step_by(4) {
+                        let Ok(raw) = memory_bus.read_u32(address as u32, AccessKind::Fetch) else {
+                            continue;
+                        };
+                        let breakpoint = breakpoints.contains(&(address as u32));
+                        let marker = match (address == pc, breakpoint) {
+                            (true, true) => "●▶",
+                            (true, false) => "▶",
+                            (false, true) => "●",
+                            (false, false) => "",
+                        };
+                        let cells = [
+                            marker.to_owned(),
+                            format!("0x{address:08x}"),
+                            format!("{raw:08x}"),
+                            disassemble(raw),
+                        ];
+                        for (column, cell) in cells.into_iter().enumerate() {
+                            let mut text = RichText::new(cell).monospace();
+                            if address == pc {
+                                text = text.background_color(highlight).strong();
+                            }
+                            if column == 1 {
+                                if ui.add(Label::new(text).sense(Sense::click())).clicked()
+                                    && !breakpoints.remove(&(address as u32))
+                                {
+                                    breakpoints.insert(address as u32);
+                                }
+                            } else {
+                                ui.label(text);
+                            }
+                        }
+                        ui.end_row();
+                    }
+                });
+        });
+    }
+}
+
+/// Configuration of the cache model, kept apart from the machine since loading a program builds a new one
+struct CacheSettings {
+    enabled: bool,
+    l1i: CacheConfig,
+    l1d: CacheConfig,
+    l2_enabled: bool,
+    l2: CacheConfig,
+    /// Which cache the sets are shown of (0 = L1I, 1 = L1D, 2 = L2)
+    shown: usize,
+    /// Why the configuration couldn't be applied
+    error: Option<String>,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            l1i: CacheConfig::L1,
+            l1d: CacheConfig::L1,
+            l2_enabled: false,
+            l2: CacheConfig::L2,
+            shown: 1,
+            error: None,
+        }
+    }
+}
+
+/// Branch predictor to plug into the CPU, kept apart from the machine like «CacheSettings»
+struct PredictorSettings {
+    enabled: bool,
+    predictor: PredictorConfig,
+}
+
+impl Default for PredictorSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            predictor: PredictorConfig::TwoBit { index_bits: 10 },
+        }
+    }
+}
+
+/// Pipeline model to put in the machine, which is built again on every load
+#[derive(Default)]
+struct PipelineSettings {
+    enabled: bool,
+    config: PipelineConfig,
+}
+
+/// Harts to give the machine, which is built again on every load
+struct HartSettings {
+    count: usize,
+    scheduling: Scheduling,
+    /// Quantum of the round robin scheduling, remembered while threads are chosen
+    quantum: usize,
+    memory_model: MemoryModel,
+    /// Wether the machine boots with a device tree describing it
+    device_tree: bool,
+}
+
+impl Default for HartSettings {
+    fn default() -> Self {
+        let scheduling = Scheduling::default();
+        let Scheduling::RoundRobin { quantum } = scheduling else {
+            unreachable!("harts take turns by default")
+        };
+        Self {
+            count: 1,
+            scheduling,
+            quantum,
+            memory_model: MemoryModel::default(),
+            device_tree: false,
+        }
+    }
+}
+
+/// Profiler to put in the machine, which is built again on every load
+struct ProfilerSettings {
+    enabled: bool,
+    period: u64,
+}
+
+impl Default for ProfilerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            period: 1,
+        }
+    }
+}
+
+/// Every kind of predictor that can be chosen, with the size of its tables to start from
+const PREDICTORS: [PredictorConfig; 6] = [
+    PredictorConfig::Static { taken: false },
+    PredictorConfig::Static { taken: true },
+    PredictorConfig::OneBit { index_bits: 10 },
+    PredictorConfig::TwoBit { index_bits: 10 },
+    PredictorConfig::Gshare { history_bits: 10 },
+    PredictorConfig::Tournament {
+        index_bits: 10,
+        history_bits: 10,
+    },
+];
+
+fn predictor_name(predictor: &PredictorConfig) -> &'static str {
+    match predictor {
+        PredictorConfig::Static { taken: false } => "Static, not taken",
+        PredictorConfig::Static { taken: true } => "Static, taken",
+        PredictorConfig::OneBit { .. } => "1-bit",
+        PredictorConfig::TwoBit { .. } => "2-bit saturating",
+        PredictorConfig::Gshare { .. } => "gshare",
+        PredictorConfig::Tournament { .. } => "Tournament",
+    }
+}
+
+/// A setting of a cache, one per row of the configuration grid
+#[derive(Clone, Copy)]
+enum CacheField {
+    Size,
+    Associativity,
+    LineSize,
+    Replacement,
+    WritePolicy,
+    WriteAllocate,
+}
+
+fn cache_field_editor(ui: &mut Ui, config: &mut CacheConfig, field: CacheField, id: impl Hash) {
+    match field {
+        CacheField::Size => {
+            ui.add(DragValue::new(&mut config.size).clamp_range(1..=1 << 24));
+        }
+        CacheField::Associativity => {
+            ui.add(DragValue::new(&mut config.associativity).clamp_range(1..=64));
+        }
+        CacheField::LineSize => {
+            ui.add(DragValue::new(&mut config.line_size).clamp_range(4..=4096));
+        }
+        CacheField::Replacement => {
+            egui::ComboBox::from_id_source(id)
+                .selected_text(format!("{:?}", config.replacement))
+                .show_ui(ui, |ui| {
+                    for replacement in [Replacement::Lru, Replacement::Fifo, Replacement::Random] {
+                        ui.selectable_value(
+                            &mut config.replacement,
+                            replacement,
+                            format!("{replacement:?}"),
+                        );
+                    }
+                });
+        }
+        CacheField::WritePolicy => {
+            egui::ComboBox::from_id_source(id)
+                .selected_text(format!("{:?}", config.write_policy))
+                .show_ui(ui, |ui| {
+                    for policy in [WritePolicy::WriteBack, WritePolicy::WriteThrough] {
+                        ui.selectable_value(
+                            &mut config.write_policy,
+                            policy,
+                            format!("{policy:?}"),
+                        );
+                    }
+                });
+        }
+        CacheField::WriteAllocate => {
+            ui.checkbox(&mut config.write_allocate, "");
+        }
+    }
+}
+
+/// Which columns of the registers pane are shown
+#[derive(Clone, Copy)]
+struct RegisterColumns {
+    abi_name: bool,
+    hex: bool,
+    binary: bool,
+    unsigned: bool,
+    signed: bool,
+}
+
+impl Default for RegisterColumns {
+    fn default() -> Self {
+        Self {
+            abi_name: true,
+            hex: true,
+            binary: false,
+            unsigned: true,
+            signed: true,
+        }
+    }
+}
+
+/// Shows the value of a register in every format selected by «columns», each of them can be edited by clicking on it
+fn register_editor(ui: &mut Ui, value: &mut u32, columns: RegisterColumns, enabled: bool) {
+    // The values are only typed in, dragging them wouldn't make much sense
+    if columns.hex {
+        ui.add_enabled(
+            enabled,
+            DragValue::new(value)
+                .speed(0.0)
+                .hexadecimal(8, false, false),
+        );
+    }
+    if columns.binary {
+        ui.add_enabled(enabled, DragValue::new(value).speed(0.0).binary(32, false));
+    }
+    if columns.unsigned {
+        ui.add_enabled(enabled, DragValue::new(value).speed(0.0));
+    }
+    if columns.signed {
+        let mut signed = *value as i32;
+        if ui
+            .add_enabled(enabled, DragValue::new(&mut signed).speed(0.0))
+            .changed()
+        {
+            *value = signed as u32;
+        }
+    }
+}
+
+/// Lays out source code in a monospace font, giving a background color to some lines (numbered from 1)
+fn code_layout(
+    ui: &Ui,
+    text: &str,
+    wrap_width: f32,
+    background: impl Fn(usize) -> Option<Color32>,
+) -> Arc<Galley> {
+    let font_id = TextStyle::Monospace.resolve(ui.style());
+    let color = ui.visuals().text_color();
+    let mut job = LayoutJob::default();
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        job.append(
+            line,
+            0.0,
+            TextFormat {
+                font_id: font_id.clone(),
+                color,
+                background: background(i + 1).unwrap_or(Color32::TRANSPARENT),
+                ..Default::default()
+            },
+        );
+    }
+    job.wrap.max_width = wrap_width;
+    ui.fonts(|fonts| fonts.layout_job(job))
 }
 
 struct MyApp {
     tree: Tree<String>,
     machine: Machine,
-    /// Wether the emulator has reached an instruction with opcode equal to zero
-    has_reached_end: bool,
+    stop_reason: Option<StopReason>,
     mem_editor: MemoryEditor,
     code: String,
+    assembly_errors: Vec<AssemblyError>,
+    source_map: SourceMap,
+    executed: BTreeSet<u32>,
+    elf_path: String,
+    load_error: Option<String>,
+    source_files: HashMap<String, String>,
+    register_columns: RegisterColumns,
+    previous_registers: [u32; 32],
+    commit_log_path: String,
+    commit_log: Option<CommitLog<BufWriter<File>>>,
+    cache_settings: CacheSettings,
+    predictor_settings: PredictorSettings,
+    pipeline_settings: PipelineSettings,
+    profiler_settings: ProfilerSettings,
+    functions: Functions,
+    hart_settings: HartSettings,
+    misaligned_accesses: MisalignedAccesses,
+    hart: usize,
 }
 
 impl Default for MyApp {
@@ -191,18 +1440,54 @@ impl Default for MyApp {
         let mut tree = Tree::new(vec!["Editor".to_owned()]);
 
         // You can modify the tree before constructing the dock
-        let [a, b] = tree.split_right(NodeIndex::root(), 0.7, vec!["Registers".to_owned()]);
+        let [a, b] = tree.split_right(
+            NodeIndex::root(),
+            0.7,
+            vec![
+                "Registers".to_owned(),
+                "CSRs".to_owned(),
+                "Disassembly".to_owned(),
+            ],
+        );
         let [_, _] = tree.split_below(a, 0.6, vec!["Memory".to_owned()]);
-        let [_, _] = tree.split_below(b, 0.5, vec!["Input/output".to_owned()]);
+        let [_, _] = tree.split_below(
+            b,
+            0.5,
+            vec![
+                "Input/output".to_owned(),
+                "Caches".to_owned(),
+                "Branches".to_owned(),
+                "Pipeline".to_owned(),
+                "Profiler".to_owned(),
+            ],
+        );
 
         Self {
             tree,
             code: "addi x2, x0, 20".to_owned(), // TODO: Remove hardcoded example code
-            machine: Machine::new(vec![]),
-            has_reached_end: false,
+            assembly_errors: vec![],
+            source_map: SourceMap::default(),
+            executed: BTreeSet::new(),
+            elf_path: String::new(),
+            load_error: None,
+            source_files: HashMap::new(),
+            register_columns: RegisterColumns::default(),
+            previous_registers: [0; 32],
+            commit_log_path: "commits.log".to_owned(),
+            commit_log: None,
+            cache_settings: CacheSettings::default(),
+            predictor_settings: PredictorSettings::default(),
+            pipeline_settings: PipelineSettings::default(),
+            profiler_settings: ProfilerSettings::default(),
+            functions: Functions::default(),
+            hart_settings: HartSettings::default(),
+            misaligned_accesses: MisalignedAccesses::default(),
+            hart: 0,
+            machine: Machine::new(vec![]).expect("an empty memory dump fits"),
+            stop_reason: None,
             // TODO: Maybe show other memory-mapped things too, not only physical memory
             mem_editor: MemoryEditor::new()
-                .with_address_range("Physical memory", RAM_BASE..MEMORY_SIZE)
+                .with_address_range("Physical memory", RAM_BASE..RAM_BASE + MEMORY_SIZE)
                 .with_window_title("Memory editor"),
         }
     }
@@ -219,7 +1504,25 @@ impl eframe::App for MyApp {
                     machine: &mut self.machine,
                     code: &mut self.code,
                     mem_editor: &mut self.mem_editor,
-                    has_reached_end: &mut self.has_reached_end,
+                    stop_reason: &mut self.stop_reason,
+                    assembly_errors: &mut self.assembly_errors,
+                    source_map: &mut self.source_map,
+                    executed: &mut self.executed,
+                    elf_path: &mut self.elf_path,
+                    load_error: &mut self.load_error,
+                    source_files: &mut self.source_files,
+                    register_columns: &mut self.register_columns,
+                    previous_registers: &mut self.previous_registers,
+                    commit_log_path: &mut self.commit_log_path,
+                    commit_log: &mut self.commit_log,
+                    cache_settings: &mut self.cache_settings,
+                    predictor_settings: &mut self.predictor_settings,
+                    pipeline_settings: &mut self.pipeline_settings,
+                    profiler_settings: &mut self.profiler_settings,
+                    functions: &mut self.functions,
+                    hart_settings: &mut self.hart_settings,
+                    misaligned_accesses: &mut self.misaligned_accesses,
+                    hart: &mut self.hart,
                 },
             );
     }
@@ -0,0 +1,41 @@
+//! Mapping from instruction addresses back to the lines of source code they were generated from
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SourceLocation {
+    /// Path of the source file, None for code that was assembled from the editor
+    pub file: Option<String>,
+    /// Line number, starting from 1
+    pub line: usize,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SourceMap {
+    /// Start address -> (size in bytes, location), the ranges never overlap
+    ranges: BTreeMap<u32, (u32, SourceLocation)>,
+}
+
+impl SourceMap {
+    /// Maps «size» bytes starting from «address» to a location, replacing any range starting at the same address
+    pub fn insert(&mut self, address: u32, size: u32, location: SourceLocation) {
+        self.ranges.insert(address, (size, location));
+    }
+
+    /// Returns the location the instruction at «address» comes from, if any
+    pub fn lookup(&self, address: u32) -> Option<&SourceLocation> {
+        let (start, (size, location)) = self.ranges.range(..=address).next_back()?;
+        (address - start < *size).then_some(location)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Every address range, in increasing order
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32, &SourceLocation)> {
+        self.ranges
+            .iter()
+            .map(|(start, (size, location))| (*start, *size, location))
+    }
+}
@@ -0,0 +1,345 @@
+//! The built-in assembler: parsing, pseudo-instructions, directives, labels and error reporting
+
+use emu::{
+    assembler::{
+        assemble,
+        parser::{parse_expr, parse_line, parse_operand, Atom, Expr, Modifier, Operand, Statement},
+        AssemblyError,
+    },
+    create_rv32,
+    machine::{memory::constants::RAM_BASE, StopReason},
+};
+
+/// Assembles a program, returning its text section as instruction words
+fn words(source: &str) -> Vec<u32> {
+    let program = assemble(source).unwrap_or_else(|errors| panic!("{errors:?}"));
+    program
+        .contents
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect()
+}
+
+fn errors(source: &str) -> Vec<AssemblyError> {
+    assemble(source).expect_err("The program should not assemble")
+}
+
+#[test]
+fn parses_labels_statements_and_comments() {
+    let line = parse_line("start: loop: addi a0, a0, 1 # increment").unwrap();
+    assert_eq!(line.labels, ["start", "loop"]);
+    assert_eq!(
+        line.statement,
+        Some(Statement::Instruction {
+            mnemonic: "addi".to_owned(),
+            operands: vec![
+                Operand::Expr(Expr::symbol("a0")),
+                Operand::Expr(Expr::symbol("a0")),
+                Operand::Expr(Expr::number(1)),
+            ],
+        })
+    );
+
+    // Commas and comment characters inside literals are left alone
+    let line = parse_line(r#"  .asciz "a, b # c" // comment"#).unwrap();
+    assert_eq!(
+        line.statement,
+        Some(Statement::Directive {
+            name: ".asciz".to_owned(),
+            args: vec![r#""a, b # c""#.to_owned()],
+        })
+    );
+
+    let line = parse_line("only_a_label:   ").unwrap();
+    assert_eq!(line.labels, ["only_a_label"]);
+    assert_eq!(line.statement, None);
+
+    assert!(parse_line(".word 1,, 2").is_err());
+    assert!(parse_line(".ascii \"unterminated").is_err());
+}
+
+#[test]
+fn parses_operands_and_expressions() {
+    let Operand::Memory { offset, base } = parse_operand("-8(sp)").unwrap() else {
+        panic!("Expected a memory operand");
+    };
+    assert_eq!(
+        (offset.evaluate(0, |_| None), base.as_str()),
+        (Ok(-8), "sp")
+    );
+    assert_eq!(
+        parse_operand("(a1)").unwrap(),
+        Operand::Memory {
+            offset: Expr::number(0),
+            base: "a1".to_owned()
+        }
+    );
+    // A modifier is an expression, not a memory operand, unless a base register follows it
+    assert_eq!(
+        parse_operand("%lo(value)").unwrap(),
+        Operand::Expr(Expr::symbol("value").with_modifier(Modifier::Lo))
+    );
+    assert_eq!(
+        parse_operand("%lo(value)(a0)").unwrap(),
+        Operand::Memory {
+            offset: Expr::symbol("value").with_modifier(Modifier::Lo),
+            base: "a0".to_owned()
+        }
+    );
+
+    let lookup = |name: &str| (name == "label").then_some(0x100);
+    for (text, value) in [
+        ("0x1f", 0x1f),
+        ("0b1010", 0b1010),
+        ("0o17", 0o17),
+        ("1_000", 1000),
+        ("'a'", 'a' as i64),
+        ("'\\n'", '\n' as i64),
+        ("label + 4 - 2", 0x102),
+        ("-label", -0x100),
+        ("%hi(0x12345fff)", 0x12346),
+        ("%lo(0x12345fff)", -1),
+    ] {
+        assert_eq!(
+            parse_expr(text).unwrap().evaluate(0, lookup),
+            Ok(value),
+            "{text}"
+        );
+    }
+    assert_eq!(
+        parse_expr("label").unwrap().terms,
+        [(false, Atom::Symbol("label".to_owned()))]
+    );
+    assert!(parse_expr("undefined")
+        .unwrap()
+        .evaluate(0, lookup)
+        .is_err());
+    assert!(parse_expr("%unknown(1)").is_err());
+    assert!(parse_expr("1 * 2").is_err());
+    assert!(parse_expr("0xg").is_err());
+}
+
+#[test]
+fn encodes_base_instructions() {
+    assert_eq!(
+        words(
+            "
+            addi a0, a0, 1
+            add t0, t1, t2
+            lw a0, 4(sp)
+            sw ra, -4(sp)
+            lui a0, 0x12345
+            csrrw x0, mscratch, a0
+            "
+        ),
+        [0x00150513, 0x007302b3, 0x00412503, 0xfe112e23, 0x12345537, 0x34051073]
+    );
+}
+
+#[test]
+fn expands_pseudo_instructions() {
+    let expansions = [
+        ("nop", "addi x0, x0, 0"),
+        ("li a0, -5", "addi a0, x0, -5"),
+        ("li a0, 0x12345000", "lui a0, 0x12345"),
+        ("li a0, 0x12345678", "lui a0, 0x12345\naddi a0, a0, 0x678"),
+        // The lower part is sign extended, so the upper one is rounded up
+        ("li a0, 0x12345fff", "lui a0, 0x12346\naddi a0, a0, -1"),
+        ("mv a0, a1", "addi a0, a1, 0"),
+        ("not a0, a1", "xori a0, a1, -1"),
+        ("neg a0, a1", "sub a0, x0, a1"),
+        ("seqz a0, a1", "sltiu a0, a1, 1"),
+        ("snez a0, a1", "sltu a0, x0, a1"),
+        ("j 8", "jal x0, 8"),
+        ("jal 8", "jal ra, 8"),
+        ("jr a0", "jalr x0, a0, 0"),
+        ("ret", "jalr x0, ra, 0"),
+        ("beqz a0, 8", "beq a0, x0, 8"),
+        ("bgt a0, a1, 8", "blt a1, a0, 8"),
+        ("bleu a0, a1, 8", "bgeu a1, a0, 8"),
+        ("csrr a0, mstatus", "csrrs a0, mstatus, x0"),
+        ("csrw mtvec, a0", "csrrw x0, mtvec, a0"),
+        ("csrsi mie, 8", "csrrsi x0, mie, 8"),
+        ("rdcycle a0", "csrrs a0, cycle, x0"),
+        ("fence", "fence 0xff"),
+        ("fence rw, w", "fence 0x31"),
+    ];
+    for (pseudo, base) in expansions {
+        assert_eq!(words(pseudo), words(base), "{pseudo}");
+    }
+}
+
+#[test]
+fn resolves_labels_in_both_directions() {
+    assert_eq!(
+        words(
+            "
+        back:
+            beq a0, a1, forward
+            j back
+        forward:
+            nop
+            "
+        ),
+        // beq +8, jal -4, nop
+        [0x00b50463, 0xffdff06f, 0x00000013]
+    );
+
+    let program = assemble(
+        "
+            la a0, value
+            lw a0, 0(a0)
+            ebreak
+        .data
+        value: .word 42
+        ",
+    )
+    .unwrap();
+    assert_eq!(program.symbols["value"], RAM_BASE as i64 + 16);
+    let mut machine = create_rv32(program.contents).unwrap();
+    assert_eq!(machine.run(100).unwrap(), StopReason::Ebreak);
+    assert_eq!(machine.harts[0].registers[10], 42);
+}
+
+#[test]
+fn lays_out_data() {
+    let program = assemble(
+        "
+            .equ ANSWER, 42
+            nop
+        .data
+        bytes: .byte 1, 2, ANSWER
+            .align 2
+        words: .word words, ANSWER
+        text: .asciz \"hi\"
+            .space 3, 0xff
+            .half -1
+        ",
+    )
+    .unwrap();
+    // The data section starts right after the text section
+    let data = RAM_BASE as i64 + 4;
+    assert_eq!(program.symbols["ANSWER"], 42);
+    assert_eq!(program.symbols["bytes"], data);
+    assert_eq!(program.symbols["words"], data + 4);
+    assert_eq!(program.symbols["text"], data + 12);
+    let words = (data + 4) as u32;
+    assert_eq!(
+        program.contents[4..],
+        [
+            [1, 2, 42, 0].as_slice(),
+            &words.to_le_bytes(),
+            &[42, 0, 0, 0],
+            b"hi\0",
+            &[0xff; 3],
+            &[0xff, 0xff],
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn pads_text_with_nops() {
+    assert_eq!(
+        words("ebreak\n.align 4\nebreak"),
+        [0x00100073, 0x13, 0x13, 0x13, 0x00100073]
+    );
+}
+
+#[test]
+fn pcrel_lo_refers_to_the_label_of_the_auipc() {
+    let program = assemble(
+        "
+        address: auipc a0, %pcrel_hi(value)
+            nop
+            addi a0, a0, %pcrel_lo(address)
+        load: auipc a1, %pcrel_hi(value)
+            lw a1, %pcrel_lo(load)(a1)
+            ebreak
+        .data
+        value: .word 0x1234
+        ",
+    )
+    .unwrap();
+    let value = program.symbols["value"] as u32;
+    let mut machine = create_rv32(program.contents).unwrap();
+    assert_eq!(machine.run(100).unwrap(), StopReason::Ebreak);
+    assert_eq!(machine.harts[0].registers[10], value);
+    assert_eq!(machine.harts[0].registers[11], 0x1234);
+}
+
+#[test]
+fn pcrel_lo_rejects_anything_but_an_auipc_label() {
+    let errors = errors(
+        "
+        here: auipc a0, %pcrel_hi(value)
+            addi a0, a0, %pcrel_lo(value)
+        not_auipc: addi a0, a0, 0
+            addi a0, a0, %pcrel_lo(not_auipc)
+            addi a0, a0, %pcrel_lo(here + 4)
+            addi a0, a0, %pcrel_lo(missing)
+        .data
+        value: .word %pcrel_lo(here)
+        ",
+    );
+    assert_eq!(
+        errors.iter().map(|error| error.line).collect::<Vec<_>>(),
+        [3, 5, 6, 7, 9]
+    );
+    assert!(errors[0].message.contains("doesn't label an auipc"));
+}
+
+#[test]
+fn reports_every_error_with_its_line() {
+    let errors = errors(
+        "
+            addi a0, a0
+            frobnicate a0
+        a0: nop
+        twice: nop
+        twice: nop
+            addi a0, a0, 4096
+            .byte 256
+            .unknown
+            beq a0, a1, nowhere
+        ",
+    );
+    let lines: Vec<_> = errors.iter().map(|error| error.line).collect();
+    assert_eq!(lines, [2, 3, 4, 6, 7, 8, 9, 10]);
+    assert_eq!(errors[1].message, "Unknown instruction «frobnicate»");
+    assert_eq!(errors[2].message, "«a0» is a register name");
+    assert_eq!(
+        errors[3].message,
+        "Symbol «twice» is defined more than once"
+    );
+    assert_eq!(errors[7].message, "Undefined symbol «nowhere»");
+    assert_eq!(
+        errors[1].to_string(),
+        "Line 3: Unknown instruction «frobnicate»"
+    );
+}
+
+#[test]
+fn rejects_what_doesnt_fit() {
+    let errors = errors(
+        "
+            slli a0, a0, 31
+            slli a0, a0, -1
+            csrr a0, -1
+        .data
+            .space 0x100000
+            .zero 16
+            .skip 0x7fffffffffff, 1
+        ",
+    );
+    let lines: Vec<_> = errors.iter().map(|error| error.line).collect();
+    assert_eq!(lines, [3, 4, 6, 8]);
+    assert_eq!(
+        errors[0].message,
+        "Invalid imm: Value -1 doesn't fit in 5 bits (allowed range is 0..=31)"
+    );
+    assert_eq!(
+        errors[2].message,
+        "The program doesn't fit in memory (1048576 bytes)"
+    );
+}
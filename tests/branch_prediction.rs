@@ -0,0 +1,121 @@
+use emu::{
+    assembler::assemble,
+    create_rv32,
+    machine::{
+        cpu::branch_prediction::{BranchCounts, BranchPrediction, PredictorConfig},
+        StopReason,
+    },
+};
+
+/// Mispredictions of a branch at a single address with the outcomes in «pattern»
+fn mispredictions(config: PredictorConfig, pattern: impl IntoIterator<Item = bool>) -> u64 {
+    let mut prediction = BranchPrediction::new(config.build());
+    for taken in pattern {
+        prediction.observe(0x8000_0010, 0x8000_0000, taken);
+    }
+    prediction.total().mispredicted
+}
+
+/// A loop branch taken 9 times, then falling through
+fn loop_exit() -> impl Iterator<Item = bool> {
+    (0..10).map(|i| i < 9)
+}
+
+fn alternating() -> impl Iterator<Item = bool> {
+    (0..100).map(|i| i % 2 == 0)
+}
+
+#[test]
+fn static_predictors() {
+    assert_eq!(
+        mispredictions(PredictorConfig::Static { taken: true }, loop_exit()),
+        1
+    );
+    assert_eq!(
+        mispredictions(PredictorConfig::Static { taken: false }, loop_exit()),
+        9
+    );
+}
+
+#[test]
+fn counters_learn_loops() {
+    // Both miss on entering and on leaving the loop
+    assert_eq!(
+        mispredictions(PredictorConfig::OneBit { index_bits: 6 }, loop_exit()),
+        2
+    );
+    assert_eq!(
+        mispredictions(PredictorConfig::TwoBit { index_bits: 6 }, loop_exit()),
+        2
+    );
+    // Running the loop again, only the 2-bit counter doesn't miss on entering it
+    let twice = || loop_exit().chain(loop_exit());
+    assert_eq!(
+        mispredictions(PredictorConfig::OneBit { index_bits: 6 }, twice()),
+        4
+    );
+    assert_eq!(
+        mispredictions(PredictorConfig::TwoBit { index_bits: 6 }, twice()),
+        3
+    );
+}
+
+#[test]
+fn history_learns_patterns() {
+    assert!(mispredictions(PredictorConfig::TwoBit { index_bits: 6 }, alternating()) >= 50);
+    assert!(mispredictions(PredictorConfig::Gshare { history_bits: 6 }, alternating()) < 10);
+    assert!(
+        mispredictions(
+            PredictorConfig::Tournament {
+                index_bits: 6,
+                history_bits: 6
+            },
+            alternating()
+        ) < 15
+    );
+}
+
+#[test]
+fn guest_program() {
+    let program = "
+        li t0, 10
+    outer:
+        li t1, 4
+    inner:
+        addi t1, t1, -1
+        bnez t1, inner
+        andi t2, t0, 1
+        beqz t2, even
+        addi a0, a0, 1
+    even:
+        addi t0, t0, -1
+        bnez t0, outer
+        ebreak
+    ";
+    let mut machine = create_rv32(assemble(program).unwrap().contents).unwrap();
+    machine.harts[0].branch_prediction = Some(BranchPrediction::new(
+        PredictorConfig::TwoBit { index_bits: 8 }.build(),
+    ));
+    assert_eq!(machine.run(1_000).unwrap(), StopReason::Ebreak);
+    let prediction = machine.harts[0].branch_prediction.as_ref().unwrap();
+    let total = prediction.total();
+    assert_eq!(total.executed, 10 * 4 + 10 + 10);
+    assert_eq!(total.taken, 10 * 3 + 5 + 9);
+    let worst = prediction.worst();
+    assert_eq!(worst.len(), 3);
+    // The inner loop branch is mispredicted on leaving the loop, and on its first execution
+    assert_eq!(worst[0].0, 0x8000_000c);
+    assert_eq!(worst[0].1.mispredicted, 10 + 1);
+    // The branch on the parity of the counter alternates, which the 2-bit counter never gets right
+    let parity = prediction.branches[&0x8000_0014];
+    assert_eq!(parity.executed, 10);
+    assert_eq!(parity.accuracy(), Some(0.0));
+    assert!(worst
+        .windows(2)
+        .all(|pair| pair[0].1.mispredicted >= pair[1].1.mispredicted));
+
+    // Resetting keeps the predictor, but forgets everything
+    machine.reset();
+    let prediction = machine.harts[0].branch_prediction.as_ref().unwrap();
+    assert_eq!(prediction.total(), BranchCounts::default());
+}
@@ -0,0 +1,174 @@
+use emu::{
+    assembler::assemble,
+    create_rv32,
+    machine::{
+        memory::{
+            cache::{CacheConfig, CacheError, Caches, Counts, Replacement, WritePolicy},
+            AccessKind::{Load, Store},
+        },
+        StopReason,
+    },
+};
+
+/// A 2-way set associative cache with 2 sets of 16-byte lines, so that addresses 32 bytes apart go to the same set
+const SMALL: CacheConfig = CacheConfig {
+    size: 64,
+    associativity: 2,
+    line_size: 16,
+    replacement: Replacement::Lru,
+    write_policy: WritePolicy::WriteBack,
+    write_allocate: true,
+};
+
+fn counts(hits: u64, misses: u64) -> Counts {
+    Counts { hits, misses }
+}
+
+fn data_cache(config: CacheConfig) -> Caches {
+    Caches::new(CacheConfig::L1, config, None).unwrap()
+}
+
+#[test]
+fn sequential_loads() {
+    let mut caches = data_cache(SMALL);
+    for address in (0x8000_0000..0x8000_0040).step_by(4) {
+        caches.access(address, 4, false, Load);
+    }
+    // One miss per line, then hits for the rest of it
+    assert_eq!(caches.l1d.statistics.load, counts(12, 4));
+    assert_eq!(caches.memory_reads, 64);
+    assert_eq!(caches.l1i.statistics.total(), counts(0, 0));
+}
+
+#[test]
+fn access_across_lines() {
+    let mut caches = data_cache(SMALL);
+    caches.access(0x8000_000e, 4, false, Load);
+    assert_eq!(caches.l1d.statistics.load, counts(0, 2));
+}
+
+/// Accesses A, B, A, C, A to the same set: the last one only hits if the replacement kept A around
+fn replaced(replacement: Replacement) -> Counts {
+    let mut caches = data_cache(CacheConfig {
+        replacement,
+        ..SMALL
+    });
+    for address in [
+        0x8000_0000,
+        0x8000_0020,
+        0x8000_0000,
+        0x8000_0040,
+        0x8000_0000,
+    ] {
+        caches.access(address, 4, false, Load);
+    }
+    caches.l1d.statistics.load
+}
+
+#[test]
+fn lru_replacement() {
+    assert_eq!(replaced(Replacement::Lru), counts(2, 3));
+}
+
+#[test]
+fn fifo_replacement() {
+    assert_eq!(replaced(Replacement::Fifo), counts(1, 4));
+}
+
+#[test]
+fn write_back() {
+    let mut caches = data_cache(SMALL);
+    caches.access(0x8000_0000, 4, true, Store);
+    caches.access(0x8000_0004, 4, true, Store);
+    assert_eq!(caches.memory_writes, 0);
+    // Replacing the dirty line writes it back
+    caches.access(0x8000_0020, 4, false, Load);
+    caches.access(0x8000_0040, 4, false, Load);
+    assert_eq!(caches.l1d.statistics.writebacks, 1);
+    assert_eq!(caches.memory_writes, 16);
+    assert_eq!(caches.l1d.statistics.store, counts(1, 1));
+}
+
+#[test]
+fn write_through_without_allocation() {
+    let mut caches = data_cache(CacheConfig {
+        write_policy: WritePolicy::WriteThrough,
+        write_allocate: false,
+        ..SMALL
+    });
+    caches.access(0x8000_0000, 4, true, Store);
+    caches.access(0x8000_0000, 4, false, Load);
+    caches.access(0x8000_0000, 1, true, Store);
+    assert_eq!(caches.l1d.statistics.store, counts(1, 1));
+    assert_eq!(caches.l1d.statistics.load, counts(0, 1));
+    assert_eq!(caches.memory_writes, 4 + 1);
+    assert_eq!(caches.l1d.statistics.writebacks, 0);
+}
+
+#[test]
+fn second_level() {
+    let mut caches = Caches::new(CacheConfig::L1, SMALL, Some(CacheConfig::L2)).unwrap();
+    // The third access misses in the first level, but the second level still has the line
+    for address in [0x8000_0000, 0x8000_0020, 0x8000_0040, 0x8000_0000] {
+        caches.access(address, 4, false, Load);
+    }
+    let l2 = caches.l2.as_ref().unwrap();
+    assert_eq!(caches.l1d.statistics.load, counts(0, 4));
+    // Both 16-byte lines at 0x80000000 and 0x80000020 are in the same 64-byte line of the second level
+    assert_eq!(l2.statistics.load, counts(2, 2));
+    assert_eq!(caches.memory_reads, 2 * 64);
+}
+
+#[test]
+fn invalid_configurations() {
+    assert_eq!(
+        Caches::new(CacheConfig { size: 48, ..SMALL }, SMALL, None).err(),
+        Some(CacheError::NotAPowerOfTwo)
+    );
+    assert_eq!(
+        Caches::new(
+            SMALL,
+            CacheConfig {
+                associativity: 8,
+                ..SMALL
+            },
+            None
+        )
+        .err(),
+        Some(CacheError::TooSmall)
+    );
+}
+
+#[test]
+fn guest_program() {
+    let program = "
+        la t0, array
+        li t1, 16
+    loop:
+        lw t2, 0(t0)
+        sw t2, 64(t0)
+        addi t0, t0, 4
+        addi t1, t1, -1
+        bnez t1, loop
+        ebreak
+        .data
+        .align 6
+    array:
+        .zero 128
+    ";
+    let mut machine = create_rv32(assemble(program).unwrap().contents).unwrap();
+    machine.caches = Some(Caches::new(SMALL, SMALL, None).unwrap());
+    assert_eq!(machine.run(1_000).unwrap(), StopReason::Ebreak);
+    let caches = machine.caches.as_ref().unwrap();
+    assert_eq!(caches.l1d.statistics.load, counts(12, 4));
+    assert_eq!(caches.l1d.statistics.store, counts(12, 4));
+    // «la», «li», 16 iterations of the loop and «ebreak»
+    let fetches = caches.l1i.statistics.fetch;
+    assert_eq!(fetches.accesses(), 2 + 1 + 16 * 5 + 1);
+    assert!(fetches.hit_rate().unwrap() > 0.9);
+    // Resetting empties the caches
+    machine.reset();
+    let caches = machine.caches.as_ref().unwrap();
+    assert_eq!(caches.l1i.statistics.total(), counts(0, 0));
+    assert!(caches.l1i.sets.iter().flatten().all(|line| !line.valid));
+}
@@ -0,0 +1,133 @@
+//! The reference logs below are written by hand in the format of `spike -l --log-commits`, with the architecturally correct effects of each instruction
+
+use emu::{
+    assembler::assemble,
+    cosim::{cosimulate, CosimError},
+    create_rv32,
+    machine::{htif::Htif, Machine},
+};
+
+const PROGRAM: &str = "
+    lui t0, 0x80001
+    li a0, -1
+    sb a0, 0(t0)
+    lbu a1, 0(t0)
+    lb a2, 0(t0)
+    csrw mscratch, a1
+    sw a2, 4(t0)
+";
+
+const REFERENCE: &str = "\
+core   0: 0x00001000 (0x00000297) auipc t0, 0x0
+core   0: 3 0x00001000 (0x00000297) x5  0x00001000
+core   0: 3 0x00001004 (0x02028593) x11 0x00001020
+core   0: 3 0x00001008 (0xf1402573) x10 0x00000000
+core   0: 3 0x0000100c (0x0182a283) x5  0x80000000 mem 0x00001018
+core   0: 3 0x00001010 (0x00028067) x0  0x00001014
+core   0: 0x80000000 (0x800012b7) lui t0, 0x80001
+core   0: 3 0x80000000 (0x800012b7) x5  0x80001000
+core   0: 0x80000004 (0xfff00513) li a0, -1
+core   0: 3 0x80000004 (0xfff00513) x10 0xffffffff
+core   0: 0x80000008 (0x00a28023) sb a0, 0(t0)
+core   0: 3 0x80000008 (0x00a28023) mem 0x80001000 0xff
+core   0: 0x8000000c (0x0002c583) lbu a1, 0(t0)
+core   0: 3 0x8000000c (0x0002c583) x11 0x000000ff mem 0x80001000
+core   0: 0x80000010 (0x00028603) lb a2, 0(t0)
+core   0: 3 0x80000010 (0x00028603) x12 0xffffffff mem 0x80001000
+core   0: 0x80000014 (0x34059073) csrw mscratch, a1
+core   0: 3 0x80000014 (0x34059073) x0  0x00000000 c832_mscratch 0x000000ff
+core   0: 0x80000018 (0x00c2a223) sw a2, 4(t0)
+core   0: 3 0x80000018 (0x00c2a223) mem 0x80001004 0xffffffff
+";
+
+fn machine() -> Machine {
+    create_rv32(assemble(PROGRAM).unwrap().contents).unwrap()
+}
+
+#[test]
+fn matching_reference() {
+    // The boot ROM instructions at 0x1000 are skipped
+    assert_eq!(cosimulate(&mut machine(), REFERENCE).unwrap(), 7);
+}
+
+#[test]
+fn register_divergence() {
+    // A sign extended lbu
+    let reference = REFERENCE.replace("x11 0x000000ff", "x11 0xffffffff");
+    match cosimulate(&mut machine(), &reference) {
+        Err(CosimError::Divergence(divergence)) => {
+            assert_eq!(divergence.index, 3);
+            assert_eq!(divergence.line, 14);
+            assert_eq!(divergence.expected.register_write, Some((11, 0xffffffff)));
+            assert_eq!(divergence.actual.register_write, Some((11, 0xff)));
+            assert_eq!(divergence.expected_registers[11], 0xffffffff);
+            assert_eq!(divergence.actual_registers[11], 0xff);
+            assert!(divergence.to_string().contains("lbu a1, 0(t0)"));
+        }
+        result => panic!("Expected a divergence, got {result:?}"),
+    }
+}
+
+#[test]
+fn memory_divergence() {
+    let reference = REFERENCE.replace("mem 0x80001004 0xffffffff", "mem 0x80001004 0x000000ff");
+    match cosimulate(&mut machine(), &reference) {
+        Err(CosimError::Divergence(divergence)) => assert_eq!(divergence.index, 6),
+        result => panic!("Expected a divergence, got {result:?}"),
+    }
+}
+
+#[test]
+fn csr_divergence() {
+    let reference = REFERENCE.replace("c832_mscratch 0x000000ff", "c832_mscratch 0x00000000");
+    match cosimulate(&mut machine(), &reference) {
+        Err(CosimError::Divergence(divergence)) => assert_eq!(divergence.index, 5),
+        result => panic!("Expected a divergence, got {result:?}"),
+    }
+}
+
+#[test]
+fn missing_start() {
+    let reference: String = REFERENCE
+        .lines()
+        .take(6)
+        .map(|line| line.to_owned() + "\n")
+        .collect();
+    assert!(matches!(
+        cosimulate(&mut machine(), &reference),
+        Err(CosimError::NoStart { pc: 0x8000_0000 })
+    ));
+}
+
+#[test]
+fn invalid_reference() {
+    let reference = REFERENCE.replace("x10 0xffffffff", "x10 ffffffff");
+    assert!(matches!(
+        cosimulate(&mut machine(), &reference),
+        Err(CosimError::Parse(_))
+    ));
+}
+
+/// The instructions are executed like on any run, so the host answers the commands written to «tohost»
+#[test]
+fn host_accesses() {
+    // Device 1, command 1: putchar
+    let program = "
+        lui t1, 0x80001
+        li t0, 0x41
+        sw t0, 0(t1)
+        lui t0, 0x1010
+        sw t0, 4(t1)
+    ";
+    let reference = "\
+core   0: 3 0x80000000 (0x80001337) x6  0x80001000
+core   0: 3 0x80000004 (0x04100293) x5  0x00000041
+core   0: 3 0x80000008 (0x00532023) mem 0x80001000 0x00000041
+core   0: 3 0x8000000c (0x010102b7) x5  0x01010000
+core   0: 3 0x80000010 (0x00532223) mem 0x80001004 0x01010000
+";
+    let mut machine = create_rv32(assemble(program).unwrap().contents).unwrap();
+    machine.htif = Some(Htif::new(0x8000_1000, None));
+    assert_eq!(cosimulate(&mut machine, reference).unwrap(), 5);
+    assert_eq!(machine.htif.unwrap().output, b"A");
+}
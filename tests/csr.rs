@@ -0,0 +1,120 @@
+//! CSR accesses, and the breakdown of their values shown by the GUI
+
+use emu::machine::cpu::csr::{
+    fields, CsrError, Csrs, CYCLE, HPMCOUNTER3H, MCAUSE, MEPC, MHARTID, MHPMEVENT3, MIE, MIP, MISA,
+    MSIP, MSTATUS, MSTATUS_MIE, MSTATUS_MPIE, MTIP, MVENDORID, SATP,
+};
+
+/// Value of the field called «name» in the breakdown of the CSR
+fn field(address: u16, value: u32, name: &str) -> String {
+    fields(address, value)
+        .into_iter()
+        .find(|(field, _)| *field == name)
+        .unwrap_or_else(|| panic!("No field «{name}» in CSR 0x{address:03x}"))
+        .1
+}
+
+#[test]
+fn mstatus_fields() {
+    let value = Csrs::new().get(MSTATUS) | MSTATUS_MIE;
+    assert_eq!(field(MSTATUS, value, "MIE"), "1");
+    assert_eq!(field(MSTATUS, value, "MPIE"), "0");
+    assert_eq!(field(MSTATUS, value, "MPP"), "M");
+    assert_eq!(field(MSTATUS, value, "SPP"), "U");
+    assert_eq!(field(MSTATUS, 0b01 << 11 | 1 << 8, "MPP"), "S");
+    assert_eq!(field(MSTATUS, 0b01 << 11 | 1 << 8, "SPP"), "S");
+    assert_eq!(field(MSTATUS, 0b10 << 11, "MPP"), "Reserved");
+    assert_eq!(field(MSTATUS, 0b11 << 13, "FS"), "3");
+}
+
+#[test]
+fn mcause_fields() {
+    let interrupt = 1 << 31 | 7;
+    assert_eq!(field(MCAUSE, interrupt, "Interrupt"), "1");
+    assert_eq!(field(MCAUSE, interrupt, "Exception code"), "7");
+    assert_eq!(field(MCAUSE, interrupt, "Cause"), "Machine timer interrupt");
+    assert_eq!(
+        field(MCAUSE, 1 << 31 | 11, "Cause"),
+        "Machine external interrupt"
+    );
+    assert_eq!(field(MCAUSE, 1 << 31 | 2, "Cause"), "Reserved");
+
+    // The same codes mean something else for exceptions
+    assert_eq!(field(MCAUSE, 7, "Interrupt"), "0");
+    assert_eq!(field(MCAUSE, 7, "Cause"), "Store/AMO access fault");
+    assert_eq!(field(MCAUSE, 11, "Cause"), "Environment call from M-mode");
+    assert_eq!(field(MCAUSE, 2, "Cause"), "Illegal instruction");
+    assert_eq!(field(MCAUSE, 10, "Cause"), "Reserved");
+}
+
+#[test]
+fn satp_fields() {
+    let value = 1 << 31 | 5 << 22 | 0x12345;
+    assert_eq!(field(SATP, value, "MODE"), "Sv32");
+    assert_eq!(field(SATP, value, "ASID"), "5");
+    assert_eq!(field(SATP, value, "PPN"), "0x012345");
+    assert_eq!(field(SATP, value, "Root page table"), "0x012345000");
+    assert_eq!(field(SATP, 0x3f_ffff, "MODE"), "Bare");
+    assert_eq!(field(SATP, 0x3f_ffff, "Root page table"), "0x3fffff000");
+}
+
+#[test]
+fn interrupt_fields() {
+    let value = MSIP | MTIP;
+    for address in [MIP, MIE] {
+        assert_eq!(
+            fields(address, value),
+            [
+                ("SSIP/SSIE", "0".to_owned()),
+                ("MSIP/MSIE", "1".to_owned()),
+                ("STIP/STIE", "0".to_owned()),
+                ("MTIP/MTIE", "1".to_owned()),
+                ("SEIP/SEIE", "0".to_owned()),
+                ("MEIP/MEIE", "0".to_owned()),
+            ]
+        );
+    }
+}
+
+#[test]
+fn only_implemented_event_selectors_have_fields() {
+    assert_eq!(field(MHPMEVENT3, 0, "Event"), "None");
+    assert_eq!(field(MHPMEVENT3 + 3, 0, "Event"), "None");
+    assert!(fields(MHPMEVENT3 + 4, 0).is_empty());
+    assert!(fields(MEPC, 0x8000_0000).is_empty());
+}
+
+#[test]
+fn read_only_csrs_refuse_writes() {
+    let mut csrs = Csrs::new();
+    for address in [MVENDORID, MHARTID, CYCLE, HPMCOUNTER3H + 3] {
+        assert!(matches!(
+            csrs.write(address, 1),
+            Err(CsrError::ReadOnly(refused)) if refused == address
+        ));
+        assert_eq!(csrs.read(address).unwrap(), 0);
+    }
+    assert!(matches!(
+        csrs.write(0x7c0, 1),
+        Err(CsrError::Unimplemented(0x7c0))
+    ));
+}
+
+#[test]
+fn writes_leave_read_only_bits_alone() {
+    let mut csrs = Csrs::new();
+    let misa = csrs.read(MISA).unwrap();
+    csrs.write(MISA, 0).unwrap();
+    assert_eq!(csrs.read(MISA).unwrap(), misa);
+
+    csrs.write(MIP, MTIP).unwrap();
+    assert_eq!(csrs.read(MIP).unwrap(), 0);
+
+    // MPP stays machine mode, whatever is written
+    csrs.write(MSTATUS, MSTATUS_MPIE).unwrap();
+    assert_eq!(field(MSTATUS, csrs.read(MSTATUS).unwrap(), "MPP"), "M");
+    assert_eq!(field(MSTATUS, csrs.read(MSTATUS).unwrap(), "MPIE"), "1");
+
+    csrs.write(MEPC, 0x8000_0003).unwrap();
+    assert_eq!(csrs.read(MEPC).unwrap(), 0x8000_0000);
+}
@@ -0,0 +1,109 @@
+//! Instructions are decoded once and cached, these check that the cache never runs stale instructions
+
+use emu::{
+    assembler::assemble,
+    create_rv32,
+    machine::{Scheduling, StopReason},
+};
+
+/// «addi a0, a0, 2»
+const ADDI_A0_2: u32 = 0x0025_0513;
+
+#[test]
+fn store_over_executed_code() {
+    let program = format!(
+        "
+        li a1, 0
+    again:
+        jal ra, patched
+        la t0, patched
+        li t1, {ADDI_A0_2}
+        sw t1, 0(t0)
+        addi a1, a1, 1
+        li t2, 2
+        bne a1, t2, again
+        ebreak
+    patched:
+        addi a0, a0, 1
+        ret
+    "
+    );
+    let mut machine = create_rv32(assemble(&program).unwrap().contents).unwrap();
+    assert_eq!(machine.run(100).unwrap(), StopReason::Ebreak);
+    assert_eq!(machine.harts[0].registers[10], 1 + 2);
+}
+
+#[test]
+fn store_ahead_in_the_same_block() {
+    let program = format!(
+        "
+        la t0, next
+        li t1, {ADDI_A0_2}
+        sw t1, 0(t0)
+    next:
+        addi a0, a0, 1
+        ebreak
+    "
+    );
+    let mut machine = create_rv32(assemble(&program).unwrap().contents).unwrap();
+    assert_eq!(machine.run(100).unwrap(), StopReason::Ebreak);
+    assert_eq!(machine.harts[0].registers[10], 2);
+}
+
+#[test]
+fn edit_from_outside() {
+    let mut machine =
+        create_rv32(assemble("loop:\naddi a0, a0, 1\nj loop").unwrap().contents).unwrap();
+    machine.run(10).unwrap();
+    assert_eq!(machine.harts[0].registers[10], 5);
+    // Whoever edits memory from outside of the CPU tells the cache about it
+    machine.memory.contents[..4].copy_from_slice(&ADDI_A0_2.to_le_bytes());
+    machine.harts[0].decode_cache.invalidate(0x8000_0000, 4);
+    machine.run(10).unwrap();
+    assert_eq!(machine.harts[0].registers[10], 5 + 2 * 5);
+}
+
+/// Code patched by another hart runs as soon as the store is seen, on every scheduling
+#[test]
+fn store_by_another_hart() {
+    let program = format!(
+        "
+        csrr t0, mhartid
+        bnez t0, patcher
+        la s0, flag
+    wait:
+        jal ra, patched
+        lw t1, 0(s0)
+        beqz t1, wait
+        li a0, 0
+        jal ra, patched
+        ebreak
+    patcher:
+        la t0, patched
+        li t1, {ADDI_A0_2}
+        sw t1, 0(t0)
+        la t0, flag
+        li t1, 1
+        sw t1, 0(t0)
+    idle:
+        j idle
+    patched:
+        addi a0, a0, 1
+        ret
+    .data
+    flag: .word 0
+    "
+    );
+    let program = assemble(&program).unwrap();
+    for scheduling in [
+        Scheduling::RoundRobin { quantum: 1 },
+        Scheduling::RoundRobin { quantum: 50 },
+        Scheduling::Threads,
+    ] {
+        let mut machine = create_rv32(program.contents.clone()).unwrap();
+        machine.set_harts(2);
+        machine.scheduling = scheduling;
+        assert_eq!(machine.run(10_000).unwrap(), StopReason::Ebreak);
+        assert_eq!(machine.harts[0].registers[10], 2, "{scheduling:?}");
+    }
+}
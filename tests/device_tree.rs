@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+
+use emu::{
+    assembler::assemble,
+    create_rv32,
+    machine::{
+        device_tree::{generate, isa_string},
+        htif::Htif,
+        memory::{AccessKind, MemoryBus},
+        StopReason,
+    },
+};
+
+/// Every property of a device tree blob by the path of its node (e.g. «/cpus/cpu@0/reg»), checking the header on the way
+fn properties(blob: &[u8]) -> BTreeMap<String, Vec<u8>> {
+    let word = |offset: usize| u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap());
+    assert_eq!(word(0), 0xd00d_feed);
+    assert_eq!(word(4) as usize, blob.len());
+    assert_eq!(word(20), 17);
+    let (structure, strings) = (word(8) as usize, word(12) as usize);
+    // The memory reservation block only has the entry ending it
+    assert!(blob[word(16) as usize..][..16]
+        .iter()
+        .all(|&byte| byte == 0));
+    let string = |offset: usize| {
+        let bytes = &blob[offset..];
+        String::from_utf8(bytes[..bytes.iter().position(|&byte| byte == 0).unwrap()].to_vec())
+            .unwrap()
+    };
+    let mut properties = BTreeMap::new();
+    let mut path: Vec<String> = vec![];
+    let mut offset = structure;
+    loop {
+        let token = word(offset);
+        offset += 4;
+        match token {
+            1 => {
+                let name = string(offset);
+                offset += (name.len() + 1).next_multiple_of(4);
+                path.push(name);
+            }
+            2 => {
+                path.pop();
+            }
+            3 => {
+                let (length, name) = (word(offset) as usize, word(offset + 4) as usize);
+                offset += 8;
+                properties.insert(
+                    format!("{}/{}", path.join("/"), string(strings + name)),
+                    blob[offset..offset + length].to_vec(),
+                );
+                offset += length.next_multiple_of(4);
+            }
+            9 => break,
+            _ => panic!("unknown token {token}"),
+        }
+    }
+    assert!(path.is_empty());
+    properties
+}
+
+fn cells(value: &[u8]) -> Vec<u32> {
+    value
+        .chunks(4)
+        .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+        .collect()
+}
+
+#[test]
+fn describes_the_machine() {
+    let mut machine = create_rv32(vec![]).unwrap();
+    machine.set_harts(2);
+    let tree = properties(&generate(&machine));
+    assert_eq!(tree["/#address-cells"], 1u32.to_be_bytes());
+    assert_eq!(tree["/cpus/cpu@1/reg"], 1u32.to_be_bytes());
+    assert_eq!(tree["/cpus/cpu@0/riscv,isa"], b"rv32ima_zicsr_zifencei\0");
+    assert_eq!(
+        tree["/cpus/cpu@1/interrupt-controller/compatible"],
+        b"riscv,cpu-intc\0"
+    );
+    assert!(!tree.contains_key("/cpus/cpu@2/reg"));
+    assert_eq!(
+        cells(&tree["/memory@80000000/reg"]),
+        [0x8000_0000, machine.memory.size() as u32]
+    );
+    assert!(!tree.contains_key("/htif/compatible"));
+
+    // Follows the changes to the machine
+    machine.set_harts(3);
+    machine.htif = Some(Htif::new(0x8000_1000, None));
+    let tree = properties(&generate(&machine));
+    assert!(tree.contains_key("/cpus/cpu@2/reg"));
+    assert_eq!(tree["/htif/compatible"], b"ucb,htif0\0");
+}
+
+#[test]
+fn isa_strings() {
+    assert_eq!(isa_string(1 << 30 | 1 << 8), "rv32i_zicsr_zifencei");
+    // Extensions in the canonical order, not the alphabetical one
+    let letters = [b'A', b'C', b'D', b'F', b'I', b'M'].map(|letter| 1 << (letter - b'A'));
+    assert_eq!(
+        isa_string(2 << 30 | letters.iter().sum::<u32>()),
+        "rv64imafdc_zicsr_zifencei"
+    );
+}
+
+/// Every hart starts with its mhartid in a0 and the address of the tree in a1
+#[test]
+fn boot() {
+    let program = "
+        lw a2, 0(a1)
+        bnez a0, park
+        ebreak
+    park:
+        j park
+    ";
+    let mut machine = create_rv32(assemble(program).unwrap().contents).unwrap();
+    machine.device_tree = true;
+    machine.set_harts(2);
+    assert_eq!(machine.run(100).unwrap(), StopReason::Ebreak);
+    let address = machine.harts[0].registers[11];
+    assert_eq!(address % 4096, 0);
+    assert_eq!(machine.harts[1].registers[10], 1);
+    assert_eq!(machine.harts[1].registers[11], address);
+    // The magic number, read as a little endian word
+    assert_eq!(machine.harts[0].registers[12], 0xedfe_0dd0);
+    let blob = generate(&machine);
+    let mut loaded = vec![0; blob.len()];
+    MemoryBus::new(&mut machine.memory)
+        .read_bytes(address, &mut loaded, AccessKind::Load)
+        .unwrap();
+    assert_eq!(loaded, blob);
+}
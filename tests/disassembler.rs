@@ -0,0 +1,150 @@
+//! The disassembler, checked against the assembler: every instruction below is assembled, disassembled and assembled again
+
+use emu::{
+    assembler::assemble,
+    machine::cpu::disassembler::{disassemble, register_name},
+};
+
+fn assemble_one(source: &str) -> u32 {
+    let program = assemble(source).unwrap_or_else(|errors| panic!("{source}: {errors:?}"));
+    assert_eq!(
+        program.contents.len(),
+        4,
+        "{source} isn't a single instruction"
+    );
+    u32::from_le_bytes(program.contents[..4].try_into().unwrap())
+}
+
+/// Checks that «source» disassembles to «expected», which assembles back to the same instruction
+///
+/// Branch and jump targets are printed relative to pc (e.g. «pc - 8»), which the assembler takes as a plain offset («-8»)
+fn round_trip(source: &str, expected: &str) {
+    let raw = assemble_one(source);
+    let disassembly = disassemble(raw);
+    assert_eq!(disassembly, expected, "{source} (0x{raw:08x})");
+    let reassembled = disassembly.replace("pc + ", "").replace("pc - ", "-");
+    assert_eq!(assemble_one(&reassembled), raw, "{disassembly}");
+}
+
+#[test]
+fn abi_register_names() {
+    let names: Vec<_> = (0..32).map(register_name).collect();
+    assert_eq!(
+        names,
+        [
+            "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3",
+            "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+            "t3", "t4", "t5", "t6",
+        ]
+    );
+    assert_eq!(register_name(32), "unknown");
+    round_trip("add x10, x2, x31", "add a0, sp, t6");
+    round_trip("lw x8, -12(x2)", "lw s0, -12(sp)");
+    round_trip("sw fp, 8(sp)", "sw s0, 8(sp)");
+}
+
+#[test]
+fn arithmetic_aliases() {
+    for (source, expected) in [
+        ("addi x0, x0, 0", "nop"),
+        ("addi a0, zero, -42", "li a0, -42"),
+        ("addi a0, a1, 0", "mv a0, a1"),
+        ("xori a0, a1, -1", "not a0, a1"),
+        ("sub a0, zero, a1", "neg a0, a1"),
+        ("sltiu a0, a1, 1", "seqz a0, a1"),
+        ("sltu a0, zero, a1", "snez a0, a1"),
+        ("slt a0, a1, zero", "sltz a0, a1"),
+        ("slt a0, zero, a1", "sgtz a0, a1"),
+        // Not aliases
+        ("addi a0, a1, 1", "addi a0, a1, 1"),
+        ("xori a0, a1, 1", "xori a0, a1, 1"),
+        ("srai a0, a1, 31", "srai a0, a1, 31"),
+        ("sub a0, a1, a2", "sub a0, a1, a2"),
+    ] {
+        round_trip(source, expected);
+    }
+}
+
+#[test]
+fn branch_and_jump_aliases() {
+    for (source, expected) in [
+        ("beq a0, zero, 8", "beqz a0, pc + 8"),
+        ("bne a0, zero, -8", "bnez a0, pc - 8"),
+        ("bge zero, a0, 16", "blez a0, pc + 16"),
+        ("bge a0, zero, 16", "bgez a0, pc + 16"),
+        ("blt a0, zero, 16", "bltz a0, pc + 16"),
+        ("blt zero, a0, 16", "bgtz a0, pc + 16"),
+        ("bltu a0, a1, -4", "bltu a0, a1, pc - 4"),
+        ("jal zero, -16", "j pc - 16"),
+        ("jal ra, 2048", "jal pc + 2048"),
+        ("jal t0, 4", "jal t0, pc + 4"),
+        ("jalr zero, ra, 0", "ret"),
+        ("jalr zero, a0, 0", "jr a0"),
+        ("jalr ra, a0, 0", "jalr a0"),
+        ("jalr t0, 4(a0)", "jalr t0, 4(a0)"),
+    ] {
+        round_trip(source, expected);
+    }
+    // The pseudo-instructions the assembler expands give the same aliases back
+    assert_eq!(
+        disassemble(assemble_one("bgt a0, a1, 8")),
+        "blt a1, a0, pc + 8"
+    );
+    assert_eq!(
+        disassemble(assemble_one("ble a0, a1, 8")),
+        "bge a1, a0, pc + 8"
+    );
+}
+
+#[test]
+fn system_and_csr_aliases() {
+    for (source, expected) in [
+        ("ecall", "ecall"),
+        ("ebreak", "ebreak"),
+        ("wfi", "wfi"),
+        ("mret", "mret"),
+        ("csrrs a0, mstatus, zero", "csrr a0, mstatus"),
+        ("csrrw zero, mtvec, a0", "csrw mtvec, a0"),
+        ("csrrs zero, mie, a0", "csrs mie, a0"),
+        ("csrrc zero, mie, a0", "csrc mie, a0"),
+        ("csrrwi zero, mscratch, 5", "csrwi mscratch, 5"),
+        ("csrrsi zero, mstatus, 8", "csrsi mstatus, 8"),
+        ("csrrci zero, mstatus, 8", "csrci mstatus, 8"),
+        ("csrrw a0, mscratch, a1", "csrrw a0, mscratch, a1"),
+        ("csrrsi a0, mstatus, 8", "csrrsi a0, mstatus, 8"),
+        ("rdcycle a0", "csrr a0, cycle"),
+        ("rdinstreth a0", "csrr a0, instreth"),
+        ("fence", "fence"),
+        ("fence rw, w", "fence rw, w"),
+        ("fence i, o", "fence i, o"),
+        ("fence.i", "fence.i"),
+    ] {
+        round_trip(source, expected);
+    }
+    // CSRs that aren't implemented are shown by address
+    assert_eq!(
+        disassemble(assemble_one("csrrs a0, 0x7ff, zero")),
+        "csrr a0, 0x7ff"
+    );
+}
+
+#[test]
+fn atomic_instructions() {
+    for (source, expected) in [
+        ("lr.w a0, (a1)", "lr.w a0, (a1)"),
+        ("sc.w.rl a0, a2, (a1)", "sc.w.rl a0, a2, (a1)"),
+        ("amoswap.w.aq a0, a2, (a1)", "amoswap.w.aq a0, a2, (a1)"),
+        ("amoadd.w.aqrl a0, a2, (a1)", "amoadd.w.aqrl a0, a2, (a1)"),
+        ("amomaxu.w zero, a2, (sp)", "amomaxu.w zero, a2, (sp)"),
+    ] {
+        round_trip(source, expected);
+    }
+}
+
+#[test]
+fn undecodable_words() {
+    assert_eq!(disassemble(0x0000_0000), ".word 0x00000000");
+    assert_eq!(disassemble(0xffff_ffff), ".word 0xffffffff");
+    // add with a funct7 that doesn't exist
+    assert_eq!(disassemble(0x4000_1033), ".word 0x40001033");
+}
@@ -0,0 +1,85 @@
+//! Guest programs writing instructions to memory and running them after a «fence.i»
+
+use emu::{assembler::assemble, create_rv32, machine::StopReason};
+
+/// Generates a function adding «n» to a0 for every «n» from 1 to 5 into the same buffer, and calls each of them
+#[test]
+fn generated_code() {
+    let program = "
+        la s0, buffer
+        li s1, 1
+    generate:
+        # addi a0, a0, n
+        slli t0, s1, 20
+        li t1, 0x00050513
+        or t0, t0, t1
+        sw t0, 0(s0)
+        # ret
+        li t0, 0x00008067
+        sw t0, 4(s0)
+        fence.i
+        jalr ra, 0(s0)
+        addi s1, s1, 1
+        li t2, 6
+        bne s1, t2, generate
+        ebreak
+    buffer:
+        nop
+        nop
+    ";
+    let mut machine = create_rv32(assemble(program).unwrap().contents).unwrap();
+    assert_eq!(machine.run(1_000).unwrap(), StopReason::Ebreak);
+    assert_eq!(machine.harts[0].registers[10], 1 + 2 + 3 + 4 + 5);
+}
+
+/// Copies a position independent payload to where it runs from, like a bootloader relocating itself
+#[test]
+fn relocated_code() {
+    let program = "
+        la t0, payload
+        la t1, payload_end
+        li t2, 0x80010000
+    copy:
+        lw t3, 0(t0)
+        sw t3, 0(t2)
+        addi t0, t0, 4
+        addi t2, t2, 4
+        bne t0, t1, copy
+        fence.i
+        li t2, 0x80010000
+        jalr zero, 0(t2)
+    payload:
+        auipc a1, 0
+        addi a0, a0, 7
+        ebreak
+    payload_end:
+    ";
+    let mut machine = create_rv32(assemble(program).unwrap().contents).unwrap();
+    assert_eq!(machine.run(1_000).unwrap(), StopReason::Ebreak);
+    assert_eq!(machine.harts[0].registers[10], 7);
+    assert_eq!(machine.harts[0].registers[11], 0x8001_0000);
+    assert_eq!(machine.harts[0].pc, 0x8001_0008);
+}
+
+/// A «fence.i» also picks up instructions written from outside of the CPU that weren't reported to the cache
+#[test]
+fn unreported_edit() {
+    let mut machine = create_rv32(
+        assemble("loop:\naddi a0, a0, 1\nfence.i\nj loop")
+            .unwrap()
+            .contents,
+    )
+    .unwrap();
+    // Stop right before the «fence.i», with the «addi» already cached
+    machine.breakpoints.insert(0x8000_0004);
+    assert_eq!(
+        machine.run(10).unwrap(),
+        StopReason::Breakpoint(0x8000_0004)
+    );
+    assert_eq!(machine.harts[0].registers[10], 1);
+    // «addi a0, a0, 2»
+    machine.memory.contents[..4].copy_from_slice(&0x0025_0513u32.to_le_bytes());
+    machine.breakpoints.clear();
+    machine.run(3).unwrap();
+    assert_eq!(machine.harts[0].registers[10], 1 + 2);
+}
@@ -0,0 +1,133 @@
+use emu::{
+    assembler::assemble,
+    create_rv32,
+    machine::{htif::Htif, Machine, StopReason},
+};
+
+/// Data section every program below talks to the host through
+const HOST_DATA: &str = "
+.data
+.align 3
+tohost: .word 0, 0
+fromhost: .word 0, 0
+magic: .space 64
+message: .ascii \"hi\\n\"
+";
+
+fn machine(program: &str) -> Machine {
+    let program = assemble(&(program.to_owned() + HOST_DATA)).unwrap();
+    let mut machine = create_rv32(program.contents).unwrap();
+    machine.htif =
+        Htif::from_symbols(|name| program.symbols.get(name).map(|&address| address as u32));
+    machine
+}
+
+/// Runs the machine until the program exits, returning its exit status
+fn run(machine: &mut Machine) -> u32 {
+    match machine.run(1000).unwrap() {
+        StopReason::HostExit(exit_code) => exit_code,
+        reason => panic!("The program didn't exit: {reason}"),
+    }
+}
+
+#[test]
+fn exit() {
+    let mut machine = machine(
+        "
+        li t0, 43
+        la t1, tohost
+        sw t0, 0(t1)
+        sw zero, 4(t1)
+    loop:
+        j loop
+    ",
+    );
+    assert_eq!(run(&mut machine), 21);
+    // The host acknowledges commands by clearing tohost
+    let tohost = machine.htif.as_ref().unwrap().tohost;
+    assert_eq!(machine.memory.contents[(tohost - 0x8000_0000) as usize], 0);
+}
+
+#[test]
+fn syscalls() {
+    let mut machine = machine(
+        "
+        # write(1, message, 3)
+        la a0, magic
+        li t0, 64
+        sw t0, 0(a0)
+        li t0, 1
+        sw t0, 8(a0)
+        la t0, message
+        sw t0, 16(a0)
+        li t0, 3
+        sw t0, 24(a0)
+        la t1, tohost
+        sw a0, 0(t1)
+        sw zero, 4(t1)
+        # The result is in magic[0] once fromhost is set
+        la t1, fromhost
+    wait:
+        lw t2, 0(t1)
+        beqz t2, wait
+        sw zero, 0(t1)
+        lw s0, 0(a0)
+        # exit(s0)
+        li t0, 93
+        sw t0, 0(a0)
+        sw s0, 8(a0)
+        la t1, tohost
+        sw a0, 0(t1)
+        sw zero, 4(t1)
+    loop:
+        j loop
+    ",
+    );
+    assert_eq!(run(&mut machine), 3);
+    assert_eq!(machine.htif.unwrap().output, b"hi\n");
+}
+
+#[test]
+fn unsupported_syscall() {
+    let mut machine = machine(
+        "
+        # Unknown system calls fail with -ENOSYS
+        la a0, magic
+        li t0, 1234
+        sw t0, 0(a0)
+        la t1, tohost
+        sw a0, 0(t1)
+        sw zero, 4(t1)
+        lw s0, 0(a0)
+        neg s0, s0
+        slli s0, s0, 1
+        ori s0, s0, 1
+        sw s0, 0(t1)
+        sw zero, 4(t1)
+    loop:
+        j loop
+    ",
+    );
+    assert_eq!(run(&mut machine), 38);
+}
+
+#[test]
+fn console() {
+    let mut machine = machine(
+        "
+        # Device 1, command 1: putchar
+        la t1, tohost
+        li t0, 0x41
+        sw t0, 0(t1)
+        li t0, 0x01010000
+        sw t0, 4(t1)
+        li t0, 1
+        sw t0, 0(t1)
+        sw zero, 4(t1)
+    loop:
+        j loop
+    ",
+    );
+    assert_eq!(run(&mut machine), 0);
+    assert_eq!(machine.htif.unwrap().output, b"A");
+}
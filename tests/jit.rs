@@ -0,0 +1,251 @@
+//! Runs programs both natively and interpreted, which must end up in the same state
+#![cfg(feature = "jit")]
+
+use std::collections::BTreeSet;
+
+use emu::{
+    assembler::assemble,
+    cpu::{
+        counters,
+        csr::{MCAUSE, MCYCLE, MEPC, MINSTRET, MTVAL},
+    },
+    create_rv32,
+    machine::memory::MisalignedAccesses,
+    memory::{AccessKind, MemoryBus},
+    Machine,
+};
+
+const PROGRAM: &str = "
+        li s0, 0x12345678
+        li s1, -7
+        li s2, 40
+    loop:
+        add t0, s0, s1
+        sub t1, s0, s1
+        sll t2, s0, s2
+        slt t3, s1, s0
+        sltu t4, s1, s0
+        xor t5, t0, t1
+        srl t6, s1, s2
+        sra a0, s1, s2
+        or a1, t2, t3
+        and a2, s0, s1
+        mul a3, s0, s1
+        addi a4, a4, -3
+        slti a5, a4, -100
+        sltiu a6, a4, 5
+        xori a7, s0, -1
+        ori s3, s1, 0x7f0
+        andi s4, s0, 0x0ff
+        slli s5, s0, 7
+        srli s6, s1, 3
+        srai s7, s1, 3
+        lui s8, 0xabcde
+        auipc s9, 0x10
+        jal s10, next
+    next:
+        addi s2, s2, 1
+        add s0, s0, a3
+        blt a4, zero, negative
+        bne s2, zero, loop
+    negative:
+        bgeu s1, s0, loop
+        ebreak
+";
+
+/// Loads and stores of every width, and calls returning through «jalr»
+const MEMORY_PROGRAM: &str = "
+        la s0, buffer
+        li s1, 0
+        li s2, 0x80
+    loop:
+        add t0, s0, s1
+        sb s2, 0(t0)
+        sh s2, 2(t0)
+        sw s1, 4(t0)
+        lb a0, 0(t0)
+        lbu a1, 0(t0)
+        lh a2, 2(t0)
+        lhu a3, 2(t0)
+        lw a4, 4(t0)
+        call accumulate
+        addi s1, s1, 8
+        addi s2, s2, 0x111
+        li t1, 256
+        blt s1, t1, loop
+        ebreak
+    accumulate:
+        add s3, s3, a0
+        add s4, s4, a1
+        add s5, s5, a2
+        add s6, s6, a3
+        xor s7, s7, a4
+        ret
+    buffer:
+        .zero 264
+";
+
+/// Accesses raising exceptions, which the handler skips, counting them
+const FAULTS_PROGRAM: &str = "
+        la t0, handler
+        csrw mtvec, t0
+        la s0, data
+        li s1, 0
+    loop:
+        lw a0, 1(s0)
+        sw a0, 2(s0)
+        lh a1, 3(s0)
+        lw a2, 0(zero)
+        sb a2, 0(zero)
+        addi s1, s1, 1
+        li t1, 20
+        blt s1, t1, loop
+        csrw mtvec, zero
+        ebreak
+    handler:
+        addi s2, s2, 1
+        csrr t2, mepc
+        addi t2, t2, 4
+        csrw mepc, t2
+        mret
+    data:
+        .word 0x11223344, 0x55667788
+";
+
+fn machine(program: &str) -> Machine {
+    create_rv32(assemble(program).unwrap().contents).unwrap()
+}
+
+/// Runs «program» natively and interpreted, «limit» instructions at a time for every limit, checking that both end up in the same state every time
+fn assert_same_state(program: &str, configure: impl Fn(&mut Machine), limits: &[usize]) {
+    let mut native = machine(program);
+    let mut interpreted = machine(program);
+    configure(&mut native);
+    configure(&mut interpreted);
+    for &limit in limits {
+        let native_reason = native.run(limit).unwrap();
+        let interpreted_reason = interpreted.run_until(limit, |_, _| false).unwrap();
+        assert_eq!(native_reason, interpreted_reason);
+        let (native_hart, interpreted_hart) = (&native.harts[0], &interpreted.harts[0]);
+        assert_eq!(native_hart.pc, interpreted_hart.pc);
+        assert_eq!(native_hart.registers, interpreted_hart.registers);
+        for counter in [MCYCLE, MINSTRET] {
+            assert_eq!(
+                counters::read(&native_hart.csrs, counter),
+                counters::read(&interpreted_hart.csrs, counter)
+            );
+        }
+        for csr in [MCAUSE, MEPC, MTVAL] {
+            assert_eq!(native_hart.csrs.get(csr), interpreted_hart.csrs.get(csr));
+        }
+        let size = native.memory.size();
+        let (mut native_memory, mut interpreted_memory) = (vec![0; size], vec![0; size]);
+        native.memory.read_bytes(0, &mut native_memory).unwrap();
+        interpreted
+            .memory
+            .read_bytes(0, &mut interpreted_memory)
+            .unwrap();
+        assert!(native_memory == interpreted_memory);
+    }
+}
+
+#[test]
+fn same_state_as_interpreted() {
+    assert_same_state(PROGRAM, |_| {}, &[1, 7, 100, 1000, 10_000]);
+}
+
+#[test]
+fn loads_and_stores() {
+    assert_same_state(MEMORY_PROGRAM, |_| {}, &[1, 9, 100, 1000, 10_000]);
+}
+
+/// The faulting accesses are left to the interpreter, which takes the trap at the right instruction
+#[test]
+fn precise_exceptions() {
+    let trap = |machine: &mut Machine| {
+        machine.memory.misaligned_accesses = MisalignedAccesses::Trap;
+    };
+    assert_same_state(FAULTS_PROGRAM, trap, &[1, 3, 50, 1000]);
+    assert_same_state(FAULTS_PROGRAM, |_| {}, &[1, 3, 50, 1000]);
+}
+
+/// «ebreak»
+const EBREAK: u32 = 0x0010_0073;
+
+/// Address of the label «loop» in «program»
+fn loop_start(program: &str) -> u32 {
+    assemble(program).unwrap().symbols["loop"] as u32
+}
+
+/// A machine running «program», interpreted for «warm_up» instructions so that the blocks it enters often are translated, then up to the start of its loop
+fn warmed_up(program: &str, warm_up: usize) -> Machine {
+    let mut machine = machine(program);
+    let start = loop_start(program);
+    machine.run_until(warm_up, |_, _| false).unwrap();
+    machine
+        .run_until(1000, |machine, _| machine.harts[0].pc == start)
+        .unwrap();
+    machine
+}
+
+#[test]
+fn runs_natively() {
+    let mut machine = warmed_up(PROGRAM, 1000);
+    let mut memory_bus = MemoryBus::new(&mut machine.memory);
+    // As far as the budget allows, without going over it
+    let run = machine.harts[0].advance_native(&mut memory_bus, 1000, None, false);
+    assert!((970..=1000).contains(&run.executed));
+    assert!(run.writes.is_empty());
+}
+
+/// Blocks are only translated once the interpreter entered them often enough
+#[test]
+fn translates_hot_blocks_only() {
+    let mut machine = machine(PROGRAM);
+    let pc = machine.harts[0].pc;
+    let mut memory_bus = MemoryBus::new(&mut machine.memory);
+    let run = machine.harts[0].advance_native(&mut memory_bus, 1000, None, false);
+    assert_eq!(run.executed, 0);
+    // The block at the start is only entered once, the loop many times
+    machine.run_until(1000, |_, _| false).unwrap();
+    assert!(!machine.harts[0].has_native(pc));
+    assert!(machine.harts[0].has_native(loop_start(PROGRAM)));
+}
+
+/// Everything up to the «ebreak» runs natively, loads, stores and calls included
+#[test]
+fn runs_memory_accesses_natively() {
+    let mut machine = warmed_up(MEMORY_PROGRAM, 400);
+    let mut memory_bus = MemoryBus::new(&mut machine.memory);
+    let run = machine.harts[0].advance_native(&mut memory_bus, 10_000, None, false);
+    assert!(run.executed > 250);
+    let pc = machine.harts[0].pc;
+    assert_eq!(
+        MemoryBus::new(&mut machine.memory).read_u32(pc, AccessKind::Fetch),
+        Ok(EBREAK)
+    );
+}
+
+/// The instructions told to have run natively, along with the ones observed, are the ones the interpreter executes
+#[test]
+fn natively_executed() {
+    let mut native = machine(PROGRAM);
+    let mut executed = BTreeSet::new();
+    native
+        .run_observed(10_000, |record| {
+            executed.insert(record.pc);
+        })
+        .unwrap();
+    native.harts[0].natively_executed(|pc| {
+        executed.insert(pc);
+    });
+    let mut interpreted = machine(PROGRAM);
+    let mut expected = BTreeSet::new();
+    interpreted
+        .run_until(10_000, |_, record| {
+            expected.insert(record.pc);
+            false
+        })
+        .unwrap();
+    assert_eq!(executed, expected);
+}
@@ -0,0 +1,211 @@
+//! Classic litmus tests, telling a sequentially consistent memory from one reordering stores through store buffers
+
+use emu::{
+    assembler::assemble,
+    create_rv32,
+    machine::{
+        litmus::{enumerate, sample, Litmus, Outcomes},
+        memory::{AccessKind, MemoryBus},
+        MemoryModel, StopReason,
+    },
+};
+
+const DATA: &str = "
+    x:
+        .word 0
+    y:
+        .word 0
+";
+
+/// Every outcome of the test made of «harts», observing a0 and a1 of the last one (and a0 of the first)
+fn outcomes(harts: [&str; 2], memory_model: MemoryModel) -> Outcomes {
+    let litmus = Litmus {
+        harts: harts.map(str::to_owned).to_vec(),
+        data: DATA.to_owned(),
+        registers: vec![(0, 10), (1, 10), (1, 11)],
+        words: vec![],
+    };
+    enumerate(&litmus, memory_model).unwrap()
+}
+
+/// Store buffering: each hart stores to a variable, then loads the other one
+fn store_buffering(fence: &str) -> [String; 2] {
+    [("x", "y"), ("y", "x")].map(|(stored, loaded)| {
+        format!(
+            "
+            la s0, {stored}
+            la s1, {loaded}
+            li t0, 1
+            sw t0, 0(s0)
+            {fence}
+            lw a0, 0(s1)
+            "
+        )
+    })
+}
+
+#[test]
+fn store_buffering_outcomes() {
+    let [first, second] = store_buffering("");
+    let harts = [first.as_str(), second.as_str()];
+    let sequential = outcomes(harts, MemoryModel::SequentiallyConsistent);
+    assert!(sequential.contains(&[0, 1, 0]));
+    assert!(sequential.contains(&[1, 0, 0]));
+    assert!(sequential.contains(&[1, 1, 0]));
+    // Both loads can only read 0 if they are performed before both stores
+    assert!(!sequential.contains(&[0, 0, 0]));
+    assert_eq!(sequential.incomplete, 0);
+    // Four memory accesses in any order that keeps the program order of each hart
+    assert_eq!(sequential.runs(), 6);
+    let relaxed = outcomes(harts, MemoryModel::Rvwmo);
+    assert!(relaxed.contains(&[0, 0, 0]));
+    assert_eq!(relaxed.counts.len(), 4);
+
+    let [first, second] = store_buffering("fence rw, rw");
+    let fenced = outcomes([&first, &second], MemoryModel::Rvwmo);
+    assert!(!fenced.contains(&[0, 0, 0]));
+    assert_eq!(fenced.counts.len(), 3);
+}
+
+/// Message passing: the first hart writes the data then sets a flag, the second reads the flag then the data
+fn message_passing(fence: &str) -> [String; 2] {
+    [
+        format!(
+            "
+            la s0, x
+            la s1, y
+            li t0, 1
+            sw t0, 0(s0)
+            {fence}
+            sw t0, 0(s1)
+            "
+        ),
+        format!(
+            "
+            la s0, x
+            la s1, y
+            lw a0, 0(s1)
+            {fence}
+            lw a1, 0(s0)
+            "
+        ),
+    ]
+}
+
+#[test]
+fn message_passing_outcomes() {
+    let [first, second] = message_passing("");
+    let harts = [first.as_str(), second.as_str()];
+    // Seeing the flag without the data
+    let stale = [0, 1, 0];
+    assert!(!outcomes(harts, MemoryModel::SequentiallyConsistent).contains(&stale));
+    assert!(outcomes(harts, MemoryModel::Rvwmo).contains(&stale));
+    let [first, second] = message_passing("fence rw, rw");
+    let fenced = outcomes([&first, &second], MemoryModel::Rvwmo);
+    assert!(!fenced.contains(&stale));
+    assert!(fenced.contains(&[0, 1, 1]));
+    assert!(fenced.contains(&[0, 0, 0]));
+}
+
+/// A hart loads its own stores before they reach memory, and stores to the same word reach it in program order
+#[test]
+fn own_stores() {
+    let hart = "
+        la s0, x
+        li t0, 1
+        sw t0, 0(s0)
+        li t0, 2
+        sh t0, 0(s0)
+        lw a0, 0(s0)
+    ";
+    let litmus = Litmus {
+        harts: vec![hart.to_owned()],
+        data: DATA.to_owned(),
+        registers: vec![(0, 10)],
+        words: vec!["x".to_owned()],
+    };
+    let outcomes = enumerate(&litmus, MemoryModel::Rvwmo).unwrap();
+    assert_eq!(outcomes.counts.keys().collect::<Vec<_>>(), [&vec![2, 2]]);
+}
+
+/// Two harts incrementing a counter in a critical section, guarded by a spinlock taken with «amoswap.w.aq» and released with «release»
+fn mutex(release: &str) -> Outcomes {
+    let hart = format!(
+        "
+        la s0, lock
+        la s1, counter
+        li t0, 1
+    acquire:
+        amoswap.w.aq t1, t0, (s0)
+        bnez t1, acquire
+        lw t2, 0(s1)
+        addi t2, t2, 1
+        sw t2, 0(s1)
+        {release}
+        "
+    );
+    let litmus = Litmus {
+        harts: vec![hart.clone(), hart.replace("acquire", "acquire_")],
+        data: "
+        lock:
+            .word 0
+        counter:
+            .word 0
+        "
+        .to_owned(),
+        registers: vec![],
+        words: vec!["counter".to_owned()],
+    };
+    sample(&litmus, MemoryModel::Rvwmo, 2_000, 1).unwrap()
+}
+
+/// Without a fence, the store releasing the lock can reach memory before the one to the counter, so the other hart increments the old value
+#[test]
+fn mutex_needs_fence() {
+    let broken = mutex("sw zero, 0(s0)");
+    assert!(broken.contains(&[1]));
+    assert!(broken.contains(&[2]));
+    for release in [
+        "fence rw, rw\nsw zero, 0(s0)",
+        "amoswap.w.rl zero, zero, (s0)",
+    ] {
+        let fixed = mutex(release);
+        assert_eq!(
+            fixed.counts.keys().collect::<Vec<_>>(),
+            [&vec![2]],
+            "{release}"
+        );
+        assert_eq!(fixed.incomplete, 0);
+    }
+}
+
+/// A program running on its own isn't affected by its stores waiting in the store buffer, which reach memory at random during a run
+#[test]
+fn single_hart_run() {
+    let program = "
+        la s0, data
+        li s1, 50
+    loop:
+        lw t0, 0(s0)
+        addi t0, t0, 3
+        sw t0, 0(s0)
+        sw t0, 4(s0)
+        addi s1, s1, -1
+        bnez s1, loop
+        lw a0, 4(s0)
+        ebreak
+    data:
+        .word 0
+        .word 0
+    ";
+    let program = assemble(program).unwrap();
+    let data = program.symbols["data"] as u32;
+    let mut machine = create_rv32(program.contents).unwrap();
+    machine.memory_model = MemoryModel::Rvwmo;
+    assert_eq!(machine.run(1_000).unwrap(), StopReason::Ebreak);
+    assert_eq!(machine.harts[0].registers[10], 150);
+    machine.drain_store_buffers();
+    assert!(machine.store_buffers[0].is_empty());
+    let memory_bus = MemoryBus::new(&mut machine.memory);
+    assert_eq!(memory_bus.read_u32(data, AccessKind::Load).unwrap(), 150);
+}
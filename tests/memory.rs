@@ -0,0 +1,171 @@
+use emu::{
+    assembler::assemble,
+    create_rv32,
+    machine::{
+        cpu::trap::{Exception, Trap},
+        memory::{
+            constants::{MEMORY_SIZE, RAM_BASE},
+            AccessKind::{Fetch, Load, Store},
+            Memory, MemoryBus, MemoryError, MisalignedAccesses, TooLarge,
+        },
+        Machine, StopReason,
+    },
+};
+
+fn machine(program: &str, misaligned_accesses: MisalignedAccesses) -> Machine {
+    let mut machine = create_rv32(assemble(program).unwrap().contents).unwrap();
+    machine.memory.misaligned_accesses = misaligned_accesses;
+    machine
+}
+
+#[test]
+fn every_width() {
+    let mut memory = Memory::new(vec![]).unwrap();
+    let mut memory_bus = MemoryBus::new(&mut memory);
+    let base = RAM_BASE as u32;
+    memory_bus
+        .write_u64(base + 8, 0x0123_4567_89ab_cdef, Store)
+        .unwrap();
+    assert_eq!(memory_bus.read_u8(base + 8, Load).unwrap(), 0xef);
+    assert_eq!(memory_bus.read_u16(base + 8, Load).unwrap(), 0xcdef);
+    assert_eq!(memory_bus.read_u32(base + 12, Load).unwrap(), 0x0123_4567);
+    memory_bus.write_u8(base + 9, 0xff, Store).unwrap();
+    memory_bus.write_u16(base + 10, 0x2345, Store).unwrap();
+    assert_eq!(
+        memory_bus.read_u64(base + 8, Load).unwrap(),
+        0x0123_4567_2345_ffef
+    );
+}
+
+#[test]
+fn bulk_accesses() {
+    let mut memory = Memory::new(vec![]).unwrap();
+    memory.misaligned_accesses = MisalignedAccesses::Trap;
+    let mut memory_bus = MemoryBus::new(&mut memory);
+    let base = RAM_BASE as u32;
+    // Bulk accesses are never misaligned
+    memory_bus
+        .write_bytes(base + 3, b"Hello, world", Store)
+        .unwrap();
+    let mut bytes = [0; 5];
+    memory_bus.read_bytes(base + 10, &mut bytes, Load).unwrap();
+    assert_eq!(&bytes, b"world");
+    assert_eq!(memory_bus.read_u32(base, Load).unwrap(), 0x4800_0000);
+}
+
+#[test]
+fn out_of_bounds() {
+    let mut memory = Memory::new(vec![]).unwrap();
+    let mut memory_bus = MemoryBus::new(&mut memory);
+    let end = (RAM_BASE + MEMORY_SIZE) as u32;
+    assert!(memory_bus.read_u64(end - 8, Load).is_ok());
+    // Accesses with only some of their bytes outside of memory fail as a whole
+    assert_eq!(
+        memory_bus.read_u32(end - 2, Load),
+        Err(MemoryError::Unmapped {
+            address: end - 2,
+            kind: Load
+        })
+    );
+    assert!(memory_bus.write_u64(end - 4, u64::MAX, Store).is_err());
+    assert_eq!(memory_bus.read_u32(end - 4, Load).unwrap(), 0);
+    assert!(memory_bus.write_bytes(end - 1, &[1, 2], Store).is_err());
+    assert_eq!(
+        memory_bus.read_u16(RAM_BASE as u32 - 1, Fetch),
+        Err(MemoryError::Unmapped {
+            address: RAM_BASE as u32 - 1,
+            kind: Fetch
+        })
+    );
+    assert!(memory_bus.read_u32(u32::MAX, Load).is_err());
+}
+
+#[test]
+fn transparent_misaligned_accesses() {
+    let mut machine = machine(
+        "
+        li t0, 0x80001001
+        li t1, 0x12345678
+        sw t1, 0(t0)
+        lh a0, 1(t0)
+        lw a1, 0(t0)
+        ebreak
+        ",
+        MisalignedAccesses::Transparent,
+    );
+    assert_eq!(machine.run(100).unwrap(), StopReason::Ebreak);
+    assert_eq!(machine.harts[0].registers[10], 0x3456);
+    assert_eq!(machine.harts[0].registers[11], 0x1234_5678);
+}
+
+#[test]
+fn trapping_misaligned_load() {
+    let mut machine = machine("li t0, 0x80001002\nlw a0, 0(t0)", MisalignedAccesses::Trap);
+    assert_eq!(
+        machine.run(100).unwrap(),
+        StopReason::UnhandledTrap {
+            pc: 0x8000_0008,
+            trap: Trap::new(Exception::LoadAddressMisaligned, 0x8000_1002)
+        }
+    );
+}
+
+#[test]
+fn trapping_misaligned_store() {
+    let mut machine = machine(
+        "li t0, 0x80001001\nsh zero, 0(t0)",
+        MisalignedAccesses::Trap,
+    );
+    assert_eq!(
+        machine.run(100).unwrap(),
+        StopReason::UnhandledTrap {
+            pc: 0x8000_0008,
+            trap: Trap::new(Exception::StoreAddressMisaligned, 0x8000_1001)
+        }
+    );
+}
+
+/// Misaligned accesses are reported as such even outside of memory, as in the specification
+#[test]
+fn misaligned_before_access_fault() {
+    let mut machine = machine("li t0, 0x1001\nlh a0, 0(t0)", MisalignedAccesses::Trap);
+    assert_eq!(
+        machine.run(100).unwrap(),
+        StopReason::UnhandledTrap {
+            pc: 0x8000_0008,
+            trap: Trap::new(Exception::LoadAddressMisaligned, 0x1001)
+        }
+    );
+}
+
+/// Atomic memory operations fail like stores, even when the read is what fails
+#[test]
+fn amo_access_fault() {
+    let mut machine = machine(
+        "li t0, 0x1000\namoadd.w a0, a1, (t0)",
+        MisalignedAccesses::Transparent,
+    );
+    assert_eq!(
+        machine.run(100).unwrap(),
+        StopReason::UnhandledTrap {
+            pc: 0x8000_0004,
+            trap: Trap::new(Exception::StoreAccessFault, 0x1000)
+        }
+    );
+}
+
+#[test]
+fn rejects_dumps_bigger_than_memory() {
+    assert_eq!(
+        Memory::new(vec![1; MEMORY_SIZE]).unwrap().size(),
+        MEMORY_SIZE
+    );
+    let error = Memory::new(vec![0; MEMORY_SIZE + 1]).err().unwrap();
+    assert_eq!(
+        error,
+        TooLarge {
+            size: MEMORY_SIZE + 1
+        }
+    );
+    assert!(create_rv32(vec![0; MEMORY_SIZE + 4]).is_err());
+}
@@ -0,0 +1,130 @@
+use emu::{
+    assembler::assemble,
+    create_rv32,
+    machine::{
+        pipeline::{Pipeline, PipelineConfig, PipelineStatistics, StageCycles},
+        Machine, StopReason,
+    },
+};
+
+fn run(program: &str, forwarding: bool) -> Machine {
+    let mut machine = create_rv32(assemble(program).unwrap().contents).unwrap();
+    machine.pipeline = Some(Pipeline::new(PipelineConfig { forwarding }));
+    assert_eq!(machine.run(100).unwrap(), StopReason::Ebreak);
+    machine
+}
+
+fn statistics(machine: &Machine) -> PipelineStatistics {
+    machine.pipeline.as_ref().unwrap().statistics
+}
+
+/// Stages of the non flushed instruction at «pc»
+fn cycles(machine: &Machine, pc: u32) -> StageCycles {
+    let pipeline = machine.pipeline.as_ref().unwrap();
+    pipeline
+        .history
+        .iter()
+        .find(|entry| entry.pc == pc && !entry.flushed)
+        .unwrap()
+        .cycles
+}
+
+/// Independent instructions enter a stage every cycle, so only filling the pipeline takes time
+#[test]
+fn independent_instructions() {
+    let machine = run("li a0, 1\nli a1, 2\nli a2, 3\nebreak", true);
+    let statistics = statistics(&machine);
+    assert_eq!(statistics.instructions, 4);
+    assert_eq!(statistics.cycles, 4 + 4);
+    assert_eq!(statistics.stalls, 0);
+    // The «ebreak» traps, flushing what was fetched after it
+    assert_eq!(statistics.flushed, 2);
+    assert_eq!(
+        cycles(&machine, 0x8000_0008),
+        StageCycles {
+            fetch: 3,
+            decode: 4,
+            execute: 5,
+            memory: 6,
+            writeback: 7
+        }
+    );
+}
+
+/// A load result is forwarded from the end of MEM, so the instruction using it right away waits a cycle in ID
+#[test]
+fn load_use_with_forwarding() {
+    let machine = run(
+        "lui t0, 0x80001\nlw t1, 0(t0)\naddi t2, t1, 1\nebreak",
+        true,
+    );
+    let statistics = statistics(&machine);
+    assert_eq!(statistics.stalls, 1);
+    assert_eq!(statistics.cycles, 9);
+    let pipeline = machine.pipeline.as_ref().unwrap();
+    let addi = pipeline.history[2];
+    assert_eq!(addi.pc, 0x8000_0008);
+    let stages: Vec<_> = (1..=9).map(|cycle| addi.stage(cycle)).collect();
+    assert_eq!(
+        stages,
+        [
+            None,
+            None,
+            Some("IF"),
+            Some("ID"),
+            Some("ID"),
+            Some("EX"),
+            Some("MEM"),
+            Some("WB"),
+            None
+        ]
+    );
+    assert!(addi.stalled(5));
+    assert!(!addi.stalled(4));
+}
+
+/// Without forwarding, operands are read in ID in the same cycle as they are written back
+#[test]
+fn without_forwarding() {
+    let machine = run(
+        "lui t0, 0x80001\nlw t1, 0(t0)\naddi t2, t1, 1\nebreak",
+        false,
+    );
+    let statistics = statistics(&machine);
+    // Two stall cycles for each dependency on the previous instruction
+    assert_eq!(statistics.stalls, 4);
+    assert_eq!(statistics.cycles, 12);
+    let addi = cycles(&machine, 0x8000_0008);
+    // The «lw» is written back in cycle 8
+    assert_eq!(addi.decode, 6);
+    assert_eq!(addi.execute, 9);
+    // The architectural state doesn't depend on the pipeline
+    assert_eq!(machine.harts[0].registers[7], 1);
+}
+
+/// A taken jump is resolved in EX, after the two instructions following it were fetched
+#[test]
+fn taken_jump() {
+    let machine = run("j skip\nli a0, 1\nli a0, 2\nskip:\nebreak", true);
+    let pipeline = machine.pipeline.as_ref().unwrap();
+    let flushed: Vec<_> = pipeline.history.iter().take(3).collect();
+    assert!(!flushed[0].flushed);
+    assert!(flushed[1].flushed && flushed[2].flushed);
+    assert_eq!(flushed[1].pc, 0x8000_0004);
+    assert_eq!(
+        (1..=4)
+            .map(|cycle| flushed[1].stage(cycle))
+            .collect::<Vec<_>>(),
+        [None, Some("IF"), Some("ID"), None]
+    );
+    assert_eq!(
+        (1..=4)
+            .map(|cycle| flushed[2].stage(cycle))
+            .collect::<Vec<_>>(),
+        [None, None, Some("IF"), None]
+    );
+    // Fetched once the jump left EX
+    assert_eq!(cycles(&machine, 0x8000_000c).fetch, 4);
+    assert_eq!(statistics(&machine).cycles, 8);
+    assert_eq!(machine.harts[0].registers[10], 0);
+}
@@ -0,0 +1,169 @@
+use emu::{
+    assembler::assemble,
+    create_rv32,
+    machine::{
+        cpu::{
+            counters,
+            csr::{MCYCLE, MINSTRET},
+        },
+        profiler::{FunctionSamples, Functions, Profiler},
+        StopReason,
+    },
+};
+
+/// Every event counter counting a different event, in a loop loading and storing on every iteration
+#[test]
+fn event_counters() {
+    let program = "
+        li t0, 1
+        csrw mhpmevent3, t0
+        li t0, 2
+        csrw mhpmevent4, t0
+        li t0, 3
+        csrw mhpmevent5, t0
+        li t0, 4
+        csrw mhpmevent6, t0
+        la s0, data
+        li s1, 10
+    loop:
+        lw t1, 0(s0)
+        addi t1, t1, 1
+        sw t1, 0(s0)
+        addi s1, s1, -1
+        bnez s1, loop
+        csrr a0, mhpmcounter3
+        csrr a1, mhpmcounter4
+        csrr a2, mhpmcounter5
+        csrr a3, mhpmcounter6
+        rdinstret a4
+        csrr a5, hpmcounter3
+        ebreak
+    data:
+        .word 0
+    ";
+    let mut machine = create_rv32(assemble(program).unwrap().contents).unwrap();
+    assert_eq!(machine.run(1_000).unwrap(), StopReason::Ebreak);
+    let registers = &machine.harts[0].registers;
+    assert_eq!(registers[10..=13], [10, 10, 10, 9]);
+    // A counter doesn't count the instruction reading it yet
+    assert_eq!(registers[14], 11 + 10 * 5 + 4);
+    assert_eq!(registers[15], 10);
+    // The «ebreak» doesn't retire, but takes a cycle
+    assert_eq!(
+        counters::read(&machine.harts[0].csrs, MINSTRET),
+        11 + 10 * 5 + 6
+    );
+    assert_eq!(
+        counters::read(&machine.harts[0].csrs, MCYCLE),
+        11 + 10 * 5 + 7
+    );
+}
+
+#[test]
+fn writes_and_inhibition() {
+    let program = "
+        # Stops minstret, but not mcycle
+        li t0, 0b100
+        csrw mcountinhibit, t0
+        nop
+        nop
+        csrw mcycle, zero
+        csrr a0, mcycle
+        rdinstret a1
+        # The low half carries into the high one
+        li t0, -1
+        csrw mcycle, t0
+        rdcycleh a2
+        rdcycleh a3
+        ebreak
+    ";
+    let mut machine = create_rv32(assemble(program).unwrap().contents).unwrap();
+    assert_eq!(machine.run(100).unwrap(), StopReason::Ebreak);
+    let registers = &machine.harts[0].registers;
+    // The counter written keeps the value written
+    assert_eq!(registers[10], 0);
+    // Only the «li» was counted
+    assert_eq!(registers[11], 1);
+    assert_eq!(registers[12..=13], [0, 1]);
+}
+
+#[test]
+fn read_only_copies() {
+    let mut machine = create_rv32(assemble("csrw cycle, zero").unwrap().contents).unwrap();
+    assert_eq!(
+        machine.run(100).unwrap(),
+        StopReason::IllegalInstruction {
+            pc: 0x8000_0000,
+            raw: 0xc000_1073
+        }
+    );
+}
+
+const CALLS: &str = "
+    main:
+        li s1, 3
+    loop:
+        call helper
+        addi s1, s1, -1
+        bnez s1, loop
+        ebreak
+    helper:
+        addi a0, a0, 1
+        addi a0, a0, 1
+        ret
+";
+
+/// Samples of «main» and «helper», with a profiler sampling every «period» instructions
+fn profile(period: u64) -> (Profiler, Vec<FunctionSamples>) {
+    let program = assemble(CALLS).unwrap();
+    // «loop» is only a label, it isn't taken as a function
+    let functions = Functions::new(
+        program
+            .symbols
+            .into_iter()
+            .filter(|(name, _)| name != "loop")
+            .map(|(name, address)| (name, address as u32)),
+    );
+    let mut machine = create_rv32(program.contents).unwrap();
+    machine.profiler = Some(Profiler::new(period));
+    assert_eq!(machine.run(100).unwrap(), StopReason::Ebreak);
+    let profiler = machine.profiler.unwrap();
+    let profile = profiler.flat_profile(&functions);
+    (profiler, profile)
+}
+
+#[test]
+fn flat_profile() {
+    let (profiler, profile) = profile(1);
+    // The «ebreak» didn't retire
+    assert_eq!(profiler.total, 1 + 3 * 4 + 3 * 3);
+    assert_eq!(
+        profile,
+        [
+            FunctionSamples {
+                name: Some("main".to_owned()),
+                samples: 1 + 3 * 4
+            },
+            FunctionSamples {
+                name: Some("helper".to_owned()),
+                samples: 3 * 3
+            }
+        ]
+    );
+    // Every instruction in the loop ran three times, the first (the «auipc» of the «call») comes first
+    assert_eq!(profiler.hot_addresses()[0], (0x8000_0004, 3));
+}
+
+#[test]
+fn sampling_period() {
+    let (profiler, profile) = profile(2);
+    assert_eq!(profiler.total, (1 + 3 * 4 + 3 * 3) / 2);
+    assert_eq!(
+        profile.iter().map(|function| function.samples).sum::<u64>(),
+        profiler.total
+    );
+    // Outside of any function
+    let functions = Functions::new([("helper".to_owned(), 0x8000_0020)]);
+    assert_eq!(functions.containing(0x8000_0000), None);
+    assert_eq!(functions.containing(0x8000_0028), Some("helper"));
+}
@@ -0,0 +1,181 @@
+//! Runs the riscv-tests ELF images in «tests/riscv-tests/isa», see the README there on how they are built
+//!
+//! Every test reports the end of its run through the host interface: it exits with status 0 if it passed, or with the number of the failed test case
+
+use std::{fs, path::Path};
+
+use emu::{
+    create_rv32,
+    elf::Executable,
+    machine::{htif::Htif, memory::MisalignedAccesses, Machine, StopReason},
+};
+
+/// Every test ends in a few thousand instructions, the rest is a test stuck in a loop
+const STEP_LIMIT: usize = 100_000;
+
+/// Runs the test in the image «name» on a machine set up by «configure», telling why it didn't pass if it didn't
+fn run(name: &str, configure: impl FnOnce(&mut Machine)) -> Result<(), String> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/riscv-tests/isa")
+        .join(name);
+    let bytes =
+        fs::read(&path).unwrap_or_else(|error| panic!("Couldn't read {}: {error}", path.display()));
+    let executable = Executable::parse(&bytes).unwrap();
+    let mut machine = create_rv32(executable.memory_dump().unwrap()).unwrap();
+    configure(&mut machine);
+    machine.reset_to(executable.entry);
+    machine.htif = Htif::from_symbols(|name| executable.symbols.get(name).copied());
+
+    match machine.run(STEP_LIMIT) {
+        Ok(StopReason::HostExit(0)) => Ok(()),
+        Ok(StopReason::HostExit(test)) => Err(format!("{name}: test #{test} failed")),
+        Ok(reason) => Err(format!(
+            "{name}: {reason} (pc = 0x{:08x})",
+            machine.harts[0].pc
+        )),
+        Err(error) => Err(format!(
+            "{name}: {error:?} at 0x{:08x}",
+            machine.harts[0].pc
+        )),
+    }
+}
+
+fn passes(name: &str) {
+    if let Err(error) = run(name, |_| {}) {
+        panic!("{error}");
+    }
+}
+
+/// Checks that the test in the image «name» still fails on a machine set up by «configure», for «reason»
+///
+/// One that starts passing fails instead, so that it's moved to its suite
+fn fails(name: &str, configure: impl FnOnce(&mut Machine), reason: &str) {
+    if run(name, configure).is_ok() {
+        panic!("{name} passed, but is expected to fail: {reason}");
+    }
+}
+
+/// Declares a module for the suite, with a test for every ELF image in it
+///
+/// Tests are named after their image, unless it isn't a valid identifier: its name then follows, in quotes
+macro_rules! suite {
+    ($suite:ident: $($test:ident $(= $image:literal)?),* $(,)?) => {
+        mod $suite {
+            $(
+                #[test]
+                fn $test() {
+                    super::passes(image!($suite, $test $(, $image)?));
+                }
+            )*
+        }
+    };
+}
+
+/// Name of the ELF image of «test» in «suite», or the one given
+macro_rules! image {
+    ($suite:ident, $test:ident) => {
+        concat!(stringify!($suite), "-p-", stringify!($test))
+    };
+    ($suite:ident, $test:ident, $image:literal) => {
+        concat!(stringify!($suite), "-p-", $image)
+    };
+}
+
+suite!(rv32ui:
+    add, addi, and, andi, auipc, beq, bge, bgeu, blt, bltu, bne, fence_i, jal, jalr, lb, lbu, lh,
+    lhu, lui, lw, ma_data, or, ori, sb, sh, simple, sll, slli, slt, slti, sltiu, sltu, sra, srai,
+    srl, srli, sub, sw, xor, xori,
+);
+
+suite!(rv32um: div, divu, mul, mulh, mulhsu, mulhu, rem, remu);
+
+suite!(rv32ua:
+    amoadd_w, amoand_w, amomax_w, amomaxu_w, amomin_w, amominu_w, amoor_w, amoswap_w,
+    amoxor_w, lrsc,
+);
+
+suite!(rv32mi:
+    csr, illegal, instret_overflow, lh_misaligned = "lh-misaligned", lw_misaligned = "lw-misaligned",
+    ma_addr, ma_fetch, mcsr, sbreak, scall, sh_misaligned = "sh-misaligned", shamt,
+    sw_misaligned = "sw-misaligned",
+);
+
+/// The tests of misaligned accesses also pass when they trap, their handlers emulating them or checking the trap
+mod misaligned_accesses_trapping {
+    use super::*;
+
+    fn passes(name: &str) {
+        let trap = |machine: &mut Machine| {
+            machine.memory.misaligned_accesses = MisalignedAccesses::Trap;
+        };
+        if let Err(error) = run(name, trap) {
+            panic!("{error}");
+        }
+    }
+
+    #[test]
+    fn ma_addr() {
+        passes("rv32mi-p-ma_addr");
+    }
+
+    #[test]
+    fn lh_misaligned() {
+        passes("rv32mi-p-lh-misaligned");
+    }
+
+    #[test]
+    fn lw_misaligned() {
+        passes("rv32mi-p-lw-misaligned");
+    }
+
+    #[test]
+    fn sh_misaligned() {
+        passes("rv32mi-p-sh-misaligned");
+    }
+
+    #[test]
+    fn sw_misaligned() {
+        passes("rv32mi-p-sw-misaligned");
+    }
+}
+
+/// Tests that can't pass on some configurations of the machine, with the reason
+mod expected_fail {
+    use super::*;
+
+    #[test]
+    fn breakpoint() {
+        fails(
+            "rv32mi-p-breakpoint",
+            |_| {},
+            "there's no trigger module, so accessing tselect raises an illegal instruction exception",
+        );
+    }
+
+    #[test]
+    fn pmpaddr() {
+        fails(
+            "rv32mi-p-pmpaddr",
+            |_| {},
+            "PMP isn't implemented, and its CSRs don't even read as zero",
+        );
+    }
+
+    #[test]
+    fn zicntr() {
+        fails(
+            "rv32mi-p-zicntr",
+            |_| {},
+            "there's no timer, so the time and timeh CSRs aren't implemented",
+        );
+    }
+
+    #[test]
+    fn ma_data_with_misaligned_accesses_trapping() {
+        fails(
+            "rv32ui-p-ma_data",
+            |machine| machine.memory.misaligned_accesses = MisalignedAccesses::Trap,
+            "the p environment doesn't emulate misaligned accesses in its trap handler",
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Guest programs running on several harts at once, incrementing a shared counter
+//!
+//! Every hart increments «counter» «ITERATIONS» times, then counts itself in «done»; the first hart waits for all of them before stopping at an «ebreak», while the others spin.
+
+use emu::{
+    assembler::assemble,
+    cpu::csr::MHARTID,
+    create_rv32,
+    machine::{Machine, Scheduling, StopReason},
+    memory::{AccessKind, MemoryBus},
+};
+
+const ITERATIONS: u32 = 100;
+
+/// Runs «increment» (which increments the word at s1, using s0 as the loop counter) on «harts» harts
+fn run(increment: &str, harts: usize, scheduling: Scheduling) -> (Machine, u32) {
+    let program = format!(
+        "
+        csrr a0, mhartid
+        li s0, {ITERATIONS}
+        la s1, counter
+        la s2, done
+        la s3, lock
+        {increment}
+        li t2, 1
+        amoadd.w zero, t2, (s2)
+        bnez a0, park
+    wait:
+        lw t3, 0(s2)
+        li t4, {harts}
+        bne t3, t4, wait
+        ebreak
+    park:
+        j park
+    counter:
+        .word 0
+    done:
+        .word 0
+    lock:
+        .word 0
+        "
+    );
+    let program = assemble(&program).unwrap();
+    let counter = program.symbols["counter"] as u32;
+    let mut machine = create_rv32(program.contents).unwrap();
+    machine.set_harts(harts);
+    machine.scheduling = scheduling;
+    assert_eq!(machine.run(1_000_000).unwrap(), StopReason::Ebreak);
+    assert_eq!(machine.current, 0);
+    let value = MemoryBus::new(&mut machine.memory)
+        .read_u32(counter, AccessKind::Load)
+        .unwrap();
+    (machine, value)
+}
+
+const LR_SC: &str = "
+    increment:
+        lr.w t0, (s1)
+        addi t0, t0, 1
+        sc.w t1, t0, (s1)
+        bnez t1, increment
+        addi s0, s0, -1
+        bnez s0, increment
+";
+
+const SPINLOCK: &str = "
+    increment:
+        li t2, 1
+        amoswap.w t3, t2, (s3)
+        bnez t3, increment
+        lw t0, 0(s1)
+        addi t0, t0, 1
+        sw t0, 0(s1)
+        amoswap.w zero, zero, (s3)
+        addi s0, s0, -1
+        bnez s0, increment
+";
+
+const RACY: &str = "
+    increment:
+        lw t0, 0(s1)
+        addi t0, t0, 1
+        sw t0, 0(s1)
+        addi s0, s0, -1
+        bnez s0, increment
+";
+
+#[test]
+fn hart_ids() {
+    let (machine, _) = run("", 4, Scheduling::default());
+    for (id, cpu) in machine.harts.iter().enumerate() {
+        assert_eq!(cpu.registers[10], id as u32);
+    }
+}
+
+/// Taking turns after every instruction, a store from another hart between an «lr.w» and its «sc.w» makes the «sc.w» fail
+#[test]
+fn lr_sc() {
+    for quantum in [1, 2, 3, 7, 100] {
+        let (_, counter) = run(LR_SC, 3, Scheduling::RoundRobin { quantum });
+        assert_eq!(counter, 3 * ITERATIONS, "quantum {quantum}");
+    }
+}
+
+#[test]
+fn spinlock() {
+    for quantum in [1, 5, 100] {
+        let (_, counter) = run(SPINLOCK, 4, Scheduling::RoundRobin { quantum });
+        assert_eq!(counter, 4 * ITERATIONS, "quantum {quantum}");
+    }
+}
+
+/// Two harts taking turns after every instruction both load the same value, so every other increment is lost
+#[test]
+fn data_race() {
+    let (_, counter) = run(RACY, 2, Scheduling::RoundRobin { quantum: 1 });
+    assert_eq!(counter, ITERATIONS);
+    let (_, counter) = run(RACY, 2, Scheduling::RoundRobin { quantum: 1000 });
+    assert_eq!(counter, 2 * ITERATIONS);
+}
+
+#[test]
+fn threads() {
+    for increment in [LR_SC, SPINLOCK] {
+        let (_, counter) = run(increment, 4, Scheduling::Threads);
+        assert_eq!(counter, 4 * ITERATIONS);
+    }
+}
+
+/// Breakpoints stop any hart reaching them, which is then the current one
+#[test]
+fn breakpoints() {
+    let program = "
+        csrr a0, mhartid
+        beqz a0, first
+    others:
+        j others
+    first:
+        j first
+    ";
+    let mut machine = create_rv32(assemble(program).unwrap().contents).unwrap();
+    machine.set_harts(3);
+    machine.scheduling = Scheduling::RoundRobin { quantum: 4 };
+    machine.breakpoints.insert(0x8000_0008);
+    assert_eq!(
+        machine.run(100).unwrap(),
+        StopReason::Breakpoint(0x8000_0008)
+    );
+    assert_eq!(machine.current, 1);
+    assert_eq!(machine.harts[0].pc, 0x8000_000c);
+    // Resetting keeps the harts
+    machine.reset();
+    assert_eq!(machine.harts.len(), 3);
+    assert_eq!(machine.harts[2].csrs.get(MHARTID), 2);
+}
@@ -0,0 +1,100 @@
+//! Source maps of assembled programs and of ELF executables with a DWARF line table
+
+use emu::{
+    assembler::assemble,
+    elf::Executable,
+    machine::memory::constants::RAM_BASE,
+    source_map::{SourceLocation, SourceMap},
+};
+
+const BASE: u32 = RAM_BASE as u32;
+
+/// Built by the Makefile next to it from «lines.S»
+const LINES: &[u8] = include_bytes!("elf/lines");
+
+/// Line the instruction at «address» comes from, if any
+fn line(source_map: &SourceMap, address: u32) -> Option<usize> {
+    source_map.lookup(address).map(|location| location.line)
+}
+
+#[test]
+fn lookup_finds_the_enclosing_range() {
+    let mut source_map = SourceMap::default();
+    assert!(source_map.is_empty());
+    let location = |line| SourceLocation { file: None, line };
+    source_map.insert(BASE, 8, location(1));
+    source_map.insert(BASE + 12, 4, location(2));
+    assert_eq!(line(&source_map, BASE), Some(1));
+    assert_eq!(line(&source_map, BASE + 7), Some(1));
+    assert_eq!(line(&source_map, BASE + 8), None);
+    assert_eq!(line(&source_map, BASE + 15), Some(2));
+    assert_eq!(line(&source_map, BASE + 16), None);
+    assert_eq!(line(&source_map, BASE - 4), None);
+
+    // Inserting at the same address replaces the range
+    source_map.insert(BASE, 4, location(3));
+    assert_eq!(line(&source_map, BASE), Some(3));
+    assert_eq!(line(&source_map, BASE + 4), None);
+    assert_eq!(
+        source_map
+            .iter()
+            .map(|(start, size, location)| (start, size, location.line))
+            .collect::<Vec<_>>(),
+        [(BASE, 4, 3), (BASE + 12, 4, 2)]
+    );
+}
+
+#[test]
+fn assembled_instructions_map_to_their_line() {
+    let program = assemble(
+        "
+        # Comments and labels don't generate anything
+        start:
+            li a0, 3
+            li a1, 0x12345678
+        loop: addi a0, a0, -1
+
+            bnez a0, loop
+            ebreak
+        .data
+        value: .word 42
+        ",
+    )
+    .unwrap();
+    let source_map = &program.source_map;
+    assert_eq!(line(source_map, BASE), Some(4));
+    // Both instructions of the expansion come from the pseudo-instruction
+    assert_eq!(line(source_map, BASE + 4), Some(5));
+    assert_eq!(line(source_map, BASE + 8), Some(5));
+    assert_eq!(line(source_map, BASE + 12), Some(6));
+    assert_eq!(line(source_map, BASE + 16), Some(8));
+    assert_eq!(line(source_map, BASE + 20), Some(9));
+    // Neither the padding of the text section nor data map to anything
+    assert_eq!(line(source_map, BASE + 24), None);
+    assert_eq!(line(source_map, program.symbols["value"] as u32), None);
+    assert!(source_map
+        .iter()
+        .all(|(_, _, location)| location.file.is_none()));
+}
+
+#[test]
+fn executables_map_to_the_lines_of_their_line_table() {
+    let executable = Executable::parse(LINES).unwrap();
+    let source_map = &executable.source_map;
+    assert_eq!(executable.entry, BASE);
+    assert_eq!(executable.symbols["loop"], BASE + 12);
+    assert!(executable.functions.contains(&("_start".to_owned(), BASE)));
+
+    assert_eq!(line(source_map, BASE), Some(6));
+    assert_eq!(line(source_map, BASE + 4), Some(7));
+    assert_eq!(line(source_map, BASE + 8), Some(7));
+    assert_eq!(line(source_map, BASE + 12), Some(9));
+    assert_eq!(line(source_map, BASE + 16), Some(10));
+    assert_eq!(line(source_map, BASE + 20), Some(11));
+    assert_eq!(line(source_map, BASE + 24), None);
+    let file = source_map.lookup(BASE).unwrap().file.as_deref();
+    assert!(
+        file.is_some_and(|file| file.ends_with("lines.S")),
+        "{file:?}"
+    );
+}
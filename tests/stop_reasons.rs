@@ -0,0 +1,132 @@
+use emu::{
+    assembler::assemble,
+    create_rv32,
+    machine::{
+        cpu::trap::{Exception, Trap},
+        Machine, StopReason,
+    },
+};
+
+fn machine(program: &str) -> Machine {
+    create_rv32(assemble(program).unwrap().contents).unwrap()
+}
+
+#[test]
+fn ebreak() {
+    let mut machine = machine("li a0, 1\nebreak");
+    assert_eq!(machine.run(100).unwrap(), StopReason::Ebreak);
+    // The trap isn't taken, so the hart is left on the ebreak
+    assert_eq!(machine.harts[0].pc, 0x8000_0004);
+}
+
+#[test]
+fn ecall_exit() {
+    let mut machine = machine("li a0, 7\nli a7, 93\necall");
+    assert_eq!(machine.run(100).unwrap(), StopReason::EcallExit(7));
+}
+
+#[test]
+fn other_ecall() {
+    let mut machine = machine("li a7, 64\necall");
+    assert_eq!(
+        machine.run(100).unwrap(),
+        StopReason::UnhandledTrap {
+            pc: 0x8000_0004,
+            trap: Trap::new(Exception::EnvironmentCallFromM, 0)
+        }
+    );
+}
+
+#[test]
+fn wfi() {
+    let mut machine = machine("nop\nwfi");
+    assert_eq!(machine.run(100).unwrap(), StopReason::WaitForInterrupt);
+}
+
+#[test]
+fn instruction_limit() {
+    let mut machine = machine("loop:\nj loop");
+    assert_eq!(machine.run(100).unwrap(), StopReason::InstructionLimit);
+    assert_eq!(machine.stopped, None);
+}
+
+#[test]
+fn breakpoint() {
+    let mut machine = machine("li a0, 0\nloop:\naddi a0, a0, 1\nj loop");
+    machine.breakpoints.insert(0x8000_0004);
+    assert_eq!(
+        machine.run(100).unwrap(),
+        StopReason::Breakpoint(0x8000_0004)
+    );
+    // Resuming from a breakpoint goes past it, up to the next time it's reached
+    assert_eq!(
+        machine.run(100).unwrap(),
+        StopReason::Breakpoint(0x8000_0004)
+    );
+    assert_eq!(machine.harts[0].registers[10], 1);
+}
+
+#[test]
+fn zero_data_is_illegal() {
+    // Running into data is an illegal instruction, not the end of the program
+    let mut machine = machine("nop\n.word 0");
+    assert_eq!(
+        machine.run(100).unwrap(),
+        StopReason::IllegalInstruction {
+            pc: 0x8000_0004,
+            raw: 0
+        }
+    );
+}
+
+#[test]
+fn handled_traps_dont_stop() {
+    let mut machine = machine(
+        "
+        la t0, handler
+        csrw mtvec, t0
+        ebreak
+        .word 0
+    handler:
+        li a0, 3
+        li a7, 93
+        # Exits through the ecall below, once there is no handler left
+        csrw mtvec, zero
+        ecall
+    ",
+    );
+    assert_eq!(machine.run(100).unwrap(), StopReason::EcallExit(3));
+}
+
+#[test]
+fn run_until() {
+    let mut machine = machine("li a0, 0\nloop:\naddi a0, a0, 1\nj loop");
+    let reason = machine
+        .run_until(100, |machine, _| machine.harts[0].registers[10] == 5)
+        .unwrap();
+    assert_eq!(reason, StopReason::Condition);
+    assert_eq!(machine.harts[0].registers[10], 5);
+}
+
+#[test]
+fn reset() {
+    let mut machine = machine(
+        "
+        la t0, handler
+        csrw mtvec, t0
+        li a0, 7
+        li a7, 93
+    handler:
+        csrw mtvec, zero
+        ecall
+    ",
+    );
+    assert_eq!(machine.run(100).unwrap(), StopReason::EcallExit(7));
+    machine.reset();
+    assert_eq!(machine.stopped, None);
+    assert_eq!(machine.harts[0].pc, 0x8000_0000);
+    assert_eq!(machine.harts[0].registers, [0; 32]);
+    assert_eq!(machine.harts[0].trap_handler(), None);
+    // The program runs again from the start
+    assert_eq!(machine.run(100).unwrap(), StopReason::EcallExit(7));
+}